@@ -0,0 +1,84 @@
+//! Synchronous (blocking) convenience wrappers of the asynchronous API.
+//!
+//! These are intended for scripts and tests that do not run inside a fibers executor.
+//! Each call runs the request on a temporary `InPlaceExecutor` and blocks the current thread until it completes.
+//!
+//! Note that connection providers driven by other futures (e.g., `ConnectionPool`)
+//! must be running on another executor while the calls are blocked.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # extern crate fibers_http_client;
+//! # extern crate url;
+//! use fibers_http_client::connection::Oneshot;
+//! use fibers_http_client::Client;
+//! use url::Url;
+//!
+//! # fn main() {
+//! let url = Url::parse("http://localhost/foo/bar").unwrap();
+//! let mut client = Client::new(Oneshot);
+//! let response = client.get_sync(&url).unwrap();
+//! println!("STATUS: {:?}", response.status_code());
+//! # }
+//! ```
+use fibers::{Executor, InPlaceExecutor, Spawn};
+use futures::Future;
+use httpcodec::Response;
+use trackable::error::ErrorKindExt;
+use url::Url;
+
+use connection::AcquireConnection;
+use {Client, Error, ErrorKind, Result};
+
+/// Runs the future to completion on a temporary executor, blocking the current thread.
+pub fn block_on<F, T>(future: F) -> Result<T>
+where
+    F: Future<Item = T, Error = Error> + Send + 'static,
+    T: Send + 'static,
+{
+    let mut executor = track!(InPlaceExecutor::new().map_err(Error::from))?;
+    let monitor = executor.spawn_monitor(future);
+    let result = track!(executor.run_fiber(monitor).map_err(Error::from))?;
+    result.map_err(|e| {
+        e.unwrap_or_else(|| track!(ErrorKind::Other.cause("The request fiber aborted")).into())
+    })
+}
+
+impl<C> Client<C>
+where
+    C: AcquireConnection + Clone + Send + 'static,
+    C::Connection: Send + 'static,
+    C::Future: Send + 'static,
+{
+    /// Executes `GET` request and blocks the current thread until the response is received.
+    ///
+    /// This is equivalent to `block_on(client.request(url).get())`.
+    pub fn get_sync(&mut self, url: &Url) -> Result<Response<Vec<u8>>> {
+        let future = self.request(url).get();
+        track!(block_on(future))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use connection::Mock;
+    use {Client, ErrorKind};
+
+    #[test]
+    fn get_sync_works() {
+        let mock = Mock::new();
+        mock.push_response("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo");
+        let mut client = Client::new(mock);
+        let url = Url::parse("http://example.com/").unwrap();
+
+        let response = client.get_sync(&url).unwrap();
+        assert_eq!(response.body(), b"foo");
+
+        // No response is queued
+        let e = client.get_sync(&url).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::UnexpectedEos);
+    }
+}
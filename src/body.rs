@@ -0,0 +1,738 @@
+//! Decoders for HTTP response bodies.
+//!
+//! The decoders in this module implement [`BodyDecode`] trait (i.e., they handle the framing of bodies by themselves),
+//! so they should be passed to [`RequestBuilder::body_decoder`] method.
+//!
+//! [`BodyDecode`]: https://docs.rs/httpcodec/0.2/httpcodec/trait.BodyDecode.html
+//! [`RequestBuilder::body_decoder`]: ../struct.RequestBuilder.html#method.body_decoder
+use bytecodec::bytes::RemainingBytesDecoder;
+use bytecodec::{ByteCount, Decode, Eos, ErrorKind, Result};
+use httpcodec::{BodyDecode, BodyDecoder, Header};
+use std::cmp;
+use std::mem;
+use std::str;
+use trackable::error::ErrorKindExt;
+
+const MAX_LINE_SIZE: usize = 8 * 1024;
+
+/// A body decoded by [`TrailersDecoder`] with the trailer fields of the response.
+///
+/// [`TrailersDecoder`]: ./struct.TrailersDecoder.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WithTrailers<T> {
+    body: T,
+    trailers: Vec<(String, String)>,
+}
+impl<T> WithTrailers<T> {
+    /// Returns a reference to the body.
+    pub fn body(&self) -> &T {
+        &self.body
+    }
+
+    /// Takes ownership of the instance, and returns the body.
+    pub fn into_body(self) -> T {
+        self.body
+    }
+
+    /// Returns the trailer fields.
+    ///
+    /// If the body was not chunked, this returns an empty slice.
+    pub fn trailers(&self) -> &[(String, String)] {
+        &self.trailers
+    }
+
+    /// Returns the value of the first trailer field that has the given name (case-insensitive).
+    pub fn get_trailer(&self, name: &str) -> Option<&str> {
+        self.trailers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// A body decoder that keeps the trailer fields of chunked responses.
+///
+/// The body is decoded by the inner decoder `D`, and
+/// the trailer fields are returned alongside the decoded body as a [`WithTrailers`].
+///
+/// # Examples
+///
+/// ```no_run
+/// # extern crate bytecodec;
+/// # extern crate fibers_global;
+/// # extern crate fibers_http_client;
+/// # extern crate url;
+/// use bytecodec::bytes::RemainingBytesDecoder;
+/// use fibers_http_client::body::TrailersDecoder;
+/// use fibers_http_client::connection::Oneshot;
+/// use fibers_http_client::Client;
+/// use url::Url;
+///
+/// # fn main() {
+/// let url = Url::parse("http://localhost/foo/bar").unwrap();
+/// let mut client = Client::new(Oneshot);
+/// let future = client
+///     .request(&url)
+///     .body_decoder(TrailersDecoder::new(RemainingBytesDecoder::new()))
+///     .get();
+///
+/// let response = fibers_global::execute(future).unwrap();
+/// println!("grpc-status: {:?}", response.body().get_trailer("grpc-status"));
+/// # }
+/// ```
+///
+/// [`WithTrailers`]: ./struct.WithTrailers.html
+#[derive(Debug, Default)]
+pub struct TrailersDecoder<D> {
+    inner: D,
+    framing: Framing,
+    trailers: Vec<(String, String)>,
+}
+impl<D: Decode> TrailersDecoder<D> {
+    /// Makes a new `TrailersDecoder` instance.
+    pub fn new(inner: D) -> Self {
+        TrailersDecoder {
+            inner,
+            framing: Framing::UntilEos,
+            trailers: Vec::new(),
+        }
+    }
+
+    fn decode_chunked(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut offset = 0;
+        loop {
+            let state = if let Framing::Chunked(ref mut state) = self.framing {
+                state
+            } else {
+                unreachable!()
+            };
+            match *state {
+                ChunkState::Size(ref mut line) => {
+                    if !track!(read_line(line, buf, &mut offset))? {
+                        return Ok(offset);
+                    }
+                    let size = track!(parse_chunk_size(line))?;
+                    *state = if size == 0 {
+                        ChunkState::Trailer(Vec::new())
+                    } else {
+                        ChunkState::Data(size)
+                    };
+                }
+                ChunkState::Data(ref mut remaining) => {
+                    let n = cmp::min(buf.len() - offset, *remaining as usize);
+                    let size =
+                        track!(self.inner.decode(&buf[offset..offset + n], Eos::new(false)))?;
+                    offset += size;
+                    *remaining -= size as u64;
+                    if *remaining == 0 {
+                        *state = ChunkState::DataEnd(Vec::new());
+                    } else if size == 0 {
+                        return Ok(offset);
+                    }
+                }
+                ChunkState::DataEnd(ref mut line) => {
+                    if !track!(read_line(line, buf, &mut offset))? {
+                        return Ok(offset);
+                    }
+                    track_assert!(line.is_empty(), ErrorKind::InvalidInput);
+                    *state = ChunkState::Size(Vec::new());
+                }
+                ChunkState::Trailer(ref mut line) => {
+                    if !track!(read_line(line, buf, &mut offset))? {
+                        return Ok(offset);
+                    }
+                    if line.is_empty() {
+                        track!(self.inner.decode(&[][..], Eos::new(true)))?;
+                        *state = ChunkState::Done;
+                        return Ok(offset);
+                    }
+                    let field = track!(parse_trailer(line))?;
+                    self.trailers.push(field);
+                    line.clear();
+                }
+                ChunkState::Done => return Ok(offset),
+            }
+        }
+    }
+}
+impl<D: Decode> Decode for TrailersDecoder<D> {
+    type Item = WithTrailers<D::Item>;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> Result<usize> {
+        match self.framing {
+            Framing::UntilEos => track!(self.inner.decode(buf, eos)),
+            Framing::Length(ref mut remaining) => {
+                let n = cmp::min(buf.len() as u64, *remaining) as usize;
+                let inner_eos = Eos::with_remaining_bytes(ByteCount::Finite(*remaining - n as u64));
+                let size = track!(self.inner.decode(&buf[..n], inner_eos))?;
+                *remaining -= size as u64;
+                Ok(size)
+            }
+            Framing::Chunked(_) => {
+                let size = track!(self.decode_chunked(buf))?;
+                if !self.is_idle() {
+                    track_assert!(!eos.is_reached(), ErrorKind::UnexpectedEos);
+                }
+                Ok(size)
+            }
+        }
+    }
+
+    fn finish_decoding(&mut self) -> Result<Self::Item> {
+        let body = track!(self.inner.finish_decoding())?;
+        let trailers = mem::take(&mut self.trailers);
+        self.framing = Framing::UntilEos;
+        Ok(WithTrailers { body, trailers })
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        match self.framing {
+            Framing::Chunked(ChunkState::Done) => ByteCount::Finite(0),
+            Framing::Chunked(_) => ByteCount::Unknown,
+            _ => self.inner.requiring_bytes(),
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        match self.framing {
+            Framing::Chunked(ref state) => *state == ChunkState::Done,
+            _ => self.inner.is_idle(),
+        }
+    }
+}
+impl<D: Decode> BodyDecode for TrailersDecoder<D> {
+    fn initialize(&mut self, header: &Header) -> Result<()> {
+        self.trailers.clear();
+        self.framing = Framing::UntilEos;
+        for field in header.fields() {
+            if field.name().eq_ignore_ascii_case("content-length") {
+                let size = track!(field
+                    .value()
+                    .parse()
+                    .map_err(|e| ErrorKind::InvalidInput.cause(e)))?;
+                self.framing = Framing::Length(size);
+                if size == 0 {
+                    track!(self.inner.decode(&[][..], Eos::new(true)))?;
+                }
+                break;
+            } else if field.name().eq_ignore_ascii_case("transfer-encoding") {
+                track_assert_eq!(field.value(), "chunked", ErrorKind::Other);
+                self.framing = Framing::Chunked(ChunkState::Size(Vec::new()));
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A body decoded by [`ContentTypeDecoder`].
+///
+/// [`ContentTypeDecoder`]: ./struct.ContentTypeDecoder.html
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Body {
+    /// A `text/*` body converted from its charset.
+    Text(String),
+
+    /// An `application/json` (or `*/*+json`) body.
+    ///
+    /// The JSON text is not parsed, so it should be deserialized by the caller (e.g., with `serde_json`).
+    Json(String),
+
+    /// Any other body as it is.
+    Bytes(Vec<u8>),
+}
+
+/// A body decoder that chooses the representation of the body by `Content-Type` header of the response.
+///
+/// The body is decoded as follows:
+/// - `text/*`: [`Body::Text`], converted from the charset specified by the `charset` parameter
+///   (`UTF-8` by default, see [`TextDecoder`] for the supported charsets)
+/// - `application/json` and `*/*+json`: [`Body::Json`], which must be UTF-8
+/// - Otherwise: [`Body::Bytes`]
+///
+/// Text bodies in unsupported charsets are returned as [`Body::Bytes`].
+/// If a body is not valid in its charset, decoding fails with an `ErrorKind::InvalidInput` error.
+///
+/// This is set by [`RequestBuilder::auto_decoder`] method.
+///
+/// [`Body::Text`]: ./enum.Body.html#variant.Text
+/// [`Body::Json`]: ./enum.Body.html#variant.Json
+/// [`Body::Bytes`]: ./enum.Body.html#variant.Bytes
+/// [`TextDecoder`]: ./struct.TextDecoder.html
+/// [`RequestBuilder::auto_decoder`]: ../struct.RequestBuilder.html#method.auto_decoder
+#[derive(Debug, Default)]
+pub struct ContentTypeDecoder {
+    inner: BodyDecoder<RemainingBytesDecoder>,
+    kind: BodyKind,
+}
+impl ContentTypeDecoder {
+    /// Makes a new `ContentTypeDecoder` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+impl Decode for ContentTypeDecoder {
+    type Item = Body;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> Result<usize> {
+        track!(self.inner.decode(buf, eos))
+    }
+
+    fn finish_decoding(&mut self) -> Result<Self::Item> {
+        let bytes = track!(self.inner.finish_decoding())?;
+        let body = match self.kind {
+            BodyKind::Text(charset) => Body::Text(track!(charset.decode(bytes, false))?),
+            BodyKind::Json => Body::Json(track!(Charset::Utf8.decode(bytes, false))?),
+            BodyKind::Bytes => Body::Bytes(bytes),
+        };
+        Ok(body)
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.inner.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+}
+impl BodyDecode for ContentTypeDecoder {
+    fn initialize(&mut self, header: &Header) -> Result<()> {
+        self.kind = header
+            .get_field("Content-Type")
+            .map_or(BodyKind::Bytes, BodyKind::from_content_type);
+        track!(self.inner.initialize(header))
+    }
+}
+
+/// A body decoder that converts text bodies into `String` according to the charset of the response.
+///
+/// The charset is taken from the `charset` parameter of `Content-Type` header.
+/// The following charsets are supported:
+/// - `UTF-8`
+/// - `ISO-8859-1` (and its aliases such as `Latin1`)
+/// - `windows-1252`
+/// - `US-ASCII` (decoded as `windows-1252`, like web browsers do)
+/// - Any other charset of the [Encoding Standard] (e.g., `Shift_JIS`), if `encoding` feature is enabled
+///
+/// If the charset is absent or unsupported, the body is decoded as UTF-8.
+/// By default, invalid bytes make the decoding fail with an `ErrorKind::InvalidInput` error.
+/// If [`lossy`] is enabled, they are replaced with `U+FFFD REPLACEMENT CHARACTER` instead.
+///
+/// # Examples
+///
+/// ```no_run
+/// # extern crate fibers_global;
+/// # extern crate fibers_http_client;
+/// # extern crate url;
+/// use fibers_http_client::body::TextDecoder;
+/// use fibers_http_client::connection::Oneshot;
+/// use fibers_http_client::Client;
+/// use url::Url;
+///
+/// # fn main() {
+/// let url = Url::parse("http://localhost/foo/bar").unwrap();
+/// let mut client = Client::new(Oneshot);
+/// let future = client
+///     .request(&url)
+///     .body_decoder(TextDecoder::new().lossy(true))
+///     .get();
+///
+/// let response = fibers_global::execute(future).unwrap();
+/// println!("{}", response.body());
+/// # }
+/// ```
+///
+/// [`lossy`]: #method.lossy
+/// [Encoding Standard]: https://encoding.spec.whatwg.org/
+#[derive(Debug, Default)]
+pub struct TextDecoder {
+    inner: BodyDecoder<RemainingBytesDecoder>,
+    charset: Charset,
+    lossy: bool,
+}
+impl TextDecoder {
+    /// Makes a new `TextDecoder` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets whether to replace invalid bytes with `U+FFFD REPLACEMENT CHARACTER` instead of failing.
+    ///
+    /// The default value is `false`.
+    pub fn lossy(mut self, enabled: bool) -> Self {
+        self.lossy = enabled;
+        self
+    }
+}
+impl Decode for TextDecoder {
+    type Item = String;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> Result<usize> {
+        track!(self.inner.decode(buf, eos))
+    }
+
+    fn finish_decoding(&mut self) -> Result<Self::Item> {
+        let bytes = track!(self.inner.finish_decoding())?;
+        track!(self.charset.decode(bytes, self.lossy))
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.inner.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+}
+impl BodyDecode for TextDecoder {
+    fn initialize(&mut self, header: &Header) -> Result<()> {
+        self.charset = header
+            .get_field("Content-Type")
+            .and_then(|content_type| {
+                let (_, charset) = parse_content_type(content_type);
+                charset.and_then(|name| Charset::from_name(&name))
+            })
+            .unwrap_or(Charset::Utf8);
+        track!(self.inner.initialize(header))
+    }
+}
+
+/// A body decoder that returns the body of the response as `bytes::Bytes`.
+///
+/// The body is copied once from the read buffer of the connection (which is reused for the subsequent responses),
+/// and the copy is handed over to `Bytes` as it is, so that the body can be shared without further copies.
+///
+/// This is set by [`RequestBuilder::bytes_decoder`] method, and available only if `bytes` feature is enabled.
+///
+/// [`RequestBuilder::bytes_decoder`]: ../struct.RequestBuilder.html#method.bytes_decoder
+#[cfg(feature = "bytes")]
+#[derive(Debug, Default)]
+pub struct BytesBodyDecoder {
+    inner: BodyDecoder<RemainingBytesDecoder>,
+}
+#[cfg(feature = "bytes")]
+impl BytesBodyDecoder {
+    /// Makes a new `BytesBodyDecoder` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+#[cfg(feature = "bytes")]
+impl Decode for BytesBodyDecoder {
+    type Item = bytes::Bytes;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> Result<usize> {
+        track!(self.inner.decode(buf, eos))
+    }
+
+    fn finish_decoding(&mut self) -> Result<Self::Item> {
+        track!(self.inner.finish_decoding()).map(bytes::Bytes::from)
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.inner.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+}
+#[cfg(feature = "bytes")]
+impl BodyDecode for BytesBodyDecoder {
+    fn initialize(&mut self, header: &Header) -> Result<()> {
+        track!(self.inner.initialize(header))
+    }
+}
+
+/// Returns the (lowercased) media type and `charset` parameter of the given `Content-Type` value.
+fn parse_content_type(content_type: &str) -> (String, Option<String>) {
+    let mut params = content_type.split(';');
+    let media_type = params.next().unwrap_or("").trim().to_ascii_lowercase();
+    let charset = params
+        .filter_map(|param| param.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_ascii_lowercase());
+    (media_type, charset)
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum BodyKind {
+    Text(Charset),
+    Json,
+    #[default]
+    Bytes,
+}
+impl BodyKind {
+    fn from_content_type(content_type: &str) -> Self {
+        let (media_type, charset) = parse_content_type(content_type);
+        if media_type == "application/json" || media_type.ends_with("+json") {
+            return BodyKind::Json;
+        }
+        if !media_type.starts_with("text/") {
+            return BodyKind::Bytes;
+        }
+        match charset {
+            None => BodyKind::Text(Charset::Utf8),
+            Some(name) => Charset::from_name(&name).map_or(BodyKind::Bytes, BodyKind::Text),
+        }
+    }
+}
+
+/// The characters of `windows-1252` for `0x80..0xA0` (the other bytes are the same as Unicode code points).
+const WINDOWS_1252: [char; 32] = [
+    '\u{20AC}', '\u{81}', '\u{201A}', '\u{192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{2C6}', '\u{2030}', '\u{160}', '\u{2039}', '\u{152}', '\u{8D}', '\u{17D}', '\u{8F}',
+    '\u{90}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{2DC}', '\u{2122}', '\u{161}', '\u{203A}', '\u{153}', '\u{9D}', '\u{17E}', '\u{178}',
+];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum Charset {
+    #[default]
+    Utf8,
+    Latin1,
+    Windows1252,
+    #[cfg(feature = "encoding")]
+    Other(&'static encoding_rs::Encoding),
+}
+impl Charset {
+    /// Returns the charset that has the given (lowercased) name, if supported.
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "utf-8" | "utf8" => Some(Charset::Utf8),
+            "iso-8859-1" | "iso8859-1" | "iso_8859-1" | "latin1" | "l1" => Some(Charset::Latin1),
+            "windows-1252" | "cp1252" | "us-ascii" | "ascii" => Some(Charset::Windows1252),
+            #[cfg(feature = "encoding")]
+            _ => encoding_rs::Encoding::for_label(name.as_bytes()).map(Charset::Other),
+            #[cfg(not(feature = "encoding"))]
+            _ => None,
+        }
+    }
+
+    fn decode(self, bytes: Vec<u8>, lossy: bool) -> Result<String> {
+        match self {
+            Charset::Utf8 if lossy => Ok(String::from_utf8_lossy(&bytes).into_owned()),
+            Charset::Utf8 => String::from_utf8(bytes)
+                .map_err(|e| track!(ErrorKind::InvalidInput.cause(e)).into()),
+            Charset::Latin1 => Ok(bytes.into_iter().map(char::from).collect()),
+            Charset::Windows1252 => Ok(bytes
+                .into_iter()
+                .map(|b| match b {
+                    0x80..=0x9F => WINDOWS_1252[usize::from(b - 0x80)],
+                    _ => char::from(b),
+                })
+                .collect()),
+            #[cfg(feature = "encoding")]
+            Charset::Other(encoding) if lossy => {
+                Ok(encoding.decode_without_bom_handling(&bytes).0.into_owned())
+            }
+            #[cfg(feature = "encoding")]
+            Charset::Other(encoding) => encoding
+                .decode_without_bom_handling_and_without_replacement(&bytes)
+                .map(|text| text.into_owned())
+                .ok_or_else(|| {
+                    track!(ErrorKind::InvalidInput
+                        .cause(format!("The body is not valid {} text", encoding.name())))
+                    .into()
+                }),
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+enum Framing {
+    #[default]
+    UntilEos,
+    Length(u64),
+    Chunked(ChunkState),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ChunkState {
+    Size(Vec<u8>),
+    Data(u64),
+    DataEnd(Vec<u8>),
+    Trailer(Vec<u8>),
+    Done,
+}
+
+/// Appends the bytes of `buf` to `line` until CRLF is found.
+///
+/// Returns `true` if a complete line (excluding the CRLF) has been read.
+fn read_line(line: &mut Vec<u8>, buf: &[u8], offset: &mut usize) -> Result<bool> {
+    while *offset < buf.len() {
+        let b = buf[*offset];
+        *offset += 1;
+        if b == b'\n' {
+            track_assert_eq!(line.pop(), Some(b'\r'), ErrorKind::InvalidInput);
+            return Ok(true);
+        }
+        line.push(b);
+        track_assert!(line.len() <= MAX_LINE_SIZE, ErrorKind::InvalidInput; line.len());
+    }
+    Ok(false)
+}
+
+fn parse_chunk_size(line: &[u8]) -> Result<u64> {
+    let line = track!(str::from_utf8(line).map_err(|e| ErrorKind::InvalidInput.cause(e)))?;
+    let size = line.split(';').next().expect("never fails").trim();
+    let size =
+        track!(u64::from_str_radix(size, 16).map_err(|e| ErrorKind::InvalidInput.cause(e)); line)?;
+    Ok(size)
+}
+
+fn parse_trailer(line: &[u8]) -> Result<(String, String)> {
+    let line = track!(str::from_utf8(line).map_err(|e| ErrorKind::InvalidInput.cause(e)))?;
+    let colon = track_assert_some!(line.find(':'), ErrorKind::InvalidInput; line);
+    let name = line[..colon].trim();
+    track_assert!(!name.is_empty(), ErrorKind::InvalidInput; line);
+    Ok((name.to_owned(), line[colon + 1..].trim().to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use bytecodec::io::IoDecodeExt;
+    use httpcodec::ResponseDecoder;
+
+    use super::*;
+
+    #[test]
+    fn trailers_decoder_works() {
+        let mut decoder = ResponseDecoder::new(TrailersDecoder::new(RemainingBytesDecoder::new()));
+        let input = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+                      3\r\nfoo\r\n3;ext=1\r\nbar\r\n0\r\ngrpc-status: 0\r\nfoo: bar\r\n\r\n";
+        let response = decoder.decode_exact(&input[..]).unwrap();
+        assert_eq!(response.body().body(), b"foobar");
+        assert_eq!(response.body().get_trailer("Grpc-Status"), Some("0"));
+        assert_eq!(response.body().trailers().len(), 2);
+
+        let input = b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo";
+        let response = decoder.decode_exact(&input[..]).unwrap();
+        assert_eq!(response.body().body(), b"foo");
+        assert!(response.body().trailers().is_empty());
+    }
+
+    #[test]
+    fn text_decoder_works() {
+        let decode = |decoder: TextDecoder, content_type: &str, body: &[u8]| {
+            let mut input = format!(
+                "HTTP/1.1 200 OK\r\n{}Content-Length: {}\r\n\r\n",
+                content_type,
+                body.len()
+            )
+            .into_bytes();
+            input.extend_from_slice(body);
+            let mut decoder = ResponseDecoder::new(decoder);
+            decoder.decode_exact(&input[..]).map(|r| r.into_body())
+        };
+        let strict = TextDecoder::new;
+        let lossy = || TextDecoder::new().lossy(true);
+
+        assert_eq!(decode(strict(), "", "café".as_bytes()).unwrap(), "café");
+        assert_eq!(
+            decode(
+                strict(),
+                "Content-Type: text/plain; charset=latin1\r\n",
+                b"caf\xe9"
+            )
+            .unwrap(),
+            "café"
+        );
+        assert_eq!(
+            decode(
+                strict(),
+                "Content-Type: text/plain; charset=windows-1252\r\n",
+                b"\x93caf\xe9\x94 \x80"
+            )
+            .unwrap(),
+            "\u{201C}café\u{201D} \u{20AC}"
+        );
+
+        // Unsupported charsets are decoded as UTF-8
+        let content_type = "Content-Type: text/plain; charset=x-unknown\r\n";
+        assert!(decode(strict(), content_type, b"\x82\xa0").is_err());
+        assert_eq!(
+            decode(lossy(), content_type, b"a\x82\xa0").unwrap(),
+            "a\u{FFFD}\u{FFFD}"
+        );
+
+        let content_type = "Content-Type: text/plain; charset=Shift_JIS\r\n";
+        #[cfg(not(feature = "encoding"))]
+        assert!(decode(strict(), content_type, b"\x82\xa0").is_err());
+        #[cfg(feature = "encoding")]
+        {
+            assert_eq!(
+                decode(strict(), content_type, b"\x82\xa0").unwrap(),
+                "\u{3042}"
+            );
+            assert!(decode(strict(), content_type, b"a\x82").is_err());
+            assert_eq!(
+                decode(lossy(), content_type, b"a\x82").unwrap(),
+                "a\u{FFFD}"
+            );
+        }
+        assert_eq!(decode(lossy(), "", b"\xffabc").unwrap(), "\u{FFFD}abc");
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn bytes_body_decoder_works() {
+        let mut decoder = ResponseDecoder::new(BytesBodyDecoder::new());
+        let input = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nfoo\r\n0\r\n\r\n";
+        let response = decoder.decode_exact(&input[..]).unwrap();
+        assert_eq!(response.into_body(), bytes::Bytes::from_static(b"foo"));
+    }
+
+    #[test]
+    fn content_type_decoder_works() {
+        let decode = |content_type: &str, body: &[u8]| {
+            let mut decoder = ResponseDecoder::new(ContentTypeDecoder::new());
+            let mut input = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+                content_type,
+                body.len()
+            )
+            .into_bytes();
+            input.extend_from_slice(body);
+            decoder.decode_exact(&input[..]).map(|r| r.into_body())
+        };
+
+        assert_eq!(
+            decode("text/plain", "café".as_bytes()).unwrap(),
+            Body::Text("café".to_owned())
+        );
+        assert_eq!(
+            decode("Text/HTML; Charset=\"ISO-8859-1\"", b"caf\xe9").unwrap(),
+            Body::Text("café".to_owned())
+        );
+        assert_eq!(
+            decode("text/plain; charset=x-unknown", b"\x82\xa0").unwrap(),
+            Body::Bytes(b"\x82\xa0".to_vec())
+        );
+        #[cfg(feature = "encoding")]
+        assert_eq!(
+            decode("text/plain; charset=shift_jis", b"\x82\xa0").unwrap(),
+            Body::Text("\u{3042}".to_owned())
+        );
+        assert!(decode("text/plain; charset=utf-8", b"\xff").is_err());
+        assert_eq!(
+            decode("application/json; charset=utf-8", b"{}").unwrap(),
+            Body::Json("{}".to_owned())
+        );
+        assert_eq!(
+            decode("application/problem+json", b"{}").unwrap(),
+            Body::Json("{}".to_owned())
+        );
+        assert_eq!(
+            decode("image/png", b"\x89PNG").unwrap(),
+            Body::Bytes(b"\x89PNG".to_vec())
+        );
+
+        // Without `Content-Type`
+        let mut decoder = ResponseDecoder::new(ContentTypeDecoder::new());
+        let input = b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo";
+        let response = decoder.decode_exact(&input[..]).unwrap();
+        assert_eq!(response.into_body(), Body::Bytes(b"foo".to_vec()));
+    }
+}
@@ -0,0 +1,481 @@
+use futures::{Async, Future, Poll};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use trackable::error::ErrorKindExt;
+
+use connection::{AcquireConnection, Connection};
+use metrics::{CircuitBreakerMetrics, MetricBuilder};
+use request::is_connect_error;
+use resolver::BoxResolveFuture;
+use {Error, ErrorKind};
+
+/// [`CircuitBreaker`] builder.
+///
+/// [`CircuitBreaker`]: ./struct.CircuitBreaker.html
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerBuilder {
+    failure_threshold: usize,
+    window: Duration,
+    cooldown: Duration,
+    metrics: MetricBuilder,
+}
+impl CircuitBreakerBuilder {
+    /// Makes a new `CircuitBreakerBuilder` instance with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the number of consecutive failures that opens the circuit of a host.
+    ///
+    /// Values less than `1` are treated as `1`.
+    ///
+    /// The default value is `5`.
+    pub fn failure_threshold(&mut self, n: usize) -> &mut Self {
+        self.failure_threshold = n;
+        self
+    }
+
+    /// Sets the window in which consecutive failures are counted.
+    ///
+    /// If the first of the consecutive failures is older than the window, the count restarts.
+    ///
+    /// The default value is `Duration::from_secs(10)`.
+    pub fn window(&mut self, window: Duration) -> &mut Self {
+        self.window = window;
+        self
+    }
+
+    /// Sets the duration for which an open circuit rejects acquisitions before allowing a trial.
+    ///
+    /// The default value is `Duration::from_secs(30)`.
+    pub fn cooldown(&mut self, cooldown: Duration) -> &mut Self {
+        self.cooldown = cooldown;
+        self
+    }
+
+    /// Sets the metrics builder used by the circuit breaker.
+    ///
+    /// The default value is `MetricBuilder::new()`.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(&mut self, metrics: MetricBuilder) -> &mut Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Makes a new [`CircuitBreaker`] instance that wraps `inner` with the given settings.
+    ///
+    /// [`CircuitBreaker`]: ./struct.CircuitBreaker.html
+    pub fn finish<C: AcquireConnection>(&self, inner: C) -> CircuitBreaker<C> {
+        let breaker = Breaker(Arc::new(BreakerInner {
+            circuits: Mutex::new(HashMap::new()),
+            failure_threshold: self.failure_threshold.max(1),
+            window: self.window,
+            cooldown: self.cooldown,
+            metrics: CircuitBreakerMetrics::new(self.metrics.clone()),
+        }));
+        CircuitBreaker { inner, breaker }
+    }
+}
+impl Default for CircuitBreakerBuilder {
+    fn default() -> Self {
+        CircuitBreakerBuilder {
+            failure_threshold: 5,
+            window: Duration::from_secs(10),
+            cooldown: Duration::from_secs(30),
+            metrics: MetricBuilder::new(),
+        }
+    }
+}
+
+/// An implementation of [`AcquireConnection`] that stops acquiring connections to failing hosts for a while.
+///
+/// A circuit is maintained for each host (i.e., TCP address) as follows:
+/// - Closed: acquisitions are passed to the inner provider.
+///   If connecting or requests fail consecutively [`failure_threshold`] times within [`window`],
+///   the circuit becomes open.
+/// - Open: acquisitions fail immediately with an `ErrorKind::TemporarilyUnavailable` error.
+///   After [`cooldown`], the circuit becomes half-open.
+/// - Half-open: only one acquisition is passed as a trial, and the others are rejected.
+///   The circuit becomes closed if the trial succeeds, and open again otherwise.
+///
+/// A request is regarded as succeeded only if its response has been received entirely.
+/// Otherwise (e.g., the connection is released due to I/O errors, malformed responses, timeouts or cancellation),
+/// it is regarded as failed.
+///
+/// [`AcquireConnection`]: ./trait.AcquireConnection.html
+/// [`failure_threshold`]: ./struct.CircuitBreakerBuilder.html#method.failure_threshold
+/// [`window`]: ./struct.CircuitBreakerBuilder.html#method.window
+/// [`cooldown`]: ./struct.CircuitBreakerBuilder.html#method.cooldown
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker<C> {
+    inner: C,
+    breaker: Breaker,
+}
+impl<C: AcquireConnection> CircuitBreaker<C> {
+    /// Makes a new `CircuitBreaker` instance with the default settings.
+    ///
+    /// If you want to customize the settings, please use [`CircuitBreakerBuilder`] instead.
+    ///
+    /// [`CircuitBreakerBuilder`]: ./struct.CircuitBreakerBuilder.html
+    pub fn new(inner: C) -> Self {
+        CircuitBreakerBuilder::new().finish(inner)
+    }
+
+    /// Returns a reference to the metrics of the circuit breaker.
+    pub fn metrics(&self) -> &CircuitBreakerMetrics {
+        &self.breaker.0.metrics
+    }
+}
+impl<C: AcquireConnection> AcquireConnection for CircuitBreaker<C> {
+    type Connection = CircuitBreakerConnection<C::Connection>;
+    type Future = CircuitBreakerConnect<C::Future>;
+
+    fn acquire_connection(&mut self, addr: SocketAddr) -> Self::Future {
+        let future = match self.breaker.admit(addr) {
+            Ok(()) => Ok(self.inner.acquire_connection(addr)),
+            Err(e) => Err(Some(e)),
+        };
+        CircuitBreakerConnect {
+            pending: future.is_ok(),
+            future,
+            addr,
+            breaker: self.breaker.clone(),
+        }
+    }
+
+    fn resolve(&mut self, host: &str, port: u16) -> BoxResolveFuture {
+        self.inner.resolve(host, port)
+    }
+}
+
+/// `Future` that acquires a connection via [`CircuitBreaker`].
+///
+/// [`CircuitBreaker`]: ./struct.CircuitBreaker.html
+#[derive(Debug)]
+pub struct CircuitBreakerConnect<F> {
+    future: Result<F, Option<Error>>, // `Err` if the acquisition has been rejected
+    pending: bool,
+    addr: SocketAddr,
+    breaker: Breaker,
+}
+impl<F> Future for CircuitBreakerConnect<F>
+where
+    F: Future<Error = Error>,
+    F::Item: AsMut<Connection>,
+{
+    type Item = CircuitBreakerConnection<F::Item>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let result = match self.future {
+            Err(ref mut e) => {
+                return Err(e.take().expect("Cannot poll CircuitBreakerConnect twice"))
+            }
+            Ok(ref mut f) => f.poll(),
+        };
+        if let Ok(Async::NotReady) = result {
+            return Ok(Async::NotReady);
+        }
+        self.pending = false;
+        match result {
+            Ok(Async::NotReady) => unreachable!(),
+            Ok(Async::Ready(mut connection)) => Ok(Async::Ready(CircuitBreakerConnection {
+                completed_requests: connection.as_mut().completed_requests(),
+                connection,
+                addr: self.addr,
+                breaker: self.breaker.clone(),
+            })),
+            Err(e) => {
+                if is_connect_error(&e) {
+                    self.breaker.on_failure(self.addr);
+                } else {
+                    // The failure is not due to the host (e.g., the pool is full)
+                    self.breaker.on_abort(self.addr);
+                }
+                Err(track!(e))
+            }
+        }
+    }
+}
+
+impl<F> Drop for CircuitBreakerConnect<F> {
+    fn drop(&mut self) {
+        if self.pending {
+            // Abandoned before the connection is acquired
+            self.breaker.on_abort(self.addr);
+        }
+    }
+}
+
+/// A connection acquired via [`CircuitBreaker`].
+///
+/// When dropped, the outcome of the request is reported to the circuit of the host.
+///
+/// [`CircuitBreaker`]: ./struct.CircuitBreaker.html
+#[derive(Debug)]
+pub struct CircuitBreakerConnection<C: AsMut<Connection>> {
+    connection: C,
+    completed_requests: usize, // The number of the completed requests at the time of the acquisition
+    addr: SocketAddr,
+    breaker: Breaker,
+}
+impl<C: AsMut<Connection>> AsMut<Connection> for CircuitBreakerConnection<C> {
+    fn as_mut(&mut self) -> &mut Connection {
+        self.connection.as_mut()
+    }
+}
+impl<C: AsMut<Connection>> Drop for CircuitBreakerConnection<C> {
+    fn drop(&mut self) {
+        // Failed requests may have closed the connection, so the state alone does not tell the outcome
+        if self.connection.as_mut().completed_requests() > self.completed_requests {
+            self.breaker.on_success(self.addr);
+        } else {
+            self.breaker.on_failure(self.addr);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Circuit {
+    Closed {
+        failures: usize,
+        since: Option<Instant>, // The time of the first of the consecutive failures
+    },
+    Open {
+        until: Instant,
+    },
+    HalfOpen {
+        trial: bool, // `true` if a trial has been admitted and its outcome is not reported yet
+    },
+}
+impl Default for Circuit {
+    fn default() -> Self {
+        Circuit::Closed {
+            failures: 0,
+            since: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BreakerInner {
+    circuits: Mutex<HashMap<SocketAddr, Circuit>>,
+    failure_threshold: usize,
+    window: Duration,
+    cooldown: Duration,
+    metrics: CircuitBreakerMetrics,
+}
+
+#[derive(Debug, Clone)]
+struct Breaker(Arc<BreakerInner>);
+impl Breaker {
+    fn admit(&self, addr: SocketAddr) -> Result<(), Error> {
+        let mut circuits = self.lock();
+        let circuit = circuits.entry(addr).or_default();
+        match *circuit {
+            Circuit::Closed { .. } => return Ok(()),
+            Circuit::Open { until } if until <= Instant::now() => {
+                *circuit = Circuit::HalfOpen { trial: true };
+                self.0.metrics.half_opened.increment();
+                return Ok(());
+            }
+            Circuit::HalfOpen { trial: false } => {
+                *circuit = Circuit::HalfOpen { trial: true };
+                return Ok(());
+            }
+            Circuit::Open { .. } | Circuit::HalfOpen { trial: true } => {}
+        }
+        self.0.metrics.rejected_acquisitions.increment();
+        let e =
+            ErrorKind::TemporarilyUnavailable.cause(format!("The circuit for {} is open", addr));
+        Err(track!(Error::from(e)))
+    }
+
+    fn on_success(&self, addr: SocketAddr) {
+        let mut circuits = self.lock();
+        let circuit = circuits.entry(addr).or_default();
+        if let Circuit::HalfOpen { .. } = *circuit {
+            self.0.metrics.closed.increment();
+        }
+        if let Circuit::Open { .. } = *circuit {
+            // Reported by a connection acquired before the circuit was opened
+            return;
+        }
+        *circuit = Circuit::default();
+    }
+
+    fn on_failure(&self, addr: SocketAddr) {
+        let mut circuits = self.lock();
+        let circuit = circuits.entry(addr).or_default();
+        let now = Instant::now();
+        let open = match *circuit {
+            Circuit::Closed { failures, since } => {
+                let (failures, since) = match since {
+                    Some(since) if now.duration_since(since) <= self.0.window => {
+                        (failures + 1, since)
+                    }
+                    _ => (1, now),
+                };
+                *circuit = Circuit::Closed {
+                    failures,
+                    since: Some(since),
+                };
+                failures >= self.0.failure_threshold
+            }
+            Circuit::Open { .. } => false,
+            Circuit::HalfOpen { .. } => true,
+        };
+        if open {
+            *circuit = Circuit::Open {
+                until: now + self.0.cooldown,
+            };
+            self.0.metrics.opened.increment();
+        }
+    }
+
+    /// Allows another trial if the trial of the circuit has ended without any outcome.
+    fn on_abort(&self, addr: SocketAddr) {
+        let mut circuits = self.lock();
+        if let Some(circuit @ &mut Circuit::HalfOpen { .. }) = circuits.get_mut(&addr) {
+            *circuit = Circuit::HalfOpen { trial: false };
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<SocketAddr, Circuit>> {
+        self.0.circuits.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::{self, Either, FutureResult};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    use super::*;
+    use connection::{ConnectionState, Mock};
+    use fibers_global;
+    use url::Url;
+    use Client;
+
+    #[test]
+    fn circuit_breaker_works() {
+        let down = Arc::new(AtomicBool::new(true));
+        let inner = Flaky(Mock::new(), down.clone());
+        let mut breaker = CircuitBreakerBuilder::new()
+            .failure_threshold(2)
+            .cooldown(Duration::from_millis(50))
+            .finish(inner);
+        let addr = "127.0.0.1:80".parse().unwrap();
+        let other = "127.0.0.1:81".parse().unwrap();
+
+        // Closed -> Open
+        let e = breaker.acquire_connection(addr).wait().err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::ConnectionRefused);
+        let e = breaker.acquire_connection(addr).wait().err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::ConnectionRefused);
+        #[cfg(feature = "metrics")]
+        assert_eq!(breaker.metrics().opened(), 1);
+
+        // Rejected while open (other hosts are unaffected)
+        down.store(false, Ordering::SeqCst);
+        let e = breaker.acquire_connection(addr).wait().err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::TemporarilyUnavailable);
+        #[cfg(feature = "metrics")]
+        assert_eq!(breaker.metrics().rejected_acquisitions(), 1);
+        assert!(breaker.acquire_connection(other).wait().is_ok());
+
+        // Open -> Half-open -> Open (the trial request fails)
+        thread::sleep(Duration::from_millis(60));
+        let trial = breaker.acquire_connection(addr).wait().unwrap();
+        #[cfg(feature = "metrics")]
+        assert_eq!(breaker.metrics().half_opened(), 1);
+        let e = breaker.acquire_connection(addr).wait().err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::TemporarilyUnavailable);
+        drop(trial); // Released while in use
+        #[cfg(feature = "metrics")]
+        assert_eq!(breaker.metrics().opened(), 2);
+        let e = breaker.acquire_connection(addr).wait().err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::TemporarilyUnavailable);
+
+        // The trial is abandoned before the connection is acquired
+        thread::sleep(Duration::from_millis(60));
+        drop(breaker.acquire_connection(addr));
+        #[cfg(feature = "metrics")]
+        assert_eq!(breaker.metrics().half_opened(), 2);
+
+        // Half-open -> Closed (the trial request succeeds)
+        let mut trial = breaker.acquire_connection(addr).wait().unwrap();
+        trial.as_mut().increment_completed_requests();
+        trial.as_mut().set_state(ConnectionState::Recyclable);
+        drop(trial);
+        #[cfg(feature = "metrics")]
+        assert_eq!(breaker.metrics().closed(), 1);
+        assert!(breaker.acquire_connection(addr).wait().is_ok());
+    }
+
+    #[test]
+    fn window_works() {
+        let inner = Flaky(Mock::new(), Arc::new(AtomicBool::new(true)));
+        let mut breaker = CircuitBreakerBuilder::new()
+            .failure_threshold(2)
+            .window(Duration::from_millis(20))
+            .finish(inner);
+        let addr = "127.0.0.1:80".parse().unwrap();
+
+        // The failures are not counted as consecutive since they are too far apart
+        assert!(breaker.acquire_connection(addr).wait().is_err());
+        thread::sleep(Duration::from_millis(30));
+        assert!(breaker.acquire_connection(addr).wait().is_err());
+        #[cfg(feature = "metrics")]
+        assert_eq!(breaker.metrics().opened(), 0);
+
+        assert!(breaker.acquire_connection(addr).wait().is_err());
+        #[cfg(feature = "metrics")]
+        assert_eq!(breaker.metrics().opened(), 1);
+    }
+
+    #[test]
+    fn incomplete_response_works() {
+        let mock = Mock::new();
+        mock.push_response("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo");
+        mock.push_response("HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nfoo");
+        let breaker = CircuitBreakerBuilder::new()
+            .failure_threshold(1)
+            .finish(mock);
+        let url = Url::parse("http://127.0.0.1:80/").unwrap();
+        let mut client = Client::new(breaker.clone());
+
+        assert!(fibers_global::execute(client.request(&url).get()).is_ok());
+        #[cfg(feature = "metrics")]
+        assert_eq!(breaker.metrics().opened(), 0);
+
+        // The peer closes the connection in the middle of the response
+        assert!(fibers_global::execute(client.request(&url).get()).is_err());
+        #[cfg(feature = "metrics")]
+        assert_eq!(breaker.metrics().opened(), 1);
+        let e = fibers_global::execute(client.request(&url).get())
+            .err()
+            .unwrap();
+        assert_eq!(*e.kind(), ErrorKind::TemporarilyUnavailable);
+    }
+
+    /// Refuses connections while the flag is set.
+    #[derive(Clone)]
+    struct Flaky(Mock, Arc<AtomicBool>);
+    impl AcquireConnection for Flaky {
+        type Connection = Connection;
+        type Future = Either<FutureResult<Connection, Error>, FutureResult<Connection, Error>>;
+
+        fn acquire_connection(&mut self, addr: SocketAddr) -> Self::Future {
+            if self.1.load(Ordering::SeqCst) {
+                let e = track!(Error::from(ErrorKind::ConnectionRefused.error()));
+                Either::B(future::err(e))
+            } else {
+                Either::A(self.0.acquire_connection(addr))
+            }
+        }
+    }
+}
@@ -1,18 +1,42 @@
 //! TCP connection.
-use bytecodec::io::BufferedIo;
+use bytecodec::io::{ReadBuf, WriteBuf};
+use fibers::net::futures::Connect;
 use fibers::net::TcpStream;
-use futures::Future;
+use fibers::time::timer::{self, Timeout};
+use futures::future::{self, FutureResult};
+use futures::{Async, Future, Poll};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use Error;
+use resolver::{AddressFamily, BoxResolveFuture, DefaultResolver, Resolve, SharedResolver};
+use trackable::error::ErrorKindExt;
+use {Error, ErrorKind, TimeoutPhase};
 
+pub use circuit_breaker::{
+    CircuitBreaker, CircuitBreakerBuilder, CircuitBreakerConnect, CircuitBreakerConnection,
+};
 pub use connection_pool::{
-    ConnectionPool, ConnectionPoolBuilder, ConnectionPoolHandle, RentedConnection,
+    ConnectionPool, ConnectionPoolBuilder, ConnectionPoolHandle, ConnectionPoolStats, OnFull,
+    RentedConnection,
 };
+pub use load_balancer::{LoadBalanced, LoadBalancedConnect};
+#[cfg(any(test, feature = "mock"))]
+pub use mock::Mock;
+pub use pipeline::Pipeline;
 
 const BUF_SIZE: usize = 4096; // FIXME: parameterize
+const MAX_ARENA_BUFFERS: usize = 256;
 
 /// This trait allows for acquiring TCP connections.
+///
+/// Note that the request methods of [`RequestBuilder`] require the implementations to be `Clone`,
+/// since connections are acquired after the host names are resolved (and again when requests are retried).
+///
+/// [`RequestBuilder`]: ../struct.RequestBuilder.html
 pub trait AcquireConnection {
     /// TCP connection.
     type Connection: AsMut<Connection>;
@@ -22,6 +46,24 @@ pub trait AcquireConnection {
 
     /// Returns a `Future` for acquiring a connection to communicate with the specified TCP server.
     fn acquire_connection(&mut self, addr: SocketAddr) -> Self::Future;
+
+    /// Returns a `Future` for acquiring a connection to any of the given TCP servers
+    /// (e.g., the addresses resolved from a host name, in the order of preference).
+    ///
+    /// `addrs` is never empty.
+    /// The default implementation acquires a connection to the first address.
+    fn acquire_connection_to_any(&mut self, addrs: &[SocketAddr]) -> Self::Future {
+        self.acquire_connection(addrs[0])
+    }
+
+    /// Returns a `Future` for resolving the given host name to the addresses of the TCP servers.
+    ///
+    /// The default implementation uses [`DefaultResolver`].
+    ///
+    /// [`DefaultResolver`]: ../resolver/struct.DefaultResolver.html
+    fn resolve(&mut self, host: &str, port: u16) -> BoxResolveFuture {
+        Box::new(DefaultResolver.resolve(host, port))
+    }
 }
 
 /// An implementation of [`AcquireConnection`] that always establishes new TCP connection
@@ -30,33 +72,357 @@ pub trait AcquireConnection {
 /// [`AcquireConnection`]: ./trait.AcquireConnection.html
 #[derive(Debug, Default, Clone)]
 pub struct Oneshot;
+impl Oneshot {
+    /// Returns a [`OneshotBuilder`] instance for making a configured `Oneshot` provider.
+    ///
+    /// [`OneshotBuilder`]: ./struct.OneshotBuilder.html
+    pub fn builder() -> OneshotBuilder {
+        OneshotBuilder::new()
+    }
+}
 impl AcquireConnection for Oneshot {
     type Connection = Connection;
-    type Future = Box<dyn Future<Item = Connection, Error = Error> + Send + 'static>;
+    type Future = OneshotConnect;
 
     fn acquire_connection(&mut self, addr: SocketAddr) -> Self::Future {
-        let future = TcpStream::connect(addr)
-            .map_err(move |e| track!(Error::from(e); addr))
-            .map(move |stream| Connection::new(addr, stream));
-        Box::new(future)
+        OneshotConnect {
+            future: TcpStream::connect(addr),
+            addr,
+            socket_options: SocketOptions::default(),
+            timeout: None,
+        }
     }
 }
 
-/// TCP connection.
+/// `Future` that establishes a new TCP connection for [`Oneshot`] and [`ConfiguredOneshot`].
+///
+/// [`Oneshot`]: ./struct.Oneshot.html
+/// [`ConfiguredOneshot`]: ./struct.ConfiguredOneshot.html
 #[derive(Debug)]
+pub struct OneshotConnect {
+    future: Connect,
+    addr: SocketAddr,
+    socket_options: SocketOptions,
+    timeout: Option<Timeout>,
+}
+impl Future for OneshotConnect {
+    type Item = Connection;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let addr = self.addr;
+        match self.future.poll() {
+            Err(e) => Err(track!(Error::from(e); addr)),
+            Ok(Async::NotReady) => {
+                let expired = match self.timeout {
+                    Some(ref mut timeout) => {
+                        track!(timeout.poll().map_err(Error::from))?.is_ready()
+                    }
+                    None => false,
+                };
+                if expired {
+                    let e = ErrorKind::Timeout.cause(TimeoutPhase::Connect);
+                    return Err(track!(Error::from(e); addr));
+                }
+                Ok(Async::NotReady)
+            }
+            Ok(Async::Ready(stream)) => {
+                // Applied after `Connection::new` so that `nodelay` takes precedence over its default
+                let connection = Connection::new(addr, stream.clone());
+                self.socket_options.apply(&stream);
+                Ok(Async::Ready(connection))
+            }
+        }
+    }
+}
+
+/// [`Oneshot`] builder.
+///
+/// The socket options are set right after each connection is established,
+/// since `fibers` creates and connects a socket at once.
+/// Hence the options that must be set before connecting (e.g., `SO_REUSEADDR`) are not supported.
+///
+/// [`Oneshot`]: ./struct.Oneshot.html
+#[derive(Debug, Default, Clone)]
+pub struct OneshotBuilder {
+    socket_options: SocketOptions,
+    connect_timeout: Option<Duration>,
+    resolver: SharedResolver,
+}
+impl OneshotBuilder {
+    /// Makes a new `OneshotBuilder` instance with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the size of the receive buffer (i.e., `SO_RCVBUF`) of the sockets.
+    ///
+    /// As the option is set after the TCP handshake, it does not affect the window scale negotiated in the handshake,
+    /// so a buffer larger than the OS default may not be fully utilized.
+    ///
+    /// The default value is determined by OS.
+    pub fn recv_buffer_size(&mut self, size: usize) -> &mut Self {
+        self.socket_options.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets the size of the send buffer (i.e., `SO_SNDBUF`) of the sockets.
+    ///
+    /// The default value is determined by OS.
+    pub fn send_buffer_size(&mut self, size: usize) -> &mut Self {
+        self.socket_options.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets whether `TCP_NODELAY` is enabled on the sockets.
+    ///
+    /// The default value is `true`.
+    pub fn nodelay(&mut self, enabled: bool) -> &mut Self {
+        self.socket_options.nodelay = Some(enabled);
+        self
+    }
+
+    /// Sets the timeout duration of TCP connect operation.
+    ///
+    /// If connecting takes longer than this, the acquisition fails with an `ErrorKind::Timeout` error
+    /// whose cause is `TimeoutPhase::Connect`.
+    ///
+    /// By default, there is no timeout other than the one of OS.
+    pub fn connect_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Sets the resolver used for resolving the host names of URLs.
+    ///
+    /// The default value is [`DefaultResolver`].
+    ///
+    /// [`DefaultResolver`]: ../resolver/struct.DefaultResolver.html
+    pub fn resolver<R>(&mut self, resolver: R) -> &mut Self
+    where
+        R: Resolve + Send + 'static,
+        R::Future: Send + 'static,
+    {
+        self.resolver.set_resolver(resolver);
+        self
+    }
+
+    /// Sets the address family tried first when a host name is resolved to multiple addresses.
+    ///
+    /// This is useful for avoiding broken IPv6 (or IPv4) paths in dual-stack environments.
+    ///
+    /// The default value is `AddressFamily::System` (i.e., the order of the resolver is kept).
+    pub fn address_family(&mut self, family: AddressFamily) -> &mut Self {
+        self.resolver.set_address_family(family);
+        self
+    }
+
+    /// Makes a new [`ConfiguredOneshot`] instance with the given settings.
+    ///
+    /// [`ConfiguredOneshot`]: ./struct.ConfiguredOneshot.html
+    pub fn finish(&self) -> ConfiguredOneshot {
+        ConfiguredOneshot {
+            socket_options: self.socket_options.clone(),
+            connect_timeout: self.connect_timeout,
+            resolver: self.resolver.clone(),
+        }
+    }
+}
+
+/// An implementation of [`AcquireConnection`] that behaves like [`Oneshot`] except
+/// it applies the settings specified via [`OneshotBuilder`] to new connections.
+///
+/// [`AcquireConnection`]: ./trait.AcquireConnection.html
+/// [`Oneshot`]: ./struct.Oneshot.html
+/// [`OneshotBuilder`]: ./struct.OneshotBuilder.html
+#[derive(Debug, Clone)]
+pub struct ConfiguredOneshot {
+    socket_options: SocketOptions,
+    connect_timeout: Option<Duration>,
+    resolver: SharedResolver,
+}
+impl AcquireConnection for ConfiguredOneshot {
+    type Connection = Connection;
+    type Future = OneshotConnect;
+
+    fn acquire_connection(&mut self, addr: SocketAddr) -> Self::Future {
+        OneshotConnect {
+            future: TcpStream::connect(addr),
+            addr,
+            socket_options: self.socket_options.clone(),
+            timeout: self.connect_timeout.map(timer::timeout),
+        }
+    }
+
+    fn resolve(&mut self, host: &str, port: u16) -> BoxResolveFuture {
+        self.resolver.resolve(host, port)
+    }
+}
+
+/// An implementation of [`AcquireConnection`] that provides an already established connection only once.
+///
+/// This is useful for sending a request over a socket connected by other means
+/// (e.g., after a custom handshake), or over an in-memory [`Transport`] in tests.
+/// Host names are resolved to the peer address of the connection without querying DNS,
+/// and the address passed to `acquire_connection` is ignored.
+///
+/// Once the connection has been acquired, subsequent acquisitions (including those for retries)
+/// fail with an `ErrorKind::Other` error.
+/// The clones of a `Preconnected` instance share the same connection.
+///
+/// # Examples
+///
+/// ```no_run
+/// # extern crate fibers;
+/// # extern crate fibers_global;
+/// # extern crate fibers_http_client;
+/// # extern crate url;
+/// use fibers::net::TcpStream;
+/// use fibers_http_client::connection::{Connection, Preconnected};
+/// use fibers_http_client::Client;
+/// use url::Url;
+///
+/// # fn main() {
+/// // The socket may be connected (and handshaked) in any way
+/// let peer_addr = "127.0.0.1:8080".parse().unwrap();
+/// let stream = fibers_global::execute(TcpStream::connect(peer_addr)).unwrap();
+///
+/// let mut client = Client::new(Preconnected::new(Connection::new(peer_addr, stream)));
+/// let url = Url::parse("http://localhost/foo").unwrap();
+/// let response = fibers_global::execute(client.request(&url).get()).unwrap();
+/// # let _ = response;
+/// # }
+/// ```
+///
+/// [`AcquireConnection`]: ./trait.AcquireConnection.html
+/// [`Transport`]: ./trait.Transport.html
+#[derive(Debug, Clone)]
+pub struct Preconnected {
+    peer_addr: SocketAddr,
+    connection: Arc<Mutex<Option<Connection>>>,
+}
+impl Preconnected {
+    /// Makes a new `Preconnected` instance that provides the given connection.
+    pub fn new(connection: Connection) -> Self {
+        Preconnected {
+            peer_addr: connection.peer_addr(),
+            connection: Arc::new(Mutex::new(Some(connection))),
+        }
+    }
+
+    /// Returns `true` if the connection has already been acquired.
+    pub fn is_used(&self) -> bool {
+        self.connection
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_none()
+    }
+}
+impl AcquireConnection for Preconnected {
+    type Connection = Connection;
+    type Future = FutureResult<Connection, Error>;
+
+    fn acquire_connection(&mut self, _addr: SocketAddr) -> Self::Future {
+        let connection = self
+            .connection
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .take();
+        match connection {
+            Some(connection) => future::ok(connection),
+            None => future::err(track!(Error::from(
+                ErrorKind::Other.cause("The pre-established connection has already been used")
+            ))),
+        }
+    }
+
+    fn resolve(&mut self, _host: &str, _port: u16) -> BoxResolveFuture {
+        Box::new(future::ok(vec![self.peer_addr]))
+    }
+}
+
+/// A byte stream over which HTTP messages are exchanged.
+///
+/// This is implemented for all `Read + Write + Send + 'static` types.
+/// Like `fibers::net::TcpStream`, the stream must be non-blocking:
+/// if it is not ready for I/O, it should return a `WouldBlock` error
+/// after arranging for the current fiber to be woken up when it becomes ready.
+pub trait Transport: Read + Write + Send + 'static {}
+impl<T: Read + Write + Send + 'static> Transport for T {}
+
+/// TCP connection.
+///
+/// The underlying stream is usually a `TcpStream`, but any [`Transport`] can be used.
+///
+/// `Connection` implements `Read` and `Write` for communicating over the connection directly
+/// (e.g., after a protocol upgrade; see [`RequestBuilder::upgrade`]).
+/// Reads return the bytes that have already been buffered before reading from the stream.
+/// Once either of them is used, the connection is never reused for HTTP requests.
+///
+/// [`Transport`]: ./trait.Transport.html
+/// [`RequestBuilder::upgrade`]: ../struct.RequestBuilder.html#method.upgrade
 pub struct Connection {
-    stream: BufferedIo<TcpStream>,
+    stream: BufferedStream,
     peer_addr: SocketAddr,
     state: ConnectionState,
+    requests: usize,
+    completed_requests: usize,
+    max_requests: Option<usize>,
+    server_keepalive_timeout: Option<Duration>,
+    arena: Option<BufferArena>,
+    peeker: Option<TcpStream>, // A handle of the underlying stream (if it is a `TcpStream`) for `MSG_PEEK`
 }
 impl Connection {
     /// Makes a new `Connection` instance.
     pub fn new(peer_addr: SocketAddr, stream: TcpStream) -> Self {
         let _ = stream.set_nodelay(true);
+        let peeker = stream.clone();
+        let buffers = (vec![0; BUF_SIZE], vec![0; BUF_SIZE]);
+        Self::with_buffers(peer_addr, Box::new(stream), buffers, None, Some(peeker))
+    }
+
+    /// Makes a new `Connection` instance that communicates over the given transport.
+    ///
+    /// `peer_addr` is used only for identifying the connection (e.g., in events and pooling).
+    pub fn with_transport<S: Transport>(peer_addr: SocketAddr, stream: S) -> Self {
+        let buffers = (vec![0; BUF_SIZE], vec![0; BUF_SIZE]);
+        Self::with_buffers(peer_addr, Box::new(stream), buffers, None, None)
+    }
+
+    /// Makes a new `Connection` instance whose buffers are taken from (and returned to) the arena.
+    pub(crate) fn with_arena(
+        peer_addr: SocketAddr,
+        stream: TcpStream,
+        arena: &BufferArena,
+    ) -> Self {
+        let _ = stream.set_nodelay(true);
+        let peeker = stream.clone();
+        let buffers = (arena.take(), arena.take());
+        let arena = Some(arena.clone());
+        Self::with_buffers(peer_addr, Box::new(stream), buffers, arena, Some(peeker))
+    }
+
+    fn with_buffers(
+        peer_addr: SocketAddr,
+        stream: Box<dyn Transport>,
+        (rbuf, wbuf): (Vec<u8>, Vec<u8>),
+        arena: Option<BufferArena>,
+        peeker: Option<TcpStream>,
+    ) -> Self {
         Connection {
             peer_addr,
-            stream: BufferedIo::new(stream, BUF_SIZE, BUF_SIZE),
+            stream: BufferedStream {
+                stream,
+                rbuf: ReadBuf::new(rbuf),
+                wbuf: WriteBuf::new(wbuf),
+            },
             state: ConnectionState::InUse,
+            requests: 0,
+            completed_requests: 0,
+            max_requests: None,
+            server_keepalive_timeout: None,
+            arena,
+            peeker,
         }
     }
 
@@ -65,27 +431,397 @@ impl Connection {
         self.peer_addr
     }
 
+    /// Returns the number of the requests issued via the connection.
+    pub fn requests(&self) -> usize {
+        self.requests
+    }
+
+    /// Returns the number of the requests whose responses have been received entirely via the connection.
+    ///
+    /// Unlike [`requests`], this does not count the requests that failed in the middle (e.g., due to timeouts).
+    ///
+    /// [`requests`]: #method.requests
+    pub fn completed_requests(&self) -> usize {
+        self.completed_requests
+    }
+
     pub(crate) fn state(&self) -> ConnectionState {
         self.state
     }
 
+    /// Sets the state of the connection.
+    ///
+    /// If the connection has served the maximum number of requests, it cannot be recyclable.
     pub(crate) fn set_state(&mut self, state: ConnectionState) {
-        self.state = state;
+        let exhausted = self.max_requests.is_some_and(|n| self.requests >= n);
+        if state == ConnectionState::Recyclable && exhausted {
+            self.state = ConnectionState::Closed;
+        } else {
+            self.state = state;
+        }
+    }
+
+    pub(crate) fn set_max_requests(&mut self, n: Option<usize>) {
+        self.max_requests = n;
+    }
+
+    /// Returns the idle timeout advertised by the server via `Keep-Alive` header of the last response.
+    pub(crate) fn server_keepalive_timeout(&self) -> Option<Duration> {
+        self.server_keepalive_timeout
+    }
+
+    pub(crate) fn set_server_keepalive_timeout(&mut self, timeout: Option<Duration>) {
+        self.server_keepalive_timeout = timeout;
     }
 
-    pub(crate) fn stream_mut(&mut self) -> &mut BufferedIo<TcpStream> {
+    /// Returns `true` if the idle connection is found to be no longer usable.
+    ///
+    /// This peeks the socket without blocking (i.e., `MSG_PEEK`), and detects FIN (a zero-byte read), errors (e.g., RST)
+    /// or unsolicited bytes sent by the peer, which would be mistaken for (a part of) the response to the next request.
+    /// It always returns `false` if the underlying stream is not a `TcpStream`.
+    pub(crate) fn is_stale(&self) -> bool {
+        let peeker = match self.peeker {
+            Some(ref peeker) => peeker,
+            None => return false,
+        };
+        match peeker.with_inner(|s| s.peek(&mut [0; 1])) {
+            Ok(_) => true,
+            Err(e) => e.kind() != io::ErrorKind::WouldBlock,
+        }
+    }
+
+    pub(crate) fn increment_requests(&mut self) {
+        self.requests += 1;
+    }
+
+    pub(crate) fn increment_completed_requests(&mut self) {
+        self.completed_requests += 1;
+    }
+
+    /// Takes the bytes that have been received but not consumed yet (e.g., the ones following a `CONNECT` response).
+    ///
+    /// As with reading, the connection is never reused for HTTP requests after this is called.
+    pub(crate) fn take_buffered_bytes(&mut self) -> Vec<u8> {
+        self.state = ConnectionState::Closed;
+        let mut bytes = Vec::new();
+        let _ = self.stream.rbuf.read_to_end(&mut bytes);
+        bytes
+    }
+
+    pub(crate) fn stream_mut(&mut self) -> &mut BufferedStream {
         &mut self.stream
     }
 }
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.state = ConnectionState::Closed;
+        if self.stream.rbuf.is_empty() {
+            self.stream.stream.read(buf)
+        } else {
+            self.stream.rbuf.read(buf)
+        }
+    }
+}
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.state = ConnectionState::Closed;
+        self.stream.flush_write_buf()?;
+        self.stream.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.state = ConnectionState::Closed;
+        self.stream.flush_write_buf()?;
+        self.stream.stream.flush()
+    }
+}
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(ref arena) = self.arena {
+            let rbuf = mem::replace(&mut self.stream.rbuf, ReadBuf::new(Vec::new()));
+            let wbuf = mem::replace(&mut self.stream.wbuf, WriteBuf::new(Vec::new()));
+            arena.put(rbuf.into_inner());
+            arena.put(wbuf.into_inner());
+        }
+    }
+}
+impl fmt::Debug for Connection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("peer_addr", &self.peer_addr)
+            .field("state", &self.state)
+            .field("requests", &self.requests)
+            .field("completed_requests", &self.completed_requests)
+            .field("max_requests", &self.max_requests)
+            .field("server_keepalive_timeout", &self.server_keepalive_timeout)
+            .finish()
+    }
+}
 impl AsMut<Connection> for Connection {
     fn as_mut(&mut self) -> &mut Self {
         self
     }
 }
 
+/// A stream with read and write buffers.
+///
+/// This behaves like `bytecodec::io::BufferedIo` except that the buffers can be taken back for reuse.
+pub(crate) struct BufferedStream {
+    stream: Box<dyn Transport>,
+    rbuf: ReadBuf<Vec<u8>>,
+    wbuf: WriteBuf<Vec<u8>>,
+}
+impl BufferedStream {
+    /// Fills the read buffer from the stream, and flushes the write buffer to the stream.
+    pub fn execute_io(&mut self) -> bytecodec::Result<()> {
+        track!(self.rbuf.fill(&mut self.stream))?;
+        track!(self.wbuf.flush(&mut self.stream))?;
+        Ok(())
+    }
+
+    /// Returns `true` if the stream has reached EOS.
+    pub fn is_eos(&self) -> bool {
+        self.rbuf.stream_state().is_eos() || self.wbuf.stream_state().is_eos()
+    }
+
+    /// Returns `true` if the previous I/O operation on the stream would block.
+    pub fn would_block(&self) -> bool {
+        self.rbuf.stream_state().would_block()
+            && (self.wbuf.is_empty() || self.wbuf.stream_state().would_block())
+    }
+
+    pub fn read_buf_mut(&mut self) -> &mut ReadBuf<Vec<u8>> {
+        &mut self.rbuf
+    }
+
+    pub fn write_buf_mut(&mut self) -> &mut WriteBuf<Vec<u8>> {
+        &mut self.wbuf
+    }
+
+    /// Flushes the bytes remaining in the write buffer (e.g., the tail of a request) to the stream.
+    ///
+    /// If they cannot be flushed entirely, this returns a `WouldBlock` error.
+    fn flush_write_buf(&mut self) -> io::Result<()> {
+        self.wbuf
+            .flush(&mut self.stream)
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        if self.wbuf.is_empty() {
+            Ok(())
+        } else if self.wbuf.stream_state().is_eos() {
+            Err(io::ErrorKind::WriteZero.into())
+        } else {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+}
+
+/// A free list of I/O buffers shared by the connections of a pool.
+///
+/// The buffers of dropped connections are recycled into new connections,
+/// which reduces allocations when connections are frequently replaced.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct BufferArena {
+    buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+}
+impl BufferArena {
+    pub fn take(&self) -> Vec<u8> {
+        self.lock().pop().unwrap_or_else(|| vec![0; BUF_SIZE])
+    }
+
+    pub fn put(&self, buf: Vec<u8>) {
+        let mut buffers = self.lock();
+        if buf.len() == BUF_SIZE && buffers.len() < MAX_ARENA_BUFFERS {
+            buffers.push(buf);
+        }
+    }
+
+    fn lock(&self) -> ::std::sync::MutexGuard<'_, Vec<Vec<u8>>> {
+        self.buffers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub(crate) enum ConnectionState {
     InUse,
     Recyclable,
     Closed,
 }
+
+/// Options applied to the sockets of new connections.
+///
+/// The options are applied after connecting, because `fibers::net::TcpStream` cannot be made from a socket created beforehand.
+/// Failures in setting options are ignored, as is the case with `TCP_NODELAY`.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct SocketOptions {
+    pub recv_buffer_size: Option<usize>,
+    pub send_buffer_size: Option<usize>,
+    pub nodelay: Option<bool>,
+}
+impl SocketOptions {
+    pub fn apply(&self, stream: &TcpStream) {
+        stream.with_inner(|s| {
+            if let Some(size) = self.recv_buffer_size {
+                let _ = s.set_recv_buffer_size(size);
+            }
+            if let Some(size) = self.send_buffer_size {
+                let _ = s.set_send_buffer_size(size);
+            }
+            if let Some(enabled) = self.nodelay {
+                let _ = s.set_nodelay(enabled);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytecodec::bytes::RemainingBytesDecoder;
+    use fibers::net::TcpStream;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+    use url::Url;
+
+    use super::*;
+    use Client;
+
+    #[test]
+    fn buffer_arena_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let arena = BufferArena::default();
+
+        // Churns connections
+        let mut ptrs = Vec::new();
+        for _ in 0..3 {
+            let stream = fibers_global::execute(TcpStream::connect(addr)).unwrap();
+            let mut connection = Connection::with_arena(addr, stream, &arena);
+            let stream = connection.stream_mut();
+            let mut pair = [
+                stream.read_buf_mut().inner_ref().as_ptr(),
+                stream.write_buf_mut().inner_ref().as_ptr(),
+            ];
+            pair.sort();
+            ptrs.push(pair);
+            drop(connection);
+            assert_eq!(arena.lock().len(), 2);
+        }
+        assert_eq!(ptrs[0], ptrs[1]);
+        assert_eq!(ptrs[1], ptrs[2]);
+
+        // Buffers of unexpected sizes are not recycled
+        arena.put(vec![0; 10]);
+        assert_eq!(arena.lock().len(), 2);
+    }
+
+    /// A global allocator that counts the bytes allocated by each thread.
+    struct CountingAllocator;
+    thread_local! {
+        static ALLOCATED: Cell<usize> = const { Cell::new(0) };
+    }
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATED.try_with(|n| n.set(n.get() + layout.size()));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    #[test]
+    fn buffer_arena_reduces_allocations() {
+        const CHURNS: usize = 16;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connect = || fibers_global::execute(TcpStream::connect(addr)).unwrap();
+        let allocated = |f: &mut dyn FnMut()| {
+            let before = ALLOCATED.with(Cell::get);
+            f();
+            ALLOCATED.with(Cell::get) - before
+        };
+
+        let mut streams = (0..CHURNS).map(|_| connect()).collect::<Vec<_>>();
+        let without_arena = allocated(&mut || {
+            for stream in streams.drain(..) {
+                drop(Connection::new(addr, stream));
+            }
+        });
+
+        let arena = BufferArena::default();
+        let mut streams = (0..CHURNS).map(|_| connect()).collect::<Vec<_>>();
+        let with_arena = allocated(&mut || {
+            for stream in streams.drain(..) {
+                drop(Connection::with_arena(addr, stream, &arena));
+            }
+        });
+
+        // Only the first connection allocates its buffers
+        assert!(without_arena >= CHURNS * 2 * BUF_SIZE, "{}", without_arena);
+        assert!(
+            with_arena < without_arena - (CHURNS - 1) * 2 * BUF_SIZE + BUF_SIZE,
+            "with: {}, without: {}",
+            with_arena,
+            without_arena
+        );
+    }
+
+    #[test]
+    fn configured_oneshot_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let _streams = listener.incoming().collect::<Vec<_>>();
+        });
+
+        let mut oneshot = Oneshot::builder().finish();
+        let connection = fibers_global::execute(oneshot.acquire_connection(addr)).unwrap();
+        assert!(connection.peeker.as_ref().unwrap().nodelay().unwrap());
+
+        let mut oneshot = Oneshot::builder()
+            .nodelay(false)
+            .connect_timeout(Duration::from_secs(5))
+            .finish();
+        let connection = fibers_global::execute(oneshot.acquire_connection(addr)).unwrap();
+        assert!(!connection.peeker.as_ref().unwrap().nodelay().unwrap());
+    }
+
+    #[test]
+    fn preconnected_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo")
+                .unwrap();
+        });
+
+        let stream = fibers_global::execute(TcpStream::connect(addr)).unwrap();
+        let provider = Preconnected::new(Connection::new(addr, stream));
+        let mut client = Client::new(provider.clone());
+        let url = Url::parse("http://example.com/").unwrap();
+        let future = client
+            .request(&url)
+            .decoder(RemainingBytesDecoder::new())
+            .get();
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(response.body(), b"foo");
+        assert!(provider.is_used());
+        handle.join().unwrap();
+
+        // The connection is provided only once
+        let e = fibers_global::execute(client.request(&url).get())
+            .err()
+            .unwrap();
+        assert_eq!(*e.kind(), ErrorKind::Other);
+    }
+}
@@ -3,28 +3,44 @@ use fibers::sync::{mpsc, oneshot};
 use fibers::time::timer::{self, Timeout, TimerExt};
 use fibers::{BoxSpawn, Spawn};
 use futures::{Async, Future, Poll, Stream};
-use prometrics::metrics::MetricBuilder;
-use std;
-use std::cmp::Reverse;
-use std::collections::{BTreeMap, BinaryHeap};
+use std::cmp::{self, Reverse};
+use std::collections::{BTreeMap, BTreeSet, BinaryHeap, HashMap, VecDeque};
 use std::net::{IpAddr, SocketAddr};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use trackable::error::ErrorKindExt;
 
-use connection::{AcquireConnection, Connection, ConnectionState};
-use metrics::ConnectionPoolMetrics;
-use {Error, ErrorKind, Result};
+use connection::{AcquireConnection, BufferArena, Connection, ConnectionState, SocketOptions};
+use metrics::{ConnectionPoolMetrics, Histogram, MetricBuilder};
+use resolver::{AddressFamily, BoxResolveFuture, Resolve, SharedResolver};
+use rng::Rng;
+use {Error, ErrorKind, Result, TimeoutPhase};
 
-const TIMER_INTERVAL_SECS: u64 = 1;
+const DEFAULT_TIMER_INTERVAL: Duration = Duration::from_secs(1);
+const MIN_TIMER_INTERVAL: Duration = Duration::from_millis(1);
 
 /// [`ConnectionPool`] builder.
 ///
+/// As with [`OneshotBuilder`], the socket options are set right after each connection is established,
+/// and the options that must be set before connecting (e.g., `SO_REUSEADDR`) are not supported.
+///
 /// [`ConnectionPool`]: ./struct.ConnectionPool.html
+/// [`OneshotBuilder`]: ./struct.OneshotBuilder.html
 #[derive(Debug)]
 pub struct ConnectionPoolBuilder {
     max_pool_size: usize,
     connect_timeout: Duration,
+    connect_parallelism: usize,
     keepalive_timeout: Duration,
+    keepalive_jitter: f64,
+    jitter_seed: Option<u64>,
+    timer_interval: Duration,
+    min_idle_per_host: usize,
+    max_idle_per_host: Option<usize>,
+    max_requests_per_connection: Option<usize>,
+    on_full: OnFull,
+    max_waiters: usize,
+    socket_options: SocketOptions,
+    resolver: SharedResolver,
     metrics: MetricBuilder,
 }
 impl ConnectionPoolBuilder {
@@ -49,19 +65,204 @@ impl ConnectionPoolBuilder {
         self
     }
 
+    /// Sets the maximum number of addresses that the pool tries to connect to concurrently
+    /// when a host name is resolved to multiple addresses.
+    ///
+    /// Up to `k` of the resolved addresses are tried at once
+    /// (in the order of the resolver, after being reordered by [`address_family`]),
+    /// and the first established connection is used.
+    /// The other attempts are canceled and their sockets are closed immediately.
+    /// If all of them fail, the acquisition fails with the last error.
+    /// Idle connections to any of the `k` addresses are also reused.
+    ///
+    /// Values less than `1` are treated as `1`.
+    ///
+    /// The default value is `1` (i.e., only the first address is tried).
+    ///
+    /// [`address_family`]: #method.address_family
+    pub fn connect_parallelism(&mut self, k: usize) -> &mut Self {
+        self.connect_parallelism = cmp::max(k, 1);
+        self
+    }
+
     /// Sets the retention duration of a pooled (inactive) connection.
     ///
     /// If a connection is inactive (i.e., unused by any clients) beyond the duration, it will removed from the pool.
     ///
+    /// If the server advertised a shorter idle timeout via `Keep-Alive: timeout=N` header of the last response,
+    /// that timeout (minus the timer interval, to avoid racing with the server) is used instead.
+    /// If nothing remains after the subtraction, the connection is closed rather than pooled.
+    ///
     /// The default value is `Duration::from_secs(10)`.
     pub fn keepalive_timeout(&mut self, timeout: Duration) -> &mut Self {
         self.keepalive_timeout = timeout;
         self
     }
 
+    /// Sets the ratio of the random jitter applied to the keepalive timeout of each pooled connection.
+    ///
+    /// If `ratio` is `0.1`, for example, the effective timeout of a connection is
+    /// chosen from `keepalive_timeout * [0.9, 1.1]` each time the connection is pooled.
+    /// This spreads out the expiration of connections created together,
+    /// which would otherwise expire (and be reconnected) at the same time.
+    ///
+    /// Note that connections pooled around the same time may be reused in a slightly different order
+    /// than without jitter.
+    ///
+    /// The value is clamped to `[0.0, 1.0]`.
+    ///
+    /// The default value is `0.0` (i.e., disabled).
+    pub fn keepalive_jitter(&mut self, ratio: f64) -> &mut Self {
+        self.keepalive_jitter = if ratio > 0.0 { ratio.min(1.0) } else { 0.0 };
+        self
+    }
+
+    /// Sets the seed of the random number generator used for [`keepalive_jitter`].
+    ///
+    /// This is useful to make the expiration deterministic (e.g., in tests).
+    ///
+    /// By default, a random seed is used.
+    ///
+    /// [`keepalive_jitter`]: #method.keepalive_jitter
+    pub fn jitter_seed(&mut self, seed: u64) -> &mut Self {
+        self.jitter_seed = Some(seed);
+        self
+    }
+
+    /// Sets the interval of the timer that drives the expiration of pooled connections.
+    ///
+    /// Expired connections are removed at the next tick,
+    /// so a connection may be retained up to `keepalive_timeout + interval`.
+    /// At each tick, the idle connections closed by the servers are also detected (by peeking the sockets) and removed.
+    /// A shorter interval is suitable for short keepalive timeouts,
+    /// while a longer one reduces needless wakeups for long keepalive timeouts.
+    ///
+    /// Intervals shorter than one millisecond are rounded up to one millisecond.
+    ///
+    /// The default value is `Duration::from_secs(1)`.
+    pub fn timer_interval(&mut self, interval: Duration) -> &mut Self {
+        self.timer_interval = cmp::max(interval, MIN_TIMER_INTERVAL);
+        self
+    }
+
+    /// Sets the minimum number of idle connections that the pool keeps for each host.
+    ///
+    /// Only the hosts warmed by [`ConnectionPoolHandle::warm`] are the targets of this setting,
+    /// so that the pool does not keep connecting to hosts that were requested only once.
+    /// If the number of idle connections to such a host falls below the value,
+    /// the pool establishes new connections in the background.
+    ///
+    /// The default value is `0` (i.e., disabled).
+    ///
+    /// [`ConnectionPoolHandle::warm`]: ./struct.ConnectionPoolHandle.html#method.warm
+    pub fn min_idle_per_host(&mut self, n: usize) -> &mut Self {
+        self.min_idle_per_host = n;
+        self
+    }
+
+    /// Sets the maximum number of idle connections that the pool keeps for each host.
+    ///
+    /// If a connection is returned to the pool while the host already has `n` idle connections,
+    /// the oldest idle connection to the host is closed to make room for the returned one.
+    /// This bounds the idle resources left after bursts of requests to a single host.
+    /// The connections established in advance (e.g., by [`ConnectionPoolHandle::warm`]) are also subject to the limit.
+    /// If `n` is `0`, returned connections are always closed.
+    ///
+    /// The default value is unlimited.
+    ///
+    /// [`ConnectionPoolHandle::warm`]: ./struct.ConnectionPoolHandle.html#method.warm
+    pub fn max_idle_per_host(&mut self, n: usize) -> &mut Self {
+        self.max_idle_per_host = Some(n);
+        self
+    }
+
+    /// Sets the maximum number of requests that a connection created by the pool can serve.
+    ///
+    /// A connection that has served `n` requests is closed instead of being returned to the pool.
+    /// This is useful for load balancers that expect clients to recycle connections periodically.
+    ///
+    /// The default value is unlimited.
+    pub fn max_requests_per_connection(&mut self, n: usize) -> &mut Self {
+        self.max_requests_per_connection = Some(n);
+        self
+    }
+
+    /// Sets the behavior of the pool when it has reached the maximum size and has no idle connections.
+    ///
+    /// The default value is `OnFull::FailFast`.
+    pub fn on_full(&mut self, on_full: OnFull) -> &mut Self {
+        self.on_full = on_full;
+        self
+    }
+
+    /// Enables the bounded queue of the acquisition requests issued while the pool is full.
+    ///
+    /// This is equivalent to `on_full(OnFull::Wait(wait_timeout))` except that
+    /// at most `max_waiters` acquisition requests can wait at the same time.
+    /// If the queue is full, requests fail immediately with an `ErrorKind::TemporarilyUnavailable` error.
+    pub fn acquire_queue(&mut self, max_waiters: usize, wait_timeout: Duration) -> &mut Self {
+        self.on_full = OnFull::Wait(wait_timeout);
+        self.max_waiters = max_waiters;
+        self
+    }
+
+    /// Sets the size of the receive buffer (i.e., `SO_RCVBUF`) of the sockets created by the pool.
+    ///
+    /// See [`OneshotBuilder::recv_buffer_size`] for the limitation of this option.
+    ///
+    /// The default value is determined by OS.
+    ///
+    /// [`OneshotBuilder::recv_buffer_size`]: ./struct.OneshotBuilder.html#method.recv_buffer_size
+    pub fn recv_buffer_size(&mut self, size: usize) -> &mut Self {
+        self.socket_options.recv_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets the size of the send buffer (i.e., `SO_SNDBUF`) of the sockets created by the pool.
+    ///
+    /// The default value is determined by OS.
+    pub fn send_buffer_size(&mut self, size: usize) -> &mut Self {
+        self.socket_options.send_buffer_size = Some(size);
+        self
+    }
+
+    /// Sets the resolver used for resolving the host names of URLs.
+    ///
+    /// The default value is [`DefaultResolver`].
+    ///
+    /// [`DefaultResolver`]: ../resolver/struct.DefaultResolver.html
+    pub fn resolver<R>(&mut self, resolver: R) -> &mut Self
+    where
+        R: Resolve + Send + 'static,
+        R::Future: Send + 'static,
+    {
+        self.resolver.set_resolver(resolver);
+        self
+    }
+
+    /// Sets the address family tried first when a host name is resolved to multiple addresses.
+    ///
+    /// This is useful for avoiding broken IPv6 (or IPv4) paths in dual-stack environments.
+    ///
+    /// The default value is `AddressFamily::System` (i.e., the order of the resolver is kept).
+    pub fn address_family(&mut self, family: AddressFamily) -> &mut Self {
+        self.resolver.set_address_family(family);
+        self
+    }
+
+    /// Forces the host name `host` with the port `port` to be resolved to `addrs` (like `curl --resolve`).
+    ///
+    /// The overrides are consulted before the resolver.
+    /// Note that the `Host` header of requests still has the original host name.
+    pub fn resolve_override(&mut self, host: &str, port: u16, addrs: Vec<SocketAddr>) -> &mut Self {
+        self.resolver.add_override(host, port, addrs);
+        self
+    }
+
     /// Sets the metrics builder used by the pool.
     ///
     /// The default value is `MetricBuilder::new()`.
+    #[cfg(feature = "metrics")]
     pub fn metrics(&mut self, metrics: MetricBuilder) -> &mut Self {
         self.metrics = metrics;
         self
@@ -77,16 +278,35 @@ impl ConnectionPoolBuilder {
         let (command_tx, command_rx) = mpsc::channel();
         let metrics = ConnectionPoolMetrics::new(self.metrics.clone());
         metrics.max_pool_size.set(self.max_pool_size as f64);
+        let mut state = ConnectionPoolState::new(self.keepalive_timeout);
+        if self.keepalive_jitter > 0.0 {
+            let rng = self.jitter_seed.map_or_else(Rng::from_entropy, Rng::new);
+            let spread = self.keepalive_timeout.mul_f64(self.keepalive_jitter);
+            state.set_jitter(spread, rng);
+        }
         ConnectionPool {
             spawner: spawner.boxed(),
             command_tx,
             command_rx,
             max_pool_size: self.max_pool_size,
-            timer: timer::timeout(Duration::from_secs(TIMER_INTERVAL_SECS)),
+            timer: timer::timeout(self.timer_interval),
             connect_timeout: self.connect_timeout,
-            keepalive_timeout: self.keepalive_timeout,
+            connect_parallelism: self.connect_parallelism,
+            timer_interval: self.timer_interval,
+            min_idle_per_host: self.min_idle_per_host,
+            max_idle_per_host: self.max_idle_per_host,
+            max_requests_per_connection: self.max_requests_per_connection,
+            on_full: self.on_full,
+            max_waiters: self.max_waiters,
+            waiters: VecDeque::new(),
+            socket_options: self.socket_options.clone(),
+            resolver: self.resolver.clone(),
             metrics,
-            state: ConnectionPoolState::new(),
+            state,
+            warming: HashMap::new(),
+            warm_hosts: BTreeSet::new(),
+            affinities: HashMap::new(),
+            buffers: BufferArena::default(),
         }
     }
 }
@@ -95,7 +315,18 @@ impl Default for ConnectionPoolBuilder {
         ConnectionPoolBuilder {
             max_pool_size: 4096,
             connect_timeout: Duration::from_secs(5),
+            connect_parallelism: 1,
             keepalive_timeout: Duration::from_secs(10),
+            keepalive_jitter: 0.0,
+            jitter_seed: None,
+            timer_interval: DEFAULT_TIMER_INTERVAL,
+            min_idle_per_host: 0,
+            max_idle_per_host: None,
+            max_requests_per_connection: None,
+            on_full: OnFull::FailFast,
+            max_waiters: usize::MAX,
+            socket_options: SocketOptions::default(),
+            resolver: SharedResolver::default(),
             metrics: MetricBuilder::new(),
         }
     }
@@ -137,9 +368,22 @@ pub struct ConnectionPool {
     max_pool_size: usize,
     timer: Timeout,
     connect_timeout: Duration,
-    keepalive_timeout: Duration,
+    connect_parallelism: usize,
+    timer_interval: Duration,
+    min_idle_per_host: usize,
+    max_idle_per_host: Option<usize>,
+    max_requests_per_connection: Option<usize>,
+    on_full: OnFull,
+    max_waiters: usize,
+    waiters: VecDeque<Waiter>,
+    socket_options: SocketOptions,
+    resolver: SharedResolver,
     metrics: ConnectionPoolMetrics,
     state: ConnectionPoolState,
+    warming: HashMap<SocketAddr, usize>,
+    warm_hosts: BTreeSet<SocketAddr>,
+    affinities: HashMap<u64, PoolKey>, // The idle connections last returned by the holders of the keys
+    buffers: BufferArena,
 }
 impl ConnectionPool {
     /// Makes a new `ConnectionPool` instance with the default settings.
@@ -158,6 +402,8 @@ impl ConnectionPool {
     pub fn handle(&self) -> ConnectionPoolHandle {
         ConnectionPoolHandle {
             command_tx: self.command_tx.clone(),
+            resolver: self.resolver.clone(),
+            affinity: None,
         }
     }
 
@@ -166,12 +412,37 @@ impl ConnectionPool {
         &self.metrics
     }
 
-    fn acquire(&mut self, addr: SocketAddr) -> Result<Option<RentedConnection>> {
-        if let Some(mut connection) = self.state.lend_pooled_connection(addr) {
+    /// `addrs` is never empty, and its first element is the primary address.
+    fn acquire(
+        &mut self,
+        addrs: &[SocketAddr],
+        affinity: Option<u64>,
+    ) -> Result<Option<RentedConnection>> {
+        let addr = addrs[0];
+        let affine = affinity
+            .and_then(|k| self.affinities.remove(&k))
+            .filter(|key| key.socket_addr() == addr)
+            .and_then(|key| self.state.lend_pooled_connection_by_key(&key));
+        if affinity.is_some() {
+            if affine.is_some() {
+                self.metrics.affinity_hits.increment();
+            } else {
+                self.metrics.affinity_misses.increment();
+            }
+        }
+        let state = &mut self.state;
+        let pooled = affine.or_else(|| {
+            addrs
+                .iter()
+                .filter_map(|&addr| state.lend_pooled_connection(addr))
+                .next()
+        });
+        if let Some(mut connection) = pooled {
             connection.set_state(ConnectionState::InUse);
             let rented = RentedConnection {
                 connection: Some(connection),
                 command_tx: self.command_tx.clone(),
+                affinity,
             };
             return Ok(Some(rented));
         }
@@ -193,24 +464,228 @@ impl ConnectionPool {
         Ok(None)
     }
 
+    fn connect(&self, addrs: Vec<SocketAddr>) -> Connect {
+        Connect::new(
+            addrs,
+            self.command_tx.clone(),
+            self.connect_timeout,
+            self.socket_options.clone(),
+            self.max_requests_per_connection,
+            self.buffers.clone(),
+            self.metrics.clone(),
+        )
+    }
+
+    fn warm(&mut self, addr: SocketAddr, count: usize) {
+        for _ in 0..count {
+            if self.state.pool_size >= self.max_pool_size {
+                break;
+            }
+            self.state.allocate_connection();
+            self.metrics.allocated_connections.increment();
+            *self.warming.entry(addr).or_insert(0) += 1;
+
+            let command_tx = self.command_tx.clone();
+            let future = self.connect(vec![addr]).then(move |result| {
+                match result {
+                    Ok(connection) => connection.deposit(),
+                    Err(_) => {
+                        let _ = command_tx.send(Command::WarmFailed { addr });
+                    }
+                }
+                Ok(())
+            });
+            self.spawner.spawn(future);
+        }
+    }
+
+    fn finish_warming(&mut self, addr: SocketAddr) {
+        if let Some(n) = self.warming.get_mut(&addr) {
+            *n -= 1;
+        }
+        if self.warming.get(&addr) == Some(&0) {
+            self.warming.remove(&addr);
+        }
+    }
+
+    fn keep_min_idle(&mut self) {
+        if self.min_idle_per_host == 0 {
+            return;
+        }
+        let hosts = self.warm_hosts.iter().cloned().collect::<Vec<_>>();
+        for addr in hosts {
+            let idle =
+                self.state.pooled_count(addr) + self.warming.get(&addr).cloned().unwrap_or(0);
+            if idle < self.min_idle_per_host {
+                self.warm(addr, self.min_idle_per_host - idle);
+            }
+        }
+    }
+
+    /// Pools the returned connection, evicting the oldest idle ones to the host if it has too many.
+    fn repool(
+        &mut self,
+        addr: SocketAddr,
+        connection: Connection,
+        timeout: Option<Duration>,
+        affinity: Option<u64>,
+    ) {
+        match self.max_idle_per_host {
+            Some(0) => {
+                self.state.release_connection();
+                self.metrics.kicked_out_connections.increment();
+                return;
+            }
+            Some(max) => {
+                while self.state.pooled_count(addr) >= max {
+                    self.state.discard_oldest_pooled_connection_to(addr);
+                    self.metrics.kicked_out_connections.increment();
+                }
+            }
+            None => {}
+        }
+        let key = self.state.pool_connection(addr, connection, timeout);
+        if let Some(affinity) = affinity {
+            self.affinities.insert(affinity, key);
+        }
+    }
+
+    /// Forgets the affinities of the connections that are no longer idle.
+    fn prune_affinities(&mut self) {
+        let state = &self.state;
+        self.affinities
+            .retain(|_, key| state.pooled_connections.contains_key(key));
+    }
+
+    fn lend(
+        &mut self,
+        addrs: Vec<SocketAddr>,
+        affinity: Option<u64>,
+        started: Instant,
+        reply_tx: oneshot::Monitored<RentedConnection, Error>,
+    ) {
+        match track!(self.acquire(&addrs, affinity)) {
+            Err(e) => {
+                observe_elapsed(&self.metrics.acquire_duration, started);
+                reply_tx.exit(Err(e))
+            }
+            Ok(Some(c)) => {
+                self.metrics.lent_connections.increment();
+                self.metrics.reused_connections.increment();
+                observe_elapsed(&self.metrics.acquire_duration, started);
+                reply_tx.exit(Ok(c))
+            }
+            Ok(None) => {
+                let metrics = self.metrics.clone();
+                let future = self.connect(addrs).then(move |result| {
+                    if result.is_ok() {
+                        metrics.lent_connections.increment();
+                        metrics.new_connections.increment();
+                    }
+                    observe_elapsed(&metrics.acquire_duration, started);
+                    reply_tx.exit(result.map(|mut c| {
+                        c.affinity = affinity;
+                        c
+                    }));
+                    Ok(())
+                });
+                self.spawner.spawn(future);
+            }
+        }
+    }
+
+    /// Returns `true` if the pool cannot lend any connection without waiting for a returned one.
+    fn is_exhausted(&self) -> bool {
+        self.state.pool_size >= self.max_pool_size && self.state.pooled_connections.is_empty()
+    }
+
+    fn serve_waiters(&mut self) {
+        while !self.waiters.is_empty() && !self.is_exhausted() {
+            let waiter = self.waiters.pop_front().expect("never fails");
+            self.lend(
+                waiter.addrs,
+                waiter.affinity,
+                waiter.started,
+                waiter.reply_tx,
+            );
+        }
+        self.metrics
+            .acquire_queue_length
+            .set(self.waiters.len() as f64);
+    }
+
+    fn expire_waiters(&mut self) -> Result<()> {
+        let mut i = 0;
+        while i < self.waiters.len() {
+            if let Async::Ready(()) = track!(self.waiters[i].timeout.poll().map_err(Error::from))? {
+                let waiter = self.waiters.remove(i).expect("never fails");
+                let e = track!(ErrorKind::Timeout.cause(TimeoutPhase::Acquire)).into();
+                observe_elapsed(&self.metrics.acquire_duration, waiter.started);
+                waiter.reply_tx.exit(Err(e));
+            } else {
+                i += 1;
+            }
+        }
+        self.metrics
+            .acquire_queue_length
+            .set(self.waiters.len() as f64);
+        Ok(())
+    }
+
+    fn stats(&self) -> ConnectionPoolStats {
+        let mut idle_by_host = BTreeMap::new();
+        for key in self.state.pooled_connections.keys() {
+            *idle_by_host
+                .entry(SocketAddr::new(key.addr, key.port))
+                .or_insert(0) += 1;
+        }
+        let idle = self.state.pooled_connections.len();
+        ConnectionPoolStats {
+            total: self.state.pool_size,
+            idle,
+            in_use: self.state.pool_size - idle,
+            waiters: self.waiters.len(),
+            idle_by_host,
+        }
+    }
+
     fn handle_command(&mut self, command: Command) {
         match command {
-            Command::Acquire { addr, reply_tx } => match track!(self.acquire(addr)) {
-                Err(e) => reply_tx.exit(Err(e)),
-                Ok(Some(c)) => {
-                    self.metrics.lent_connections.increment();
-                    reply_tx.exit(Ok(c))
-                }
-                Ok(None) => {
-                    self.metrics.lent_connections.increment();
-                    let future = Connect::new(addr, self.command_tx.clone(), self.connect_timeout)
-                        .then(move |result| {
-                            reply_tx.exit(result);
-                            Ok(())
+            Command::Acquire {
+                mut addrs,
+                affinity,
+                reply_tx,
+            } => {
+                addrs.truncate(self.connect_parallelism);
+                let started = Instant::now();
+                let must_wait = !self.waiters.is_empty() || self.is_exhausted();
+                match self.on_full {
+                    OnFull::Wait(timeout) if must_wait && self.waiters.len() < self.max_waiters => {
+                        self.waiters.push_back(Waiter {
+                            addrs,
+                            affinity,
+                            started,
+                            reply_tx,
+                            timeout: timer::timeout(timeout),
                         });
-                    self.spawner.spawn(future);
+                    }
+                    _ => self.lend(addrs, affinity, started, reply_tx),
                 }
-            },
+            }
+            Command::Warm { addr, count } => {
+                if self.min_idle_per_host > 0 {
+                    self.warm_hosts.insert(addr);
+                }
+                self.warm(addr, count);
+            }
+            Command::Deposit { connection } => {
+                let addr = connection.peer_addr();
+                self.finish_warming(addr);
+                self.repool(addr, connection, None, None);
+            }
+            Command::WarmFailed { addr } => {
+                self.finish_warming(addr);
+            }
             Command::Discard { reason } => {
                 self.metrics.returned_connections.increment();
                 self.state.release_connection();
@@ -226,12 +701,34 @@ impl ConnectionPool {
                     }
                 }
             }
-            Command::Reuse { connection } => {
+            Command::Reuse {
+                connection,
+                affinity,
+            } => {
                 self.metrics.returned_connections.increment();
-                self.state
-                    .pool_connection(connection.peer_addr(), connection);
+
+                // Expired connections are detected at ticks, so the timer interval is subtracted
+                // from the timeout advertised by the server so as not to reuse connections closed by it
+                let addr = connection.peer_addr();
+                match connection
+                    .server_keepalive_timeout()
+                    .map(|t| t.checked_sub(self.timer_interval))
+                {
+                    None => self.repool(addr, connection, None, affinity),
+                    Some(Some(timeout)) if timeout > Duration::from_secs(0) => {
+                        self.repool(addr, connection, Some(timeout), affinity)
+                    }
+                    Some(_) => {
+                        self.state.release_connection();
+                        self.metrics.closed_connections.increment();
+                    }
+                }
+            }
+            Command::Stats { reply_tx } => {
+                let _ = reply_tx.send(self.stats());
             }
         }
+        self.serve_waiters();
     }
 }
 impl Future for ConnectionPool {
@@ -240,33 +737,148 @@ impl Future for ConnectionPool {
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         while let Async::Ready(()) = track!(self.timer.poll().map_err(Error::from))? {
-            let interval = Duration::from_secs(TIMER_INTERVAL_SECS);
-            let removed = self.state.tick(interval, self.keepalive_timeout);
+            let removed = self.state.tick(self.timer_interval);
             self.metrics.expired_connections.add_u64(removed as u64);
-            self.timer = timer::timeout(interval);
+            let removed = self.state.remove_closed(Connection::is_stale);
+            self.metrics.peer_closed_connections.add_u64(removed as u64);
+            self.prune_affinities();
+            self.timer = timer::timeout(self.timer_interval);
+            self.serve_waiters();
+            self.keep_min_idle();
         }
         while let Async::Ready(command) = self.command_rx.poll().expect("never fails") {
             let command = command.expect("never fails");
             self.handle_command(command);
         }
+        track!(self.expire_waiters())?;
         Ok(Async::NotReady)
     }
 }
 
+/// The behavior of [`ConnectionPool`] when it is full (i.e., it has reached the maximum size and has no idle connections).
+///
+/// [`ConnectionPool`]: ./struct.ConnectionPool.html
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnFull {
+    /// Acquisition requests fail immediately with an `ErrorKind::TemporarilyUnavailable` error.
+    ///
+    /// This is suitable for latency-critical applications that prefer shedding load.
+    FailFast,
+
+    /// Acquisition requests wait in FIFO order until connections become available.
+    ///
+    /// If a request has waited beyond the duration, it fails with an `ErrorKind::Timeout` error.
+    Wait(Duration),
+}
+
 /// Handle for operating [`ConnectionPool`].
 ///
 /// [`ConnectionPool`]: ./struct.ConnectionPool.html
 #[derive(Debug, Clone)]
 pub struct ConnectionPoolHandle {
     command_tx: mpsc::Sender<Command>,
+    resolver: SharedResolver,
+    affinity: Option<u64>,
+}
+impl ConnectionPoolHandle {
+    /// Returns a handle that acquires connections with the given affinity key.
+    ///
+    /// The pool prefers lending the idle connection last returned by a holder of the same key,
+    /// so that successive requests of a logical session (e.g., a user) are likely to be sent over the same connection.
+    /// This is useful for servers that keep per-connection state.
+    /// If the connection is in use or gone, any idle connection to the server is lent (or a new one is established) as usual.
+    ///
+    /// The hit rate can be monitored via `ConnectionPoolMetrics::affinity_hits` and `affinity_misses`.
+    pub fn with_affinity(&self, key: u64) -> Self {
+        ConnectionPoolHandle {
+            command_tx: self.command_tx.clone(),
+            resolver: self.resolver.clone(),
+            affinity: Some(key),
+        }
+    }
+
+    /// Establishes `count` connections to `addr` in advance, and parks them in the pool.
+    ///
+    /// This is useful for reducing the latency of the first requests to the server.
+    /// Connections are not established beyond the maximum size of the pool.
+    ///
+    /// If [`ConnectionPoolBuilder::min_idle_per_host`] is specified,
+    /// the pool keeps that many idle connections to `addr` afterwards (even if `count` is `0`).
+    ///
+    /// [`ConnectionPoolBuilder::min_idle_per_host`]: ./struct.ConnectionPoolBuilder.html#method.min_idle_per_host
+    pub fn warm(&self, addr: SocketAddr, count: usize) {
+        let _ = self.command_tx.send(Command::Warm { addr, count });
+    }
+
+    /// Returns a future that takes a snapshot of the current state of the pool.
+    pub fn stats(&self) -> impl Future<Item = ConnectionPoolStats, Error = Error> + Send + 'static {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let _ = self.command_tx.send(Command::Stats { reply_tx });
+        reply_rx.map_err(|_| {
+            track!(Error::from(
+                ErrorKind::Other.cause("`ConnectionPool` has been dropped")
+            ))
+        })
+    }
+}
+
+/// A snapshot of the state of [`ConnectionPool`].
+///
+/// This is returned by [`ConnectionPoolHandle::stats`].
+///
+/// [`ConnectionPool`]: ./struct.ConnectionPool.html
+/// [`ConnectionPoolHandle::stats`]: ./struct.ConnectionPoolHandle.html#method.stats
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionPoolStats {
+    total: usize,
+    idle: usize,
+    in_use: usize,
+    waiters: usize,
+    idle_by_host: BTreeMap<SocketAddr, usize>,
+}
+impl ConnectionPoolStats {
+    /// Returns the number of the connections allocated by the pool.
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Returns the number of the idle connections parked in the pool.
+    pub fn idle(&self) -> usize {
+        self.idle
+    }
+
+    /// Returns the number of the connections that are not idle.
+    ///
+    /// This includes the connections being established.
+    pub fn in_use(&self) -> usize {
+        self.in_use
+    }
+
+    /// Returns the number of the acquisition requests waiting for available connections.
+    pub fn waiters(&self) -> usize {
+        self.waiters
+    }
+
+    /// Returns the number of the idle connections for each server.
+    pub fn idle_by_host(&self) -> &BTreeMap<SocketAddr, usize> {
+        &self.idle_by_host
+    }
 }
 impl AcquireConnection for ConnectionPoolHandle {
     type Connection = RentedConnection;
     type Future = Box<dyn Future<Item = Self::Connection, Error = Error> + Send + 'static>;
 
     fn acquire_connection(&mut self, addr: SocketAddr) -> Self::Future {
+        self.acquire_connection_to_any(&[addr])
+    }
+
+    fn acquire_connection_to_any(&mut self, addrs: &[SocketAddr]) -> Self::Future {
         let (reply_tx, reply_rx) = oneshot::monitor();
-        let command = Command::Acquire { addr, reply_tx };
+        let command = Command::Acquire {
+            addrs: addrs.to_vec(),
+            affinity: self.affinity,
+            reply_tx,
+        };
         let _ = self.command_tx.send(command);
 
         let future = reply_rx.map_err(|e| {
@@ -276,21 +888,74 @@ impl AcquireConnection for ConnectionPoolHandle {
         });
         Box::new(future)
     }
+
+    fn resolve(&mut self, host: &str, port: u16) -> BoxResolveFuture {
+        self.resolver.resolve(host, port)
+    }
 }
 
 /// A connection rented to a client.
+///
+/// The connection is returned to the pool when it is dropped.
+/// Whether it is pooled for reuse or closed depends on the outcome of the last request issued via the connection
+/// (e.g., connections dropped in the middle of requests are closed).
+/// [`recycle`] and [`release`] make the decision explicit.
+///
+/// [`recycle`]: #method.recycle
+/// [`release`]: #method.release
 #[derive(Debug)]
 pub struct RentedConnection {
     connection: Option<Connection>,
     command_tx: mpsc::Sender<Command>,
+    affinity: Option<u64>,
 }
 impl RentedConnection {
     fn new(connection: Connection, command_tx: mpsc::Sender<Command>) -> Self {
         RentedConnection {
             connection: Some(connection),
             command_tx,
+            affinity: None,
+        }
+    }
+
+    /// Returns the TCP address of the peer.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.connection.as_ref().expect("never fails").peer_addr()
+    }
+
+    /// Returns the number of the requests that the connection has served (i.e., whose responses have been received entirely).
+    ///
+    /// The count is kept while the connection is pooled,
+    /// so this is `0` for a new connection and positive for a reused one.
+    /// See also `ConnectionPoolBuilder::max_requests_per_connection`.
+    pub fn completed_requests(&self) -> usize {
+        self.connection
+            .as_ref()
+            .expect("never fails")
+            .completed_requests()
+    }
+
+    /// Returns the connection to the pool immediately, so that it can be reused by subsequent acquisitions.
+    ///
+    /// Unlike dropping, this pools the connection even if no request has been issued via it.
+    /// Note that the connection is still closed if it has been closed by the peer or has served the maximum number of requests.
+    pub fn recycle(mut self) {
+        let connection = self.as_mut();
+        if connection.state() != ConnectionState::Closed {
+            connection.set_state(ConnectionState::Recyclable);
         }
     }
+
+    /// Closes the connection, and returns its slot to the pool immediately.
+    pub fn release(mut self) {
+        self.as_mut().set_state(ConnectionState::Closed);
+    }
+
+    fn deposit(mut self) {
+        let mut connection = self.connection.take().expect("never fails");
+        connection.set_state(ConnectionState::Recyclable);
+        let _ = self.command_tx.send(Command::Deposit { connection });
+    }
 }
 impl AsMut<Connection> for RentedConnection {
     fn as_mut(&mut self) -> &mut Connection {
@@ -299,9 +964,16 @@ impl AsMut<Connection> for RentedConnection {
 }
 impl Drop for RentedConnection {
     fn drop(&mut self) {
-        let connection = self.connection.take().expect("never fails");
+        let connection = if let Some(connection) = self.connection.take() {
+            connection
+        } else {
+            return;
+        };
         let command = match connection.state() {
-            ConnectionState::Recyclable => Command::Reuse { connection },
+            ConnectionState::Recyclable => Command::Reuse {
+                connection,
+                affinity: self.affinity,
+            },
             ConnectionState::Closed => Command::Discard {
                 reason: DiscardReason::Closed,
             },
@@ -316,34 +988,92 @@ impl Drop for RentedConnection {
 #[derive(Debug)]
 enum Command {
     Acquire {
-        addr: SocketAddr,
+        addrs: Vec<SocketAddr>, // Never empty
+        affinity: Option<u64>,
         reply_tx: oneshot::Monitored<RentedConnection, Error>,
     },
     Reuse {
         connection: Connection,
+        affinity: Option<u64>,
     },
     Discard {
         reason: DiscardReason,
     },
+    Warm {
+        addr: SocketAddr,
+        count: usize,
+    },
+    Deposit {
+        connection: Connection,
+    },
+    WarmFailed {
+        addr: SocketAddr,
+    },
+    Stats {
+        reply_tx: oneshot::Sender<ConnectionPoolStats>,
+    },
+}
+
+fn observe_elapsed(histogram: &Histogram, started: Instant) {
+    histogram.observe(started.elapsed().as_secs_f64());
+}
+
+#[derive(Debug)]
+struct Waiter {
+    addrs: Vec<SocketAddr>,
+    affinity: Option<u64>,
+    started: Instant,
+    reply_tx: oneshot::Monitored<RentedConnection, Error>,
+    timeout: Timeout,
 }
 
 struct Connect {
-    future: Box<dyn Future<Item = TcpStream, Error = Error> + Send + 'static>,
-    addr: SocketAddr,
+    future: ConnectAny,
+    addrs: Vec<SocketAddr>,
     command_tx: mpsc::Sender<Command>,
+    socket_options: SocketOptions,
+    max_requests: Option<usize>,
+    buffers: BufferArena,
+    metrics: ConnectionPoolMetrics,
+    started: Instant,
 }
 impl Connect {
-    fn new(addr: SocketAddr, command_tx: mpsc::Sender<Command>, timeout: Duration) -> Self {
-        let future = TcpStream::connect(addr)
-            .map_err(|e| track!(Error::from(e)))
-            .timeout_after(timeout)
-            .map_err(|e| {
-                e.unwrap_or_else(|| track!(ErrorKind::Timeout.cause("TCP connect timeout")).into())
-            });
+    fn new(
+        addrs: Vec<SocketAddr>,
+        command_tx: mpsc::Sender<Command>,
+        timeout: Duration,
+        socket_options: SocketOptions,
+        max_requests: Option<usize>,
+        buffers: BufferArena,
+        metrics: ConnectionPoolMetrics,
+    ) -> Self {
+        let attempts = addrs
+            .iter()
+            .map(|&addr| {
+                let future = TcpStream::connect(addr)
+                    .map_err(|e| track!(Error::from(e)))
+                    .timeout_after(timeout)
+                    .map_err(|e| {
+                        e.unwrap_or_else(|| {
+                            track!(ErrorKind::Timeout.cause(TimeoutPhase::Connect)).into()
+                        })
+                    });
+                let future: BoxConnectFuture = Box::new(future);
+                (addr, future)
+            })
+            .collect();
         Connect {
-            future: Box::new(future),
-            addr,
+            future: ConnectAny {
+                attempts,
+                last_error: None,
+            },
+            addrs,
             command_tx,
+            socket_options,
+            max_requests,
+            buffers,
+            metrics,
+            started: Instant::now(),
         }
     }
 }
@@ -352,8 +1082,9 @@ impl Future for Connect {
     type Error = Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        match track!(self.future.poll(); self.addr) {
+        match track!(self.future.poll(); self.addrs) {
             Err(e) => {
+                observe_elapsed(&self.metrics.failed_connect_duration, self.started);
                 let command = Command::Discard {
                     reason: DiscardReason::ConnectFailed,
                 };
@@ -361,8 +1092,11 @@ impl Future for Connect {
                 Err(e)
             }
             Ok(Async::NotReady) => Ok(Async::NotReady),
-            Ok(Async::Ready(stream)) => {
-                let connection = Connection::new(self.addr, stream);
+            Ok(Async::Ready((addr, stream))) => {
+                observe_elapsed(&self.metrics.succeeded_connect_duration, self.started);
+                self.socket_options.apply(&stream);
+                let mut connection = Connection::with_arena(addr, stream, &self.buffers);
+                connection.set_max_requests(self.max_requests);
                 Ok(Async::Ready(RentedConnection::new(
                     connection,
                     self.command_tx.clone(),
@@ -372,25 +1106,71 @@ impl Future for Connect {
     }
 }
 
+type BoxConnectFuture = Box<dyn Future<Item = TcpStream, Error = Error> + Send + 'static>;
+
+/// `Future` that connects to the addresses concurrently, and yields the first established stream.
+///
+/// The other attempts are dropped (i.e., their sockets are closed) as soon as one succeeds.
+/// If all of them fail, this fails with the last error.
+struct ConnectAny {
+    attempts: Vec<(SocketAddr, BoxConnectFuture)>,
+    last_error: Option<Error>,
+}
+impl Future for ConnectAny {
+    type Item = (SocketAddr, TcpStream);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut i = 0;
+        while i < self.attempts.len() {
+            match self.attempts[i].1.poll() {
+                Err(e) => {
+                    let _ = self.attempts.remove(i);
+                    self.last_error = Some(e);
+                }
+                Ok(Async::NotReady) => i += 1,
+                Ok(Async::Ready(stream)) => {
+                    let (addr, _) = self.attempts.remove(i);
+                    self.attempts.clear();
+                    return Ok(Async::Ready((addr, stream)));
+                }
+            }
+        }
+        if self.attempts.is_empty() {
+            let e = self.last_error.take().expect("never fails");
+            return Err(track!(e));
+        }
+        Ok(Async::NotReady)
+    }
+}
+
 #[derive(Debug)]
 struct ConnectionPoolState<C = Connection> {
     pooled_connections: BTreeMap<PoolKey, C>,
     timeout_queue: BinaryHeap<QueueEntry>,
     elapsed_time: Duration, // Approximate elapsed time since the pool was created
+    keepalive_timeout: Duration,
     pool_size: usize,
     seqno: u64,
+    jitter: Option<Jitter>,
 }
 impl<C> ConnectionPoolState<C> {
-    fn new() -> Self {
+    fn new(keepalive_timeout: Duration) -> Self {
         ConnectionPoolState {
             pooled_connections: BTreeMap::new(),
             timeout_queue: BinaryHeap::new(),
             elapsed_time: Duration::from_secs(0),
+            keepalive_timeout,
             pool_size: 0,
             seqno: 0,
+            jitter: None,
         }
     }
 
+    fn set_jitter(&mut self, spread: Duration, rng: Rng) {
+        self.jitter = Some(Jitter { spread, rng });
+    }
+
     fn allocate_connection(&mut self) {
         self.pool_size += 1;
     }
@@ -401,7 +1181,7 @@ impl<C> ConnectionPoolState<C> {
     }
 
     fn lend_pooled_connection(&mut self, addr: SocketAddr) -> Option<C> {
-        // Tries to select the most recently used connection
+        // Tries to select the most recently used connection (i.e., the one that expires last)
         let (lower, upper) = PoolKey::range(addr);
         let selected = self
             .pooled_connections
@@ -417,6 +1197,11 @@ impl<C> ConnectionPoolState<C> {
         }
     }
 
+    fn lend_pooled_connection_by_key(&mut self, key: &PoolKey) -> Option<C> {
+        // The stale entry left in `timeout_queue` (if any) is skipped at the next tick
+        self.pooled_connections.remove(key)
+    }
+
     fn discard_oldest_pooled_connection(&mut self) -> Option<C> {
         while let Some(entry) = self.timeout_queue.pop() {
             let removed = self.pooled_connections.remove(&entry.to_pool_key());
@@ -431,6 +1216,14 @@ impl<C> ConnectionPoolState<C> {
         None
     }
 
+    fn discard_oldest_pooled_connection_to(&mut self, addr: SocketAddr) -> Option<C> {
+        // The stale entry left in `timeout_queue` (if any) is skipped at the next tick
+        let key = self.get_oldest(addr)?;
+        let connection = self.pooled_connections.remove(&key).expect("never fails");
+        self.release_connection();
+        Some(connection)
+    }
+
     fn get_oldest(&self, addr: SocketAddr) -> Option<PoolKey> {
         let (lower, upper) = PoolKey::range(addr);
         self.pooled_connections
@@ -439,27 +1232,56 @@ impl<C> ConnectionPoolState<C> {
             .map(|(key, _)| key.clone())
     }
 
-    fn pool_connection(&mut self, addr: SocketAddr, connection: C) {
-        let key = PoolKey::new(addr, self.elapsed_time, self.seqno);
+    /// Pools the connection until the keepalive timeout (or `timeout` if it is shorter) expires.
+    fn pool_connection(
+        &mut self,
+        addr: SocketAddr,
+        connection: C,
+        timeout: Option<Duration>,
+    ) -> PoolKey {
+        // The jitter shifts the keepalive timeout by `[-spread, +spread]`
+        let keepalive_timeout = match self.jitter {
+            Some(ref mut j) => self.keepalive_timeout + j.offset() - j.spread,
+            None => self.keepalive_timeout,
+        };
+        let timeout = timeout.map_or(keepalive_timeout, |t| cmp::min(t, keepalive_timeout));
+        let key = PoolKey::new(addr, self.elapsed_time + timeout, self.seqno);
         self.seqno += 1;
 
-        if !self.pool_contains(addr) {
-            self.timeout_queue.push(key.to_queue_entry());
+        // With jitter or a shorter timeout, the new connection may expire earlier than the ones already pooled
+        match self.get_oldest(addr) {
+            Some(ref oldest) if *oldest < key => {}
+            _ => self.timeout_queue.push(key.to_queue_entry()),
         }
-        self.pooled_connections.insert(key, connection);
+        self.pooled_connections.insert(key.clone(), connection);
+        key
     }
 
-    fn pool_contains(&self, addr: SocketAddr) -> bool {
+    /// Removes the pooled connections for which `is_closed` returns `true`.
+    fn remove_closed<F>(&mut self, mut is_closed: F) -> usize
+    where
+        F: FnMut(&C) -> bool,
+    {
+        // The stale entries left in `timeout_queue` (if any) are skipped at the next tick
+        let before = self.pooled_connections.len();
+        self.pooled_connections.retain(|_, c| !is_closed(c));
+        let removed_count = before - self.pooled_connections.len();
+        for _ in 0..removed_count {
+            self.release_connection();
+        }
+        removed_count
+    }
+
+    fn pooled_count(&self, addr: SocketAddr) -> usize {
         let (lower, upper) = PoolKey::range(addr);
-        self.pooled_connections.range(lower..upper).nth(0).is_some()
+        self.pooled_connections.range(lower..upper).count()
     }
 
-    fn tick(&mut self, duration: Duration, keepalive_timeout: Duration) -> usize {
+    fn tick(&mut self, duration: Duration) -> usize {
         self.elapsed_time += duration;
-        let now = self.elapsed_time;
         let mut removed_count = 0;
         while let Some(entry) = self.timeout_queue.peek().cloned() {
-            if entry.pooled_time.0 + keepalive_timeout < now {
+            if entry.expiration_time.0 < self.elapsed_time {
                 let _ = self.timeout_queue.pop();
                 let removed = self
                     .pooled_connections
@@ -480,32 +1302,49 @@ impl<C> ConnectionPoolState<C> {
     }
 }
 
+/// The jitter of keepalive timeouts.
+#[derive(Debug)]
+struct Jitter {
+    spread: Duration,
+    rng: Rng,
+}
+impl Jitter {
+    /// Returns a random duration in `[0, 2 * spread]`.
+    fn offset(&mut self) -> Duration {
+        (self.spread * 2).mul_f64(self.rng.next_f64())
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 struct PoolKey {
     addr: IpAddr,
     port: u16,
-    pooled_time: Duration,
+    expiration_time: Duration,
     seqno: u64,
 }
 impl PoolKey {
-    fn new(addr: SocketAddr, now: Duration, seqno: u64) -> Self {
+    fn new(addr: SocketAddr, expiration_time: Duration, seqno: u64) -> Self {
         PoolKey {
             addr: addr.ip(),
             port: addr.port(),
-            pooled_time: now,
+            expiration_time,
             seqno,
         }
     }
 
     fn range(addr: SocketAddr) -> (Self, Self) {
         let lower = PoolKey::new(addr, Duration::from_secs(0), 0);
-        let upper = PoolKey::new(addr, Duration::from_secs(std::u64::MAX), std::u64::MAX);
+        let upper = PoolKey::new(addr, Duration::from_secs(u64::MAX), u64::MAX);
         (lower, upper)
     }
 
+    fn socket_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.addr, self.port)
+    }
+
     fn to_queue_entry(&self) -> QueueEntry {
         QueueEntry {
-            pooled_time: Reverse(self.pooled_time),
+            expiration_time: Reverse(self.expiration_time),
             addr: self.addr,
             port: self.port,
             seqno: self.seqno,
@@ -515,7 +1354,7 @@ impl PoolKey {
 
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 struct QueueEntry {
-    pooled_time: Reverse<Duration>,
+    expiration_time: Reverse<Duration>,
     addr: IpAddr,
     port: u16,
     seqno: u64,
@@ -529,7 +1368,7 @@ impl QueueEntry {
         PoolKey {
             addr: self.addr,
             port: self.port,
-            pooled_time: self.pooled_time.0,
+            expiration_time: self.expiration_time.0,
             seqno: self.seqno,
         }
     }
@@ -544,11 +1383,359 @@ enum DiscardReason {
 
 #[cfg(test)]
 mod tests {
+    use fibers_global;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+
     use super::*;
 
+    #[test]
+    fn acquire_queue_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let _streams = listener.incoming().collect::<Vec<_>>();
+        });
+
+        let pool = ConnectionPoolBuilder::new()
+            .max_pool_size(1)
+            .acquire_queue(1, Duration::from_millis(100))
+            .finish(fibers_global::handle());
+        let mut handle = pool.handle();
+        #[cfg(feature = "metrics")]
+        let metrics = pool.metrics().clone();
+        fibers_global::spawn(pool.map_err(|e| panic!("{}", e)));
+
+        let connection = fibers_global::execute(handle.acquire_connection(addr)).unwrap();
+        assert_eq!(connection.peer_addr(), addr);
+        let stats = fibers_global::execute(handle.stats()).unwrap();
+        assert_eq!((stats.total(), stats.idle(), stats.in_use()), (1, 0, 1));
+
+        // Times out
+        let e = fibers_global::execute(handle.acquire_connection(addr))
+            .err()
+            .unwrap();
+        assert_eq!(e.timeout_phase(), Some(TimeoutPhase::Acquire));
+
+        // Served when the connection is returned
+        let waiting = handle.acquire_connection(addr);
+        let rejected = handle.acquire_connection(addr);
+        drop(connection);
+        let mut connection = fibers_global::execute(waiting).unwrap();
+        connection.as_mut().set_state(ConnectionState::Recyclable);
+        drop(connection);
+        let stats = fibers_global::execute(handle.stats()).unwrap();
+        assert_eq!((stats.total(), stats.idle(), stats.in_use()), (1, 1, 0));
+        assert_eq!(stats.idle_by_host().get(&addr), Some(&1));
+
+        let e = fibers_global::execute(rejected).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::TemporarilyUnavailable);
+
+        // Pool hit (the first connection was a miss, and so was its replacement
+        // since the first one was discarded after being dropped while in use)
+        let connection = fibers_global::execute(handle.acquire_connection(addr)).unwrap();
+        drop(connection);
+        #[cfg(feature = "metrics")]
+        assert_eq!(metrics.new_connections(), 2);
+        #[cfg(feature = "metrics")]
+        assert_eq!(metrics.reused_connections(), 1);
+
+        // All of the five acquisitions are observed, including the one timed out after 100 milliseconds
+        #[cfg(feature = "metrics")]
+        assert_eq!(metrics.acquire_duration().count(), 5);
+        #[cfg(feature = "metrics")]
+        assert_eq!(metrics.succeeded_connect_duration().count(), 2);
+        #[cfg(feature = "metrics")]
+        assert!(metrics.acquire_duration().sum() >= 0.1);
+    }
+
+    #[test]
+    fn connect_duration_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        let pool = ConnectionPool::new(fibers_global::handle());
+        let mut handle = pool.handle();
+        #[cfg(feature = "metrics")]
+        let metrics = pool.metrics().clone();
+        fibers_global::spawn(pool.map_err(|e| panic!("{}", e)));
+
+        assert!(fibers_global::execute(handle.acquire_connection(addr)).is_err());
+        #[cfg(feature = "metrics")]
+        assert_eq!(metrics.failed_connect_duration().count(), 1);
+        #[cfg(feature = "metrics")]
+        assert_eq!(metrics.succeeded_connect_duration().count(), 0);
+        #[cfg(feature = "metrics")]
+        assert_eq!(metrics.new_connections(), 0);
+        #[cfg(feature = "metrics")]
+        assert_eq!(metrics.lent_connections(), 0);
+    }
+
+    #[test]
+    fn recycle_and_release_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let _streams = listener.incoming().collect::<Vec<_>>();
+        });
+
+        let pool = ConnectionPool::new(fibers_global::handle());
+        let mut handle = pool.handle();
+        #[cfg(feature = "metrics")]
+        let metrics = pool.metrics().clone();
+        fibers_global::spawn(pool.map_err(|e| panic!("{}", e)));
+
+        // Pooled even though no request has been issued
+        let connection = fibers_global::execute(handle.acquire_connection(addr)).unwrap();
+        connection.recycle();
+        let stats = fibers_global::execute(handle.stats()).unwrap();
+        assert_eq!((stats.total(), stats.idle(), stats.in_use()), (1, 1, 0));
+
+        let connection = fibers_global::execute(handle.acquire_connection(addr)).unwrap();
+        #[cfg(feature = "metrics")]
+        assert_eq!(metrics.reused_connections(), 1);
+        connection.release();
+        let stats = fibers_global::execute(handle.stats()).unwrap();
+        assert_eq!((stats.total(), stats.idle(), stats.in_use()), (0, 0, 0));
+        #[cfg(feature = "metrics")]
+        assert_eq!(metrics.closed_connections(), 1);
+
+        // Dropping an unused connection discards it
+        let connection = fibers_global::execute(handle.acquire_connection(addr)).unwrap();
+        drop(connection);
+        let stats = fibers_global::execute(handle.stats()).unwrap();
+        assert_eq!(stats.total(), 0);
+        #[cfg(feature = "metrics")]
+        assert_eq!(metrics.request_failed_connections(), 1);
+    }
+
+    #[test]
+    fn affinity_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let _streams = listener.incoming().collect::<Vec<_>>();
+        });
+
+        let pool = ConnectionPool::new(fibers_global::handle());
+        let mut handle = pool.handle();
+        let mut affine = handle.with_affinity(1);
+        #[cfg(feature = "metrics")]
+        let metrics = pool.metrics().clone();
+        fibers_global::spawn(pool.map_err(|e| panic!("{}", e)));
+
+        // The connections are distinguished by their request counts
+        let mut a = fibers_global::execute(affine.acquire_connection(addr)).unwrap();
+        a.as_mut().increment_requests();
+        let b = fibers_global::execute(handle.acquire_connection(addr)).unwrap();
+        a.recycle();
+        b.recycle();
+        #[cfg(feature = "metrics")]
+        assert_eq!((metrics.affinity_hits(), metrics.affinity_misses()), (0, 1));
+
+        // The connection associated with the key is preferred to the most recently used one
+        let a = fibers_global::execute(affine.acquire_connection(addr)).unwrap();
+        assert_eq!(a.connection.as_ref().unwrap().requests(), 1);
+        #[cfg(feature = "metrics")]
+        assert_eq!((metrics.affinity_hits(), metrics.affinity_misses()), (1, 1));
+
+        // Falls back to another connection while the affine one is in use
+        let b = fibers_global::execute(affine.acquire_connection(addr)).unwrap();
+        assert_eq!(b.connection.as_ref().unwrap().requests(), 0);
+        #[cfg(feature = "metrics")]
+        assert_eq!((metrics.affinity_hits(), metrics.affinity_misses()), (1, 2));
+        a.release();
+        b.recycle();
+
+        // The last holder of the key wins
+        let b = fibers_global::execute(affine.acquire_connection(addr)).unwrap();
+        assert_eq!(b.connection.as_ref().unwrap().requests(), 0);
+        #[cfg(feature = "metrics")]
+        assert_eq!((metrics.affinity_hits(), metrics.affinity_misses()), (2, 2));
+        drop(b);
+    }
+
+    #[test]
+    fn peer_closed_connections_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut streams = listener.incoming();
+            drop(streams.next()); // Closes the first connection
+            let _streams = streams.collect::<Vec<_>>();
+        });
+
+        let pool = ConnectionPoolBuilder::new()
+            .timer_interval(Duration::from_millis(10))
+            .finish(fibers_global::handle());
+        let mut handle = pool.handle();
+        #[cfg(feature = "metrics")]
+        let metrics = pool.metrics().clone();
+        fibers_global::spawn(pool.map_err(|e| panic!("{}", e)));
+
+        let a = fibers_global::execute(handle.acquire_connection(addr)).unwrap();
+        let b = fibers_global::execute(handle.acquire_connection(addr)).unwrap();
+        a.recycle();
+        b.recycle();
+        thread::sleep(Duration::from_millis(200));
+
+        let stats = fibers_global::execute(handle.stats()).unwrap();
+        assert_eq!((stats.total(), stats.idle()), (1, 1));
+        #[cfg(feature = "metrics")]
+        assert_eq!(metrics.peer_closed_connections(), 1);
+        #[cfg(feature = "metrics")]
+        assert_eq!(metrics.expired_connections(), 0);
+        #[cfg(feature = "metrics")]
+        assert_eq!(metrics.pool_size(), 1);
+    }
+
+    #[test]
+    fn warm_with_max_idle_per_host_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let _streams = listener.incoming().collect::<Vec<_>>();
+        });
+
+        let pool = ConnectionPoolBuilder::new()
+            .max_idle_per_host(2)
+            .finish(fibers_global::handle());
+        let handle = pool.handle();
+        #[cfg(feature = "metrics")]
+        let metrics = pool.metrics().clone();
+        fibers_global::spawn(pool.map_err(|e| panic!("{}", e)));
+
+        handle.warm(addr, 4);
+        thread::sleep(Duration::from_millis(200));
+
+        let stats = fibers_global::execute(handle.stats()).unwrap();
+        assert_eq!((stats.total(), stats.idle()), (2, 2));
+        assert_eq!(stats.idle_by_host().get(&addr), Some(&2));
+        #[cfg(feature = "metrics")]
+        assert_eq!(metrics.kicked_out_connections(), 2);
+    }
+
+    #[test]
+    fn min_idle_per_host_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let _streams = listener.incoming().collect::<Vec<_>>();
+        });
+        let other_listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let other_addr = other_listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let _streams = other_listener.incoming().collect::<Vec<_>>();
+        });
+
+        let pool = ConnectionPoolBuilder::new()
+            .min_idle_per_host(2)
+            .timer_interval(Duration::from_millis(10))
+            .finish(fibers_global::handle());
+        let mut handle = pool.handle();
+        fibers_global::spawn(pool.map_err(|e| panic!("{}", e)));
+
+        // Hosts that are only requested are not kept warm
+        let connection = fibers_global::execute(handle.acquire_connection(other_addr)).unwrap();
+        drop(connection);
+
+        handle.warm(addr, 0);
+        thread::sleep(Duration::from_millis(200));
+
+        let stats = fibers_global::execute(handle.stats()).unwrap();
+        assert_eq!(stats.idle_by_host().get(&addr), Some(&2));
+        assert_eq!(stats.idle_by_host().get(&other_addr), None);
+    }
+
+    #[test]
+    fn unsolicited_bytes_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut streams = listener.incoming();
+            let mut first = streams.next().unwrap().unwrap();
+            first.write_all(b"HTTP/1.1 200 OK\r\n\r\n").unwrap(); // Not requested
+            let _streams = streams.collect::<Vec<_>>();
+            drop(first);
+        });
+
+        let pool = ConnectionPoolBuilder::new()
+            .timer_interval(Duration::from_millis(10))
+            .finish(fibers_global::handle());
+        let mut handle = pool.handle();
+        #[cfg(feature = "metrics")]
+        let metrics = pool.metrics().clone();
+        fibers_global::spawn(pool.map_err(|e| panic!("{}", e)));
+
+        let a = fibers_global::execute(handle.acquire_connection(addr)).unwrap();
+        let b = fibers_global::execute(handle.acquire_connection(addr)).unwrap();
+        a.recycle();
+        b.recycle();
+        thread::sleep(Duration::from_millis(200));
+
+        let stats = fibers_global::execute(handle.stats()).unwrap();
+        assert_eq!((stats.total(), stats.idle()), (1, 1));
+        #[cfg(feature = "metrics")]
+        assert_eq!(metrics.peer_closed_connections(), 1);
+    }
+
+    #[test]
+    fn connect_parallelism_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let live = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let _streams = listener.incoming().collect::<Vec<_>>();
+        });
+        let dead = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        // Only the first address is tried by default
+        let pool = ConnectionPool::new(fibers_global::handle());
+        let mut handle = pool.handle();
+        fibers_global::spawn(pool.map_err(|e| panic!("{}", e)));
+        let e = fibers_global::execute(handle.acquire_connection_to_any(&[dead, live]))
+            .err()
+            .unwrap();
+        assert_eq!(*e.kind(), ErrorKind::ConnectionRefused);
+
+        let pool = ConnectionPoolBuilder::new()
+            .connect_parallelism(2)
+            .finish(fibers_global::handle());
+        let mut handle = pool.handle();
+        #[cfg(feature = "metrics")]
+        let metrics = pool.metrics().clone();
+        fibers_global::spawn(pool.map_err(|e| panic!("{}", e)));
+
+        let mut connection =
+            fibers_global::execute(handle.acquire_connection_to_any(&[dead, live])).unwrap();
+        assert_eq!(connection.as_mut().peer_addr(), live);
+        #[cfg(feature = "metrics")]
+        assert_eq!(metrics.allocated_connections(), 1);
+        #[cfg(feature = "metrics")]
+        assert_eq!(metrics.connect_failed_connections(), 0);
+        connection.recycle();
+
+        // Idle connections to any of the addresses are reused
+        let mut connection =
+            fibers_global::execute(handle.acquire_connection_to_any(&[dead, live])).unwrap();
+        assert_eq!(connection.as_mut().peer_addr(), live);
+        #[cfg(feature = "metrics")]
+        assert_eq!(metrics.reused_connections(), 1);
+        connection.release();
+
+        let e = fibers_global::execute(handle.acquire_connection_to_any(&[dead, dead]))
+            .err()
+            .unwrap();
+        assert_eq!(*e.kind(), ErrorKind::ConnectionRefused);
+    }
+
     #[test]
     fn allocate_and_release_works() {
-        let mut state = ConnectionPoolState::<&'static str>::new();
+        let mut state = ConnectionPoolState::<&'static str>::new(secs(100));
 
         state.allocate_connection();
         assert_eq!(state.pool_size, 1);
@@ -559,21 +1746,21 @@ mod tests {
 
     #[test]
     fn lend_works() {
-        let mut state = ConnectionPoolState::<&'static str>::new();
+        let mut state = ConnectionPoolState::<&'static str>::new(secs(100));
         for _ in 0..4 {
             state.allocate_connection();
         }
-        state.pool_connection(addr(80), "foo");
-        state.tick(secs(1), secs(100));
+        state.pool_connection(addr(80), "foo", None);
+        state.tick(secs(1));
 
-        state.pool_connection(addr(80), "bar");
-        state.tick(secs(1), secs(100));
+        state.pool_connection(addr(80), "bar", None);
+        state.tick(secs(1));
 
-        state.pool_connection(addr(80), "baz");
-        state.tick(secs(1), secs(100));
+        state.pool_connection(addr(80), "baz", None);
+        state.tick(secs(1));
 
-        state.pool_connection(addr(90), "qux");
-        state.tick(secs(1), secs(100));
+        state.pool_connection(addr(90), "qux", None);
+        state.tick(secs(1));
 
         assert_eq!(state.lend_pooled_connection(addr(79)), None);
         assert_eq!(state.lend_pooled_connection(addr(81)), None);
@@ -583,23 +1770,61 @@ mod tests {
         assert_eq!(state.lend_pooled_connection(addr(80)), None);
     }
 
+    #[test]
+    fn discard_oldest_pooled_connection_to_works() {
+        let mut state = ConnectionPoolState::<&'static str>::new(secs(100));
+        for _ in 0..4 {
+            state.allocate_connection();
+        }
+        state.pool_connection(addr(80), "foo", None);
+        state.tick(secs(1));
+
+        state.pool_connection(addr(90), "bar", None);
+        state.tick(secs(1));
+
+        state.pool_connection(addr(80), "baz", None);
+        state.tick(secs(1));
+
+        state.pool_connection(addr(80), "qux", None);
+        state.tick(secs(1));
+
+        assert_eq!(
+            state.discard_oldest_pooled_connection_to(addr(80)),
+            Some("foo")
+        );
+        assert_eq!(
+            state.discard_oldest_pooled_connection_to(addr(80)),
+            Some("baz")
+        );
+        assert_eq!(state.pooled_count(addr(80)), 1);
+        assert_eq!(state.pool_size, 2);
+
+        // The stale queue entries do not affect the expiration of the remaining connections
+        assert_eq!(state.tick(secs(98)), 1);
+        assert_eq!(state.lend_pooled_connection(addr(90)), None);
+        assert_eq!(state.tick(secs(2)), 1);
+        assert_eq!(state.lend_pooled_connection(addr(80)), None);
+        assert_eq!(state.pool_size, 0);
+        assert_eq!(state.discard_oldest_pooled_connection_to(addr(80)), None);
+    }
+
     #[test]
     fn discard_oldest_pooled_connection_works() {
-        let mut state = ConnectionPoolState::<&'static str>::new();
+        let mut state = ConnectionPoolState::<&'static str>::new(secs(100));
 
         // All connections are in pool
         for _ in 0..3 {
             state.allocate_connection();
         }
 
-        state.pool_connection(addr(80), "foo");
-        state.tick(secs(1), secs(100));
+        state.pool_connection(addr(80), "foo", None);
+        state.tick(secs(1));
 
-        state.pool_connection(addr(90), "bar");
-        state.tick(secs(1), secs(100));
+        state.pool_connection(addr(90), "bar", None);
+        state.tick(secs(1));
 
-        state.pool_connection(addr(80), "baz");
-        state.tick(secs(1), secs(100));
+        state.pool_connection(addr(80), "baz", None);
+        state.tick(secs(1));
 
         assert_eq!(state.pool_size, 3);
         assert_eq!(state.discard_oldest_pooled_connection(), Some("foo"));
@@ -613,14 +1838,14 @@ mod tests {
             state.allocate_connection();
         }
 
-        state.pool_connection(addr(80), "foo");
-        state.tick(secs(1), secs(100));
+        state.pool_connection(addr(80), "foo", None);
+        state.tick(secs(1));
 
-        state.pool_connection(addr(90), "bar");
-        state.tick(secs(1), secs(100));
+        state.pool_connection(addr(90), "bar", None);
+        state.tick(secs(1));
 
-        state.pool_connection(addr(80), "baz");
-        state.tick(secs(1), secs(100));
+        state.pool_connection(addr(80), "baz", None);
+        state.tick(secs(1));
 
         assert_eq!(state.lend_pooled_connection(addr(90)), Some("bar"));
 
@@ -633,25 +1858,25 @@ mod tests {
 
     #[test]
     fn tick_works() {
-        let mut state = ConnectionPoolState::<&'static str>::new();
+        let mut state = ConnectionPoolState::<&'static str>::new(secs(3));
 
         for _ in 0..3 {
             state.allocate_connection();
         }
 
-        state.pool_connection(addr(80), "foo");
-        state.tick(secs(1), secs(3));
+        state.pool_connection(addr(80), "foo", None);
+        state.tick(secs(1));
 
-        state.pool_connection(addr(90), "bar");
-        state.tick(secs(1), secs(3));
+        state.pool_connection(addr(90), "bar", None);
+        state.tick(secs(1));
 
-        state.pool_connection(addr(80), "baz");
-        state.tick(secs(1), secs(3));
+        state.pool_connection(addr(80), "baz", None);
+        state.tick(secs(1));
 
         assert_eq!(state.elapsed_time, secs(3));
         assert_eq!(state.pool_size, 3);
 
-        let expired_count = state.tick(secs(1), secs(3));
+        let expired_count = state.tick(secs(1));
         assert_eq!(expired_count, 1);
         assert_eq!(state.pool_size, 2);
 
@@ -659,11 +1884,98 @@ mod tests {
         assert_eq!(state.lend_pooled_connection(addr(90)), Some("bar"));
         assert_eq!(state.lend_pooled_connection(addr(80)), None);
 
-        let expired_count = state.tick(secs(1), secs(3));
+        let expired_count = state.tick(secs(1));
         assert_eq!(expired_count, 0);
         assert_eq!(state.pool_size, 2);
     }
 
+    #[test]
+    fn remove_closed_works() {
+        let mut state = ConnectionPoolState::<&'static str>::new(secs(3));
+        for _ in 0..3 {
+            state.allocate_connection();
+        }
+        state.pool_connection(addr(80), "foo", None);
+        state.pool_connection(addr(80), "bar", None);
+        state.pool_connection(addr(90), "baz", None);
+
+        assert_eq!(state.remove_closed(|c| c.starts_with('b')), 2);
+        assert_eq!(state.pool_size, 1);
+        assert_eq!(state.remove_closed(|c| c.starts_with('b')), 0);
+
+        // The stale entries in the timeout queue are skipped
+        assert_eq!(state.tick(secs(4)), 1);
+        assert_eq!(state.pool_size, 0);
+        assert!(state.pooled_connections.is_empty());
+    }
+
+    #[test]
+    fn tick_with_short_interval_works() {
+        let mut state = ConnectionPoolState::<&'static str>::new(Duration::from_millis(600));
+        state.allocate_connection();
+        state.pool_connection(addr(80), "foo", None);
+
+        let interval = Duration::from_millis(250);
+        assert_eq!(state.tick(interval), 0);
+        assert_eq!(state.tick(interval), 0);
+        assert_eq!(state.elapsed_time, Duration::from_millis(500));
+
+        // Expired at 750ms (i.e., within an interval after the timeout)
+        assert_eq!(state.tick(interval), 1);
+        assert_eq!(state.pool_size, 0);
+    }
+
+    #[test]
+    fn tick_with_jitter_works() {
+        let mut state = ConnectionPoolState::<usize>::new(Duration::from_millis(1000));
+        state.set_jitter(Duration::from_millis(100), Rng::new(0));
+        for i in 0..100 {
+            state.allocate_connection();
+            state.pool_connection(addr(80), i, None);
+        }
+
+        // Expired between 900ms and 1100ms
+        let interval = Duration::from_millis(10);
+        let mut expired = Vec::new();
+        for i in 1..=120 {
+            let n = state.tick(interval);
+            if n > 0 {
+                expired.push((i * 10, n));
+            }
+        }
+        assert_eq!(state.pool_size, 0);
+        assert!(expired.iter().all(|&(t, _)| (900..=1110).contains(&t)));
+        assert!(expired.len() > 10, "{:?}", expired);
+
+        // Deterministic for the same seed
+        let offsets = |seed| {
+            let mut jitter = Jitter {
+                spread: secs(1),
+                rng: Rng::new(seed),
+            };
+            (0..3).map(|_| jitter.offset()).collect::<Vec<_>>()
+        };
+        assert_eq!(offsets(1), offsets(1));
+        assert_ne!(offsets(1), offsets(2));
+    }
+
+    #[test]
+    fn tick_with_server_timeout_works() {
+        let mut state = ConnectionPoolState::<&'static str>::new(secs(10));
+        for _ in 0..3 {
+            state.allocate_connection();
+        }
+        state.pool_connection(addr(80), "foo", None);
+        state.pool_connection(addr(80), "bar", Some(secs(2)));
+        state.pool_connection(addr(80), "baz", Some(secs(30))); // Capped by the keepalive timeout
+
+        assert_eq!(state.tick(secs(3)), 1);
+        assert_eq!(state.pooled_count(addr(80)), 2);
+
+        assert_eq!(state.tick(secs(8)), 2);
+        assert_eq!(state.pool_size, 0);
+    }
+
     fn addr(port: u16) -> SocketAddr {
         ([127, 0, 0, 1], port).into()
     }
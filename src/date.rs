@@ -0,0 +1,167 @@
+//! HTTP-date parsing and formatting (RFC 7231, section 7.1.1.1).
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MAX_YEAR: u64 = 9999; // HTTP-date has a four-digit year
+
+/// Parses an HTTP-date.
+///
+/// All of the three formats (IMF-fixdate, RFC 850 and asctime) are accepted.
+/// Dates out of the range of four-digit years (or `SystemTime`) are rejected.
+pub fn parse_http_date(s: &str) -> Option<SystemTime> {
+    let s = s.trim();
+    let fields = s.split_whitespace().collect::<Vec<_>>();
+    let (day, month, year, time) = match fields.len() {
+        // IMF-fixdate: "Sun, 06 Nov 1994 08:49:37 GMT"
+        6 if fields[5] == "GMT" => {
+            let year = fields[3].parse().ok()?;
+            (fields[1], fields[2], year, fields[4])
+        }
+        // RFC 850: "Sunday, 06-Nov-94 08:49:37 GMT"
+        4 if fields[3] == "GMT" => {
+            let mut date = fields[1].split('-');
+            let day = date.next()?;
+            let month = date.next()?;
+            let year: u64 = date.next()?.parse().ok()?;
+            let year = if year < 70 { 2000 + year } else { 1900 + year };
+            (day, month, year, fields[2])
+        }
+        // asctime: "Sun Nov  6 08:49:37 1994"
+        5 => {
+            let year = fields[4].parse().ok()?;
+            (fields[2], fields[1], year, fields[3])
+        }
+        _ => return None,
+    };
+
+    let day: u64 = day.parse().ok()?;
+    let month = MONTHS.iter().position(|m| *m == month)? as u64 + 1;
+    let mut time = time.split(':').map(|x| x.parse::<u64>().ok());
+    let hour = time.next()??;
+    let minute = time.next()??;
+    let second = time.next()??;
+    if !(1970..=MAX_YEAR).contains(&year) || day == 0 || day > 31 {
+        return None;
+    }
+    if hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+
+    let days = days_from_civil(year, month, day);
+    let seconds = days
+        .checked_mul(86_400)?
+        .checked_add(hour * 3600 + minute * 60 + second)?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(seconds))
+}
+
+/// Formats the time as an IMF-fixdate (e.g., "Sun, 06 Nov 1994 08:49:37 GMT").
+///
+/// Sub-second precision is truncated, and times before the UNIX epoch are clamped to it.
+pub fn format_http_date(time: SystemTime) -> String {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let days = seconds / 86_400;
+    let (year, month, day) = civil_from_days(days);
+    let seconds = seconds % 86_400;
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(days % 7) as usize],
+        day,
+        MONTHS[(month - 1) as usize],
+        year,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Returns the number of days since 1970-01-01.
+fn days_from_civil(year: u64, month: u64, day: u64) -> u64 {
+    // See http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year / 400;
+    let yoe = year - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Returns the date (year, month, day) that is the given number of days after 1970-01-01.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // See http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_http_date_works() {
+        let expected = UNIX_EPOCH + Duration::from_secs(784_111_777);
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"),
+            Some(expected)
+        );
+        assert_eq!(
+            parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"),
+            Some(expected)
+        );
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), Some(expected));
+        assert_eq!(
+            parse_http_date("Thu, 01 Jan 1970 00:00:00 GMT"),
+            Some(UNIX_EPOCH)
+        );
+        assert_eq!(parse_http_date("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+        assert_eq!(parse_http_date("120"), None);
+
+        // Huge years must not overflow
+        assert_eq!(
+            parse_http_date("Sun, 06 Nov 300000000000 08:49:37 GMT"),
+            None
+        );
+        assert_eq!(
+            parse_http_date("Sun Nov  6 08:49:37 18446744073709551615"),
+            None
+        );
+        assert!(parse_http_date("Fri, 31 Dec 9999 23:59:59 GMT").is_some());
+    }
+
+    #[test]
+    fn format_http_date_works() {
+        let time = UNIX_EPOCH + Duration::from_millis(784_111_777_500);
+        assert_eq!(format_http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(
+            format_http_date(UNIX_EPOCH),
+            "Thu, 01 Jan 1970 00:00:00 GMT"
+        );
+        assert_eq!(
+            format_http_date(UNIX_EPOCH - Duration::from_secs(1)),
+            "Thu, 01 Jan 1970 00:00:00 GMT"
+        );
+
+        // Leap days
+        let time = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!(format_http_date(time), "Tue, 29 Feb 2000 00:00:00 GMT");
+
+        for &seconds in &[0, 59, 86_399, 1_234_567_890, 4_102_444_800] {
+            let time = UNIX_EPOCH + Duration::from_secs(seconds);
+            assert_eq!(parse_http_date(&format_http_date(time)), Some(time));
+        }
+    }
+}
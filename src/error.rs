@@ -1,15 +1,124 @@
 use bytecodec;
 use std;
+use std::fmt;
+use std::ops::Deref;
 use trackable::error::TrackableError;
 use trackable::error::{ErrorKind as TrackableErrorKind, ErrorKindExt};
+use trackable::{History, Location, Trackable};
 use url;
 
 /// This crate specific `Error` type.
-#[derive(Debug, Clone, trackable::TrackableError)]
+///
+/// The cause of the error (e.g., `std::io::Error`) can be retrieved via `std::error::Error::source` method.
+#[derive(Debug, Clone)]
 pub struct Error(TrackableError<ErrorKind>);
+impl Error {
+    /// Returns the phase in which the timeout occurred if the kind of this error is `ErrorKind::Timeout`.
+    pub fn timeout_phase(&self) -> Option<TimeoutPhase> {
+        self.concrete_cause::<TimeoutPhase>().cloned()
+    }
+
+    /// Returns `true` if the kind of this error is `ErrorKind::Timeout`.
+    pub fn is_timeout(&self) -> bool {
+        *self.kind() == ErrorKind::Timeout
+    }
+
+    /// Returns `true` if the kind of this error is `ErrorKind::TemporarilyUnavailable`.
+    pub fn is_temporarily_unavailable(&self) -> bool {
+        *self.kind() == ErrorKind::TemporarilyUnavailable
+    }
+
+    /// Returns `true` if the kind of this error is `ErrorKind::InvalidInput`.
+    pub fn is_invalid_input(&self) -> bool {
+        *self.kind() == ErrorKind::InvalidInput
+    }
+
+    /// Returns `true` if the kind of this error is `ErrorKind::Canceled`.
+    pub fn is_canceled(&self) -> bool {
+        *self.kind() == ErrorKind::Canceled
+    }
+
+    /// Returns `true` if the kind of this error is `ErrorKind::StatusError`.
+    pub fn is_status_error(&self) -> bool {
+        *self.kind() == ErrorKind::StatusError
+    }
+
+    /// Returns `true` if this error is caused by the network
+    /// (i.e., the kind is `Dns`, `ConnectionRefused`, `ConnectionClosed` or `Io`).
+    pub fn is_network_error(&self) -> bool {
+        matches!(
+            *self.kind(),
+            ErrorKind::Dns
+                | ErrorKind::ConnectionRefused
+                | ErrorKind::ConnectionClosed
+                | ErrorKind::Io
+        )
+    }
+
+    fn typed_cause<T: std::error::Error + 'static>(
+        &self,
+    ) -> Option<&(dyn std::error::Error + 'static)> {
+        self.concrete_cause::<T>()
+            .map(|e| e as &(dyn std::error::Error + 'static))
+    }
+}
+impl Deref for Error {
+    type Target = TrackableError<ErrorKind>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        // `TrackableError` only exposes its cause via the deprecated `cause` method
+        // (without the `'static` bound), so the known concrete types are tried in turn.
+        self.typed_cause::<std::io::Error>()
+            .or_else(|| self.typed_cause::<url::ParseError>())
+            .or_else(|| self.typed_cause::<std::sync::mpsc::RecvError>())
+            .or_else(|| self.typed_cause::<StatusError>())
+            .or_else(|| self.typed_cause::<TimeoutPhase>())
+    }
+}
+impl Trackable for Error {
+    type Event = Location;
+
+    fn history(&self) -> Option<&History<Location>> {
+        self.0.history()
+    }
+
+    fn history_mut(&mut self) -> Option<&mut History<Location>> {
+        self.0.history_mut()
+    }
+}
+impl From<TrackableError<ErrorKind>> for Error {
+    fn from(f: TrackableError<ErrorKind>) -> Self {
+        Error(f)
+    }
+}
+impl From<Error> for TrackableError<ErrorKind> {
+    fn from(f: Error) -> Self {
+        f.0
+    }
+}
+impl From<ErrorKind> for Error {
+    fn from(f: ErrorKind) -> Self {
+        f.error().into()
+    }
+}
 impl From<std::io::Error> for Error {
     fn from(f: std::io::Error) -> Self {
-        ErrorKind::Other.cause(f).into()
+        io_error_kind(&f).cause(f).into()
+    }
+}
+impl From<std::convert::Infallible> for Error {
+    fn from(f: std::convert::Infallible) -> Self {
+        match f {}
     }
 }
 impl From<std::sync::mpsc::RecvError> for Error {
@@ -23,7 +132,9 @@ impl From<bytecodec::Error> for Error {
         let kind = match *f.kind() {
             bytecodec::ErrorKind::InvalidInput => ErrorKind::InvalidInput,
             bytecodec::ErrorKind::UnexpectedEos => ErrorKind::UnexpectedEos,
-            _ => ErrorKind::Other,
+            _ => f
+                .concrete_cause::<std::io::Error>()
+                .map_or(ErrorKind::Other, io_error_kind),
         };
         track!(kind.takes_over(f); bytecodec_error_kind).into()
     }
@@ -42,6 +153,132 @@ pub enum ErrorKind {
     UnexpectedEos,
     Timeout,
     TemporarilyUnavailable,
+    StatusError,
+    HeaderTooLarge,
+
+    /// Resolving a host name has failed.
+    Dns,
+
+    /// The server refused the TCP connection.
+    ConnectionRefused,
+
+    /// The TCP connection has been reset or aborted by the peer.
+    ConnectionClosed,
+
+    /// An I/O error other than the above ones.
+    Io,
+
+    /// The request has been canceled via `CancelHandle`.
+    Canceled,
+
+    /// The proxy responded with `407 Proxy Authentication Required`.
+    ProxyAuthenticationRequired,
+
     Other,
 }
 impl TrackableErrorKind for ErrorKind {}
+
+fn io_error_kind(e: &std::io::Error) -> ErrorKind {
+    use std::io::ErrorKind as IoErrorKind;
+    match e.kind() {
+        IoErrorKind::ConnectionRefused => ErrorKind::ConnectionRefused,
+        IoErrorKind::ConnectionReset
+        | IoErrorKind::ConnectionAborted
+        | IoErrorKind::BrokenPipe
+        | IoErrorKind::NotConnected => ErrorKind::ConnectionClosed,
+        IoErrorKind::TimedOut => ErrorKind::Timeout,
+        IoErrorKind::NotFound => ErrorKind::Dns,
+        IoErrorKind::UnexpectedEof => ErrorKind::UnexpectedEos,
+        _ => ErrorKind::Io,
+    }
+}
+
+/// The cause of an `ErrorKind::StatusError` error.
+///
+/// This holds the status code and the (at most 64 KiB) body of the `4xx` or `5xx` response.
+#[derive(Debug, Clone)]
+pub struct StatusError {
+    status_code: u16,
+    reason_phrase: String,
+    body: Vec<u8>,
+}
+impl StatusError {
+    pub(crate) fn new(status_code: u16, reason_phrase: String, body: Vec<u8>) -> Self {
+        StatusError {
+            status_code,
+            reason_phrase,
+            body,
+        }
+    }
+
+    /// Returns the status code of the response.
+    pub fn status_code(&self) -> u16 {
+        self.status_code
+    }
+
+    /// Returns the reason phrase of the response.
+    pub fn reason_phrase(&self) -> &str {
+        &self.reason_phrase
+    }
+
+    /// Returns the body of the response.
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+}
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.status_code, self.reason_phrase)
+    }
+}
+impl std::error::Error for StatusError {}
+
+/// The cause of an `ErrorKind::Timeout` error.
+///
+/// This indicates which timeout has been expired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TimeoutPhase {
+    /// Establishing a TCP connection has timed out (see `ConnectionPoolBuilder::connect_timeout`).
+    Connect,
+
+    /// The whole request execution has timed out (see `RequestBuilder::timeout`).
+    Request,
+
+    /// Waiting for an available connection in the pool has timed out (see `ConnectionPoolBuilder::on_full`).
+    Acquire,
+}
+impl fmt::Display for TimeoutPhase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TimeoutPhase::Connect => write!(f, "TCP connect timeout"),
+            TimeoutPhase::Request => write!(f, "Request timeout"),
+            TimeoutPhase::Acquire => write!(f, "Connection acquisition timeout"),
+        }
+    }
+}
+impl std::error::Error for TimeoutPhase {}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as StdError;
+    use std::io;
+
+    use super::*;
+
+    #[test]
+    fn source_works() {
+        let e = Error::from(io::Error::new(io::ErrorKind::ConnectionRefused, "foo"));
+        let e = track!(e);
+        let source = e.source().expect("no source");
+        let io_error = source
+            .downcast_ref::<io::Error>()
+            .expect("not an I/O error");
+        assert_eq!(io_error.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(*e.kind(), ErrorKind::ConnectionRefused);
+        assert!(e.is_network_error());
+        assert!(!e.is_timeout());
+
+        let e = Error::from(ErrorKind::Other);
+        assert!(e.source().is_none());
+    }
+}
@@ -0,0 +1,163 @@
+use httpcodec::Header;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use trace;
+use Error;
+
+/// An event that occurs during the execution of an HTTP request.
+///
+/// The handler registered via [`Client::on_event`] is invoked with the events.
+///
+/// [`Client::on_event`]: ./struct.Client.html#method.on_event
+#[derive(Debug)]
+pub enum RequestEvent<'a> {
+    /// A request is about to be sent to the server.
+    Requested {
+        /// The address of the server.
+        peer_addr: SocketAddr,
+
+        /// The method of the request.
+        method: &'a str,
+
+        /// The target of the request.
+        target: &'a str,
+
+        /// The header of the request.
+        header: Header<'a>,
+    },
+
+    /// An interim (`1xx`) response (e.g., `103 Early Hints`) has been received from the server.
+    ///
+    /// Interim responses other than `101 Switching Protocols` are skipped,
+    /// and the execution continues until the final response arrives.
+    Informational {
+        /// The address of the server.
+        peer_addr: SocketAddr,
+
+        /// The status code of the response.
+        status_code: u16,
+
+        /// The reason phrase of the response.
+        reason_phrase: &'a str,
+
+        /// The header of the response (e.g., `Link` fields of early hints).
+        header: Header<'a>,
+    },
+
+    /// A response has been received from the server.
+    Responded {
+        /// The address of the server.
+        peer_addr: SocketAddr,
+
+        /// The status code of the response.
+        status_code: u16,
+
+        /// The reason phrase of the response.
+        reason_phrase: &'a str,
+
+        /// The header of the response.
+        header: Header<'a>,
+    },
+
+    /// The request has failed.
+    Failed {
+        /// The reason of the failure.
+        error: &'a Error,
+    },
+}
+
+type EventHandler = dyn Fn(&RequestEvent) + Send + Sync + 'static;
+
+#[derive(Clone, Default)]
+pub(crate) struct EventHook(Option<Arc<EventHandler>>);
+impl EventHook {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&RequestEvent) + Send + Sync + 'static,
+    {
+        EventHook(Some(Arc::new(f)))
+    }
+
+    pub fn emit(&self, event: &RequestEvent) {
+        trace::event(event);
+        if let Some(ref f) = self.0 {
+            f(event);
+        }
+    }
+}
+impl fmt::Debug for EventHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "EventHook {{ .. }}")
+    }
+}
+
+/// The progress of the transfer of an HTTP request and its response.
+///
+/// The handler registered via [`RequestBuilder::on_progress`] is invoked with this.
+///
+/// [`RequestBuilder::on_progress`]: ./struct.RequestBuilder.html#method.on_progress
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// The number of bytes of the request (including the head) sent so far.
+    pub sent: u64,
+
+    /// The total number of bytes of the request, if known.
+    ///
+    /// This is unknown if the body is chunked.
+    pub send_total: Option<u64>,
+
+    /// The number of bytes of the response body received so far.
+    ///
+    /// If the body is chunked, this includes the bytes for the framing.
+    pub received: u64,
+
+    /// The total number of bytes of the response body (i.e., the value of `Content-Length` header), if known.
+    pub receive_total: Option<u64>,
+}
+
+type ProgressHandler = dyn Fn(Progress) + Send + Sync + 'static;
+
+#[derive(Clone, Default)]
+pub(crate) struct ProgressHook(Option<Arc<ProgressHandler>>);
+impl ProgressHook {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(Progress) + Send + Sync + 'static,
+    {
+        ProgressHook(Some(Arc::new(f)))
+    }
+}
+impl fmt::Debug for ProgressHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ProgressHook {{ .. }}")
+    }
+}
+
+/// Tracks the progress and notifies the hook of its changes.
+#[derive(Debug, Default)]
+pub(crate) struct ProgressTracker {
+    hook: ProgressHook,
+    pub progress: Progress,
+    reported: Option<Progress>,
+}
+impl ProgressTracker {
+    pub fn new(hook: ProgressHook) -> Self {
+        ProgressTracker {
+            hook,
+            progress: Progress::default(),
+            reported: None,
+        }
+    }
+
+    /// Invokes the hook if the progress has changed since the last report.
+    pub fn report(&mut self) {
+        if let Some(ref f) = self.hook.0 {
+            if self.reported != Some(self.progress) {
+                f(self.progress);
+                self.reported = Some(self.progress);
+            }
+        }
+    }
+}
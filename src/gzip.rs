@@ -0,0 +1,304 @@
+//! A minimal gzip (RFC 1952) compressor for request bodies.
+//!
+//! The data is compressed into a single deflate block with the fixed Huffman codes (RFC 1951, section 3.2.6).
+//! It is less compact than the output of zlib, but is good enough for redundant payloads like JSON.
+use std::cmp;
+
+const WINDOW_SIZE: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const MAX_CHAIN: usize = 64;
+const HASH_BITS: u32 = 15;
+
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// Compresses `data` into the gzip format.
+pub(crate) fn compress(data: &[u8]) -> Vec<u8> {
+    // ID1, ID2, CM (deflate), FLG, MTIME (unknown), XFL and OS (unknown)
+    let header = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 0xff];
+    let mut writer = BitWriter {
+        out: header,
+        bits: 0,
+        len: 0,
+    };
+    deflate(data, &mut writer);
+    let mut out = writer.finish();
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+fn deflate(data: &[u8], writer: &mut BitWriter) {
+    writer.write_bits(1, 1); // BFINAL
+    writer.write_bits(1, 2); // BTYPE (fixed Huffman codes)
+
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut prev = vec![usize::MAX; data.len()];
+    let mut i = 0;
+    while i < data.len() {
+        let (length, distance) = longest_match(data, i, &head, &prev);
+        if length >= MIN_MATCH {
+            write_length(writer, length);
+            write_distance(writer, distance);
+        } else {
+            write_symbol(writer, u16::from(data[i]));
+        }
+        let end = i + cmp::max(length, 1);
+        while i < end {
+            if i + MIN_MATCH <= data.len() {
+                let h = hash(data, i);
+                prev[i] = head[h];
+                head[h] = i;
+            }
+            i += 1;
+        }
+    }
+    write_symbol(writer, 256); // End of block
+}
+
+fn hash(data: &[u8], i: usize) -> usize {
+    let v = u32::from(data[i]) << 16 | u32::from(data[i + 1]) << 8 | u32::from(data[i + 2]);
+    (v.wrapping_mul(0x9E37_79B1) >> (32 - HASH_BITS)) as usize
+}
+
+fn longest_match(data: &[u8], i: usize, head: &[usize], prev: &[usize]) -> (usize, usize) {
+    if i + MIN_MATCH > data.len() {
+        return (0, 0);
+    }
+    let max_length = cmp::min(MAX_MATCH, data.len() - i);
+    let mut best = (0, 0);
+    let mut candidate = head[hash(data, i)];
+    let mut chain = 0;
+    while candidate != usize::MAX && i - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+        let length = data[candidate..]
+            .iter()
+            .zip(&data[i..i + max_length])
+            .take_while(|(a, b)| a == b)
+            .count();
+        if length > best.0 {
+            best = (length, i - candidate);
+            if length == max_length {
+                break;
+            }
+        }
+        candidate = prev[candidate];
+        chain += 1;
+    }
+    best
+}
+
+/// Writes a literal/length symbol with the fixed Huffman code.
+fn write_symbol(writer: &mut BitWriter, symbol: u16) {
+    let (code, len) = match symbol {
+        0..=143 => (0x30 + symbol, 8),
+        144..=255 => (0x190 + symbol - 144, 9),
+        256..=279 => (symbol - 256, 7),
+        _ => (0xC0 + symbol - 280, 8),
+    };
+    writer.write_code(code, len);
+}
+
+fn write_length(writer: &mut BitWriter, length: usize) {
+    let i = LENGTH_BASES
+        .iter()
+        .rposition(|&base| usize::from(base) <= length)
+        .expect("never fails");
+    write_symbol(writer, 257 + i as u16);
+    let extra = length as u32 - u32::from(LENGTH_BASES[i]);
+    writer.write_bits(extra, u32::from(LENGTH_EXTRA_BITS[i]));
+}
+
+fn write_distance(writer: &mut BitWriter, distance: usize) {
+    let i = DISTANCE_BASES
+        .iter()
+        .rposition(|&base| usize::from(base) <= distance)
+        .expect("never fails");
+    writer.write_code(i as u16, 5);
+    let extra = distance as u32 - u32::from(DISTANCE_BASES[i]);
+    writer.write_bits(extra, u32::from(DISTANCE_EXTRA_BITS[i]));
+}
+
+/// Packs bits into bytes starting from the least significant bit.
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u64,
+    len: u32,
+}
+impl BitWriter {
+    fn write_bits(&mut self, value: u32, len: u32) {
+        self.bits |= u64::from(value) << self.len;
+        self.len += len;
+        while self.len >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.len -= 8;
+        }
+    }
+
+    /// Writes a Huffman code, which is packed starting from the most significant bit.
+    fn write_code(&mut self, code: u16, len: u32) {
+        let reversed = code.reverse_bits() >> (16 - len);
+        self.write_bits(u32::from(reversed), len);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Decompresses gzip data that consists of stored or fixed Huffman blocks (e.g., the output of `compress`).
+#[cfg(test)]
+pub(crate) fn decompress(data: &[u8]) -> Vec<u8> {
+    assert_eq!(&data[..4], &[0x1f, 0x8b, 8, 0]);
+    let mut reader = BitReader {
+        data: &data[10..],
+        pos: 0,
+    };
+    let mut out = Vec::new();
+    loop {
+        let last = reader.read_bits(1) == 1;
+        match reader.read_bits(2) {
+            0 => {
+                reader.pos = reader.pos.div_ceil(8) * 8;
+                let len = reader.read_bits(16) as usize;
+                let _nlen = reader.read_bits(16);
+                for _ in 0..len {
+                    out.push(reader.read_bits(8) as u8);
+                }
+            }
+            1 => loop {
+                let symbol = reader.read_symbol();
+                if symbol < 256 {
+                    out.push(symbol as u8);
+                    continue;
+                } else if symbol == 256 {
+                    break;
+                }
+                let i = usize::from(symbol - 257);
+                let length = usize::from(LENGTH_BASES[i])
+                    + reader.read_bits(u32::from(LENGTH_EXTRA_BITS[i])) as usize;
+                let i = reader.read_code(5) as usize;
+                let distance = usize::from(DISTANCE_BASES[i])
+                    + reader.read_bits(u32::from(DISTANCE_EXTRA_BITS[i])) as usize;
+                for _ in 0..length {
+                    let b = out[out.len() - distance];
+                    out.push(b);
+                }
+            },
+            n => panic!("Unsupported block type: {}", n),
+        }
+        if last {
+            break;
+        }
+    }
+    let trailer = &reader.data[reader.pos.div_ceil(8)..];
+    assert_eq!(trailer[..4], crc32(&out).to_le_bytes());
+    assert_eq!(trailer[4..8], (out.len() as u32).to_le_bytes());
+    out
+}
+
+#[cfg(test)]
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+#[cfg(test)]
+impl<'a> BitReader<'a> {
+    fn read_bits(&mut self, len: u32) -> u32 {
+        let mut value = 0;
+        for i in 0..len {
+            let bit = (self.data[self.pos / 8] >> (self.pos % 8)) & 1;
+            value |= u32::from(bit) << i;
+            self.pos += 1;
+        }
+        value
+    }
+
+    fn read_code(&mut self, len: u32) -> u16 {
+        (0..len).fold(0, |code, _| code << 1 | self.read_bits(1) as u16)
+    }
+
+    fn read_symbol(&mut self) -> u16 {
+        let code = self.read_code(7);
+        if code <= 0x17 {
+            return code + 256;
+        }
+        let code = code << 1 | self.read_bits(1) as u16;
+        match code {
+            0x30..=0xBF => code - 0x30,
+            0xC0..=0xC7 => code - 0xC0 + 280,
+            _ => (code << 1 | self.read_bits(1) as u16) - 0x190 + 144,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compress_works() {
+        let inputs: Vec<Vec<u8>> = vec![
+            Vec::new(),
+            b"a".to_vec(),
+            br#"{"id":1,"name":"foo"}"#.repeat(100),
+            (0..100_000u32).map(|i| (i * 7 % 251) as u8).collect(),
+            vec![0xff; 70_000],
+        ];
+        for input in inputs {
+            let compressed = compress(&input);
+            assert_eq!(decompress(&compressed), input);
+        }
+
+        let json = br#"{"id":1,"name":"foo"}"#.repeat(100);
+        assert!(compress(&json).len() < json.len() / 10);
+    }
+
+    #[test]
+    fn decompress_works() {
+        // The output of `gzip.compress(b"hello hello hello hello", mtime=0)` of Python
+        let compressed = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xcb, 0x48, 0xcd, 0xc9,
+            0xc9, 0x57, 0xc8, 0x40, 0x27, 0x01, 0xe3, 0x51, 0x3d, 0x8d, 0x17, 0x00, 0x00, 0x00,
+        ];
+        assert_eq!(decompress(&compressed), b"hello hello hello hello");
+    }
+
+    #[test]
+    fn crc32_works() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+}
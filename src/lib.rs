@@ -22,25 +22,62 @@
 //! ```
 #![warn(missing_docs)]
 extern crate bytecodec;
+#[cfg(feature = "bytes")]
+extern crate bytes;
+#[cfg(feature = "encoding")]
+extern crate encoding_rs;
 extern crate fibers;
 extern crate futures;
 extern crate httpcodec;
+#[cfg(feature = "metrics")]
 extern crate prometrics;
+#[cfg(feature = "tower")]
+extern crate tower_service;
 #[macro_use]
 extern crate trackable;
+#[cfg(feature = "tracing")]
+#[macro_use]
+extern crate tracing;
 extern crate url;
 
-pub use client::Client;
-pub use error::{Error, ErrorKind};
-pub use request::RequestBuilder;
+#[cfg(test)]
+extern crate fibers_global;
+
+pub use client::{get, Client, ClientBuilder, GetFuture};
+pub use error::{Error, ErrorKind, StatusError, TimeoutPhase};
+pub use event::{Progress, RequestEvent};
+pub use request::{
+    CancelHandle, RequestBuilder, RequestTemplate, ResponseFuture, TargetForm, Timed,
+    TimedResponseFuture, Tunnel, Upgraded,
+};
+pub use response::{ContentRange, ResponseExt, StatusClass};
 
+mod circuit_breaker;
 mod client;
 mod connection_pool;
+mod date;
 mod error;
+mod event;
+mod gzip;
+mod limiter;
+mod load_balancer;
+#[cfg(any(test, feature = "mock"))]
+mod mock;
+mod pipeline;
+mod proxy;
 mod request;
+mod response;
+mod rng;
+mod trace;
 
+pub mod blocking;
+pub mod body;
 pub mod connection;
 pub mod metrics;
+pub mod middleware;
+pub mod resolver;
+#[cfg(feature = "tower")]
+pub mod service;
 
 /// This crate specific `Result` type.
 pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,101 @@
+//! Client-side concurrency limiting.
+use fibers::sync::oneshot;
+use futures::future::{self, Future};
+use std::collections::VecDeque;
+use std::sync::mpsc::SendError;
+use std::sync::{Arc, Mutex, MutexGuard};
+use trackable::error::ErrorKindExt;
+
+use metrics::Gauge;
+use {Error, ErrorKind};
+
+/// `Future` that returns a permit to issue a request.
+pub(crate) type AcquirePermit = Box<dyn Future<Item = Permit, Error = Error> + Send + 'static>;
+
+/// A semaphore that limits the number of requests being executed concurrently.
+///
+/// Waiters are given permits in FIFO order.
+#[derive(Debug, Clone)]
+pub(crate) struct Limiter {
+    state: Arc<Mutex<LimiterState>>,
+    max: usize,
+    fail_fast: bool,
+    permits_in_use: Gauge,
+}
+impl Limiter {
+    pub fn new(max: usize, fail_fast: bool, permits_in_use: Gauge) -> Self {
+        let state = LimiterState {
+            available: max,
+            waiters: VecDeque::new(),
+        };
+        Limiter {
+            state: Arc::new(Mutex::new(state)),
+            max,
+            fail_fast,
+            permits_in_use,
+        }
+    }
+
+    /// Returns a `Future` that acquires a permit.
+    ///
+    /// If no permit is available and `fail_fast` is enabled, the future fails with an `ErrorKind::TemporarilyUnavailable` error.
+    pub fn acquire(&self) -> AcquirePermit {
+        let mut state = self.lock();
+        if state.available > 0 {
+            state.available -= 1;
+            self.permits_in_use.increment();
+            return Box::new(future::ok(Permit(Some(self.clone()))));
+        }
+        if self.fail_fast {
+            let e = ErrorKind::TemporarilyUnavailable.cause(format!(
+                "The number of concurrent requests has reached the limit ({})",
+                self.max
+            ));
+            return Box::new(future::err(track!(Error::from(e))));
+        }
+
+        let (tx, rx) = oneshot::channel();
+        state.waiters.push_back(tx);
+        Box::new(rx.map_err(|e| track!(Error::from(ErrorKind::Other.cause(e)))))
+    }
+
+    fn release(&self) {
+        let mut state = self.lock();
+        while let Some(tx) = state.waiters.pop_front() {
+            // The permit is handed over as it is, so `permits_in_use` is unchanged
+            match tx.send(Permit(Some(self.clone()))) {
+                Ok(()) => return,
+                Err(SendError(mut permit)) => {
+                    // The waiter has gone
+                    permit.0 = None;
+                }
+            }
+        }
+        state.available += 1;
+        self.permits_in_use.decrement();
+    }
+
+    fn lock(&self) -> MutexGuard<'_, LimiterState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    available: usize,
+    waiters: VecDeque<oneshot::Sender<Permit>>,
+}
+
+/// A permit to issue a request, which is returned to the limiter when dropped.
+///
+/// If a waiter is dropped after the permit has been sent to it,
+/// the permit is dropped along with the channel and thus returned.
+#[derive(Debug)]
+pub(crate) struct Permit(Option<Limiter>);
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(limiter) = self.0.take() {
+            limiter.release();
+        }
+    }
+}
@@ -0,0 +1,264 @@
+use futures::{future, Async, Future, Poll};
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use trackable::error::ErrorKindExt;
+
+use connection::AcquireConnection;
+use metrics::{LoadBalancerMetrics, MetricBuilder};
+use request::is_connect_error;
+use resolver::BoxResolveFuture;
+use {Error, ErrorKind, Result};
+
+/// An implementation of [`AcquireConnection`] that spreads connections across multiple upstreams.
+///
+/// Each acquisition selects an upstream by smooth weighted round-robin,
+/// and acquires a connection to it via the inner provider (e.g., [`ConnectionPoolHandle`] or [`Oneshot`]).
+/// If connecting to the selected upstream fails (e.g., the connection is refused or times out),
+/// the other upstreams are tried in turn.
+///
+/// Upstreams whose weight is `0` are never selected, but used as fallbacks.
+///
+/// Because the upstreams are equivalent, the address resolved from the URL of a request is ignored
+/// (the host name in the URL is still used as the value of `Host` header).
+///
+/// [`AcquireConnection`]: ./trait.AcquireConnection.html
+/// [`ConnectionPoolHandle`]: ./struct.ConnectionPoolHandle.html
+/// [`Oneshot`]: ./struct.Oneshot.html
+#[derive(Debug, Clone)]
+pub struct LoadBalanced<C> {
+    inner: C,
+    upstreams: Arc<Vec<(SocketAddr, u32)>>,
+    current_weights: Arc<Mutex<Vec<i64>>>,
+    metrics: LoadBalancerMetrics,
+}
+impl<C: AcquireConnection> LoadBalanced<C> {
+    /// Makes a new `LoadBalanced` instance that distributes connections across `upstreams`.
+    ///
+    /// Each upstream is a pair of an address and a weight.
+    ///
+    /// If no upstream has a positive weight, an `ErrorKind::InvalidInput` error will be returned.
+    pub fn new<I>(inner: C, upstreams: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = (SocketAddr, u32)>,
+    {
+        Self::with_metric_builder(inner, upstreams, MetricBuilder::new())
+    }
+
+    /// Makes a new `LoadBalanced` instance with the given metrics builder.
+    #[cfg(feature = "metrics")]
+    pub fn with_metrics<I>(inner: C, upstreams: I, metrics: MetricBuilder) -> Result<Self>
+    where
+        I: IntoIterator<Item = (SocketAddr, u32)>,
+    {
+        track!(Self::with_metric_builder(inner, upstreams, metrics))
+    }
+
+    fn with_metric_builder<I>(inner: C, upstreams: I, metrics: MetricBuilder) -> Result<Self>
+    where
+        I: IntoIterator<Item = (SocketAddr, u32)>,
+    {
+        let upstreams = upstreams.into_iter().collect::<Vec<_>>();
+        track_assert!(
+            upstreams.iter().any(|&(_, weight)| weight > 0),
+            ErrorKind::InvalidInput,
+            "No upstream has a positive weight"
+        );
+        let addrs = upstreams.iter().map(|&(addr, _)| addr).collect::<Vec<_>>();
+        Ok(LoadBalanced {
+            inner,
+            current_weights: Arc::new(Mutex::new(vec![0; upstreams.len()])),
+            upstreams: Arc::new(upstreams),
+            metrics: LoadBalancerMetrics::new(metrics, &addrs),
+        })
+    }
+
+    /// Returns a reference to the metrics of the load balancer.
+    pub fn metrics(&self) -> &LoadBalancerMetrics {
+        &self.metrics
+    }
+
+    /// Returns the upstreams in the order of trial: the selected one first, followed by the fallbacks.
+    fn select(&self) -> VecDeque<SocketAddr> {
+        let mut current = self
+            .current_weights
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let total = self
+            .upstreams
+            .iter()
+            .map(|&(_, w)| i64::from(w))
+            .sum::<i64>();
+        let mut selected = 0;
+        for (i, &(_, weight)) in self.upstreams.iter().enumerate() {
+            current[i] += i64::from(weight);
+            if current[i] > current[selected] {
+                selected = i;
+            }
+        }
+        current[selected] -= total;
+
+        let n = self.upstreams.len();
+        (0..n)
+            .map(|i| self.upstreams[(selected + i) % n].0)
+            .collect()
+    }
+}
+impl<C> AcquireConnection for LoadBalanced<C>
+where
+    C: AcquireConnection + Clone,
+{
+    type Connection = C::Connection;
+    type Future = LoadBalancedConnect<C>;
+
+    fn acquire_connection(&mut self, _addr: SocketAddr) -> Self::Future {
+        let mut candidates = self.select();
+        let addr = candidates.pop_front().expect("never fails");
+        let connect = LoadBalancedConnect {
+            future: self.inner.acquire_connection(addr),
+            addr,
+            candidates,
+            inner: self.inner.clone(),
+            metrics: self.metrics.clone(),
+        };
+        connect.count_attempt();
+        connect
+    }
+
+    fn resolve(&mut self, _host: &str, _port: u16) -> BoxResolveFuture {
+        // The resolved address is ignored by `acquire_connection`
+        Box::new(future::ok(vec![self.upstreams[0].0]))
+    }
+}
+
+/// `Future` that acquires a connection to one of the upstreams of [`LoadBalanced`].
+///
+/// [`LoadBalanced`]: ./struct.LoadBalanced.html
+#[derive(Debug)]
+pub struct LoadBalancedConnect<C: AcquireConnection> {
+    future: C::Future,
+    addr: SocketAddr,
+    candidates: VecDeque<SocketAddr>,
+    inner: C,
+    metrics: LoadBalancerMetrics,
+}
+impl<C: AcquireConnection> LoadBalancedConnect<C> {
+    fn count_attempt(&self) {
+        if let Some(m) = self.metrics.upstream(self.addr) {
+            m.attempts.increment();
+        }
+    }
+}
+impl<C: AcquireConnection> Future for LoadBalancedConnect<C> {
+    type Item = C::Connection;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match self.future.poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(connection)) => return Ok(Async::Ready(connection)),
+                Err(e) => {
+                    if let Some(m) = self.metrics.upstream(self.addr) {
+                        m.failures.increment();
+                    }
+                    if !is_connect_error(&e) {
+                        return Err(track!(e; self.addr));
+                    }
+                    match self.candidates.pop_front() {
+                        None => {
+                            let e = ErrorKind::ConnectionRefused.takes_over(e);
+                            return Err(track!(Error::from(e); "All upstreams failed"));
+                        }
+                        Some(addr) => {
+                            self.addr = addr;
+                            self.future = self.inner.acquire_connection(addr);
+                            self.count_attempt();
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::{self, Either, FutureResult};
+    use std::collections::HashSet;
+
+    use super::*;
+    use connection::{Connection, Mock};
+
+    #[test]
+    fn weighted_round_robin_works() {
+        let upstreams = vec![(addr(1), 3), (addr(2), 1), (addr(3), 0)];
+        let mut lb = LoadBalanced::new(Mock::new(), upstreams).unwrap();
+
+        let selected = (0..8)
+            .map(|_| {
+                let connection = lb.acquire_connection(addr(0)).wait().unwrap();
+                connection.peer_addr().port()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(selected, [1, 1, 2, 1, 1, 1, 2, 1]);
+        #[cfg(feature = "metrics")]
+        assert_eq!(lb.metrics().attempts(addr(1)), 6);
+        #[cfg(feature = "metrics")]
+        assert_eq!(lb.metrics().attempts(addr(2)), 2);
+        #[cfg(feature = "metrics")]
+        assert_eq!(lb.metrics().attempts(addr(3)), 0);
+
+        let e = LoadBalanced::new(Mock::new(), vec![(addr(1), 0)])
+            .err()
+            .unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn fallback_works() {
+        let down = [addr(1), addr(2)].iter().cloned().collect();
+        let inner = Refuse(Mock::new(), Arc::new(down));
+        let upstreams = vec![(addr(1), 1), (addr(2), 1), (addr(3), 0)];
+        let mut lb = LoadBalanced::new(inner, upstreams).unwrap();
+
+        let connection = lb.acquire_connection(addr(0)).wait().unwrap();
+        assert_eq!(connection.peer_addr(), addr(3));
+        #[cfg(feature = "metrics")]
+        assert_eq!(lb.metrics().attempts(addr(1)), 1);
+        #[cfg(feature = "metrics")]
+        assert_eq!(lb.metrics().failures(addr(1)), 1);
+        #[cfg(feature = "metrics")]
+        assert_eq!(lb.metrics().failures(addr(2)), 1);
+        #[cfg(feature = "metrics")]
+        assert_eq!(lb.metrics().failures(addr(3)), 0);
+
+        // All down
+        let down = [addr(1)].iter().cloned().collect();
+        let inner = Refuse(Mock::new(), Arc::new(down));
+        let mut lb = LoadBalanced::new(inner, vec![(addr(1), 1)]).unwrap();
+        let e = lb.acquire_connection(addr(0)).wait().err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    /// Refuses connections to the given addresses.
+    #[derive(Clone)]
+    struct Refuse(Mock, Arc<HashSet<SocketAddr>>);
+    impl AcquireConnection for Refuse {
+        type Connection = Connection;
+        type Future = Either<FutureResult<Connection, Error>, FutureResult<Connection, Error>>;
+
+        fn acquire_connection(&mut self, addr: SocketAddr) -> Self::Future {
+            if self.1.contains(&addr) {
+                let e = track!(Error::from(ErrorKind::ConnectionRefused.error()));
+                Either::B(future::err(e))
+            } else {
+                Either::A(self.0.acquire_connection(addr))
+            }
+        }
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        ([127, 0, 0, 1], port).into()
+    }
+}
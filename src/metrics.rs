@@ -1,7 +1,23 @@
 //! [Prometheus] metrics.
 //!
+//! The metrics are collected only if the `metrics` feature (enabled by default) is enabled.
+//! Without the feature, `prometrics` is not depended on, the methods that take `MetricBuilder`
+//! or return histograms are absent, and the other methods always return zero.
+//!
 //! [Prometheus]: https://prometheus.io/
-use prometrics::metrics::{Counter, Gauge, MetricBuilder};
+#[cfg(feature = "metrics")]
+pub(crate) use prometrics::metrics::{Counter, Gauge, Histogram, MetricBuilder};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use ErrorKind;
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) use self::noop::{Counter, Gauge, Histogram, MetricBuilder};
+
+const DURATION_BUCKETS: [f64; 12] = [
+    0.0001, 0.0005, 0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0,
+];
 
 /// [`ConnectionPool`] metrics.
 ///
@@ -18,16 +34,29 @@ pub struct ConnectionPoolMetrics {
     pub(crate) connect_failed_connections: Counter,
     pub(crate) request_failed_connections: Counter,
     pub(crate) expired_connections: Counter,
+    pub(crate) peer_closed_connections: Counter,
     pub(crate) kicked_out_connections: Counter,
 
     // lent
     pub(crate) lent_connections: Counter,
+    pub(crate) reused_connections: Counter,
+    pub(crate) new_connections: Counter,
+    pub(crate) affinity_hits: Counter,
+    pub(crate) affinity_misses: Counter,
 
     // returned
     pub(crate) returned_connections: Counter,
 
     // error
     pub(crate) no_available_connection_errors: Counter,
+
+    // queue
+    pub(crate) acquire_queue_length: Gauge,
+
+    // latency
+    pub(crate) acquire_duration: Histogram,
+    pub(crate) succeeded_connect_duration: Histogram,
+    pub(crate) failed_connect_duration: Histogram,
 }
 impl ConnectionPoolMetrics {
     /// Maximum number of pooled connections.
@@ -47,6 +76,7 @@ impl ConnectionPoolMetrics {
             + self.connect_failed_connections()
             + self.request_failed_connections()
             + self.expired_connections()
+            + self.peer_closed_connections()
             + self.kicked_out_connections();
         let allocated = self.allocated_connections();
         allocated.saturating_sub(released) as usize
@@ -87,6 +117,17 @@ impl ConnectionPoolMetrics {
         self.expired_connections.value() as u64
     }
 
+    /// Number of idle connections released from the pool because the servers had closed them
+    /// (or had sent unsolicited bytes on them).
+    ///
+    /// These are detected by peeking the sockets at each tick of the timer (see `ConnectionPoolBuilder::timer_interval`)
+    /// before their keepalive timeouts expire.
+    ///
+    /// Metric: `fibers_http_client_connection_released_connections_total { reason="peer_closed" } <COUNTER>`
+    pub fn peer_closed_connections(&self) -> u64 {
+        self.peer_closed_connections.value() as u64
+    }
+
     /// Number of connections kicked out from the pool.
     ///
     /// Metric: `fibers_http_client_connection_released_connections_total { reason="kicked_out" } <COUNTER>`
@@ -101,6 +142,43 @@ impl ConnectionPoolMetrics {
         self.lent_connections.value() as u64
     }
 
+    /// Number of pooled connections lent to clients (i.e., reused for new requests).
+    ///
+    /// This is the number of pool hits (i.e., acquisitions served by idle connections).
+    ///
+    /// Metric: `fibers_http_client_connection_acquisitions_total { connection="reused" } <COUNTER>`
+    pub fn reused_connections(&self) -> u64 {
+        self.reused_connections.value() as u64
+    }
+
+    /// Number of newly established connections lent to clients.
+    ///
+    /// This is the number of pool misses (i.e., acquisitions that required new connections).
+    /// Acquisitions that failed to establish connections are not counted.
+    /// The reuse ratio can be calculated as `reused_connections / (reused_connections + new_connections)`.
+    ///
+    /// Metric: `fibers_http_client_connection_acquisitions_total { connection="new" } <COUNTER>`
+    pub fn new_connections(&self) -> u64 {
+        self.new_connections.value() as u64
+    }
+
+    /// Number of acquisitions with affinity keys that were served by the connections associated with the keys.
+    ///
+    /// See `ConnectionPoolHandle::with_affinity` for details.
+    /// The hit rate can be calculated as `affinity_hits / (affinity_hits + affinity_misses)`.
+    ///
+    /// Metric: `fibers_http_client_connection_affinity_acquisitions_total { result="hit" } <COUNTER>`
+    pub fn affinity_hits(&self) -> u64 {
+        self.affinity_hits.value() as u64
+    }
+
+    /// Number of acquisitions with affinity keys that were served by other connections.
+    ///
+    /// Metric: `fibers_http_client_connection_affinity_acquisitions_total { result="miss" } <COUNTER>`
+    pub fn affinity_misses(&self) -> u64 {
+        self.affinity_misses.value() as u64
+    }
+
     /// Number of connections returned from clients.
     ///
     /// Metric: `fibers_http_client_connection_returned_connections_total <COUNTER>`
@@ -123,6 +201,45 @@ impl ConnectionPoolMetrics {
         self.no_available_connection_errors.value() as u64
     }
 
+    /// Current number of acquisition requests waiting for available connections.
+    ///
+    /// See `ConnectionPoolBuilder::on_full` for details.
+    ///
+    /// Metric: `fibers_http_client_connection_acquire_queue_length <GAUGE>`
+    pub fn acquire_queue_length(&self) -> usize {
+        self.acquire_queue_length.value() as usize
+    }
+
+    /// Histogram of the time taken for acquiring connections, in seconds.
+    ///
+    /// The time is measured from the receipt of an acquisition request by the pool until it is answered,
+    /// so it includes the time spent waiting in the acquire queue and establishing a new connection.
+    /// Failed acquisitions (e.g., timeouts) are also observed.
+    ///
+    /// Metric: `fibers_http_client_connection_acquire_duration_seconds <HISTOGRAM>`
+    #[cfg(feature = "metrics")]
+    pub fn acquire_duration(&self) -> &Histogram {
+        &self.acquire_duration
+    }
+
+    /// Histogram of the time taken for establishing TCP connections successfully, in seconds.
+    ///
+    /// The time is measured from the start of TCP connect until its completion (i.e., name resolution is excluded).
+    ///
+    /// Metric: `fibers_http_client_connection_connect_duration_seconds { outcome="success" } <HISTOGRAM>`
+    #[cfg(feature = "metrics")]
+    pub fn succeeded_connect_duration(&self) -> &Histogram {
+        &self.succeeded_connect_duration
+    }
+
+    /// Histogram of the time taken for TCP connect operations that failed (including timeouts), in seconds.
+    ///
+    /// Metric: `fibers_http_client_connection_connect_duration_seconds { outcome="failure" } <HISTOGRAM>`
+    #[cfg(feature = "metrics")]
+    pub fn failed_connect_duration(&self) -> &Histogram {
+        &self.failed_connect_duration
+    }
+
     pub(crate) fn new(mut builder: MetricBuilder) -> Self {
         builder
             .namespace("fibers_http_client")
@@ -162,6 +279,12 @@ impl ConnectionPoolMetrics {
                 .label("reason", "expired")
                 .finish()
                 .expect("never fails"),
+            peer_closed_connections: builder
+                .counter("released_connections_total")
+                .help("Number of connections released from pools so far")
+                .label("reason", "peer_closed")
+                .finish()
+                .expect("never fails"),
             kicked_out_connections: builder
                 .counter("released_connections_total")
                 .help("Number of connections released from pools so far")
@@ -173,6 +296,30 @@ impl ConnectionPoolMetrics {
                 .help("Number of connections lent to clients so far")
                 .finish()
                 .expect("never fails"),
+            reused_connections: builder
+                .counter("acquisitions_total")
+                .help("Number of connection acquisitions by clients so far")
+                .label("connection", "reused")
+                .finish()
+                .expect("never fails"),
+            new_connections: builder
+                .counter("acquisitions_total")
+                .help("Number of connection acquisitions by clients so far")
+                .label("connection", "new")
+                .finish()
+                .expect("never fails"),
+            affinity_hits: builder
+                .counter("affinity_acquisitions_total")
+                .help("Number of connection acquisitions with affinity keys so far")
+                .label("result", "hit")
+                .finish()
+                .expect("never fails"),
+            affinity_misses: builder
+                .counter("affinity_acquisitions_total")
+                .help("Number of connection acquisitions with affinity keys so far")
+                .label("result", "miss")
+                .finish()
+                .expect("never fails"),
             returned_connections: builder
                 .counter("returned_connections_total")
                 .help("Number of connections returned from clients so far")
@@ -184,6 +331,453 @@ impl ConnectionPoolMetrics {
                 .label("reason", "no_available_connection")
                 .finish()
                 .expect("never fails"),
+            acquire_queue_length: builder
+                .gauge("acquire_queue_length")
+                .help("Number of acquisition requests waiting for available connections")
+                .finish()
+                .expect("never fails"),
+            acquire_duration: builder
+                .histogram("acquire_duration_seconds")
+                .help("Time taken for acquiring connections")
+                .buckets(DURATION_BUCKETS.iter().cloned())
+                .finish()
+                .expect("never fails"),
+            succeeded_connect_duration: builder
+                .histogram("connect_duration_seconds")
+                .help("Time taken for TCP connect operations")
+                .label("outcome", "success")
+                .buckets(DURATION_BUCKETS.iter().cloned())
+                .finish()
+                .expect("never fails"),
+            failed_connect_duration: builder
+                .histogram("connect_duration_seconds")
+                .help("Time taken for TCP connect operations")
+                .label("outcome", "failure")
+                .buckets(DURATION_BUCKETS.iter().cloned())
+                .finish()
+                .expect("never fails"),
+        }
+    }
+}
+
+/// [`LoadBalanced`] metrics.
+///
+/// The metrics are labeled by the address of upstreams.
+///
+/// [`LoadBalanced`]: ../connection/struct.LoadBalanced.html
+#[derive(Debug, Clone)]
+pub struct LoadBalancerMetrics {
+    upstreams: Vec<(SocketAddr, UpstreamMetrics)>,
+}
+impl LoadBalancerMetrics {
+    /// Number of connection attempts to the given upstream (including fallbacks).
+    ///
+    /// Metric: `fibers_http_client_load_balancer_attempts_total { upstream="..." } <COUNTER>`
+    pub fn attempts(&self, upstream: SocketAddr) -> u64 {
+        self.upstream(upstream)
+            .map_or(0, |m| m.attempts.value() as u64)
+    }
+
+    /// Number of failed connection attempts to the given upstream.
+    ///
+    /// Metric: `fibers_http_client_load_balancer_failures_total { upstream="..." } <COUNTER>`
+    pub fn failures(&self, upstream: SocketAddr) -> u64 {
+        self.upstream(upstream)
+            .map_or(0, |m| m.failures.value() as u64)
+    }
+
+    pub(crate) fn upstream(&self, upstream: SocketAddr) -> Option<&UpstreamMetrics> {
+        self.upstreams
+            .iter()
+            .find(|(addr, _)| *addr == upstream)
+            .map(|(_, m)| m)
+    }
+
+    pub(crate) fn new(mut builder: MetricBuilder, upstreams: &[SocketAddr]) -> Self {
+        builder
+            .namespace("fibers_http_client")
+            .subsystem("load_balancer");
+        let upstreams = upstreams
+            .iter()
+            .map(|addr| {
+                let label = addr.to_string();
+                let metrics = UpstreamMetrics {
+                    attempts: builder
+                        .counter("attempts_total")
+                        .help("Number of connection attempts to upstreams so far")
+                        .label("upstream", &label)
+                        .finish()
+                        .expect("never fails"),
+                    failures: builder
+                        .counter("failures_total")
+                        .help("Number of failed connection attempts to upstreams so far")
+                        .label("upstream", &label)
+                        .finish()
+                        .expect("never fails"),
+                };
+                (*addr, metrics)
+            })
+            .collect();
+        LoadBalancerMetrics { upstreams }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct UpstreamMetrics {
+    pub(crate) attempts: Counter,
+    pub(crate) failures: Counter,
+}
+
+/// [`CircuitBreaker`] metrics.
+///
+/// [`CircuitBreaker`]: ../connection/struct.CircuitBreaker.html
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerMetrics {
+    pub(crate) opened: Counter,
+    pub(crate) half_opened: Counter,
+    pub(crate) closed: Counter,
+    pub(crate) rejected_acquisitions: Counter,
+}
+impl CircuitBreakerMetrics {
+    /// Number of transitions of circuits to the open state (i.e., hosts that started to be rejected).
+    ///
+    /// Metric: `fibers_http_client_circuit_breaker_transitions_total { state="open" } <COUNTER>`
+    pub fn opened(&self) -> u64 {
+        self.opened.value() as u64
+    }
+
+    /// Number of transitions of circuits to the half-open state (i.e., trial requests allowed after cooldowns).
+    ///
+    /// Metric: `fibers_http_client_circuit_breaker_transitions_total { state="half_open" } <COUNTER>`
+    pub fn half_opened(&self) -> u64 {
+        self.half_opened.value() as u64
+    }
+
+    /// Number of transitions of circuits from the half-open state to the closed state (i.e., recovered hosts).
+    ///
+    /// Metric: `fibers_http_client_circuit_breaker_transitions_total { state="closed" } <COUNTER>`
+    pub fn closed(&self) -> u64 {
+        self.closed.value() as u64
+    }
+
+    /// Number of connection acquisitions rejected by open circuits.
+    ///
+    /// Metric: `fibers_http_client_circuit_breaker_rejected_acquisitions_total <COUNTER>`
+    pub fn rejected_acquisitions(&self) -> u64 {
+        self.rejected_acquisitions.value() as u64
+    }
+
+    pub(crate) fn new(mut builder: MetricBuilder) -> Self {
+        builder
+            .namespace("fibers_http_client")
+            .subsystem("circuit_breaker");
+        let transitions = |state| {
+            builder
+                .counter("transitions_total")
+                .help("Number of state transitions of circuits so far")
+                .label("state", state)
+                .finish()
+                .expect("never fails")
+        };
+        CircuitBreakerMetrics {
+            opened: transitions("open"),
+            half_opened: transitions("half_open"),
+            closed: transitions("closed"),
+            rejected_acquisitions: builder
+                .counter("rejected_acquisitions_total")
+                .help("Number of connection acquisitions rejected by open circuits so far")
+                .finish()
+                .expect("never fails"),
         }
     }
 }
+
+const METHODS: [&str; 9] = [
+    "GET", "HEAD", "POST", "PUT", "DELETE", "CONNECT", "OPTIONS", "TRACE", "PATCH",
+];
+
+const ERROR_KINDS: [(ErrorKind, &str); 13] = [
+    (ErrorKind::InvalidInput, "invalid_input"),
+    (ErrorKind::UnexpectedEos, "unexpected_eos"),
+    (ErrorKind::Timeout, "timeout"),
+    (ErrorKind::TemporarilyUnavailable, "temporarily_unavailable"),
+    (ErrorKind::StatusError, "status_error"),
+    (ErrorKind::HeaderTooLarge, "header_too_large"),
+    (ErrorKind::Dns, "dns"),
+    (ErrorKind::ConnectionRefused, "connection_refused"),
+    (ErrorKind::ConnectionClosed, "connection_closed"),
+    (ErrorKind::Io, "io"),
+    (ErrorKind::Canceled, "canceled"),
+    (
+        ErrorKind::ProxyAuthenticationRequired,
+        "proxy_authentication_required",
+    ),
+    (ErrorKind::Other, "other"),
+];
+
+/// [`Client`] metrics.
+///
+/// The metrics are labeled by the method of requests.
+/// Non-standard methods are aggregated into the `method="other"` label.
+///
+/// [`Client`]: ../struct.Client.html
+#[derive(Debug, Clone)]
+pub struct ClientMetrics {
+    methods: Vec<MethodMetrics>, // The last entry is for non-standard methods
+    in_flight_requests: Gauge,
+    permits_in_use: Gauge,
+}
+impl ClientMetrics {
+    /// Number of requests currently being executed.
+    ///
+    /// A request is counted while it is sent to the server and its response is received
+    /// (i.e., the time spent for acquiring a connection is excluded).
+    /// This metric is not labeled by methods.
+    ///
+    /// Metric: `fibers_http_client_client_in_flight_requests <GAUGE>`
+    pub fn in_flight_requests(&self) -> u64 {
+        self.in_flight_requests.value() as u64
+    }
+
+    /// Number of permits held by requests under the concurrency limit of the client.
+    ///
+    /// This is always zero unless `Client::max_concurrency` is set.
+    ///
+    /// Metric: `fibers_http_client_client_permits_in_use <GAUGE>`
+    pub fn permits_in_use(&self) -> u64 {
+        self.permits_in_use.value() as u64
+    }
+
+    /// Number of requests issued with the given method.
+    ///
+    /// Metric: `fibers_http_client_client_requests_total { method="..." } <COUNTER>`
+    pub fn requests(&self, method: &str) -> u64 {
+        self.method(method).requests.value() as u64
+    }
+
+    /// Number of requests completed successfully with the given method.
+    ///
+    /// Metric: `fibers_http_client_client_completed_requests_total { method="...", outcome="success" } <COUNTER>`
+    pub fn succeeded_requests(&self, method: &str) -> u64 {
+        self.method(method).succeeded_requests.value() as u64
+    }
+
+    /// Number of requests failed with the given method.
+    ///
+    /// Metric: `fibers_http_client_client_completed_requests_total { method="...", outcome="failure" } <COUNTER>`
+    pub fn failed_requests(&self, method: &str) -> u64 {
+        self.method(method).failed_requests.value() as u64
+    }
+
+    /// Number of requests failed with the given kind of error (e.g., `ErrorKind::Timeout`).
+    ///
+    /// Decoding errors of responses are classified as `ErrorKind::InvalidInput`.
+    /// This metric is not labeled by methods.
+    ///
+    /// Metric: `fibers_http_client_client_request_errors_total { kind="..." } <COUNTER>`
+    pub fn request_errors(&self, kind: ErrorKind) -> u64 {
+        self.methods[0].errors.get(kind).value() as u64
+    }
+
+    pub(crate) fn method(&self, method: &str) -> &MethodMetrics {
+        let i = METHODS
+            .iter()
+            .position(|m| *m == method)
+            .unwrap_or(METHODS.len());
+        &self.methods[i]
+    }
+
+    pub(crate) fn in_flight(&self) -> InFlight {
+        self.in_flight_requests.increment();
+        InFlight(self.in_flight_requests.clone())
+    }
+
+    pub(crate) fn permits_in_use_gauge(&self) -> Gauge {
+        self.permits_in_use.clone()
+    }
+
+    pub(crate) fn new(mut builder: MetricBuilder) -> Self {
+        builder.namespace("fibers_http_client").subsystem("client");
+        let errors = ErrorMetrics(Arc::new(
+            ERROR_KINDS
+                .iter()
+                .map(|&(_, kind)| {
+                    builder
+                        .counter("request_errors_total")
+                        .help("Number of requests failed so far")
+                        .label("kind", kind)
+                        .finish()
+                        .expect("never fails")
+                })
+                .collect(),
+        ));
+        let methods = METHODS
+            .iter()
+            .chain(["other"].iter())
+            .map(|method| MethodMetrics {
+                requests: builder
+                    .counter("requests_total")
+                    .help("Number of requests issued so far")
+                    .label("method", method)
+                    .finish()
+                    .expect("never fails"),
+                succeeded_requests: builder
+                    .counter("completed_requests_total")
+                    .help("Number of requests completed so far")
+                    .label("method", method)
+                    .label("outcome", "success")
+                    .finish()
+                    .expect("never fails"),
+                failed_requests: builder
+                    .counter("completed_requests_total")
+                    .help("Number of requests completed so far")
+                    .label("method", method)
+                    .label("outcome", "failure")
+                    .finish()
+                    .expect("never fails"),
+                errors: errors.clone(),
+            })
+            .collect();
+        let in_flight_requests = builder
+            .gauge("in_flight_requests")
+            .help("Number of requests currently being executed")
+            .finish()
+            .expect("never fails");
+        let permits_in_use = builder
+            .gauge("permits_in_use")
+            .help("Number of permits held by requests under the concurrency limit")
+            .finish()
+            .expect("never fails");
+        ClientMetrics {
+            methods,
+            in_flight_requests,
+            permits_in_use,
+        }
+    }
+}
+impl Default for ClientMetrics {
+    fn default() -> Self {
+        Self::new(MetricBuilder::new())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct MethodMetrics {
+    pub(crate) requests: Counter,
+    pub(crate) succeeded_requests: Counter,
+    pub(crate) failed_requests: Counter,
+    errors: ErrorMetrics, // Shared by all methods
+}
+impl MethodMetrics {
+    /// Counts a request failed with the given kind of error.
+    pub(crate) fn fail(&self, kind: ErrorKind) {
+        self.failed_requests.increment();
+        self.errors.get(kind).increment();
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ErrorMetrics(Arc<Vec<Counter>>); // Indexed in the order of `ERROR_KINDS`
+impl ErrorMetrics {
+    fn get(&self, kind: ErrorKind) -> &Counter {
+        let i = ERROR_KINDS
+            .iter()
+            .position(|&(k, _)| k == kind)
+            .expect("never fails");
+        &self.0[i]
+    }
+}
+
+/// A guard that decrements the `in_flight_requests` gauge when dropped.
+#[derive(Debug)]
+pub(crate) struct InFlight(Gauge);
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.decrement();
+    }
+}
+
+/// No-op stand-ins for the types of `prometrics`, used if the `metrics` feature is disabled.
+#[cfg(not(feature = "metrics"))]
+mod noop {
+    use std::marker::PhantomData;
+
+    #[derive(Debug, Default, Clone)]
+    pub struct MetricBuilder;
+    impl MetricBuilder {
+        pub fn new() -> Self {
+            MetricBuilder
+        }
+
+        pub fn namespace(&mut self, _namespace: &str) -> &mut Self {
+            self
+        }
+
+        pub fn subsystem(&mut self, _subsystem: &str) -> &mut Self {
+            self
+        }
+
+        pub fn counter(&self, _name: &str) -> Builder<Counter> {
+            Builder(PhantomData)
+        }
+
+        pub fn gauge(&self, _name: &str) -> Builder<Gauge> {
+            Builder(PhantomData)
+        }
+
+        pub fn histogram(&self, _name: &str) -> Builder<Histogram> {
+            Builder(PhantomData)
+        }
+    }
+
+    pub struct Builder<T>(PhantomData<T>);
+    impl<T: Default> Builder<T> {
+        pub fn help(&mut self, _help: &str) -> &mut Self {
+            self
+        }
+
+        pub fn label(&mut self, _name: &str, _value: &str) -> &mut Self {
+            self
+        }
+
+        pub fn buckets<I: IntoIterator<Item = f64>>(&mut self, _upper_bounds: I) -> &mut Self {
+            self
+        }
+
+        pub fn finish(&self) -> Result<T, ()> {
+            Ok(T::default())
+        }
+    }
+
+    #[derive(Debug, Default, Clone)]
+    pub struct Counter;
+    impl Counter {
+        pub fn value(&self) -> f64 {
+            0.0
+        }
+
+        pub fn increment(&self) {}
+
+        pub fn add_u64(&self, _count: u64) {}
+    }
+
+    #[derive(Debug, Default, Clone)]
+    pub struct Gauge;
+    impl Gauge {
+        pub fn value(&self) -> f64 {
+            0.0
+        }
+
+        pub fn set(&self, _value: f64) {}
+
+        pub fn increment(&self) {}
+
+        pub fn decrement(&self) {}
+    }
+
+    #[derive(Debug, Default, Clone)]
+    pub struct Histogram;
+    impl Histogram {
+        pub fn observe(&self, _value: f64) {}
+    }
+}
@@ -0,0 +1,717 @@
+//! Middlewares that wrap the execution of requests.
+//!
+//! A middleware implements cross-cutting behavior (e.g., authentication, logging or retrying)
+//! around the requests issued by [`RequestBuilder::call`].
+//! The middlewares registered via [`Client::middleware`] are run in the order of registration:
+//! the first one receives the request first, and passes it to the next one by invoking [`Next::run`].
+//! The last [`Next`] of the chain sends the request to the server.
+//!
+//! [`Cache`] is a built-in middleware that caches responses and revalidates them with conditional requests.
+//!
+//! # Examples
+//!
+//! ```no_run
+//! # extern crate fibers_http_client;
+//! # extern crate httpcodec;
+//! # extern crate url;
+//! use fibers_http_client::connection::Oneshot;
+//! use fibers_http_client::middleware::{Middleware, MiddlewareFuture, Next};
+//! use fibers_http_client::Client;
+//! use httpcodec::{HeaderField, Request};
+//! use url::Url;
+//!
+//! struct Auth(String);
+//! impl Middleware for Auth {
+//!     fn around(&self, mut request: Request<Vec<u8>>, next: Next) -> MiddlewareFuture {
+//!         let field = HeaderField::new("Authorization", &self.0).unwrap();
+//!         request.header_mut().add_field(field);
+//!         next.run(request)
+//!     }
+//! }
+//!
+//! # fn main() {
+//! let url = Url::parse("http://localhost/foo/bar").unwrap();
+//! let mut client = Client::new(Oneshot);
+//! client.middleware(Auth("Bearer secret".to_owned()));
+//! let future = client.request(&url).call("GET", Vec::new());
+//! # }
+//! ```
+//!
+//! [`RequestBuilder::call`]: ../struct.RequestBuilder.html#method.call
+//! [`Client::middleware`]: ../struct.Client.html#method.middleware
+//! [`Next::run`]: ./struct.Next.html#method.run
+//! [`Next`]: ./struct.Next.html
+//! [`Cache`]: ./struct.Cache.html
+use futures::future;
+use futures::Future;
+use httpcodec::{HeaderField, Request, Response};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use Error;
+
+const DEFAULT_CACHE_CAPACITY: usize = 1024;
+
+/// `Future` that resolves to the response of a request processed by middlewares.
+pub type MiddlewareFuture =
+    Box<dyn Future<Item = Response<Vec<u8>>, Error = Error> + Send + 'static>;
+
+/// A middleware that wraps the execution of requests.
+///
+/// It is also implemented by closures of the form `Fn(Request<Vec<u8>>, Next) -> MiddlewareFuture`.
+pub trait Middleware: Send + Sync + 'static {
+    /// Processes the request and returns a future that resolves to its response.
+    ///
+    /// The request is passed to the rest of the chain via `next.run(request)`.
+    /// The middleware may return a response without invoking `next` (e.g., a cached one),
+    /// or invoke it more than once (e.g., for retrying the request built by [`clone_request`]).
+    ///
+    /// [`clone_request`]: ./fn.clone_request.html
+    fn around(&self, request: Request<Vec<u8>>, next: Next) -> MiddlewareFuture;
+}
+impl<F> Middleware for F
+where
+    F: Fn(Request<Vec<u8>>, Next) -> MiddlewareFuture + Send + Sync + 'static,
+{
+    fn around(&self, request: Request<Vec<u8>>, next: Next) -> MiddlewareFuture {
+        self(request, next)
+    }
+}
+
+type Endpoint = dyn Fn(Request<Vec<u8>>) -> MiddlewareFuture + Send + Sync + 'static;
+
+/// The rest of the middleware chain.
+///
+/// This is cheap to clone, and can be run any number of times.
+#[derive(Clone)]
+pub struct Next {
+    chain: MiddlewareChain,
+    index: usize,
+    deadline: Option<Instant>,
+    endpoint: Arc<Endpoint>,
+}
+impl Next {
+    /// Returns the deadline of the whole execution, derived from the timeout (or the deadline) of the request builder.
+    ///
+    /// Every request sent via [`run`] shares this deadline,
+    /// so a request sent after it has passed fails immediately with an `ErrorKind::Timeout` error.
+    ///
+    /// [`run`]: #method.run
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Returns the time remaining until [`deadline`] (zero if it has already passed).
+    ///
+    /// This is useful for middlewares to decide whether retrying is worthwhile.
+    ///
+    /// [`deadline`]: #method.deadline
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|d| d.saturating_duration_since(Instant::now()))
+    }
+
+    /// Passes the request to the next middleware (or sends it to the server if there are no more middlewares).
+    pub fn run(&self, request: Request<Vec<u8>>) -> MiddlewareFuture {
+        match self.chain.0.get(self.index) {
+            Some(middleware) => {
+                let next = Next {
+                    chain: self.chain.clone(),
+                    index: self.index + 1,
+                    deadline: self.deadline,
+                    endpoint: Arc::clone(&self.endpoint),
+                };
+                middleware.around(request, next)
+            }
+            None => (self.endpoint)(request),
+        }
+    }
+}
+impl fmt::Debug for Next {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Next {{ remaining: {} }}",
+            self.chain.0.len() - self.index
+        )
+    }
+}
+
+/// Returns a copy of the given request.
+///
+/// `httpcodec::Request` does not implement `Clone`, so this is useful for middlewares that send a request more than once.
+pub fn clone_request<T: Clone>(request: &Request<T>) -> Request<T> {
+    let mut cloned = Request::new(
+        request.method(),
+        request.request_target(),
+        request.http_version(),
+        request.body().clone(),
+    );
+    for field in request.header().fields() {
+        let field = unsafe { HeaderField::new_unchecked(field.name(), field.value()) };
+        cloned.header_mut().add_field(field);
+    }
+    cloned
+}
+
+/// A middleware that caches the responses of `GET` requests, and revalidates them with conditional requests.
+///
+/// Successful responses are stored keyed by the `Host` header and the request target.
+/// While a cached response is fresh (i.e., within its `Cache-Control: max-age`), it is returned without contacting the server.
+/// Otherwise the request is sent with `If-None-Match` and/or `If-Modified-Since`
+/// built from the `ETag` and `Last-Modified` of the cached response,
+/// and the cached response is returned if the server replies with `304 Not Modified`.
+///
+/// Responses that have `Cache-Control: no-store`, or that have neither validators nor `max-age`, are not stored.
+/// Since the key does not include any header fields other than `Host`,
+/// responses that have `Vary` are not stored either.
+/// Requests that already have conditional header fields, and requests that have `Authorization`
+/// (whose responses may be specific to the credentials), are passed through as they are.
+///
+/// At most [`capacity`] responses are stored, and the least recently used one is evicted to make room for a new one.
+///
+/// This is cheap to clone, and the clones share the same storage.
+///
+/// [`capacity`]: #method.with_capacity
+#[derive(Clone)]
+pub struct Cache {
+    storage: Arc<Mutex<CacheStorage>>,
+    capacity: usize,
+}
+impl Cache {
+    /// Makes a new empty `Cache` instance.
+    ///
+    /// This is equivalent to `Cache::with_capacity(1024)`.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes a new empty `Cache` instance that stores at most `capacity` responses.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Cache {
+            storage: Arc::new(Mutex::new(CacheStorage::default())),
+            capacity,
+        }
+    }
+
+    /// Returns the number of the cached responses.
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    /// Returns `true` if there are no cached responses.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Removes all the cached responses.
+    pub fn clear(&self) {
+        self.lock().entries.clear();
+    }
+
+    fn lookup(&self, key: &str) -> Option<CacheEntry> {
+        let mut storage = self.lock();
+        let now = storage.tick();
+        storage.entries.get_mut(key).map(|entry| {
+            entry.last_used = now;
+            entry.clone()
+        })
+    }
+
+    fn store(&self, key: String, response: &Response<Vec<u8>>) {
+        let mut storage = self.lock();
+        match CacheEntry::new(response) {
+            Some(mut entry) if self.capacity > 0 => {
+                entry.last_used = storage.tick();
+                storage.entries.insert(key, entry);
+                if storage.entries.len() > self.capacity {
+                    storage.evict_least_recently_used();
+                }
+            }
+            _ => {
+                storage.entries.remove(&key);
+            }
+        }
+    }
+
+    fn refresh(&self, key: &str, not_modified: &Response<Vec<u8>>) {
+        let directives = CacheControl::parse(not_modified);
+        let mut storage = self.lock();
+        if directives.no_store {
+            storage.entries.remove(key);
+        } else if let Some(entry) = storage.entries.get_mut(key) {
+            entry.expires_at = directives.expires_at();
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CacheStorage> {
+        self.storage.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+impl Default for Cache {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_CACHE_CAPACITY)
+    }
+}
+impl Middleware for Cache {
+    fn around(&self, mut request: Request<Vec<u8>>, next: Next) -> MiddlewareFuture {
+        let is_conditional = ["If-None-Match", "If-Modified-Since"]
+            .iter()
+            .any(|name| request.header().get_field(name).is_some());
+        let is_authorized = request.header().get_field("Authorization").is_some();
+        if request.method().as_str() != "GET" || is_conditional || is_authorized {
+            return next.run(request);
+        }
+
+        let key = format!(
+            "{}{}",
+            request.header().get_field("Host").unwrap_or(""),
+            request.request_target().as_str()
+        );
+        let cached = self.lookup(&key);
+        if let Some(ref entry) = cached {
+            if entry.is_fresh() {
+                return Box::new(future::ok(clone_response(&entry.response)));
+            }
+            if let Some(ref etag) = entry.etag {
+                let field = unsafe { HeaderField::new_unchecked("If-None-Match", etag) };
+                request.header_mut().add_field(field);
+            }
+            if let Some(ref last_modified) = entry.last_modified {
+                let field =
+                    unsafe { HeaderField::new_unchecked("If-Modified-Since", last_modified) };
+                request.header_mut().add_field(field);
+            }
+        }
+
+        let cache = self.clone();
+        Box::new(next.run(request).map(move |response| {
+            match (response.status_code().as_u16(), cached) {
+                (304, Some(entry)) => {
+                    cache.refresh(&key, &response);
+                    clone_response(&entry.response)
+                }
+                (200, _) => {
+                    cache.store(key, &response);
+                    response
+                }
+                _ => response,
+            }
+        }))
+    }
+}
+impl fmt::Debug for Cache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Cache {{ len: {} }}", self.len())
+    }
+}
+
+#[derive(Debug, Default)]
+struct CacheStorage {
+    entries: HashMap<String, CacheEntry>,
+    clock: u64, // Advanced at each access, for finding the least recently used entry
+}
+impl CacheStorage {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        let key = self
+            .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| key.clone());
+        if let Some(key) = key {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    response: Response<Vec<u8>>,
+    etag: Option<String>,
+    last_modified: Option<String>,
+    expires_at: Option<Instant>,
+    last_used: u64,
+}
+impl CacheEntry {
+    fn new(response: &Response<Vec<u8>>) -> Option<Self> {
+        let directives = CacheControl::parse(response);
+        if directives.no_store {
+            return None;
+        }
+        let header = response.header();
+        if header.get_field("Vary").is_some() {
+            return None;
+        }
+        let etag = header.get_field("ETag").map(str::to_owned);
+        let last_modified = header.get_field("Last-Modified").map(str::to_owned);
+        let expires_at = directives.expires_at();
+        if etag.is_none() && last_modified.is_none() && expires_at.is_none() {
+            return None;
+        }
+        Some(CacheEntry {
+            response: clone_response(response),
+            etag,
+            last_modified,
+            expires_at,
+            last_used: 0,
+        })
+    }
+
+    fn is_fresh(&self) -> bool {
+        self.expires_at.is_some_and(|t| Instant::now() < t)
+    }
+}
+impl Clone for CacheEntry {
+    fn clone(&self) -> Self {
+        CacheEntry {
+            response: clone_response(&self.response),
+            etag: self.etag.clone(),
+            last_modified: self.last_modified.clone(),
+            expires_at: self.expires_at,
+            last_used: self.last_used,
+        }
+    }
+}
+
+/// The `Cache-Control` directives that are taken into account by `Cache`.
+#[derive(Debug, Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    max_age: Option<u64>,
+}
+impl CacheControl {
+    fn parse(response: &Response<Vec<u8>>) -> Self {
+        let mut directives = CacheControl::default();
+        let header = response.header();
+        let values = header
+            .fields()
+            .filter(|f| f.name().eq_ignore_ascii_case("Cache-Control"))
+            .flat_map(|f| f.value().split(','));
+        for value in values {
+            let value = value.trim();
+            if value.eq_ignore_ascii_case("no-store") {
+                directives.no_store = true;
+            } else if value.eq_ignore_ascii_case("no-cache") {
+                directives.no_cache = true;
+            } else if let Some((name, seconds)) = value.split_once('=') {
+                if name.trim().eq_ignore_ascii_case("max-age") {
+                    directives.max_age = seconds.trim().trim_matches('"').parse().ok();
+                }
+            }
+        }
+        directives
+    }
+
+    fn expires_at(&self) -> Option<Instant> {
+        if self.no_cache {
+            return None;
+        }
+        self.max_age
+            .filter(|&seconds| seconds > 0)
+            .and_then(|seconds| Instant::now().checked_add(Duration::from_secs(seconds)))
+    }
+}
+
+fn clone_response(response: &Response<Vec<u8>>) -> Response<Vec<u8>> {
+    let mut cloned = Response::new(
+        response.http_version(),
+        response.status_code(),
+        response.reason_phrase(),
+        response.body().clone(),
+    );
+    for field in response.header().fields() {
+        let field = unsafe { HeaderField::new_unchecked(field.name(), field.value()) };
+        cloned.header_mut().add_field(field);
+    }
+    cloned
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct MiddlewareChain(Arc<Vec<Arc<dyn Middleware>>>);
+impl MiddlewareChain {
+    pub fn push<M: Middleware>(&mut self, middleware: M) {
+        Arc::make_mut(&mut self.0).push(Arc::new(middleware));
+    }
+
+    /// Runs the request through the middlewares, and then passes it to `endpoint`.
+    ///
+    /// `deadline` is exposed to the middlewares via `Next::deadline`.
+    pub fn run<F>(
+        &self,
+        request: Request<Vec<u8>>,
+        deadline: Option<Instant>,
+        endpoint: F,
+    ) -> MiddlewareFuture
+    where
+        F: Fn(Request<Vec<u8>>) -> MiddlewareFuture + Send + Sync + 'static,
+    {
+        let next = Next {
+            chain: self.clone(),
+            index: 0,
+            deadline,
+            endpoint: Arc::new(endpoint),
+        };
+        next.run(request)
+    }
+}
+impl fmt::Debug for MiddlewareChain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MiddlewareChain {{ len: {} }}", self.0.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytecodec::bytes::RemainingBytesDecoder;
+    use bytecodec::DecodeExt;
+    use fibers_global;
+    use futures::future;
+    use httpcodec::{BodyDecoder, HttpVersion, ResponseDecoder};
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+    use std::thread;
+    use url::Url;
+
+    use super::*;
+    use connection::{Mock, Oneshot};
+    use {Client, ErrorKind};
+
+    #[test]
+    fn middleware_order_works() {
+        let mock = Mock::new();
+        mock.push_response("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo");
+        let mut client = Client::new(mock.clone());
+        let log = Arc::new(Mutex::new(Vec::new()));
+        for name in &["first", "second"] {
+            let log = Arc::clone(&log);
+            client.middleware(move |mut request: Request<Vec<u8>>, next: Next| {
+                log.lock().unwrap().push(*name);
+                let field = HeaderField::new("X-Middleware", name).unwrap();
+                request.header_mut().add_field(field);
+                next.run(request)
+            });
+        }
+
+        let url = Url::parse("http://example.com/").unwrap();
+        let future = client.request(&url).call("GET", Vec::new());
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(response.body(), b"foo");
+        assert_eq!(*log.lock().unwrap(), ["first", "second"]);
+        assert_eq!(
+            mock.requests(),
+            vec![b"GET / HTTP/1.1\r\nHost: example.com\r\nX-Middleware: first\r\nX-Middleware: second\r\nContent-Length: 0\r\n\r\n".to_vec()]
+        );
+
+        // `HEAD` responses have no body
+        mock.push_response("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n");
+        let future = client.request(&url).call("HEAD", Vec::new());
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(response.body(), b"");
+    }
+
+    #[test]
+    fn short_circuit_works() {
+        let mock = Mock::new();
+        let mut client = Client::new(mock.clone());
+        client.middleware(
+            |_request: Request<Vec<u8>>, _next: Next| -> MiddlewareFuture {
+                let mut decoder = ResponseDecoder::<BodyDecoder<RemainingBytesDecoder>>::default();
+                let response = decoder
+                    .decode_from_bytes(b"HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\ncached")
+                    .unwrap();
+                Box::new(future::ok(response))
+            },
+        );
+
+        let url = Url::parse("http://example.com/").unwrap();
+        let future = client.request(&url).call("GET", Vec::new());
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(response.http_version(), HttpVersion::V1_1);
+        assert_eq!(response.body(), b"cached");
+        assert!(mock.requests().is_empty());
+    }
+
+    #[test]
+    fn retry_works() {
+        let mock = Mock::new();
+        mock.push_response("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n");
+        mock.push_response("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo");
+        let mut client = Client::new(mock.clone());
+        client.middleware(
+            |request: Request<Vec<u8>>, next: Next| -> MiddlewareFuture {
+                let retry = clone_request(&request);
+                Box::new(next.run(request).and_then(move |response| {
+                    if response.status_code().as_u16() == 503 {
+                        next.run(retry)
+                    } else {
+                        Box::new(future::ok(response))
+                    }
+                }))
+            },
+        );
+
+        let url = Url::parse("http://example.com/").unwrap();
+        let future = client.request(&url).call("POST", b"abc".to_vec());
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(response.body(), b"foo");
+
+        let request = b"POST / HTTP/1.1\r\nContent-Type: application/octet-stream\r\nHost: example.com\r\nContent-Length: 3\r\n\r\nabc".to_vec();
+        assert_eq!(mock.requests(), vec![request.clone(), request]);
+    }
+
+    #[test]
+    fn deadline_works() {
+        // Accepts connections but never replies
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let _streams = listener.incoming().collect::<Vec<_>>();
+        });
+
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let mut client = Client::new(Oneshot);
+        {
+            let attempts = Arc::clone(&attempts);
+            client.middleware(move |request: Request<Vec<u8>>, next: Next| {
+                fn retry(
+                    request: Request<Vec<u8>>,
+                    next: Next,
+                    n: usize,
+                    attempts: Arc<Mutex<Vec<Option<Duration>>>>,
+                ) -> MiddlewareFuture {
+                    attempts.lock().unwrap().push(next.remaining());
+                    let retried = clone_request(&request);
+                    Box::new(next.run(request).or_else(move |e| -> MiddlewareFuture {
+                        if n == 0 {
+                            Box::new(future::err(e))
+                        } else {
+                            retry(retried, next, n - 1, attempts)
+                        }
+                    }))
+                }
+                retry(request, next, 2, Arc::clone(&attempts))
+            });
+        }
+
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let start = Instant::now();
+        let future = client
+            .request(&url)
+            .timeout(Duration::from_millis(100))
+            .call("GET", Vec::new());
+        let e = fibers_global::execute(future).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::Timeout);
+
+        // The retries do not extend the timeout
+        assert!(start.elapsed() < Duration::from_millis(250));
+        let attempts = attempts.lock().unwrap();
+        assert_eq!(attempts.len(), 3);
+        assert!(attempts[0].unwrap() <= Duration::from_millis(100));
+        assert_eq!(attempts[1], Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn cache_works() {
+        let mock = Mock::new();
+        let cache = Cache::new();
+        let mut client = Client::new(mock.clone());
+        client.middleware(cache.clone());
+        let url = Url::parse("http://example.com/config").unwrap();
+        let get = |client: &mut Client<Mock>| {
+            let future = client.request(&url).call("GET", Vec::new());
+            fibers_global::execute(future).unwrap()
+        };
+
+        // Validators are stored
+        mock.push_response("HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nLast-Modified: Sun, 06 Nov 1994 08:49:37 GMT\r\nContent-Length: 3\r\n\r\nfoo");
+        assert_eq!(get(&mut client).body(), b"foo");
+        assert_eq!(cache.len(), 1);
+
+        // Served from the cache on `304`
+        mock.push_response("HTTP/1.1 304 Not Modified\r\nContent-Length: 0\r\n\r\n");
+        let response = get(&mut client);
+        assert_eq!(response.status_code().as_u16(), 200);
+        assert_eq!(response.body(), b"foo");
+        let requests = mock.requests();
+        assert_eq!(
+            requests[1],
+            b"GET /config HTTP/1.1\r\nHost: example.com\r\nIf-None-Match: \"v1\"\r\nIf-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\nContent-Length: 0\r\n\r\n".to_vec()
+        );
+
+        // Replaced by a modified one
+        mock.push_response(
+            "HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: 3\r\n\r\nbar",
+        );
+        assert_eq!(get(&mut client).body(), b"bar");
+
+        // Fresh responses are served without requests
+        assert_eq!(get(&mut client).body(), b"bar");
+        assert_eq!(mock.requests().len(), 3);
+
+        // `no-store`
+        cache.clear();
+        mock.push_response("HTTP/1.1 200 OK\r\nETag: \"v2\"\r\nCache-Control: private, no-store\r\nContent-Length: 3\r\n\r\nbaz");
+        assert_eq!(get(&mut client).body(), b"baz");
+        assert!(cache.is_empty());
+
+        // Other methods are not cached
+        mock.push_response("HTTP/1.1 200 OK\r\nETag: \"v3\"\r\nContent-Length: 0\r\n\r\n");
+        let future = client.request(&url).call("POST", Vec::new());
+        fibers_global::execute(future).unwrap();
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn cache_limits_work() {
+        let mock = Mock::new();
+        let cache = Cache::with_capacity(2);
+        let mut client = Client::new(mock.clone());
+        client.middleware(cache.clone());
+        let get = |client: &mut Client<Mock>, path: &str| {
+            let url = Url::parse(&format!("http://example.com/{}", path)).unwrap();
+            let future = client.request(&url).call("GET", Vec::new());
+            fibers_global::execute(future).unwrap()
+        };
+        let fresh = "HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nContent-Length: 0\r\n\r\n";
+
+        // Responses that vary by request header fields are not stored
+        mock.push_response("HTTP/1.1 200 OK\r\nCache-Control: max-age=60\r\nVary: Accept\r\nContent-Length: 0\r\n\r\n");
+        get(&mut client, "a");
+        assert!(cache.is_empty());
+
+        // Requests with credentials are passed through
+        mock.push_response(fresh);
+        let url = Url::parse("http://example.com/a").unwrap();
+        let future = client
+            .request(&url)
+            .header_field("Authorization", "Bearer secret")
+            .call("GET", Vec::new());
+        fibers_global::execute(future).unwrap();
+        assert!(cache.is_empty());
+
+        // The least recently used response is evicted
+        for _ in 0..3 {
+            mock.push_response(fresh);
+        }
+        get(&mut client, "a");
+        get(&mut client, "b");
+        get(&mut client, "a"); // Served from the cache
+        get(&mut client, "c");
+        assert_eq!(cache.len(), 2);
+        assert_eq!(mock.requests().len(), 5);
+
+        get(&mut client, "a"); // Still cached
+        assert_eq!(mock.requests().len(), 5);
+        mock.push_response(fresh);
+        get(&mut client, "b"); // Evicted
+        assert_eq!(mock.requests().len(), 6);
+    }
+}
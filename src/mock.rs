@@ -0,0 +1,101 @@
+use futures::future::{self, FutureResult};
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use connection::{AcquireConnection, Connection};
+use resolver::BoxResolveFuture;
+use Error;
+
+/// An implementation of [`AcquireConnection`] that provides in-memory connections.
+///
+/// Each acquired connection replies with the next response pushed by [`push_response`]
+/// and then behaves as if the server closed it.
+/// If no response is pushed, the connection is closed without replying.
+///
+/// The bytes written to the connections can be retrieved by [`requests`] method.
+/// Host names are resolved to `127.0.0.1` without querying DNS.
+///
+/// This is available only if the `mock` feature is enabled.
+///
+/// [`AcquireConnection`]: ./trait.AcquireConnection.html
+/// [`push_response`]: #method.push_response
+/// [`requests`]: #method.requests
+#[derive(Debug, Default, Clone)]
+pub struct Mock {
+    state: Arc<Mutex<MockState>>,
+}
+impl Mock {
+    /// Makes a new `Mock` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues the raw bytes of a response to be returned by the next acquired connection.
+    pub fn push_response<B: Into<Vec<u8>>>(&self, response: B) -> &Self {
+        self.lock().responses.push_back(response.into());
+        self
+    }
+
+    /// Returns the bytes written to each of the acquired connections, in the order of acquisition.
+    pub fn requests(&self) -> Vec<Vec<u8>> {
+        self.lock().requests.clone()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+impl AcquireConnection for Mock {
+    type Connection = Connection;
+    type Future = FutureResult<Connection, Error>;
+
+    fn acquire_connection(&mut self, addr: SocketAddr) -> Self::Future {
+        let mut state = self.lock();
+        let response = state.responses.pop_front().unwrap_or_default();
+        let index = state.requests.len();
+        state.requests.push(Vec::new());
+
+        let stream = MockStream {
+            response: io::Cursor::new(response),
+            state: self.state.clone(),
+            index,
+        };
+        future::ok(Connection::with_transport(addr, stream))
+    }
+
+    fn resolve(&mut self, _host: &str, port: u16) -> BoxResolveFuture {
+        let addr = SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port);
+        Box::new(future::ok(vec![addr]))
+    }
+}
+
+#[derive(Debug, Default)]
+struct MockState {
+    responses: VecDeque<Vec<u8>>,
+    requests: Vec<Vec<u8>>,
+}
+
+#[derive(Debug)]
+struct MockStream {
+    response: io::Cursor<Vec<u8>>,
+    state: Arc<Mutex<MockState>>,
+    index: usize,
+}
+impl Read for MockStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.response.read(buf)
+    }
+}
+impl Write for MockStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.requests[self.index].extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,409 @@
+use bytecodec::bytes::{BytesEncoder, RemainingBytesDecoder};
+use bytecodec::io::{IoDecodeExt, IoEncodeExt};
+use bytecodec::{Decode, Encode, Eos};
+use futures::{Async, Poll, Stream};
+use httpcodec::{
+    BodyDecode, BodyDecoder, BodyEncoder, DecodeOptions, NoBodyDecoder, Request, RequestEncoder,
+    Response, ResponseDecoder,
+};
+use std::collections::VecDeque;
+use trackable::error::ErrorKindExt;
+
+use connection::{Connection, ConnectionState};
+use request::{check_framing, is_bodiless, is_keep_alive, DEFAULT_MAX_HEADER_SIZE};
+use {Error, ErrorKind};
+
+/// A `Stream` that pipelines HTTP requests on a single connection.
+///
+/// All of the requests are written back-to-back without waiting for the preceding responses,
+/// and the responses are yielded in the order of the requests.
+///
+/// The connection is marked as recyclable only after the last response has been received.
+/// If the server closes the connection before replying all the responses,
+/// the stream will return an `ErrorKind::UnexpectedEos` error.
+/// The number of the responses received until then can be known via [`Pipeline::completed`].
+///
+/// Note that the bodies of the responses (except the last one) must be framed by
+/// `Content-Length` or chunked transfer encoding.
+/// The responses that never have a body (i.e., the ones to `HEAD` requests and `1xx`, `204` and `304` responses)
+/// are not required to be framed, and interim (`1xx`) responses are skipped.
+///
+/// [`Pipeline::completed`]: ./struct.Pipeline.html#method.completed
+#[derive(Debug)]
+#[must_use = "streams do nothing unless polled"]
+pub struct Pipeline<C> {
+    connection: C,
+    pending: VecDeque<Request<Vec<u8>>>,
+    head_requests: VecDeque<bool>, // Whether each of the requests awaiting its response is `HEAD`
+    encoder: RequestEncoder<BodyEncoder<BytesEncoder>>,
+    head_decoder: ResponseDecoder<NoBodyDecoder>,
+    head: Option<Response<()>>,
+    head_size: usize,
+    max_header_size: usize,
+    decoder: BodyDecoder<RemainingBytesDecoder>,
+    requests: usize,
+    completed: usize,
+    closed: bool,
+}
+impl<C: AsMut<Connection>> Pipeline<C> {
+    /// Makes a new `Pipeline` instance that sends the given requests via `connection`.
+    pub fn new<I>(connection: C, requests: I) -> Self
+    where
+        I: IntoIterator<Item = Request<Vec<u8>>>,
+    {
+        let pending = requests.into_iter().collect::<VecDeque<_>>();
+        let head_requests = pending
+            .iter()
+            .map(|r| r.method().as_str() == "HEAD")
+            .collect();
+        Pipeline {
+            connection,
+            requests: pending.len(),
+            pending,
+            head_requests,
+            encoder: RequestEncoder::new(BodyEncoder::new(BytesEncoder::new())),
+            head_decoder: head_decoder(DEFAULT_MAX_HEADER_SIZE),
+            head: None,
+            head_size: 0,
+            max_header_size: DEFAULT_MAX_HEADER_SIZE,
+            decoder: BodyDecoder::new(RemainingBytesDecoder::new()),
+            completed: 0,
+            closed: false,
+        }
+    }
+
+    /// Sets the maximum size of the head (i.e., the status line and the header) of each response.
+    ///
+    /// If the head of a response exceeds the limit,
+    /// the stream will return an `ErrorKind::HeaderTooLarge` error and the connection will be closed.
+    /// The heads of the interim (`1xx`) responses preceding a final one are counted towards the limit of the final one.
+    ///
+    /// The default value is `64 * 1024`.
+    pub fn max_header_size(mut self, bytes: usize) -> Self {
+        self.head_decoder = head_decoder(bytes);
+        self.max_header_size = bytes;
+        self
+    }
+
+    /// Returns the number of the requests issued by this pipeline.
+    pub fn requests(&self) -> usize {
+        self.requests
+    }
+
+    /// Returns the number of the requests whose responses have been received.
+    pub fn completed(&self) -> usize {
+        self.completed
+    }
+
+    fn header_too_large(&mut self) -> Error {
+        self.connection.as_mut().set_state(ConnectionState::Closed);
+        track!(Error::from(ErrorKind::HeaderTooLarge.cause(format!(
+            "The head of the response exceeds the limit of {} bytes",
+            self.max_header_size
+        ))))
+    }
+
+    /// Decodes the head of the next response, skipping interim ones.
+    ///
+    /// Returns `true` if the head of a final response has been decoded.
+    fn decode_head(&mut self) -> Result<bool, Error> {
+        loop {
+            let buf = self.connection.as_mut().stream_mut().read_buf_mut();
+            let before = buf.len();
+            if let Err(e) = self.head_decoder.decode_from_read_buf(buf) {
+                if self.head_size + before > self.max_header_size {
+                    return Err(self.header_too_large());
+                }
+                return Err(track!(Error::from(e)));
+            }
+            self.head_size += before - buf.len();
+            if self.head_size > self.max_header_size {
+                return Err(self.header_too_large());
+            }
+            if !self.head_decoder.is_idle() {
+                return Ok(false);
+            }
+
+            let head = track!(self.head_decoder.finish_decoding())?;
+            if let Err(e) = check_framing(&head.header()) {
+                self.connection.as_mut().set_state(ConnectionState::Closed);
+                return Err(track!(e));
+            }
+            let status_code = head.status_code().as_u16();
+            if status_code < 200 && status_code != 101 {
+                // Interim responses precede the final one (RFC 7231, section 6.2),
+                // and their heads share the limit with it
+                continue;
+            }
+            self.head_size = 0;
+
+            // The body is empty regardless of the header (RFC 7230, section 3.3.3)
+            let head_request = self.head_requests.pop_front().unwrap_or(false);
+            self.decoder = BodyDecoder::new(RemainingBytesDecoder::new());
+            if head_request || is_bodiless(&head) {
+                track!(self.decoder.decode(&[][..], Eos::new(true)))?;
+            } else {
+                track!(self.decoder.initialize(&head.header()))?;
+            }
+            self.head = Some(head);
+            return Ok(true);
+        }
+    }
+
+    fn close_early(&mut self) -> Error {
+        self.connection.as_mut().set_state(ConnectionState::Closed);
+        track!(Error::from(ErrorKind::UnexpectedEos.cause(format!(
+            "The connection was closed after {} of {} pipelined responses",
+            self.completed, self.requests
+        ))))
+    }
+}
+impl<C: AsMut<Connection>> Stream for Pipeline<C> {
+    type Item = Response<Vec<u8>>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.completed == self.requests {
+            return Ok(Async::Ready(None));
+        }
+        if self.closed {
+            return Err(self.close_early());
+        }
+        loop {
+            let stream = self.connection.as_mut().stream_mut();
+
+            track!(stream.execute_io())?;
+            loop {
+                if self.encoder.is_idle() {
+                    if let Some(request) = self.pending.pop_front() {
+                        // `BodyEncoder` cannot be reused, so a new encoder is created for each request
+                        self.encoder = RequestEncoder::new(BodyEncoder::new(BytesEncoder::new()));
+                        track!(self.encoder.start_encoding(request))?;
+                    } else {
+                        break;
+                    }
+                }
+                track!(self.encoder.encode_to_write_buf(stream.write_buf_mut()))?;
+                if !self.encoder.is_idle() {
+                    break;
+                }
+            }
+            if self.head.is_none() {
+                track!(self.decode_head())?;
+            }
+
+            let stream = self.connection.as_mut().stream_mut();
+            if self.head.is_some() && !self.decoder.is_idle() {
+                track!(self.decoder.decode_from_read_buf(stream.read_buf_mut()))?;
+            }
+            if self.head.is_some() && self.decoder.is_idle() {
+                let body = track!(self.decoder.finish_decoding())?;
+                let head = self.head.take().expect("never fails");
+                let switched = head.status_code().as_u16() == 101;
+                let response = head.map_body(|()| body);
+                self.completed += 1;
+                self.connection.as_mut().increment_requests();
+                self.connection.as_mut().increment_completed_requests();
+
+                // After `101 Switching Protocols`, the connection no longer speaks HTTP/1.1
+                let keep_alive = !switched && is_keep_alive(&response);
+                if self.completed == self.requests {
+                    let state = if keep_alive {
+                        ConnectionState::Recyclable
+                    } else {
+                        ConnectionState::Closed
+                    };
+                    self.connection.as_mut().set_state(state);
+                } else if !keep_alive {
+                    self.closed = true;
+                }
+                return Ok(Async::Ready(Some(response)));
+            }
+
+            if stream.is_eos() {
+                return Err(self.close_early());
+            }
+            if stream.would_block() {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+/// Makes a decoder of response heads whose limits are loosened so that oversized heads are detected by `decode_head`.
+fn head_decoder(max_header_size: usize) -> ResponseDecoder<NoBodyDecoder> {
+    let options = DecodeOptions {
+        max_start_line_size: max_header_size.saturating_add(1),
+        max_header_size: max_header_size.saturating_add(1),
+    };
+    ResponseDecoder::with_options(NoBodyDecoder, options)
+}
+
+#[cfg(test)]
+mod tests {
+    use fibers_global;
+    use futures::{Future, Stream};
+    use httpcodec::{HeaderField, HttpVersion, Method, Request, RequestTarget};
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::thread;
+
+    use super::*;
+    use connection::{AcquireConnection, Oneshot};
+
+    #[test]
+    fn pipeline_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = Vec::new();
+            let mut chunk = [0; 1024];
+            while count_heads(&buf) < 3 {
+                let n = stream.read(&mut chunk).unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+            }
+            for body in &["foo", "bar", "baz"] {
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n{}",
+                    body
+                )
+                .unwrap();
+            }
+        });
+
+        let requests = vec![
+            request(addr, "/0"),
+            request(addr, "/1"),
+            request(addr, "/2"),
+        ];
+        let future = Oneshot
+            .acquire_connection(addr)
+            .and_then(|connection| Pipeline::new(connection, requests).collect());
+        let responses = fibers_global::execute(future).unwrap();
+        let bodies = responses
+            .iter()
+            .map(|r| r.body().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            bodies,
+            vec![b"foo".to_vec(), b"bar".to_vec(), b"baz".to_vec()]
+        );
+    }
+
+    #[test]
+    fn bodiless_responses_work() {
+        let addr = serve(
+            3,
+            "HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n\
+             HTTP/1.1 100 Continue\r\n\r\n\
+             HTTP/1.1 204 No Content\r\n\r\n\
+             HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo",
+        );
+        let requests = vec![
+            request_with_method(addr, "HEAD", "/0"),
+            request(addr, "/1"),
+            request(addr, "/2"),
+        ];
+        let future = Oneshot
+            .acquire_connection(addr)
+            .and_then(|connection| Pipeline::new(connection, requests).collect());
+        let responses = fibers_global::execute(future).unwrap();
+        let statuses = responses
+            .iter()
+            .map(|r| (r.status_code().as_u16(), r.body().clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            statuses,
+            vec![(200, Vec::new()), (204, Vec::new()), (200, b"foo".to_vec())]
+        );
+    }
+
+    #[test]
+    fn invalid_heads_work() {
+        // Too large
+        let addr = serve(
+            1,
+            "HTTP/1.1 200 OK\r\nFoo: 0123456789\r\nContent-Length: 0\r\n\r\n",
+        );
+        let future = Oneshot
+            .acquire_connection(addr)
+            .and_then(move |connection| {
+                Pipeline::new(connection, vec![request(addr, "/")])
+                    .max_header_size(32)
+                    .collect()
+            });
+        let e = fibers_global::execute(future).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::HeaderTooLarge);
+
+        // Too many interim responses
+        let addr = serve(
+            1,
+            "HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 100 Continue\r\n\r\n\
+             HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n",
+        );
+        let future = Oneshot
+            .acquire_connection(addr)
+            .and_then(move |connection| {
+                Pipeline::new(connection, vec![request(addr, "/")])
+                    .max_header_size(64)
+                    .collect()
+            });
+        let e = fibers_global::execute(future).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::HeaderTooLarge);
+
+        // Ambiguous framing
+        let addr = serve(
+            1,
+            "HTTP/1.1 200 OK\r\nContent-Length: 3\r\nContent-Length: 4\r\n\r\nfoo",
+        );
+        let future = Oneshot
+            .acquire_connection(addr)
+            .and_then(move |connection| {
+                Pipeline::new(connection, vec![request(addr, "/")]).collect()
+            });
+        let e = fibers_global::execute(future).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+    }
+
+    /// Replies with `responses` after receiving `n` requests on a connection.
+    fn serve(n: usize, responses: &'static str) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = Vec::new();
+            let mut chunk = [0; 1024];
+            while count_heads(&buf) < n {
+                let n = stream.read(&mut chunk).unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+            }
+            stream.write_all(responses.as_bytes()).unwrap();
+            let _ = stream.read(&mut chunk);
+        });
+        addr
+    }
+
+    fn request(addr: SocketAddr, path: &'static str) -> Request<Vec<u8>> {
+        request_with_method(addr, "GET", path)
+    }
+
+    fn request_with_method(addr: SocketAddr, method: &str, path: &'static str) -> Request<Vec<u8>> {
+        let mut request = Request::new(
+            Method::new(method).unwrap(),
+            RequestTarget::new(path).unwrap(),
+            HttpVersion::V1_1,
+            Vec::new(),
+        );
+        let host = addr.to_string();
+        request
+            .header_mut()
+            .add_field(HeaderField::new("Host", &host).unwrap());
+        request
+    }
+
+    fn count_heads(buf: &[u8]) -> usize {
+        buf.windows(4).filter(|w| *w == b"\r\n\r\n").count()
+    }
+}
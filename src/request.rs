@@ -1,20 +1,51 @@
-use bytecodec::bytes::{BytesEncoder, RemainingBytesDecoder};
+use bytecodec::bytes::{BytesEncoder, RemainingBytesDecoder, Utf8Encoder};
 use bytecodec::io::{IoDecodeExt, IoEncodeExt};
-use bytecodec::{Decode, Encode};
-use fibers::time::timer::TimerExt;
-use futures::future::{failed, Either};
+use bytecodec::{self, ByteCount, Decode, Encode, EncodeExt, Eos};
+use fibers::sync::oneshot;
+use fibers::time::timer::{self, Timeout, TimerExt};
+use futures::future::{self, failed, Either};
 use futures::{Async, Future, Poll};
 use httpcodec::{
-    BodyDecode, BodyDecoder, BodyEncoder, HeaderField, HttpVersion, Method, NoBodyDecoder, Request,
-    RequestEncoder, RequestTarget, Response, ResponseDecoder,
+    BodyDecode, BodyDecoder, BodyEncode, BodyEncoder, DecodeOptions, Header, HeaderField,
+    HttpVersion, Method, NoBodyDecoder, Request, RequestEncoder, RequestTarget, Response,
+    ResponseDecoder,
 };
 use std::borrow::Cow;
-use std::time::Duration;
+use std::cmp;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use trackable::error::ErrorKindExt;
-use url::{Position, Url};
+use url::{Host, Position, Url};
 
+#[cfg(feature = "bytes")]
+use body::BytesBodyDecoder;
+use body::ContentTypeDecoder;
 use connection::{AcquireConnection, Connection, ConnectionState};
-use {Error, ErrorKind, Result};
+use date;
+use event::{EventHook, Progress, ProgressHook, ProgressTracker, RequestEvent};
+use gzip;
+use limiter::{AcquirePermit, Limiter, Permit};
+use metrics::{ClientMetrics, InFlight};
+use middleware::{MiddlewareChain, MiddlewareFuture};
+use proxy::Proxy;
+use rng::Rng;
+use trace::{self, RequestSpan};
+use {Error, ErrorKind, ResponseExt, Result, StatusClass, StatusError, TimeoutPhase};
+
+const MAX_CAPTURE_SIZE: usize = 64 * 1024;
+pub(crate) const DEFAULT_MAX_HEADER_SIZE: usize = 64 * 1024;
+const DEFAULT_MAX_HEADER_FIELDS: usize = 128;
+const DEFAULT_MAX_RETRY_AFTER_SECS: u64 = 60;
+const DEFAULT_BACKOFF_BASE: Duration = Duration::from_millis(100);
+const DEFAULT_BACKOFF_MAX: Duration = Duration::from_secs(10);
+const DEFAULT_DRAIN_THRESHOLD: u64 = 4 * 1024;
+const DEFAULT_RETRY_STATUSES: [u16; 2] = [429, 503];
+const OCTET_STREAM: &str = "application/octet-stream";
+const TEXT_PLAIN: &str = "text/plain; charset=utf-8";
 
 /// HTTP request builder.
 ///
@@ -22,123 +53,399 @@ use {Error, ErrorKind, Result};
 ///
 /// [`Client::request`]: ./struct.Client.html#method.request
 #[derive(Debug)]
-pub struct RequestBuilder<'a, C: 'a, E = BytesEncoder, D = RemainingBytesDecoder> {
+pub struct RequestBuilder<'a, C: 'a, E = BytesEncoder, D = BodyDecoder<RemainingBytesDecoder>> {
     connection_provider: &'a mut C,
-    url: &'a Url,
+    url: Cow<'a, Url>,
     header_fields: Vec<(Cow<'a, str>, Cow<'a, str>)>,
     encoder: E,
+    content_type: Option<&'static str>, // The value of `Content-Type` header implied by the encoder
     decoder: D,
-    timeout: Option<Duration>,
+    options: RequestOptions,
 }
 impl<'a, C: 'a> RequestBuilder<'a, C> {
-    pub(crate) fn new(connection_provider: &'a mut C, url: &'a Url) -> Self {
+    pub(crate) fn new(
+        connection_provider: &'a mut C,
+        url: Cow<'a, Url>,
+        mut options: RequestOptions,
+    ) -> Self {
+        if options
+            .proxy
+            .as_ref()
+            .is_some_and(|proxy| proxy.is_bypassed(&url))
+        {
+            options.proxy = None;
+        }
         RequestBuilder {
             connection_provider,
             url,
             header_fields: Vec::new(),
             encoder: BytesEncoder::default(),
-            decoder: RemainingBytesDecoder::default(),
-            timeout: None,
+            content_type: Some(OCTET_STREAM),
+            decoder: BodyDecoder::new(RemainingBytesDecoder::default()),
+            options,
+        }
+    }
+}
+impl<'a, C: 'a> RequestBuilder<'a, C>
+where
+    C: AcquireConnection + Clone + Send + 'static,
+    C::Connection: Send + 'static,
+    C::Future: Send + 'static,
+{
+    /// Executes the request of the given method through the middlewares registered via [`Client::middleware`].
+    ///
+    /// The request is built as [`build`] does, and then passed to the first middleware.
+    /// The last middleware of the chain sends the request to the server,
+    /// with the settings of the builder applied to each sending.
+    /// The exception is [`timeout`] (and [`deadline`]), which bounds the whole execution:
+    /// each sending is given only the remaining time (see [`Next::remaining`]),
+    /// and fails immediately with an `ErrorKind::Timeout` error if no time remains.
+    /// The request is never retried by the builder since the middlewares take charge of it.
+    ///
+    /// The other methods that execute requests (e.g., [`get`]) do not run the middlewares.
+    ///
+    /// [`Client::middleware`]: ./struct.Client.html#method.middleware
+    /// [`build`]: #method.build
+    /// [`timeout`]: #method.timeout
+    /// [`deadline`]: #method.deadline
+    /// [`Next::remaining`]: ./middleware/struct.Next.html#method.remaining
+    /// [`get`]: #method.get
+    pub fn call(self, method: &str, body: Vec<u8>) -> MiddlewareFuture {
+        let mut request = match track!(self.build(method, body)) {
+            Err(e) => return Box::new(failed(e)),
+            Ok(request) => request,
+        };
+        if let Some(min_size) = self.compression_threshold(method) {
+            compress_body(&mut request, min_size);
         }
+        let chain = self.options.middleware.clone();
+        let connection_provider = self.connection_provider.clone();
+        let url = self.url.into_owned();
+        let mut options = self.options;
+
+        // As with the other methods, the remaining time is determined when the future is polled for the first time
+        Box::new(future::lazy(move || {
+            let deadline = earliest_deadline(Instant::now(), options.timeout, options.deadline);
+            options.timeout = None;
+            options.deadline = deadline;
+            let state = Mutex::new((connection_provider, url, options));
+            chain.run(request, deadline, move |request| -> MiddlewareFuture {
+                let (mut connection_provider, url, options) = {
+                    let state = state.lock().unwrap_or_else(|e| e.into_inner());
+                    (state.0.clone(), state.1.clone(), state.2.clone())
+                };
+                let builder =
+                    RequestBuilder::new(&mut connection_provider, Cow::Owned(url), options);
+                let method = request.method().as_str().to_owned();
+                if method == "HEAD" {
+                    let future = builder
+                        .dispatch(&method, Ok(request), None, BodyEncoder::new, |_| {
+                            NoBodyDecoder
+                        })
+                        .map(|response| response.map_body(|()| Vec::new()));
+                    Box::new(future)
+                } else {
+                    Box::new(builder.dispatch(&method, Ok(request), None, BodyEncoder::new, |d| d))
+                }
+            })
+        }))
     }
 }
 impl<'a, C: 'a, E, D> RequestBuilder<'a, C, E, D>
 where
-    C: AcquireConnection,
+    C: AcquireConnection + Clone,
     E: Encode,
-    D: Decode,
+    D: BodyDecode,
 {
     /// Executes `GET` request.
-    pub fn get(mut self) -> impl Future<Item = Response<D::Item>, Error = Error> {
-        let timeout = self.timeout;
-        let f = move || {
-            let request = track!(self.build_request("GET", Vec::new()))?;
-            let connect = track!(self.connect())?;
-            let decoder = ResponseDecoder::new(BodyDecoder::new(self.decoder));
-            let mut encoder = RequestEncoder::new(BodyEncoder::new(BytesEncoder::new()));
-            track!(encoder.start_encoding(request))?;
-            Ok(connect.and_then(move |connection| Execute {
+    pub fn get(
+        self,
+    ) -> ResponseFuture<impl Future<Item = (Response<D::Item>, SocketAddr), Error = Error>> {
+        self.send(
+            "GET",
+            Vec::new(),
+            Some(|| (BodyEncoder::new(BytesEncoder::new()), Vec::new())),
+            |_| BodyEncoder::new(BytesEncoder::new()),
+            |d| d,
+        )
+    }
+
+    /// Executes `GET` request that asks the server to switch to the given protocol (e.g., `"websocket"`).
+    ///
+    /// `Connection: Upgrade` and `Upgrade: {protocol}` headers are added unless they are given explicitly.
+    /// Other headers required by the protocol (e.g., `Sec-WebSocket-Key`) should be added via [`header_field`].
+    ///
+    /// If the server replies with `101 Switching Protocols`, the resulting [`Upgraded`] holds the response
+    /// and the connection, over which the caller can continue with the new protocol.
+    /// The connection is never returned to the pool.
+    /// Otherwise, the request fails with an `ErrorKind::StatusError` error
+    /// whose cause holds the status code and the body of the response.
+    ///
+    /// The request is never retried, and the encoder and the decoder set to the builder are unused.
+    ///
+    /// [`header_field`]: #method.header_field
+    /// [`Upgraded`]: ./struct.Upgraded.html
+    pub fn upgrade(
+        mut self,
+        protocol: &str,
+    ) -> impl Future<Item = Upgraded<C::Connection>, Error = Error> {
+        if !self.has_header_field("Connection") {
+            self.header_fields
+                .push((Cow::Borrowed("Connection"), Cow::Borrowed("Upgrade")));
+        }
+        if !self.has_header_field("Upgrade") {
+            self.header_fields
+                .push((Cow::Borrowed("Upgrade"), Cow::Owned(protocol.to_owned())));
+        }
+        self.hand_over("GET")
+            .map(|(response, connection)| Upgraded {
+                response,
                 connection,
-                encoder,
-                decoder,
-            }))
-        };
-        track_err!(Self::execute(f(), timeout))
+            })
     }
 
-    /// Executes `HEAD` request.
-    pub fn head(mut self) -> impl Future<Item = Response<()>, Error = Error> {
-        let timeout = self.timeout;
-        let mut f = move || {
-            let request = track!(self.build_request("HEAD", Vec::new()))?;
-            let connect = track!(self.connect())?;
-            let decoder = ResponseDecoder::new(NoBodyDecoder);
-            let mut encoder = RequestEncoder::new(BodyEncoder::new(BytesEncoder::new()));
-            track!(encoder.start_encoding(request))?;
-            Ok(connect.and_then(move |connection| Execute {
+    /// Executes `CONNECT` request that asks the server (usually a proxy) to open a tunnel to the host and the port of the URL.
+    ///
+    /// The request-target is in the authority-form (e.g., `CONNECT example.com:443 HTTP/1.1` for `http://example.com:443/`).
+    ///
+    /// If the server replies with a `2xx` status, the resulting [`Tunnel`] holds the response
+    /// and the connection, over which the caller can speak any protocol (e.g., TLS) with the destination.
+    /// The connection is never returned to the pool.
+    /// Otherwise, the request fails with an `ErrorKind::StatusError` error
+    /// whose cause holds the status code and the body of the response.
+    ///
+    /// The request is never retried, and the encoder and the decoder set to the builder are unused.
+    ///
+    /// [`Tunnel`]: ./struct.Tunnel.html
+    pub fn connect_tunnel(self) -> impl Future<Item = Tunnel<C::Connection>, Error = Error> {
+        self.hand_over("CONNECT")
+            .map(|(response, connection)| Tunnel {
+                response,
                 connection,
-                encoder,
-                decoder,
-            }))
-        };
-        track_err!(Self::execute(f(), timeout))
+            })
     }
 
-    /// Executes `DELETE` request.
-    pub fn delete(mut self) -> impl Future<Item = Response<D::Item>, Error = Error> {
-        let timeout = self.timeout;
+    /// Executes a bodiless request whose connection is handed over to the caller on success
+    /// (i.e., `101 Switching Protocols` for upgrades and `2xx` for `CONNECT`).
+    fn hand_over(
+        self,
+        method: &'static str,
+    ) -> impl Future<Item = (Response<()>, C::Connection), Error = Error> {
+        let timeout = self.options.timeout;
+        let deadline = self.options.deadline;
+        let hook = self.options.hook.clone();
+        let metrics = self.options.metrics.method(method).clone();
+        metrics.requests.increment();
+        let span = RequestSpan::new(method, self.url.host_str().unwrap_or(""));
         let f = move || {
-            let request = track!(self.build_request("DELETE", Vec::new()))?;
-            let connect = track!(self.connect())?;
-            let decoder = ResponseDecoder::new(BodyDecoder::new(self.decoder));
-            let mut encoder = RequestEncoder::new(BodyEncoder::new(BytesEncoder::new()));
-            track!(encoder.start_encoding(request))?;
-            Ok(connect.and_then(move |connection| Execute {
-                connection,
-                encoder,
-                decoder,
+            let request = track!(self.build_request(method, Vec::new()))?;
+            let mut connector = track!(self.connector())?;
+            let options = self.options;
+            Ok(connector.connect().and_then(move |connection| {
+                ExecuteHandOver(Some(Execute::new(
+                    connection,
+                    request,
+                    NoBodyEncoder::new(),
+                    BodyDecoder::new(RemainingBytesDecoder::new()),
+                    false,
+                    &options,
+                )))
             }))
         };
-        track_err!(Self::execute(f(), timeout))
+        let future = span
+            .clone()
+            .instrument(Self::execute(f(), timeout, deadline).then(move |result| {
+                match result {
+                    Ok((ref response, _)) => {
+                        metrics.succeeded_requests.increment();
+                        span.finish(Some(response.status_code().as_u16()));
+                    }
+                    Err(ref e) => {
+                        metrics.fail(*e.kind());
+                        span.finish(None);
+                        hook.emit(&RequestEvent::Failed { error: e });
+                    }
+                }
+                result
+            }));
+        track_err!(future)
     }
 
-    /// Executes `PUT` request.
-    pub fn put(mut self, body: E::Item) -> impl Future<Item = Response<D::Item>, Error = Error> {
-        let timeout = self.timeout;
-        let f = move || {
-            let request = track!(self.build_request("PUT", body))?;
-            let connect = track!(self.connect())?;
-            let decoder = ResponseDecoder::new(BodyDecoder::new(self.decoder));
-            let mut encoder = RequestEncoder::new(BodyEncoder::new(self.encoder));
-            track!(encoder.start_encoding(request))?;
-            Ok(connect.and_then(move |connection| Execute {
-                connection,
-                encoder,
-                decoder,
-            }))
+    /// Builds the request that would be sent by the method named `method` (e.g., `"POST"`), without sending it.
+    ///
+    /// The returned request contains the header fields added implicitly by the builder
+    /// (e.g., `Host`, the default headers of the client and the `Content-Type` of [`auto_content_type`]),
+    /// so it can be inspected or signed and then sent via [`send_request`].
+    /// Note that `Content-Length` (or `Transfer-Encoding`) header is added by the encoder when the request is sent.
+    ///
+    /// If `method` is not a valid method name, an `ErrorKind::InvalidInput` error will be returned.
+    ///
+    /// [`auto_content_type`]: #method.auto_content_type
+    /// [`send_request`]: #method.send_request
+    pub fn build(&self, method: &str, body: E::Item) -> Result<Request<E::Item>> {
+        track_assert!(
+            Method::new(method).is_ok(),
+            ErrorKind::InvalidInput,
+            "Invalid method: {:?}",
+            method
+        );
+        track!(self.build_request(method, body))
+    }
+
+    /// Makes a template that holds the URL, the header fields and the settings (e.g., the timeout) of the builder.
+    ///
+    /// Unlike the builder, the template does not borrow the client and can be cloned,
+    /// so it is useful for issuing the same request repeatedly or to multiple hosts
+    /// (see [`Client::request_from`] for making builders from the template).
+    ///
+    /// The encoder and the decoder are not included since they are not clonable in general.
+    /// Builders made from the template use the default ones, so custom ones need to be set again.
+    /// The writer set by [`tee`] and the handler set by [`on_progress`] are shared among the builders.
+    ///
+    /// [`Client::request_from`]: ./struct.Client.html#method.request_from
+    /// [`tee`]: #method.tee
+    /// [`on_progress`]: #method.on_progress
+    pub fn to_template(&self) -> RequestTemplate {
+        RequestTemplate {
+            url: self.url.clone().into_owned(),
+            header_fields: self
+                .header_fields
+                .iter()
+                .map(|(n, v)| (n.clone().into_owned(), v.clone().into_owned()))
+                .collect(),
+            options: self.options.clone(),
+        }
+    }
+
+    /// Sends the given request as it is.
+    ///
+    /// This is useful for sending requests constructed by other tools.
+    /// The URL of the builder is used only for determining the server to connect to (or the proxy, if any),
+    /// and the header fields set to the builder (including the default ones of the client) are not added to the request.
+    /// The body of the request is encoded by the encoder of the builder (with `Content-Length` header or
+    /// chunked transfer encoding, depending on the encoder), and the response is decoded by the decoder of the builder.
+    ///
+    /// The request is never retried since its body cannot be re-created.
+    /// `HEAD` requests are rejected with an `ErrorKind::InvalidInput` error (use [`head`] instead).
+    ///
+    /// [`head`]: #method.head
+    pub fn send_request(
+        self,
+        request: Request<E::Item>,
+    ) -> ResponseFuture<impl Future<Item = (Response<D::Item>, SocketAddr), Error = Error>> {
+        let method = request.method().as_str().to_owned();
+        let request = if method == "HEAD" {
+            Err(track!(Error::from(ErrorKind::InvalidInput.cause(
+                "Use `RequestBuilder::head` for sending HEAD requests"
+            ))))
+        } else {
+            Ok(request)
         };
-        track_err!(Self::execute(f(), timeout))
+        self.dispatch(&method, request, None, BodyEncoder::new, |d| d)
+    }
+
+    /// Executes `HEAD` request.
+    ///
+    /// The size of the resource declared by the server can be retrieved via [`ResponseExt::content_length`].
+    ///
+    /// [`ResponseExt::content_length`]: ./trait.ResponseExt.html#tymethod.content_length
+    pub fn head(
+        self,
+    ) -> ResponseFuture<impl Future<Item = (Response<()>, SocketAddr), Error = Error>> {
+        self.send(
+            "HEAD",
+            Vec::new(),
+            Some(|| (BodyEncoder::new(BytesEncoder::new()), Vec::new())),
+            |_| BodyEncoder::new(BytesEncoder::new()),
+            |_| NoBodyDecoder,
+        )
+    }
+
+    /// Executes `DELETE` request.
+    pub fn delete(
+        self,
+    ) -> ResponseFuture<impl Future<Item = (Response<D::Item>, SocketAddr), Error = Error>> {
+        self.send(
+            "DELETE",
+            Vec::new(),
+            Some(|| (BodyEncoder::new(BytesEncoder::new()), Vec::new())),
+            |_| BodyEncoder::new(BytesEncoder::new()),
+            |d| d,
+        )
+    }
+
+    /// Executes `PUT` request.
+    ///
+    /// See [`auto_content_type`] for the `Content-Type` header added implicitly,
+    /// and [`compress_request`] for the compression of the body.
+    ///
+    /// [`auto_content_type`]: #method.auto_content_type
+    /// [`compress_request`]: #method.compress_request
+    pub fn put(
+        self,
+        body: E::Item,
+    ) -> ResponseFuture<impl Future<Item = (Response<D::Item>, SocketAddr), Error = Error>> {
+        self.send_body("PUT", body)
     }
 
     /// Executes `POST` request.
-    pub fn post(mut self, body: E::Item) -> impl Future<Item = Response<D::Item>, Error = Error> {
-        let timeout = self.timeout;
-        let f = move || {
-            let request = track!(self.build_request("POST", body))?;
-            let connect = track!(self.connect())?;
-            let decoder = ResponseDecoder::new(BodyDecoder::new(self.decoder));
-            let mut encoder = RequestEncoder::new(BodyEncoder::new(self.encoder));
-            track!(encoder.start_encoding(request))?;
-            Ok(connect.and_then(move |connection| Execute {
-                connection,
-                encoder,
-                decoder,
-            }))
-        };
-        track_err!(Self::execute(f(), timeout))
+    ///
+    /// See [`auto_content_type`] for the `Content-Type` header added implicitly,
+    /// and [`compress_request`] for the compression of the body.
+    ///
+    /// [`auto_content_type`]: #method.auto_content_type
+    /// [`compress_request`]: #method.compress_request
+    pub fn post(
+        self,
+        body: E::Item,
+    ) -> ResponseFuture<impl Future<Item = (Response<D::Item>, SocketAddr), Error = Error>> {
+        self.send_body("POST", body)
+    }
+
+    /// Executes `PUT` request without a body.
+    ///
+    /// The request has `Content-Length: 0` header, and `Content-Type` header is not added implicitly.
+    /// Unlike [`put`], the request can be retried (see [`max_retries`]) since the body is always empty.
+    ///
+    /// [`put`]: #method.put
+    /// [`max_retries`]: #method.max_retries
+    pub fn put_empty(
+        self,
+    ) -> ResponseFuture<impl Future<Item = (Response<D::Item>, SocketAddr), Error = Error>> {
+        self.send_empty("PUT")
+    }
+
+    /// Executes `POST` request without a body.
+    ///
+    /// The request has `Content-Length: 0` header, and `Content-Type` header is not added implicitly.
+    /// Unlike [`post`], the request can be retried (see [`max_retries`]) since the body is always empty.
+    ///
+    /// [`post`]: #method.post
+    /// [`max_retries`]: #method.max_retries
+    pub fn post_empty(
+        self,
+    ) -> ResponseFuture<impl Future<Item = (Response<D::Item>, SocketAddr), Error = Error>> {
+        self.send_empty("POST")
+    }
+
+    /// Executes `TRACE` request.
+    ///
+    /// The request is sent without a body (and without `Content-Length` header).
+    pub fn trace(
+        self,
+    ) -> ResponseFuture<impl Future<Item = (Response<D::Item>, SocketAddr), Error = Error>> {
+        self.send(
+            "TRACE",
+            Vec::new(),
+            Some(|| (NoBodyEncoder::new(), Vec::new())),
+            |_| NoBodyEncoder::new(),
+            |d| d,
+        )
     }
 
     /// Adds a field to the tail of the HTTP header of the request.
+    ///
+    /// If a `Host` field is added via this method, it overrides the one derived from the URL.
     pub fn header_field<N, V>(mut self, name: N, value: V) -> Self
     where
         N: Into<Cow<'a, str>>,
@@ -148,158 +455,4224 @@ where
         self
     }
 
+    /// Adds the fields to the tail of the HTTP header of the request, in the order yielded by the iterator.
+    ///
+    /// This is equivalent to calling [`header_field`] for each of the fields.
+    ///
+    /// [`header_field`]: #method.header_field
+    pub fn headers<I, N, V>(mut self, fields: I) -> Self
+    where
+        I: IntoIterator<Item = (N, V)>,
+        N: Into<Cow<'a, str>>,
+        V: Into<Cow<'a, str>>,
+    {
+        self.header_fields.extend(
+            fields
+                .into_iter()
+                .map(|(name, value)| (name.into(), value.into())),
+        );
+        self
+    }
+
+    /// Adds `If-None-Match` field to the HTTP header of the request.
+    ///
+    /// `etag` is quoted unless it is already an entity-tag (e.g., `"foo"` or `W/"foo"`) or `*`.
+    /// If the server replies with `304 Not Modified`, the response is returned as usual (with an empty body).
+    pub fn if_none_match<V>(self, etag: V) -> Self
+    where
+        V: Into<Cow<'a, str>>,
+    {
+        let etag = etag.into();
+        let is_entity_tag = etag == "*"
+            || (etag.len() > 1
+                && etag.ends_with('"')
+                && (etag.starts_with('"') || etag.starts_with("W/\"")));
+        let value = if is_entity_tag {
+            etag
+        } else {
+            Cow::Owned(format!("\"{}\"", etag))
+        };
+        self.header_field("If-None-Match", value)
+    }
+
+    /// Adds `If-Modified-Since` field, which is formatted as an HTTP-date, to the HTTP header of the request.
+    ///
+    /// If the server replies with `304 Not Modified`, the response is returned as usual (with an empty body).
+    pub fn if_modified_since(self, time: SystemTime) -> Self {
+        self.header_field("If-Modified-Since", date::format_http_date(time))
+    }
+
+    /// Registers a handler that is invoked with the progress of the transfer.
+    ///
+    /// The handler is called after each I/O step that sends or receives bytes
+    /// (see [`Progress`] for the reported counts).
+    /// Like [`Client::on_event`], it is called synchronously and should not block.
+    /// If the request is retried, the counts restart from zero.
+    ///
+    /// [`Progress`]: ./struct.Progress.html
+    /// [`Client::on_event`]: ./struct.Client.html#method.on_event
+    pub fn on_progress<F>(mut self, f: F) -> Self
+    where
+        F: Fn(Progress) + Send + Sync + 'static,
+    {
+        self.options.progress = ProgressHook::new(f);
+        self
+    }
+
     /// Sets the timeout of the request.
     pub fn timeout(mut self, timeout: Duration) -> Self {
-        self.timeout = Some(timeout);
+        self.options.timeout = Some(timeout);
         self
     }
 
-    /// Sets the encoder for serializing the body of the HTTP request.
+    /// Suppresses the `Host` header derived from the URL.
     ///
-    /// This is only meaningful at the case the method of the request is `PUT` or `POST`.
-    pub fn encoder<T>(self, encoder: T) -> RequestBuilder<'a, C, T, D> {
-        RequestBuilder {
-            connection_provider: self.connection_provider,
-            url: self.url,
-            header_fields: self.header_fields,
-            encoder,
-            decoder: self.decoder,
-            timeout: self.timeout,
-        }
+    /// This is useful for sending HTTP/1.0 style requests.
+    /// Note that a `Host` field added explicitly via [`header_field`] is still sent.
+    ///
+    /// [`header_field`]: #method.header_field
+    pub fn no_host(mut self) -> Self {
+        self.options.host_header = false;
+        self
     }
 
-    /// Sets the decoder for deserializing the body of the HTTP response replied from the server.
+    /// Asks the server to close the connection after responding.
     ///
-    /// The decoder is unused if the method of the request is `HEAD`.
-    pub fn decoder<T>(self, decoder: T) -> RequestBuilder<'a, C, E, T> {
-        RequestBuilder {
-            connection_provider: self.connection_provider,
-            url: self.url,
-            header_fields: self.header_fields,
-            encoder: self.encoder,
-            decoder,
-            timeout: self.timeout,
-        }
+    /// This adds `Connection: close` header to the request (unless `Connection` header is given explicitly),
+    /// and the connection is closed after the response is received regardless of the keep-alive signaling of the response.
+    /// It is useful for one-off requests to servers that will not be accessed again.
+    ///
+    /// By default, the connection is kept alive if the response allows it.
+    pub fn close_connection(mut self) -> Self {
+        self.options.close_connection = true;
+        self
     }
 
-    fn build_request<T>(&self, method: &str, body: T) -> Result<Request<T>> {
-        track_assert_eq!(self.url.scheme(), "http", ErrorKind::InvalidInput; self.url);
+    /// Sets the form of the request-target in the request line.
+    ///
+    /// By default, `CONNECT` requests use [`TargetForm::Authority`],
+    /// requests sent through a proxy use [`TargetForm::Absolute`],
+    /// and the others use [`TargetForm::Origin`].
+    /// The form is not checked against the method, so that unusual requests can be sent to test servers.
+    ///
+    /// [`TargetForm::Authority`]: ./enum.TargetForm.html#variant.Authority
+    /// [`TargetForm::Absolute`]: ./enum.TargetForm.html#variant.Absolute
+    /// [`TargetForm::Origin`]: ./enum.TargetForm.html#variant.Origin
+    pub fn target_form(mut self, form: TargetForm) -> Self {
+        self.options.target_form = Some(form);
+        self
+    }
 
-        let method = unsafe { Method::new_unchecked(method) };
-        let target = track!(RequestTarget::new(&self.url[Position::BeforePath..]); self.url)?;
-        let mut request = Request::new(method, target, HttpVersion::V1_1, body);
+    /// Requests only the given byte range of the resource via `Range` header (RFC 7233).
+    ///
+    /// `start` and `end` are the positions of the first and the last bytes (inclusive).
+    /// If `end` is `None`, the range extends to the end of the resource (e.g., for resuming a download).
+    ///
+    /// The range declared by the server can be retrieved via [`ResponseExt::content_range`].
+    /// If the server returns a `2xx` response other than `206 Partial Content` (i.e., it ignored the range),
+    /// or the `Content-Range` of the response does not match the requested range,
+    /// the request fails with an `ErrorKind::InvalidInput` error.
+    /// Other responses (e.g., `416 Range Not Satisfiable`) are returned as usual.
+    ///
+    /// If `end` is less than `start`, the request fails with an `ErrorKind::InvalidInput` error.
+    ///
+    /// [`ResponseExt::content_range`]: ./trait.ResponseExt.html#tymethod.content_range
+    pub fn range(mut self, start: u64, end: Option<u64>) -> Self {
+        self.options.range = Some((start, end));
+        self
+    }
 
-        let mut has_host = false;
-        for (name, value) in &self.header_fields {
-            if !has_host && name.eq_ignore_ascii_case("Host") {
-                has_host = true;
-            }
-            let field = track!(HeaderField::new(&name, &value); name, value)?;
-            request.header_mut().add_field(field);
-        }
-        if !has_host {
-            let host = &self.url[Position::BeforeHost..Position::AfterPort];
-            let field = track!(HeaderField::new("Host", host); host)?;
-            request.header_mut().add_field(field);
-        }
-        Ok(request)
+    /// Sets the deadline of the request.
+    ///
+    /// This is useful for sharing an overall time budget among multiple operations.
+    /// The remaining time is calculated when the request future is polled for the first time,
+    /// and the request fails immediately with an `ErrorKind::Timeout` error if the deadline has already passed.
+    ///
+    /// If both [`timeout`] and `deadline` are specified, the earlier one is used.
+    ///
+    /// [`timeout`]: #method.timeout
+    pub fn deadline(mut self, deadline: Instant) -> Self {
+        self.options.deadline = Some(deadline);
+        self
     }
 
-    fn connect(&mut self) -> Result<C::Future> {
-        let url = self.url;
-        let server_addrs = track!(url.socket_addrs(|| None).map_err(Error::from); url)?;
-        let server_addr =
-            track_assert_some!(server_addrs.get(0).copied(), ErrorKind::InvalidInput; url);
-        Ok(self.connection_provider.acquire_connection(server_addr))
+    /// Sets whether to add `Content-Type` header to `PUT` and `POST` requests implicitly.
+    ///
+    /// If enabled, the value of the header is determined by the encoder as follows:
+    /// - The default encoder (`BytesEncoder`): `application/octet-stream`
+    /// - [`text_encoder`] method: `text/plain; charset=utf-8`
+    /// - [`bytes_encoder`] method: `application/octet-stream`
+    ///
+    /// The header is never added if the caller has specified it via [`header_field`] method
+    /// or the encoder has been set by [`encoder`] method.
+    ///
+    /// The default value is `true`.
+    ///
+    /// [`text_encoder`]: #method.text_encoder
+    /// [`bytes_encoder`]: #method.bytes_encoder
+    /// [`header_field`]: #method.header_field
+    /// [`encoder`]: #method.encoder
+    pub fn auto_content_type(mut self, enabled: bool) -> Self {
+        self.options.auto_content_type = enabled;
+        self
     }
 
-    fn execute<F>(
-        future: Result<F>,
-        timeout: Option<Duration>,
-    ) -> impl Future<Item = F::Item, Error = Error>
-    where
-        F: Future<Error = Error>,
-    {
-        match future {
-            Err(e) => Either::B(failed(track!(e))),
-            Ok(future) => {
-                if let Some(timeout) = timeout {
-                    let future = future.timeout_after(timeout).map_err(|e| {
-                        e.unwrap_or_else(|| track!(Error::from(ErrorKind::Timeout.error())))
-                    });
-                    Either::A(Either::A(future))
-                } else {
-                    Either::A(Either::B(future))
-                }
-            }
-        }
+    /// Compresses the body of the request with gzip if it is at least `min_size` bytes.
+    ///
+    /// The compressed body is sent with `Content-Encoding: gzip` header.
+    /// This applies to the requests issued by [`put`] and [`post`], and the `PUT`, `POST` and `PATCH` requests issued by [`call`].
+    /// The body is sent as it is if the caller has specified `Content-Encoding` header
+    /// or the compression does not reduce the size.
+    ///
+    /// Note that the server must accept gzip-encoded requests.
+    /// And the body is encoded into a buffer in advance to determine its size.
+    ///
+    /// By default, request bodies are never compressed.
+    ///
+    /// [`put`]: #method.put
+    /// [`post`]: #method.post
+    /// [`call`]: #method.call
+    pub fn compress_request(mut self, min_size: usize) -> Self {
+        self.options.compress_request = Some(min_size);
+        self
     }
-}
 
-#[derive(Debug)]
-struct Execute<C, E, D> {
-    connection: C,
-    encoder: E,
-    decoder: ResponseDecoder<D>,
-}
-impl<C, E, D> Future for Execute<C, E, D>
-where
-    C: AsMut<Connection>,
-    E: Encode,
-    D: BodyDecode,
-{
-    type Item = Response<D::Item>;
-    type Error = Error;
+    /// Makes the request fail if the status code of the response is `4xx` or `5xx`.
+    ///
+    /// In that case, the resulting error has the kind `ErrorKind::StatusError`
+    /// and its cause is a [`StatusError`] that holds the status code and the body of the response.
+    /// The body is not passed to the decoder of the request; it is drained into the error
+    /// if the size declared by `Content-Length` header is at most [`drain_threshold`].
+    /// Otherwise (or if the size is unknown), the body is left empty and the connection is closed.
+    ///
+    /// The default value is `false`.
+    ///
+    /// [`StatusError`]: ./struct.StatusError.html
+    /// [`drain_threshold`]: #method.drain_threshold
+    pub fn error_for_status(mut self, enabled: bool) -> Self {
+        self.options.error_for_status = enabled;
+        self
+    }
 
-    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        let mut do_close = false;
-        let mut response = None;
-        loop {
-            let stream = self.connection.as_mut().stream_mut();
+    /// Sets the address of the server to which the request is sent.
+    ///
+    /// If this is specified, the host name of the URL is not resolved
+    /// (but it is still used as the value of `Host` header).
+    pub fn connect_addr(mut self, addr: SocketAddr) -> Self {
+        self.options.connect_addr = Some(addr);
+        self
+    }
 
-            track!(stream.execute_io())?;
-            track!(self.encoder.encode_to_write_buf(stream.write_buf_mut()))?;
-            track!(self.decoder.decode_from_read_buf(stream.read_buf_mut()))?;
-            if self.decoder.is_idle() {
-                if !self.encoder.is_idle() {
-                    do_close = true;
-                }
+    /// Sets the maximum size of the head (i.e., the status line and the header) of the response.
+    ///
+    /// If the head of the response exceeds the limit,
+    /// the request will fail with an `ErrorKind::HeaderTooLarge` error and the connection will be closed.
+    /// The heads of the interim (`1xx`) responses preceding the final one are counted towards the limit as well,
+    /// so that a server cannot send interim responses endlessly.
+    ///
+    /// The default value is `64 * 1024`.
+    pub fn max_header_size(mut self, bytes: usize) -> Self {
+        self.options.max_header_size = bytes;
+        self
+    }
 
-                let res = track!(self.decoder.finish_decoding())?;
-                match res.http_version() {
-                    HttpVersion::V1_0 => {
-                        if res.header().get_field("Connection") != Some("keep-alive") {
-                            do_close = true;
-                        }
-                    }
-                    HttpVersion::V1_1 => {
-                        if res.header().get_field("Connection") == Some("close") {
-                            do_close = true;
-                        }
-                    }
-                }
-                response = Some(res);
-                break;
-            }
+    /// Sets the maximum number of the header fields of the response.
+    ///
+    /// This guards against heads consisting of a huge number of tiny fields, which [`max_header_size`] alone cannot prevent.
+    /// If the header of the response has more fields than the limit,
+    /// the request will fail with an `ErrorKind::HeaderTooLarge` error and the connection will be closed.
+    /// The fields are counted while the head is being received, so the request fails as soon as the limit is exceeded,
+    /// without waiting for the end of the head.
+    ///
+    /// The default value is `128`.
+    ///
+    /// [`max_header_size`]: #method.max_header_size
+    pub fn max_header_fields(mut self, n: usize) -> Self {
+        self.options.max_header_fields = n;
+        self
+    }
 
-            if stream.is_eos() {
-                track_panic!(ErrorKind::UnexpectedEos);
-            }
-            if stream.would_block() {
-                break;
-            }
-        }
-        if let Some(response) = response {
-            if do_close {
-                self.connection.as_mut().set_state(ConnectionState::Closed);
-            } else {
-                self.connection
-                    .as_mut()
-                    .set_state(ConnectionState::Recyclable);
-            }
-            Ok(Async::Ready(response))
-        } else {
-            Ok(Async::NotReady)
+    /// Sets the maximum number of retries of the request.
+    ///
+    /// Requests without a body (i.e., `GET`, `HEAD`, `DELETE`, `TRACE` and the ones sent by
+    /// [`put_empty`] and [`post_empty`]) are retried
+    /// if connecting to the server fails or the server replies with `429 Too Many Requests` or
+    /// `503 Service Unavailable` (the statuses can be changed by [`retry_on_status`]).
+    /// In the latter case, the client waits for the delay indicated by `Retry-After` header (if any)
+    /// before re-issuing the request (see [`max_retry_after`]).
+    /// Otherwise, the delay is determined by [`backoff`].
+    ///
+    /// If the retries are exhausted, the last response (or error) is returned.
+    /// Note that the timeout set by [`timeout`] covers all of the attempts,
+    /// and the number of the attempts can also be bounded by [`max_connect_attempts`].
+    ///
+    /// The default value is `0`.
+    ///
+    /// [`put_empty`]: #method.put_empty
+    /// [`post_empty`]: #method.post_empty
+    /// [`retry_on_status`]: #method.retry_on_status
+    /// [`max_retry_after`]: #method.max_retry_after
+    /// [`backoff`]: #method.backoff
+    /// [`timeout`]: #method.timeout
+    /// [`max_connect_attempts`]: #method.max_connect_attempts
+    pub fn max_retries(mut self, n: usize) -> Self {
+        self.options.max_retries = n;
+        self
+    }
+
+    /// Sets the maximum number of connection attempts for the request, including those for retries.
+    ///
+    /// Each attempt (i.e., the first one and each retry) acquires a connection to one of the resolved addresses.
+    /// Once this limit is reached, the request is no longer retried even if [`max_retries`] allows it,
+    /// and the last response (or error) is returned.
+    /// This bounds the worst-case latency when the server is unreachable.
+    ///
+    /// A value of `0` is treated as `1`.
+    ///
+    /// By default, the number of attempts is bounded only by [`max_retries`].
+    ///
+    /// [`max_retries`]: #method.max_retries
+    pub fn max_connect_attempts(mut self, n: usize) -> Self {
+        self.options.max_connect_attempts = cmp::max(n, 1);
+        self
+    }
+
+    /// Sets the status codes of the responses that cause the request to be retried (see [`max_retries`]).
+    ///
+    /// This replaces the default statuses, so include `429` and `503` in `status_codes` if they are still needed
+    /// (e.g., `&[429, 502, 503, 504]`).
+    /// The body of a discarded response is drained if it is small enough (see [`drain_threshold`]),
+    /// and otherwise the connection is closed.
+    ///
+    /// The default value is `&[429, 503]`.
+    ///
+    /// [`max_retries`]: #method.max_retries
+    /// [`drain_threshold`]: #method.drain_threshold
+    pub fn retry_on_status(mut self, status_codes: &[u16]) -> Self {
+        self.options.retry_statuses = Arc::new(status_codes.to_vec());
+        self
+    }
+
+    /// Sets the maximum delay honored when a response has `Retry-After` header.
+    ///
+    /// Longer delays indicated by servers are truncated to this value.
+    ///
+    /// The default value is `60` seconds.
+    pub fn max_retry_after(mut self, max: Duration) -> Self {
+        self.options.max_retry_after = max;
+        self
+    }
+
+    /// Sets the delays between retries (see [`max_retries`]).
+    ///
+    /// The delay before the `n`-th retry is `min(base * 2^(n - 1), max)`.
+    /// If `jitter` is `true`, a random delay between zero and that value is used instead (i.e., "full jitter").
+    ///
+    /// If the delay would exceed the deadline set by [`timeout`],
+    /// the request fails immediately with an `ErrorKind::Timeout` error.
+    ///
+    /// The default value is `backoff(Duration::from_millis(100), Duration::from_secs(10), false)`.
+    ///
+    /// [`max_retries`]: #method.max_retries
+    /// [`timeout`]: #method.timeout
+    pub fn backoff(mut self, base: Duration, max: Duration, jitter: bool) -> Self {
+        let rng = self.options.backoff.rng.take();
+        self.options.backoff = Backoff {
+            base,
+            max,
+            jitter,
+            rng,
+        };
+        self
+    }
+
+    /// Sets the seed of the random number generator used for the jitter of [`backoff`].
+    ///
+    /// This is useful to make the delays between retries deterministic (e.g., in tests).
+    ///
+    /// By default, a random seed is used.
+    ///
+    /// [`backoff`]: #method.backoff
+    pub fn jitter_seed(mut self, seed: u64) -> Self {
+        self.options.backoff.rng = Some(Rng::new(seed));
+        self
+    }
+
+    /// Sets the maximum size of the body of a response that is drained (i.e., read and discarded) for reusing the connection.
+    ///
+    /// When a response is discarded for retrying the request (see [`max_retries`])
+    /// or turned into an error (see [`error_for_status`]),
+    /// its body is drained if the size declared by `Content-Length` header is at most `bytes`,
+    /// so that the connection can be kept alive.
+    /// Otherwise (or if the size is unknown), the connection is closed instead.
+    ///
+    /// The default value is `4096`.
+    ///
+    /// [`max_retries`]: #method.max_retries
+    /// [`error_for_status`]: #method.error_for_status
+    pub fn drain_threshold(mut self, bytes: u64) -> Self {
+        self.options.drain_threshold = bytes;
+        self
+    }
+
+    /// Sets the writer to which the raw bytes of the response body are written while decoding.
+    ///
+    /// The writer receives the body exactly as received from the server
+    /// (e.g., chunked bodies are written with their framing).
+    /// If writing fails, the request fails with the I/O error as its cause.
+    ///
+    /// The writer is unused if the method of the request is `HEAD`,
+    /// and it does not receive the bodies failed by [`error_for_status`].
+    ///
+    /// [`error_for_status`]: #method.error_for_status
+    pub fn tee<W>(mut self, writer: W) -> Self
+    where
+        W: Write + Send + 'static,
+    {
+        self.options.tee = Some(Tee(Arc::new(Mutex::new(writer))));
+        self
+    }
+
+    /// Sets the encoder for serializing the body of the HTTP request.
+    ///
+    /// This is only meaningful at the case the method of the request is `PUT` or `POST`.
+    ///
+    /// `BytesEncoder<B>` accepts any `B: AsRef<[u8]>` as the body,
+    /// so reference-counted buffers (e.g., `Arc<[u8]>` or `bytes::Bytes`) can be sent without copying
+    /// by `encoder(BytesEncoder::<B>::new())` (see also [`bytes_encoder`]).
+    ///
+    /// Since the media type of the body is unknown to the builder,
+    /// `Content-Type` header is not added implicitly (see [`auto_content_type`]).
+    ///
+    /// [`bytes_encoder`]: #method.bytes_encoder
+    /// [`auto_content_type`]: #method.auto_content_type
+    pub fn encoder<T>(self, encoder: T) -> RequestBuilder<'a, C, T, D> {
+        self.with_encoder(encoder, None)
+    }
+
+    /// Sets the encoder that takes the body of the HTTP request as `String`.
+    ///
+    /// `Content-Type: text/plain; charset=utf-8` header is added implicitly (see [`auto_content_type`]).
+    ///
+    /// [`auto_content_type`]: #method.auto_content_type
+    pub fn text_encoder(self) -> RequestBuilder<'a, C, Utf8Encoder, D> {
+        self.with_encoder(Utf8Encoder::new(), Some(TEXT_PLAIN))
+    }
+
+    fn with_encoder<T>(
+        self,
+        encoder: T,
+        content_type: Option<&'static str>,
+    ) -> RequestBuilder<'a, C, T, D> {
+        RequestBuilder {
+            connection_provider: self.connection_provider,
+            url: self.url,
+            header_fields: self.header_fields,
+            encoder,
+            content_type,
+            decoder: self.decoder,
+            options: self.options,
+        }
+    }
+
+    /// Sets the decoder for deserializing the body of the HTTP response replied from the server.
+    ///
+    /// The decoder is unused if the method of the request is `HEAD`.
+    ///
+    /// The default decoder collects the body into a `Vec<u8>`,
+    /// which can be handed over to reference-counted buffers (e.g., `bytes::Bytes::from`) without copying
+    /// (see also [`bytes_decoder`]).
+    /// The body is copied once from the read buffer of the connection, since the buffer is reused for the subsequent responses.
+    ///
+    /// [`bytes_decoder`]: #method.bytes_decoder
+    pub fn decoder<T: Decode>(self, decoder: T) -> RequestBuilder<'a, C, E, BodyDecoder<T>> {
+        self.body_decoder(BodyDecoder::new(decoder))
+    }
+
+    /// Sets the decoder that returns the body of the HTTP response as raw bytes (i.e., `Vec<u8>`).
+    ///
+    /// This is the same as the default decoder.
+    /// The body is read according to its framing: if the response has `Content-Length` header or
+    /// is chunked, exactly the body part is read. Otherwise, the body is read until the connection is closed.
+    ///
+    /// The decoder is unused if the method of the request is `HEAD`.
+    pub fn raw(self) -> RequestBuilder<'a, C, E, BodyDecoder<RemainingBytesDecoder>> {
+        self.decoder(RemainingBytesDecoder::new())
+    }
+
+    /// Sets the encoder that takes the body of the HTTP request as `bytes::Bytes`.
+    ///
+    /// The body is written to the connection without being copied into a `Vec<u8>` beforehand.
+    /// `Content-Type: application/octet-stream` header is added implicitly (see [`auto_content_type`]).
+    ///
+    /// This method is available only if `bytes` feature is enabled.
+    ///
+    /// [`auto_content_type`]: #method.auto_content_type
+    #[cfg(feature = "bytes")]
+    pub fn bytes_encoder(self) -> RequestBuilder<'a, C, BytesEncoder<bytes::Bytes>, D> {
+        self.with_encoder(BytesEncoder::new(), Some(OCTET_STREAM))
+    }
+
+    /// Sets the decoder that returns the body of the HTTP response as `bytes::Bytes`.
+    ///
+    /// See [`BytesBodyDecoder`] for details.
+    /// The decoder is unused if the method of the request is `HEAD`.
+    ///
+    /// This method is available only if `bytes` feature is enabled.
+    ///
+    /// [`BytesBodyDecoder`]: ./body/struct.BytesBodyDecoder.html
+    #[cfg(feature = "bytes")]
+    pub fn bytes_decoder(self) -> RequestBuilder<'a, C, E, BytesBodyDecoder> {
+        self.body_decoder(BytesBodyDecoder::new())
+    }
+
+    /// Sets the decoder that chooses the representation of the body by `Content-Type` header of the response.
+    ///
+    /// Textual bodies are returned as strings and the others as raw bytes (see [`ContentTypeDecoder`] for details).
+    ///
+    /// The decoder is unused if the method of the request is `HEAD`.
+    ///
+    /// [`ContentTypeDecoder`]: ./body/struct.ContentTypeDecoder.html
+    pub fn auto_decoder(self) -> RequestBuilder<'a, C, E, ContentTypeDecoder> {
+        self.body_decoder(ContentTypeDecoder::new())
+    }
+
+    /// Sets the body decoder for deserializing the body of the HTTP response replied from the server.
+    ///
+    /// Unlike [`decoder`], the given decoder is responsible for handling the framing
+    /// (e.g., `Content-Length` or chunked transfer encoding) of the body.
+    /// See the [`body`] module for the decoders provided by this crate.
+    /// Note that if the status code of the response is `1xx`, `204` or `304`,
+    /// the decoder is not initialized and immediately receives the end of the (empty) body.
+    ///
+    /// The decoder is unused if the method of the request is `HEAD`.
+    ///
+    /// [`decoder`]: #method.decoder
+    /// [`body`]: ./body/index.html
+    pub fn body_decoder<T: BodyDecode>(self, decoder: T) -> RequestBuilder<'a, C, E, T> {
+        RequestBuilder {
+            connection_provider: self.connection_provider,
+            url: self.url,
+            header_fields: self.header_fields,
+            encoder: self.encoder,
+            content_type: self.content_type,
+            decoder,
+            options: self.options,
+        }
+    }
+
+    fn send<B, T, F, G>(
+        self,
+        method: &str,
+        body: B::Item,
+        replay: Option<Replay<B>>,
+        make_encoder: F,
+        make_decoder: G,
+    ) -> ResponseFuture<impl Future<Item = (Response<T::Item>, SocketAddr), Error = Error>>
+    where
+        B: BodyEncode,
+        T: BodyDecode,
+        F: FnOnce(E) -> B,
+        G: FnOnce(Capture<D>) -> T,
+    {
+        let request = self.build_request(method, body);
+        self.dispatch(method, request, replay, make_encoder, make_decoder)
+    }
+
+    fn send_empty(
+        mut self,
+        method: &str,
+    ) -> ResponseFuture<impl Future<Item = (Response<D::Item>, SocketAddr), Error = Error>> {
+        // `Content-Type` is meaningless for empty bodies
+        self.options.auto_content_type = false;
+        self.send(
+            method,
+            Vec::new(),
+            Some(|| (BodyEncoder::new(BytesEncoder::new()), Vec::new())),
+            |_| BodyEncoder::new(BytesEncoder::new()),
+            |d| d,
+        )
+    }
+
+    /// Sends the request with the body that is compressed if [`compress_request`] applies.
+    ///
+    /// [`compress_request`]: #method.compress_request
+    fn send_body(
+        mut self,
+        method: &str,
+        body: E::Item,
+    ) -> ResponseFuture<impl Future<Item = (Response<D::Item>, SocketAddr), Error = Error>> {
+        let request = self.build_request(method, body).and_then(|request| {
+            let min_size = match self.compression_threshold(method) {
+                None => return Ok(request.map_body(RequestBody::Item)),
+                Some(min_size) => min_size,
+            };
+            let (request, body) = request.take_body();
+            let body = track!(self.encoder.encode_into_bytes(body).map_err(Error::from))?;
+            let mut request = request.map_body(|()| body);
+            compress_body(&mut request, min_size);
+            Ok(request.map_body(RequestBody::Bytes))
+        });
+        self.dispatch(
+            method,
+            request,
+            None,
+            |e| BodyEncoder::new(RequestBodyEncoder::new(e)),
+            |d| d,
+        )
+    }
+
+    fn dispatch<B, T, F, G>(
+        self,
+        method: &str,
+        request: Result<Request<B::Item>>,
+        replay: Option<Replay<B>>,
+        make_encoder: F,
+        make_decoder: G,
+    ) -> ResponseFuture<impl Future<Item = (Response<T::Item>, SocketAddr), Error = Error>>
+    where
+        B: BodyEncode,
+        T: BodyDecode,
+        F: FnOnce(E) -> B,
+        G: FnOnce(Capture<D>) -> T,
+    {
+        let timeout = self.options.timeout;
+        let deadline = self.options.deadline;
+        let (cancel, cancel_rx) = CancelHandle::new();
+        let hook = self.options.hook.clone();
+        let metrics = self.options.metrics.method(method).clone();
+        metrics.requests.increment();
+        let span = RequestSpan::new(method, self.url.host_str().unwrap_or(""));
+        let f = move || {
+            let request = track!(request)?;
+            let mut connector = track!(self.connector())?;
+            let decoder = make_decoder(Capture::new(self.decoder, self.options.tee.clone()));
+            let encoder = make_encoder(self.encoder);
+            Ok(Retry::new(
+                move || connector.connect(),
+                request,
+                replay,
+                encoder,
+                decoder,
+                self.options,
+            )
+            .cancel_on(cancel_rx))
+        };
+        let future = span
+            .clone()
+            .instrument(Self::execute(f(), timeout, deadline).then(move |result| {
+                match result {
+                    Ok((ref response, _)) => {
+                        metrics.succeeded_requests.increment();
+                        span.finish(Some(response.status_code().as_u16()));
+                    }
+                    Err(ref e) => {
+                        metrics.fail(*e.kind());
+                        span.finish(None);
+                        hook.emit(&RequestEvent::Failed { error: e });
+                    }
+                }
+                result
+            }));
+        ResponseFuture::new(track_err!(future), cancel)
+    }
+
+    fn compression_threshold(&self, method: &str) -> Option<usize> {
+        if method != "PUT" && method != "POST" && method != "PATCH" {
+            return None;
+        }
+        if self.has_header_field("Content-Encoding") {
+            return None;
+        }
+        self.options.compress_request
+    }
+
+    fn implicit_content_type(&self, method: &str) -> Option<&'static str> {
+        if method != "PUT" && method != "POST" {
+            return None;
+        }
+        if !self.options.auto_content_type || self.has_header_field("Content-Type") {
+            return None;
+        }
+        self.content_type
+    }
+
+    fn build_request<T>(&self, method: &str, body: T) -> Result<Request<T>> {
+        track_assert_eq!(self.url.scheme(), "http", ErrorKind::InvalidInput; self.url);
+
+        let method = unsafe { Method::new_unchecked(method) };
+        let target = track!(self.request_target(method.as_str()))?;
+        let target = track!(RequestTarget::new(&target); self.url)?;
+        let mut request = Request::new(method, target, HttpVersion::V1_1, body);
+
+        for (name, value) in &self.header_fields {
+            let field = track!(new_header_field(name, value))?;
+            request.header_mut().add_field(field);
+        }
+        if let Some(content_type) = self.implicit_content_type(request.method().as_str()) {
+            let field = track!(new_header_field("Content-Type", content_type))?;
+            request.header_mut().add_field(field);
+        }
+        for (name, value) in self.options.default_headers.iter() {
+            if self
+                .header_fields
+                .iter()
+                .any(|(n, _)| n.eq_ignore_ascii_case(name))
+            {
+                continue;
+            }
+            let field = track!(new_header_field(name, value))?;
+            request.header_mut().add_field(field);
+        }
+        if !self.has_header_field("Host") && self.options.host_header {
+            // `Url` serializes internationalized domain names in the punycode (A-label) form
+            let host = &self.url[Position::BeforeHost..Position::AfterPort];
+            let field = track!(HeaderField::new("Host", host); host)?;
+            request.header_mut().add_field(field);
+        }
+        if self.options.close_connection && !self.has_header_field("Connection") {
+            let field = HeaderField::new("Connection", "close").expect("never fails");
+            request.header_mut().add_field(field);
+        }
+        if let Some((start, end)) = self.options.range {
+            if !self.has_header_field("Range") {
+                track_assert!(end.is_none_or(|end| start <= end), ErrorKind::InvalidInput; start, end);
+                let end = end.map(|n| n.to_string()).unwrap_or_default();
+                let value = format!("bytes={}-{}", start, end);
+                let field = unsafe { HeaderField::new_unchecked("Range", &value) };
+                request.header_mut().add_field(field);
+            }
+        }
+        if let Some(authorization) = self.options.proxy.as_ref().and_then(|p| p.authorization()) {
+            if !self.has_header_field("Proxy-Authorization") {
+                // `HeaderField::new` rejects the space after the scheme, though it is a valid field value
+                let field =
+                    unsafe { HeaderField::new_unchecked("Proxy-Authorization", authorization) };
+                request.header_mut().add_field(field);
+            }
+        }
+        Ok(request)
+    }
+
+    fn request_target(&self, method: &str) -> Result<String> {
+        let form = self.options.target_form.unwrap_or_else(|| {
+            if method == "CONNECT" {
+                TargetForm::Authority
+            } else if self.options.proxy.is_some() {
+                TargetForm::Absolute
+            } else {
+                TargetForm::Origin
+            }
+        });
+
+        // `Url` keeps the path and the query percent-encoded, so the slices consist of only VCHARs.
+        // The fragment is never sent (RFC 7230, section 5.1).
+        let target = match form {
+            TargetForm::Origin => self.url[Position::BeforePath..Position::AfterQuery].to_owned(),
+            TargetForm::Absolute => self.url[..Position::AfterQuery].to_owned(),
+            TargetForm::Authority => {
+                // The port cannot be omitted (RFC 7230, section 5.3.3)
+                let port = track_assert_some!(self.url.port_or_known_default(), ErrorKind::InvalidInput; self.url);
+                let host = &self.url[Position::BeforeHost..Position::AfterHost];
+                format!("{}:{}", host, port)
+            }
+            TargetForm::Asterisk => "*".to_owned(),
+        };
+        Ok(target)
+    }
+
+    /// Returns `true` if the field is given by either the caller or the default headers of the client.
+    fn has_header_field(&self, name: &str) -> bool {
+        self.header_fields
+            .iter()
+            .map(|(n, _)| n.as_ref())
+            .chain(self.options.default_headers.iter().map(|(n, _)| n.as_str()))
+            .any(|n| n.eq_ignore_ascii_case(name))
+    }
+
+    fn connector(&self) -> Result<Connector<C>> {
+        let provider = self.connection_provider.clone();
+        if let Some(addr) = self.options.connect_addr {
+            return Ok(Connector::Addr(provider, addr));
+        }
+
+        let url = self.options.proxy.as_ref().map_or(&*self.url, |p| p.url());
+        let port = track_assert_some!(url.port_or_known_default(), ErrorKind::InvalidInput; url);
+        let ip = match track_assert_some!(url.host(), ErrorKind::InvalidInput; url) {
+            Host::Ipv4(ip) => IpAddr::from(ip),
+            Host::Ipv6(ip) => IpAddr::from(ip),
+            Host::Domain(host) => return Ok(Connector::Host(provider, host.to_owned(), port)),
+        };
+        Ok(Connector::Addr(provider, SocketAddr::new(ip, port)))
+    }
+
+    fn execute<F>(
+        future: Result<F>,
+        timeout: Option<Duration>,
+        deadline: Option<Instant>,
+    ) -> impl Future<Item = F::Item, Error = Error>
+    where
+        F: Future<Error = Error>,
+    {
+        let future = match future {
+            Err(e) => return Either::B(failed(track!(e))),
+            Ok(future) => future,
+        };
+
+        // The remaining time is determined when the future is polled for the first time
+        Either::A(future::lazy(move || {
+            let now = Instant::now();
+            if let Some(deadline) = earliest_deadline(now, timeout, deadline) {
+                if deadline <= now {
+                    let e = ErrorKind::Timeout.cause(TimeoutPhase::Request);
+                    return Either::B(failed(track!(Error::from(e))));
+                }
+                let future = future.timeout_after(deadline - now).map_err(|e| {
+                    e.unwrap_or_else(|| {
+                        track!(Error::from(ErrorKind::Timeout.cause(TimeoutPhase::Request)))
+                    })
+                });
+                Either::A(Either::A(future))
+            } else {
+                Either::A(Either::B(future))
+            }
+        }))
+    }
+}
+
+/// `Future` that represents an HTTP request execution.
+///
+/// This is returned by the methods that issue a request (e.g., [`RequestBuilder::get`]).
+///
+/// [`RequestBuilder::get`]: ./struct.RequestBuilder.html#method.get
+#[derive(Debug)]
+#[must_use = "futures do nothing unless polled"]
+pub struct ResponseFuture<F> {
+    inner: F,
+    cancel: CancelHandle,
+    start: Instant,
+}
+impl<F, T> ResponseFuture<F>
+where
+    F: Future<Item = (Response<T>, SocketAddr), Error = Error>,
+{
+    fn new(inner: F, cancel: CancelHandle) -> Self {
+        ResponseFuture {
+            inner,
+            cancel,
+            start: Instant::now(),
+        }
+    }
+
+    /// Returns a handle for canceling the request.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+
+    /// Converts the future into one that also returns the address of the peer that served the request.
+    ///
+    /// This is useful for knowing which address has been selected when the host name has multiple addresses.
+    pub fn with_peer_addr(self) -> F {
+        self.inner
+    }
+
+    /// Converts the future into one that also returns the time taken by the request.
+    ///
+    /// The time is measured from the creation of this future (i.e., the call of the method that issued the request)
+    /// until the response is received, so it includes the time for acquiring a connection and for retries.
+    ///
+    /// This is a lightweight alternative to the histograms of [`ClientMetrics`].
+    ///
+    /// [`ClientMetrics`]: ./metrics/struct.ClientMetrics.html
+    pub fn timed(self) -> TimedResponseFuture<F> {
+        TimedResponseFuture(self)
+    }
+}
+impl<F, T> Future for ResponseFuture<F>
+where
+    F: Future<Item = (Response<T>, SocketAddr), Error = Error>,
+{
+    type Item = Response<T>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        Ok(self.inner.poll()?.map(|(response, _)| response))
+    }
+}
+
+/// `Future` that returns the response of a request along with the time taken by the request.
+///
+/// This is created by calling [`ResponseFuture::timed`] method.
+///
+/// [`ResponseFuture::timed`]: ./struct.ResponseFuture.html#method.timed
+#[derive(Debug)]
+pub struct TimedResponseFuture<F>(ResponseFuture<F>);
+impl<F, T> TimedResponseFuture<F>
+where
+    F: Future<Item = (Response<T>, SocketAddr), Error = Error>,
+{
+    /// Returns a handle for canceling the request.
+    pub fn cancel_handle(&self) -> CancelHandle {
+        self.0.cancel_handle()
+    }
+}
+impl<F, T> Future for TimedResponseFuture<F>
+where
+    F: Future<Item = (Response<T>, SocketAddr), Error = Error>,
+{
+    type Item = Timed<Response<T>>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let start = self.0.start;
+        Ok(self.0.poll()?.map(|response| Timed {
+            value: response,
+            elapsed: start.elapsed(),
+        }))
+    }
+}
+
+/// A value (e.g., a response) paired with the time taken to obtain it.
+///
+/// This is returned by [`TimedResponseFuture`].
+///
+/// [`TimedResponseFuture`]: ./struct.TimedResponseFuture.html
+#[derive(Debug)]
+pub struct Timed<T> {
+    value: T,
+    elapsed: Duration,
+}
+impl<T> Timed<T> {
+    /// Returns the time taken to obtain the value.
+    pub fn elapsed(&self) -> Duration {
+        self.elapsed
+    }
+
+    /// Returns a reference to the value.
+    pub fn get_ref(&self) -> &T {
+        &self.value
+    }
+
+    /// Takes ownership of the value.
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+}
+
+/// The result of a successful protocol upgrade (see [`RequestBuilder::upgrade`]).
+///
+/// The connection can be used for the new protocol via its `Read` and `Write` implementations
+/// (e.g., `upgraded.connection_mut().as_mut().read(..)`).
+/// The bytes sent by the server right after the response (if any) are returned by the first reads.
+///
+/// [`RequestBuilder::upgrade`]: ./struct.RequestBuilder.html#method.upgrade
+#[derive(Debug)]
+pub struct Upgraded<C> {
+    response: Response<()>,
+    connection: C,
+}
+impl<C: AsMut<Connection>> Upgraded<C> {
+    /// Returns a reference to the `101 Switching Protocols` response.
+    pub fn response(&self) -> &Response<()> {
+        &self.response
+    }
+
+    /// Returns a mutable reference to the connection.
+    pub fn connection_mut(&mut self) -> &mut C {
+        &mut self.connection
+    }
+
+    /// Takes ownership of `Upgraded` and returns the response and the connection.
+    pub fn into_parts(self) -> (Response<()>, C) {
+        (self.response, self.connection)
+    }
+}
+
+/// The result of a successful `CONNECT` request (see [`RequestBuilder::connect_tunnel`]).
+///
+/// The connection can be used for the tunneled protocol via its `Read` and `Write` implementations
+/// (e.g., `tunnel.connection_mut().as_mut().read(..)`),
+/// in which case the bytes sent by the destination right after the response (if any) are returned by the first reads.
+/// Alternatively, [`into_parts`] takes them out of the connection, so that they can be fed to another stream (e.g., a TLS session).
+///
+/// [`RequestBuilder::connect_tunnel`]: ./struct.RequestBuilder.html#method.connect_tunnel
+/// [`into_parts`]: #method.into_parts
+#[derive(Debug)]
+pub struct Tunnel<C> {
+    response: Response<()>,
+    connection: C,
+}
+impl<C: AsMut<Connection>> Tunnel<C> {
+    /// Returns a reference to the `2xx` response.
+    pub fn response(&self) -> &Response<()> {
+        &self.response
+    }
+
+    /// Returns a mutable reference to the connection.
+    pub fn connection_mut(&mut self) -> &mut C {
+        &mut self.connection
+    }
+
+    /// Takes ownership of `Tunnel` and returns the response, the connection
+    /// and the bytes that have been received following the response but not read yet.
+    pub fn into_parts(mut self) -> (Response<()>, C, Vec<u8>) {
+        let buffered = self.connection.as_mut().take_buffered_bytes();
+        (self.response, self.connection, buffered)
+    }
+}
+
+/// `Future` that executes an upgrade (or `CONNECT`) request and hands over the connection on success.
+struct ExecuteHandOver<C>(Option<Execute<C, NoBodyEncoder, BodyDecoder<RemainingBytesDecoder>>>);
+impl<C: AsMut<Connection>> Future for ExecuteHandOver<C> {
+    type Item = (Response<()>, C);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let execute = self.0.as_mut().expect("Cannot poll ExecuteHandOver twice");
+        let response = match track!(execute.poll())? {
+            Async::NotReady => return Ok(Async::NotReady),
+            Async::Ready(Outcome::Response(response, _)) => response,
+            Async::Ready(Outcome::Retry(_)) => unreachable!(), // The request is not retryable
+        };
+        let status_code = response.status_code().as_u16();
+        let handed_over = if execute.connect_request {
+            is_tunneled(&response)
+        } else {
+            status_code == 101
+        };
+        if !handed_over {
+            let reason_phrase = response.reason_phrase().as_str().to_owned();
+            let cause = StatusError::new(status_code, reason_phrase, response.into_body());
+            return Err(track!(Error::from(ErrorKind::StatusError.cause(cause))));
+        }
+        let connection = self.0.take().expect("never fails").into_connection();
+        Ok(Async::Ready((response.map_body(|_| ()), connection)))
+    }
+}
+
+/// A clonable snapshot of the settings of a `RequestBuilder`.
+///
+/// This is created by [`RequestBuilder::to_template`], and turned back into builders by [`Client::request_from`].
+///
+/// [`RequestBuilder::to_template`]: ./struct.RequestBuilder.html#method.to_template
+/// [`Client::request_from`]: ./struct.Client.html#method.request_from
+#[derive(Debug, Clone)]
+pub struct RequestTemplate {
+    url: Url,
+    header_fields: Vec<(String, String)>,
+    options: RequestOptions,
+}
+impl RequestTemplate {
+    /// Returns the URL of the request.
+    pub fn url(&self) -> &Url {
+        &self.url
+    }
+
+    /// Replaces the URL of the request.
+    ///
+    /// This is useful for sending the same request to multiple hosts.
+    /// Note that an explicit `Host` field given via [`RequestBuilder::header_field`] is kept as it is.
+    ///
+    /// [`RequestBuilder::header_field`]: ./struct.RequestBuilder.html#method.header_field
+    pub fn set_url(&mut self, url: Url) -> &mut Self {
+        self.url = url;
+        self
+    }
+
+    pub(crate) fn builder<'a, C: 'a>(
+        &self,
+        connection_provider: &'a mut C,
+        proxy: Option<Arc<Proxy>>,
+    ) -> RequestBuilder<'a, C> {
+        let mut options = self.options.clone();
+        options.proxy = proxy;
+        let mut builder =
+            RequestBuilder::new(connection_provider, Cow::Owned(self.url.clone()), options);
+        builder.header_fields = self
+            .header_fields
+            .iter()
+            .map(|(n, v)| (Cow::Owned(n.clone()), Cow::Owned(v.clone())))
+            .collect();
+        builder
+    }
+}
+
+/// Handle for canceling a request.
+///
+/// This is obtained by calling [`ResponseFuture::cancel_handle`] method.
+///
+/// Once [`cancel`] is called, the corresponding future fails with an `ErrorKind::Canceled` error
+/// and the connection used by the request is closed (i.e., it is never returned to the pool).
+///
+/// [`ResponseFuture::cancel_handle`]: ./struct.ResponseFuture.html#method.cancel_handle
+/// [`cancel`]: #method.cancel
+#[derive(Debug, Clone)]
+pub struct CancelHandle(Arc<Mutex<Option<oneshot::Sender<()>>>>);
+impl CancelHandle {
+    fn new() -> (Self, oneshot::Receiver<()>) {
+        let (tx, rx) = oneshot::channel();
+        (CancelHandle(Arc::new(Mutex::new(Some(tx)))), rx)
+    }
+
+    /// Cancels the request.
+    ///
+    /// This has no effect if the request has already been completed.
+    pub fn cancel(&self) {
+        if let Some(tx) = self.0.lock().expect("never fails").take() {
+            let _ = tx.send(());
+        }
+    }
+
+    /// Returns `true` if `cancel` has been called.
+    pub fn is_canceled(&self) -> bool {
+        self.0.lock().expect("never fails").is_none()
+    }
+}
+
+/// The form of the request-target in request lines (RFC 7230, section 5.3).
+///
+/// See [`RequestBuilder::target_form`] for the default form of each request.
+///
+/// [`RequestBuilder::target_form`]: ./struct.RequestBuilder.html#method.target_form
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TargetForm {
+    /// The path and the query of the URL (e.g., `/foo?bar`).
+    Origin,
+
+    /// The whole URL except for the fragment (e.g., `http://example.com/foo?bar`).
+    Absolute,
+
+    /// The host and the port of the URL (e.g., `example.com:80`).
+    Authority,
+
+    /// A single asterisk (i.e., `*`), which is used by server-wide `OPTIONS` requests.
+    Asterisk,
+}
+
+/// A body encoder for requests that must not have a body.
+///
+/// Unlike `BodyEncoder<BytesEncoder>`, this does not add `Content-Length: 0` header to requests.
+#[derive(Debug, Default)]
+struct NoBodyEncoder(BytesEncoder);
+impl NoBodyEncoder {
+    fn new() -> Self {
+        Self::default()
+    }
+}
+impl Encode for NoBodyEncoder {
+    type Item = Vec<u8>;
+
+    fn encode(&mut self, buf: &mut [u8], eos: Eos) -> bytecodec::Result<usize> {
+        track!(self.0.encode(buf, eos))
+    }
+
+    fn start_encoding(&mut self, item: Self::Item) -> bytecodec::Result<()> {
+        track_assert!(item.is_empty(), bytecodec::ErrorKind::InvalidInput);
+        track!(self.0.start_encoding(item))
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.0.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.0.is_idle()
+    }
+}
+impl BodyEncode for NoBodyEncoder {}
+
+/// Compresses the body of the request with gzip if it is at least `min_size` bytes and the compression pays.
+fn compress_body(request: &mut Request<Vec<u8>>, min_size: usize) {
+    if request.body().len() < min_size {
+        return;
+    }
+    let compressed = gzip::compress(request.body());
+    if compressed.len() < request.body().len() {
+        *request.body_mut() = compressed;
+        let field = HeaderField::new("Content-Encoding", "gzip").expect("never fails");
+        request.header_mut().add_field(field);
+    }
+}
+
+/// The body of a request, which may have been encoded in advance (e.g., for compression).
+#[derive(Debug)]
+enum RequestBody<T> {
+    Item(T),
+    Bytes(Vec<u8>),
+}
+
+/// An encoder for `RequestBody`.
+#[derive(Debug)]
+struct RequestBodyEncoder<E> {
+    inner: E,
+    bytes: BytesEncoder,
+}
+impl<E> RequestBodyEncoder<E> {
+    fn new(inner: E) -> Self {
+        RequestBodyEncoder {
+            inner,
+            bytes: BytesEncoder::new(),
+        }
+    }
+}
+impl<E: Encode> Encode for RequestBodyEncoder<E> {
+    type Item = RequestBody<E::Item>;
+
+    fn encode(&mut self, buf: &mut [u8], eos: Eos) -> bytecodec::Result<usize> {
+        if self.bytes.is_idle() {
+            track!(self.inner.encode(buf, eos))
+        } else {
+            track!(self.bytes.encode(buf, eos))
+        }
+    }
+
+    fn start_encoding(&mut self, item: Self::Item) -> bytecodec::Result<()> {
+        match item {
+            RequestBody::Item(item) => track!(self.inner.start_encoding(item)),
+            RequestBody::Bytes(bytes) => track!(self.bytes.start_encoding(bytes)),
+        }
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        if self.bytes.is_idle() {
+            self.inner.requiring_bytes()
+        } else {
+            self.bytes.requiring_bytes()
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle() && self.bytes.is_idle()
+    }
+}
+
+/// Acquires connections to the destination of a request.
+#[derive(Debug)]
+enum Connector<C> {
+    Addr(C, SocketAddr),
+    Host(C, String, u16),
+}
+impl<C: AcquireConnection + Clone> Connector<C> {
+    fn connect(&mut self) -> impl Future<Item = C::Connection, Error = Error> {
+        match *self {
+            Connector::Addr(ref mut provider, addr) => Either::A(provider.acquire_connection(addr)),
+            Connector::Host(ref mut provider, ref host, port) => {
+                let mut provider = provider.clone();
+                let future = provider.resolve(host, port).and_then(move |addrs| {
+                    if !addrs.is_empty() {
+                        Either::A(provider.acquire_connection_to_any(&addrs))
+                    } else {
+                        let e = ErrorKind::Dns.cause("No address resolved");
+                        Either::B(failed(track!(Error::from(e))))
+                    }
+                });
+                Either::B(future)
+            }
+        }
+    }
+}
+
+/// Makes the body encoder and the body for re-issuing a request.
+type Replay<E> = fn() -> (E, <E as Encode>::Item);
+
+enum Attempt<F, C, E: Encode, D> {
+    Acquire(AcquirePermit),
+    Connect(F),
+    Execute(Box<Execute<C, E, D>>),
+    Wait(Timeout),
+}
+
+/// `Future` that executes a request, and re-issues it if needed (see `RequestBuilder::max_retries`).
+struct Retry<N, F, E: Encode, D>
+where
+    F: Future,
+{
+    connect: N,
+    head: Request<()>,
+    request: Option<(E, Request<E::Item>)>,
+    replay: Option<Replay<E>>,
+    decoder: Option<D>,
+    options: RequestOptions,
+    deadline: Option<Instant>,
+    cancel_rx: Option<oneshot::Receiver<()>>,
+    retries: usize,
+    connect_attempts: usize,
+    attempt: Attempt<F, F::Item, E, D>,
+    permit: Option<Permit>, // Declared after `attempt` so that it is released after the connection
+}
+impl<N, F, E, D> Retry<N, F, E, D>
+where
+    N: FnMut() -> F,
+    F: Future<Error = Error>,
+    F::Item: AsMut<Connection>,
+    E: BodyEncode,
+    D: BodyDecode,
+{
+    fn new(
+        mut connect: N,
+        request: Request<E::Item>,
+        replay: Option<Replay<E>>,
+        encoder: E,
+        decoder: D,
+        options: RequestOptions,
+    ) -> Self {
+        let (attempt, connect_attempts) = match options.limiter {
+            Some(ref limiter) => (Attempt::Acquire(limiter.acquire()), 0),
+            None => (Attempt::Connect(connect()), 1),
+        };
+        Retry {
+            connect,
+            head: copy_request(&request, ()),
+            request: Some((encoder, request)),
+            replay,
+            decoder: Some(decoder),
+            deadline: earliest_deadline(Instant::now(), options.timeout, options.deadline),
+            cancel_rx: None,
+            options,
+            retries: 0,
+            connect_attempts,
+            attempt,
+            permit: None,
+        }
+    }
+
+    fn cancel_on(mut self, cancel_rx: oneshot::Receiver<()>) -> Self {
+        self.cancel_rx = Some(cancel_rx);
+        self
+    }
+
+    fn poll_attempt(&mut self) -> Poll<(Response<D::Item>, SocketAddr), Error> {
+        loop {
+            let next = match self.attempt {
+                Attempt::Acquire(ref mut f) => match track!(f.poll())? {
+                    Async::NotReady => return Ok(Async::NotReady),
+                    Async::Ready(permit) => {
+                        self.permit = Some(permit);
+                        self.connect()
+                    }
+                },
+                Attempt::Connect(ref mut f) => match f.poll() {
+                    Err(e) => {
+                        if !(self.is_retryable() && is_connect_error(&e)) {
+                            return Err(track!(e));
+                        }
+                        track!(self.wait(None))?
+                    }
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(connection)) => self.execute(connection),
+                },
+                Attempt::Execute(ref mut f) => match track!(f.poll())? {
+                    Async::NotReady => return Ok(Async::NotReady),
+                    Async::Ready(Outcome::Response(response, peer_addr)) => {
+                        return Ok(Async::Ready((response, peer_addr)));
+                    }
+                    Async::Ready(Outcome::Retry(retry_after)) => track!(self.wait(retry_after))?,
+                },
+                Attempt::Wait(ref mut f) => {
+                    if let Async::NotReady = track!(f.poll().map_err(Error::from))? {
+                        return Ok(Async::NotReady);
+                    }
+                    self.connect()
+                }
+            };
+            if let Attempt::Execute(execute) = mem::replace(&mut self.attempt, next) {
+                self.decoder = Some(execute.into_decoder());
+            }
+        }
+    }
+
+    fn is_retryable(&self) -> bool {
+        // Each retry requires a new connection attempt
+        self.replay.is_some()
+            && self.retries < self.options.max_retries
+            && self.connect_attempts < self.options.max_connect_attempts
+    }
+
+    fn connect(&mut self) -> Attempt<F, F::Item, E, D> {
+        self.connect_attempts += 1;
+        Attempt::Connect((self.connect)())
+    }
+
+    fn wait(&mut self, retry_after: Option<Duration>) -> Result<Attempt<F, F::Item, E, D>> {
+        self.retries += 1;
+        let delay = match retry_after {
+            Some(d) => cmp::min(d, self.options.max_retry_after),
+            None => self.options.backoff.delay(self.retries),
+        };
+        if let Some(deadline) = self.deadline {
+            if Instant::now() + delay >= deadline {
+                let e = ErrorKind::Timeout.cause(TimeoutPhase::Request);
+                return Err(track!(Error::from(e); delay, self.retries));
+            }
+        }
+        Ok(Attempt::Wait(timer::timeout(delay)))
+    }
+
+    fn execute(&mut self, connection: F::Item) -> Attempt<F, F::Item, E, D> {
+        let (encoder, request) = self.request.take().unwrap_or_else(|| {
+            let (encoder, body) = (self.replay.expect("never fails"))();
+            (encoder, copy_request(&self.head, body))
+        });
+        let decoder = self.decoder.take().expect("never fails");
+        Attempt::Execute(Box::new(Execute::new(
+            connection,
+            request,
+            encoder,
+            decoder,
+            self.is_retryable(),
+            &self.options,
+        )))
+    }
+}
+impl<N, F, E, D> Future for Retry<N, F, E, D>
+where
+    N: FnMut() -> F,
+    F: Future<Error = Error>,
+    F::Item: AsMut<Connection>,
+    E: BodyEncode,
+    D: BodyDecode,
+{
+    type Item = (Response<D::Item>, SocketAddr);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let canceled = match self.cancel_rx.as_mut().map(Future::poll) {
+            None | Some(Ok(Async::NotReady)) => false,
+            Some(Ok(Async::Ready(()))) => true,
+            Some(Err(_)) => {
+                // All of the handles have been dropped
+                self.cancel_rx = None;
+                false
+            }
+        };
+        if canceled {
+            if let Attempt::Execute(ref mut execute) = self.attempt {
+                execute.abort();
+            }
+            self.permit = None;
+            track_panic!(ErrorKind::Canceled, "The request has been canceled");
+        }
+        let result = self.poll_attempt();
+        if !matches!(result, Ok(Async::NotReady)) {
+            // The completed request no longer counts towards the concurrency limit
+            self.permit = None;
+        }
+        result
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct RequestOptions {
+    pub timeout: Option<Duration>,
+    pub deadline: Option<Instant>,
+    pub error_for_status: bool,
+    pub auto_content_type: bool,
+    pub compress_request: Option<usize>,
+    pub host_header: bool,
+    pub close_connection: bool,
+    pub target_form: Option<TargetForm>,
+    pub range: Option<(u64, Option<u64>)>,
+    pub max_header_size: usize,
+    pub max_header_fields: usize,
+    pub drain_threshold: u64,
+    pub connect_addr: Option<SocketAddr>,
+    pub max_retries: usize,
+    pub max_connect_attempts: usize,
+    pub max_retry_after: Duration,
+    pub retry_statuses: Arc<Vec<u16>>,
+    pub backoff: Backoff,
+    pub tee: Option<Tee>,
+    pub default_headers: Arc<Vec<(String, String)>>,
+    pub proxy: Option<Arc<Proxy>>,
+    pub limiter: Option<Limiter>,
+    pub hook: EventHook,
+    pub progress: ProgressHook,
+    pub middleware: MiddlewareChain,
+    pub metrics: ClientMetrics,
+}
+impl Default for RequestOptions {
+    fn default() -> Self {
+        RequestOptions {
+            timeout: None,
+            deadline: None,
+            error_for_status: false,
+            auto_content_type: true,
+            compress_request: None,
+            host_header: true,
+            close_connection: false,
+            target_form: None,
+            range: None,
+            max_header_size: DEFAULT_MAX_HEADER_SIZE,
+            max_header_fields: DEFAULT_MAX_HEADER_FIELDS,
+            drain_threshold: DEFAULT_DRAIN_THRESHOLD,
+            connect_addr: None,
+            max_retries: 0,
+            max_connect_attempts: usize::MAX,
+            max_retry_after: Duration::from_secs(DEFAULT_MAX_RETRY_AFTER_SECS),
+            retry_statuses: Arc::new(DEFAULT_RETRY_STATUSES.to_vec()),
+            backoff: Backoff {
+                base: DEFAULT_BACKOFF_BASE,
+                max: DEFAULT_BACKOFF_MAX,
+                jitter: false,
+                rng: None,
+            },
+            tee: None,
+            default_headers: Arc::new(Vec::new()),
+            proxy: None,
+            limiter: None,
+            hook: EventHook::default(),
+            progress: ProgressHook::default(),
+            middleware: MiddlewareChain::default(),
+            metrics: ClientMetrics::default(),
+        }
+    }
+}
+
+/// Exponential backoff between retries.
+#[derive(Debug, Clone)]
+pub(crate) struct Backoff {
+    base: Duration,
+    max: Duration,
+    jitter: bool,
+    rng: Option<Rng>, // Used only if `RequestBuilder::jitter_seed` is called
+}
+impl Backoff {
+    /// Returns the delay before the `retries`-th retry.
+    fn delay(&mut self, retries: usize) -> Duration {
+        let exponent = cmp::min(retries.saturating_sub(1), 31) as u32;
+        let delay = self
+            .base
+            .checked_mul(1 << exponent)
+            .map_or(self.max, |d| cmp::min(d, self.max));
+        if self.jitter {
+            let random = match self.rng {
+                Some(ref mut rng) => rng.next_f64(),
+                None => Rng::from_entropy().next_f64(),
+            };
+            delay.mul_f64(random)
+        } else {
+            delay
+        }
+    }
+}
+
+/// A writer shared by the copies of `RequestOptions`.
+#[derive(Clone)]
+pub(crate) struct Tee(Arc<Mutex<dyn Write + Send>>);
+impl fmt::Debug for Tee {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Tee {{ .. }}")
+    }
+}
+
+#[derive(Debug)]
+struct Execute<C, E: Encode, D> {
+    connection: C,
+    request: Option<Request<E::Item>>,
+    encoder: RequestEncoder<E>,
+    head_decoder: HeadDecoder,
+    head: Option<Response<()>>,
+    head_size: usize,
+    body_size: usize,
+    content_length: Option<u64>, // The size of the body declared by `Content-Length` header
+    head_request: bool,
+    connect_request: bool,
+    range: Option<(u64, Option<u64>)>, // Used only if `RequestBuilder::range` is called
+    drain_threshold: u64,
+    draining: Option<Drain>,
+    max_header_size: usize,
+    max_header_fields: usize,
+    decoder: D,
+    error_for_status: bool,
+    retryable: bool,
+    retry_statuses: Arc<Vec<u16>>,
+    proxy_authorization: Option<bool>, // `None` if the request is not sent through a proxy
+    close_connection: bool,
+    request_sent: bool,
+    hook: EventHook,
+    progress: ProgressTracker,
+    _in_flight: InFlight,
+}
+impl<C, E, D> Execute<C, E, D>
+where
+    C: AsMut<Connection>,
+    E: BodyEncode,
+    D: BodyDecode,
+{
+    fn new(
+        mut connection: C,
+        request: Request<E::Item>,
+        encoder: E,
+        decoder: D,
+        retryable: bool,
+        options: &RequestOptions,
+    ) -> Self {
+        trace::connected(
+            connection.as_mut().peer_addr(),
+            connection.as_mut().completed_requests(),
+        );
+        // The limits of httpcodec are loosened so that oversized heads are detected by `decode_head`
+        let decode_options = DecodeOptions {
+            max_start_line_size: options.max_header_size.saturating_add(1),
+            max_header_size: options.max_header_size.saturating_add(1),
+        };
+        let proxy_authorization = options
+            .proxy
+            .as_ref()
+            .map(|_| request.header().get_field("Proxy-Authorization").is_some());
+        let head_request = request.method().as_str() == "HEAD";
+        let connect_request = request.method().as_str() == "CONNECT";
+        let close_connection = request
+            .header()
+            .get_field("Connection")
+            .is_some_and(|v| v.eq_ignore_ascii_case("close"));
+        Execute {
+            connection,
+            request: Some(request),
+            encoder: RequestEncoder::new(encoder),
+            head_decoder: HeadDecoder::new(decode_options, options.max_header_fields),
+            head: None,
+            head_size: 0,
+            body_size: 0,
+            content_length: None,
+            head_request,
+            connect_request,
+            range: options.range,
+            drain_threshold: options.drain_threshold,
+            draining: None,
+            max_header_size: options.max_header_size,
+            max_header_fields: options.max_header_fields,
+            decoder,
+            error_for_status: options.error_for_status,
+            retryable,
+            retry_statuses: options.retry_statuses.clone(),
+            proxy_authorization,
+            close_connection,
+            request_sent: false,
+            hook: options.hook.clone(),
+            progress: ProgressTracker::new(options.progress.clone()),
+            _in_flight: options.metrics.in_flight(),
+        }
+    }
+
+    /// Closes the connection so that it is never reused in a dirty state.
+    fn abort(&mut self) {
+        self.connection.as_mut().set_state(ConnectionState::Closed);
+    }
+
+    fn into_decoder(self) -> D {
+        self.decoder
+    }
+
+    fn into_connection(self) -> C {
+        self.connection
+    }
+
+    /// Returns the size of the body of `head` if it is known and small enough to be drained.
+    fn drainable_size(&self, head: &Response<()>) -> Option<u64> {
+        if self.head_request || is_bodiless(head) {
+            Some(0)
+        } else if head.header().get_field("Transfer-Encoding").is_some() {
+            None
+        } else {
+            head.content_length().filter(|&n| n <= self.drain_threshold)
+        }
+    }
+
+    fn can_keep_alive(&self, head: &Response<()>) -> bool {
+        !self.close_connection && is_keep_alive(head)
+    }
+
+    fn header_too_large(&mut self) -> Error {
+        self.connection.as_mut().set_state(ConnectionState::Closed);
+        track!(Error::from(ErrorKind::HeaderTooLarge.cause(format!(
+            "The head of the response exceeds the limit of {} bytes",
+            self.max_header_size
+        ))))
+    }
+
+    fn too_many_header_fields(&mut self) -> Error {
+        self.connection.as_mut().set_state(ConnectionState::Closed);
+        track!(Error::from(ErrorKind::HeaderTooLarge.cause(format!(
+            "The header of the response has more than {} fields",
+            self.max_header_fields
+        ))))
+    }
+
+    /// `pending` is the number of the received bytes that have not been consumed by the decoder.
+    fn unexpected_eos(&mut self, pending: usize) -> Error {
+        self.connection.as_mut().set_state(ConnectionState::Closed);
+        if let (Some(_), Some(n)) = (&self.head, self.content_length) {
+            return track!(Error::from(ErrorKind::InvalidInput.cause(format!(
+                "The connection was closed after receiving {} bytes of the response body \
+                 (Content-Length: {})",
+                self.body_size + pending,
+                n
+            ))));
+        }
+        let message = if self.head.is_none() {
+            format!(
+                "The connection was closed after receiving {} bytes of the response head",
+                self.head_size + pending
+            )
+        } else {
+            let expected = match self.decoder.requiring_bytes() {
+                ByteCount::Finite(n) => {
+                    format!(
+                        "{} more bytes were expected",
+                        n.saturating_sub(pending as u64)
+                    )
+                }
+                _ => "more bytes were expected".to_owned(),
+            };
+            format!(
+                "The connection was closed after receiving {} bytes of the response body ({})",
+                self.body_size + pending,
+                expected
+            )
+        };
+        track!(Error::from(ErrorKind::UnexpectedEos.cause(message)))
+    }
+
+    /// `excess` is the number of the bytes received after the body.
+    fn content_length_exceeded(&mut self, excess: usize) -> Error {
+        self.connection.as_mut().set_state(ConnectionState::Closed);
+        track!(Error::from(ErrorKind::InvalidInput.cause(format!(
+            "The server sent {} bytes after the response body (Content-Length: {})",
+            excess,
+            self.content_length.unwrap_or(0)
+        ))))
+    }
+
+    fn proxy_authentication_required(&mut self, credentials_sent: bool) -> Error {
+        self.connection.as_mut().set_state(ConnectionState::Closed);
+        let reason = if credentials_sent {
+            "The proxy rejected the credentials"
+        } else {
+            "The proxy requires credentials"
+        };
+        track!(Error::from(
+            ErrorKind::ProxyAuthenticationRequired.cause(reason)
+        ))
+    }
+}
+impl<C, E, D> Future for Execute<C, E, D>
+where
+    C: AsMut<Connection>,
+    E: BodyEncode,
+    D: BodyDecode,
+{
+    type Item = Outcome<D::Item>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some(request) = self.request.take() {
+            self.hook.emit(&RequestEvent::Requested {
+                peer_addr: self.connection.as_mut().peer_addr(),
+                method: request.method().as_str(),
+                target: request.request_target().as_str(),
+                header: request.header(),
+            });
+            track!(self.encoder.start_encoding(request))?;
+            if let ByteCount::Finite(n) = self.encoder.requiring_bytes() {
+                self.progress.progress.send_total = Some(n);
+            }
+            self.connection.as_mut().increment_requests();
+        }
+
+        let peer_addr = self.connection.as_mut().peer_addr();
+        let mut do_close = false;
+        let mut response = None;
+        loop {
+            let stream = self.connection.as_mut().stream_mut();
+
+            // Encodes first so that the request is flushed even if the response is already readable
+            track!(self.encoder.encode_to_write_buf(stream.write_buf_mut()))?;
+            let unsent = stream.write_buf_mut().len();
+            track!(stream.execute_io())?;
+            self.progress.progress.sent += (unsent - stream.write_buf_mut().len()) as u64;
+            if !self.request_sent && self.encoder.is_idle() && stream.write_buf_mut().is_empty() {
+                self.request_sent = true;
+                trace::request_sent(peer_addr, self.progress.progress.sent);
+            }
+            if let Some(mut drain) = self.draining.take() {
+                let buf = stream.read_buf_mut();
+                let mut body = (&mut *buf).take(drain.remaining);
+                let drained = match drain.status {
+                    Some(ref mut status) => io::copy(&mut body, &mut status.body),
+                    None => io::copy(&mut body, &mut io::sink()),
+                };
+                drain.remaining -= track!(drained.map_err(Error::from))?;
+                if drain.remaining == 0 {
+                    // Bytes beyond the body mean that the connection is no longer in sync
+                    let recyclable = drain.keep_alive && buf.is_empty() && self.encoder.is_idle();
+                    let connection = self.connection.as_mut();
+                    connection.increment_completed_requests();
+                    if recyclable {
+                        connection.set_server_keepalive_timeout(drain.keep_alive_timeout);
+                        connection.set_state(ConnectionState::Recyclable);
+                    } else {
+                        connection.set_state(ConnectionState::Closed);
+                    }
+                    return drain.finish();
+                }
+                if stream.is_eos() {
+                    self.connection.as_mut().set_state(ConnectionState::Closed);
+                    return drain.finish();
+                }
+                self.draining = Some(drain);
+                if stream.would_block() {
+                    break;
+                }
+                continue;
+            }
+            if self.head.is_none() {
+                let buf = stream.read_buf_mut();
+                let before = buf.len();
+                if let Err(e) = self.head_decoder.decode_from_read_buf(buf) {
+                    if self.head_decoder.has_too_many_fields() {
+                        return Err(self.too_many_header_fields());
+                    }
+                    if self.head_size + before > self.max_header_size {
+                        return Err(self.header_too_large());
+                    }
+                    if *e.kind() == bytecodec::ErrorKind::UnexpectedEos {
+                        return Err(track!(self.unexpected_eos(before); e));
+                    }
+                    return Err(track!(Error::from(e)));
+                }
+                self.head_size += before - buf.len();
+                if self.head_size > self.max_header_size {
+                    return Err(self.header_too_large());
+                }
+                if self.head_decoder.is_idle() {
+                    let head = track!(self.head_decoder.finish_decoding())?;
+                    if head.header().fields().count() > self.max_header_fields {
+                        return Err(self.too_many_header_fields());
+                    }
+                    if let Err(e) = check_framing(&head.header()) {
+                        self.connection.as_mut().set_state(ConnectionState::Closed);
+                        return Err(track!(e));
+                    }
+                    let status_code = head.status_code().as_u16();
+                    if status_code < 200 && status_code != 101 {
+                        // Interim responses precede the final one (RFC 7231, section 6.2)
+                        self.hook.emit(&RequestEvent::Informational {
+                            peer_addr,
+                            status_code,
+                            reason_phrase: head.reason_phrase().as_str(),
+                            header: head.header(),
+                        });
+                        // The heads of interim responses share the limit with the final one
+                        continue;
+                    }
+                    if let Some(credentials_sent) = self.proxy_authorization {
+                        if head.status_code().as_u16() == 407 {
+                            let peer_addr = self.connection.as_mut().peer_addr();
+                            self.hook.emit(&RequestEvent::Responded {
+                                peer_addr,
+                                status_code: 407,
+                                reason_phrase: head.reason_phrase().as_str(),
+                                header: head.header(),
+                            });
+                            return Err(self.proxy_authentication_required(credentials_sent));
+                        }
+                    }
+                    if self.retryable && is_retryable_status(&head, &self.retry_statuses) {
+                        let peer_addr = self.connection.as_mut().peer_addr();
+                        self.hook.emit(&RequestEvent::Responded {
+                            peer_addr,
+                            status_code: head.status_code().as_u16(),
+                            reason_phrase: head.reason_phrase().as_str(),
+                            header: head.header(),
+                        });
+
+                        // Small bodies are drained for reusing the connection, and the others are discarded along with it
+                        match self.drainable_size(&head) {
+                            Some(n) if self.can_keep_alive(&head) => {
+                                self.draining = Some(Drain::new(n, &head, true, None));
+                                continue;
+                            }
+                            _ => {
+                                self.connection.as_mut().set_state(ConnectionState::Closed);
+                                return Ok(Async::Ready(Outcome::Retry(retry_after(&head))));
+                            }
+                        }
+                    }
+                    if self.error_for_status && head.status_code().as_u16() >= 400 {
+                        let peer_addr = self.connection.as_mut().peer_addr();
+                        self.hook.emit(&RequestEvent::Responded {
+                            peer_addr,
+                            status_code: head.status_code().as_u16(),
+                            reason_phrase: head.reason_phrase().as_str(),
+                            header: head.header(),
+                        });
+
+                        // Small bodies are drained into the error, and the others are discarded along with the connection
+                        let status = StatusBody::new(&head);
+                        match self.drainable_size(&head) {
+                            Some(n) => {
+                                let keep_alive = self.can_keep_alive(&head);
+                                self.draining =
+                                    Some(Drain::new(n, &head, keep_alive, Some(status)));
+                                continue;
+                            }
+                            None => {
+                                self.connection.as_mut().set_state(ConnectionState::Closed);
+                                return Err(status.into_error());
+                            }
+                        }
+                    }
+                    if let Some(range) = self.range {
+                        if let Err(e) = check_partial_content(&head, range) {
+                            self.connection.as_mut().set_state(ConnectionState::Closed);
+                            return Err(track!(e));
+                        }
+                    }
+                    let tunneled = self.connect_request && is_tunneled(&head);
+                    if is_bodiless(&head) || tunneled {
+                        // The body is empty regardless of the header (RFC 7230, section 3.3.3)
+                        track!(self.decoder.decode(&[][..], Eos::new(true)))?;
+                        self.progress.progress.receive_total = Some(0);
+                    } else {
+                        track!(self.decoder.initialize(&head.header()))?;
+                        self.progress.progress.receive_total = head.content_length();
+                        if !self.head_request
+                            && head.header().get_field("Transfer-Encoding").is_none()
+                        {
+                            self.content_length = head.content_length();
+                        }
+                    }
+                    self.head = Some(head);
+                }
+            }
+            if self.head.is_some() {
+                let buf = stream.read_buf_mut();
+                let before = buf.len();
+                let result = self.decoder.decode_from_read_buf(buf);
+                self.body_size += before - buf.len();
+                self.progress.progress.received = self.body_size as u64;
+                if let Err(e) = result {
+                    if *e.kind() == bytecodec::ErrorKind::UnexpectedEos {
+                        return Err(track!(self.unexpected_eos(before); e));
+                    }
+                    return Err(track!(Error::from(e)));
+                }
+                if self.decoder.is_idle() {
+                    if !self.encoder.is_idle() {
+                        do_close = true;
+                    }
+
+                    let body = track!(self.decoder.finish_decoding())?;
+                    let head = self.head.take().expect("never fails");
+                    let res = head.map_body(|()| body);
+                    // After `101 Switching Protocols` or a successful `CONNECT`, the connection no longer speaks HTTP/1.1
+                    let switched = res.status_code().as_u16() == 101
+                        || (self.connect_request && is_tunneled(&res));
+                    if switched || self.close_connection || !is_keep_alive(&res) {
+                        do_close = true;
+                    }
+                    // The excess bytes would be taken as (a part of) the next response on the connection
+                    let excess = stream.read_buf_mut().len();
+                    if self.content_length.is_some() && excess > 0 {
+                        return Err(self.content_length_exceeded(excess));
+                    }
+                    response = Some(res);
+                    self.progress.report();
+                    break;
+                }
+            }
+            self.progress.report();
+
+            if stream.is_eos() {
+                return Err(self.unexpected_eos(0));
+            }
+            if stream.would_block() {
+                break;
+            }
+        }
+        if let Some(response) = response {
+            self.connection.as_mut().increment_completed_requests();
+            self.hook.emit(&RequestEvent::Responded {
+                peer_addr: self.connection.as_mut().peer_addr(),
+                status_code: response.status_code().as_u16(),
+                reason_phrase: response.reason_phrase().as_str(),
+                header: response.header(),
+            });
+            if do_close {
+                self.connection.as_mut().set_state(ConnectionState::Closed);
+            } else {
+                let connection = self.connection.as_mut();
+                connection.set_server_keepalive_timeout(keep_alive_timeout(&response));
+                connection.set_state(ConnectionState::Recyclable);
+            }
+            let peer_addr = self.connection.as_mut().peer_addr();
+            Ok(Async::Ready(Outcome::Response(response, peer_addr)))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+/// Decoder of response heads that rejects heads with too many header fields while they are still being received.
+///
+/// The lines of the head are counted as the bytes are consumed,
+/// so that a head consisting of a huge number of tiny fields is not buffered until its end.
+#[derive(Debug)]
+struct HeadDecoder {
+    inner: ResponseDecoder<NoBodyDecoder>,
+    lines: usize, // The number of the line breaks consumed so far, including the one of the start line
+    max_fields: usize,
+}
+impl HeadDecoder {
+    fn new(options: DecodeOptions, max_fields: usize) -> Self {
+        HeadDecoder {
+            inner: ResponseDecoder::with_options(NoBodyDecoder, options),
+            lines: 0,
+            max_fields,
+        }
+    }
+
+    fn has_too_many_fields(&self) -> bool {
+        // The start line and `max_fields` fields have been consumed, yet the head does not end
+        self.lines > self.max_fields.saturating_add(1) && !self.inner.is_idle()
+    }
+}
+impl Decode for HeadDecoder {
+    type Item = Response<()>;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        let size = track!(self.inner.decode(buf, eos))?;
+        self.lines += buf[..size].iter().filter(|&&b| b == b'\n').count();
+        track_assert!(
+            !self.has_too_many_fields(),
+            bytecodec::ErrorKind::InvalidInput,
+            "Too many header fields"
+        );
+        Ok(size)
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        self.lines = 0;
+        track!(self.inner.finish_decoding())
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.inner.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+}
+
+/// The state of draining the body of a response discarded for retrying (or failed by `error_for_status`).
+#[derive(Debug)]
+struct Drain {
+    remaining: u64,
+    keep_alive: bool,
+    retry_after: Option<Duration>,
+    keep_alive_timeout: Option<Duration>,
+    status: Option<StatusBody>, // `Some` if the response is turned into a `StatusError`
+}
+impl Drain {
+    fn new(
+        remaining: u64,
+        head: &Response<()>,
+        keep_alive: bool,
+        status: Option<StatusBody>,
+    ) -> Self {
+        Drain {
+            remaining,
+            keep_alive,
+            retry_after: retry_after(head),
+            keep_alive_timeout: keep_alive_timeout(head),
+            status,
+        }
+    }
+
+    fn finish<T>(self) -> Poll<Outcome<T>, Error> {
+        match self.status {
+            Some(status) => Err(status.into_error()),
+            None => Ok(Async::Ready(Outcome::Retry(self.retry_after))),
+        }
+    }
+}
+
+/// The status and the (possibly truncated) body of a response failed by `error_for_status`.
+///
+/// At most `MAX_CAPTURE_SIZE` bytes of the body are kept.
+#[derive(Debug)]
+struct StatusBody {
+    status_code: u16,
+    reason_phrase: String,
+    body: Vec<u8>,
+}
+impl StatusBody {
+    fn new(head: &Response<()>) -> Self {
+        StatusBody {
+            status_code: head.status_code().as_u16(),
+            reason_phrase: head.reason_phrase().as_str().to_owned(),
+            body: Vec::new(),
+        }
+    }
+
+    fn into_error(self) -> Error {
+        let cause = StatusError::new(self.status_code, self.reason_phrase, self.body);
+        track!(Error::from(ErrorKind::StatusError.cause(cause)))
+    }
+}
+impl Write for StatusBody {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let room = MAX_CAPTURE_SIZE.saturating_sub(self.body.len());
+        self.body
+            .extend_from_slice(&buf[..cmp::min(buf.len(), room)]);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum Outcome<T> {
+    Response(Response<T>, SocketAddr),
+    Retry(Option<Duration>),
+}
+
+/// Checks that the response to a range request contains the requested range.
+///
+/// Only `2xx` responses are checked, so the others (e.g., `416 Range Not Satisfiable`) are returned as usual.
+fn check_partial_content<T>(
+    response: &Response<T>,
+    (start, end): (u64, Option<u64>),
+) -> Result<()> {
+    if response.status_class() != Some(StatusClass::Success) {
+        return Ok(());
+    }
+    let status_code = response.status_code().as_u16();
+    if status_code != 206 {
+        track_panic!(
+            ErrorKind::InvalidInput,
+            "The server ignored the Range header and responded with {} {}",
+            status_code,
+            response.reason_phrase().as_str()
+        );
+    }
+    let matched = response
+        .content_range()
+        .is_some_and(|r| r.first == start && end.is_none_or(|end| r.last <= end));
+    if !matched {
+        track_panic!(
+            ErrorKind::InvalidInput,
+            "The Content-Range of the response ({:?}) does not match the requested range (bytes={}-{})",
+            response.header().get_field("Content-Range"),
+            start,
+            end.map(|n| n.to_string()).unwrap_or_default()
+        );
+    }
+    Ok(())
+}
+
+/// Rejects the header that frames the body ambiguously,
+/// since intermediaries may interpret it differently (i.e., response smuggling).
+///
+/// That is, `Content-Length` must not coexist with `Transfer-Encoding`,
+/// and multiple `Content-Length` values must be identical (RFC 7230, section 3.3.3).
+pub(crate) fn check_framing(header: &Header) -> Result<()> {
+    let mut content_length = None;
+    let mut transfer_encoding = false;
+    for field in header.fields() {
+        if field.name().eq_ignore_ascii_case("Transfer-Encoding") {
+            transfer_encoding = true;
+        } else if field.name().eq_ignore_ascii_case("Content-Length") {
+            for value in field.value().split(',') {
+                let n: u64 = track!(value
+                    .trim()
+                    .parse()
+                    .map_err(|e| Error::from(ErrorKind::InvalidInput.cause(e))); value)?;
+                track_assert!(
+                    content_length.is_none_or(|m| m == n),
+                    ErrorKind::InvalidInput,
+                    "Conflicting Content-Length values: {} and {}",
+                    content_length.unwrap_or(0),
+                    n
+                );
+                content_length = Some(n);
+            }
+        }
+    }
+    track_assert!(
+        !(transfer_encoding && content_length.is_some()),
+        ErrorKind::InvalidInput,
+        "The response has both Content-Length and Transfer-Encoding"
+    );
+    Ok(())
+}
+
+/// Returns the delay indicated by `Retry-After` header of the response.
+fn retry_after<T>(response: &Response<T>) -> Option<Duration> {
+    let header = response.header();
+    let value = header.get_field("Retry-After")?.trim();
+    if let Ok(seconds) = value.parse() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = date::parse_http_date(value)?;
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or_else(|_| Duration::from_secs(0)),
+    )
+}
+
+/// Returns the earlier of `deadline` and the one derived from `timeout`.
+fn earliest_deadline(
+    now: Instant,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+) -> Option<Instant> {
+    match (timeout.map(|timeout| now + timeout), deadline) {
+        (Some(a), Some(b)) => Some(cmp::min(a, b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Returns `true` if the request should be re-issued because of the status code of the response.
+fn is_retryable_status<T>(response: &Response<T>, retry_statuses: &[u16]) -> bool {
+    retry_statuses.contains(&response.status_code().as_u16())
+}
+
+/// Returns `true` if the error has occurred before sending the request to the server.
+pub(crate) fn is_connect_error(e: &Error) -> bool {
+    match *e.kind() {
+        ErrorKind::Dns | ErrorKind::ConnectionRefused => true,
+        ErrorKind::Timeout => e.timeout_phase() == Some(TimeoutPhase::Connect),
+        _ => false,
+    }
+}
+
+/// Makes a copy of the head of `request` that has the given body.
+fn copy_request<T, U>(request: &Request<T>, body: U) -> Request<U> {
+    let mut copy = Request::new(
+        request.method(),
+        request.request_target(),
+        request.http_version(),
+        body,
+    );
+    for field in request.header().fields() {
+        copy.header_mut().add_field(field);
+    }
+    copy
+}
+
+/// Returns the `Content-Type` implied by the encoder type `E`.
+/// Makes a `HeaderField` after validating it.
+///
+/// Unlike `HeaderField::new`, spaces and tabs are allowed in the value (e.g., `Bearer foo`)
+/// as the field-value of RFC 7230 permits them.
+fn new_header_field<'n, 'v>(name: &'n str, value: &'v str) -> Result<HeaderField<'n, 'v>> {
+    track!(HeaderField::new(name, ""); name)?;
+    let is_field_value = value
+        .bytes()
+        .all(|b| b == b' ' || b == b'\t' || (0x21..=0x7E).contains(&b) || b >= 0x80);
+    track_assert!(is_field_value, ErrorKind::InvalidInput; name, value);
+    Ok(unsafe { HeaderField::new_unchecked(name, value) })
+}
+
+/// Returns `true` if the response never has a body because of its status code (i.e., `1xx`, `204` or `304`).
+pub(crate) fn is_bodiless<T>(response: &Response<T>) -> bool {
+    let status_code = response.status_code().as_u16();
+    status_code < 200 || status_code == 204 || status_code == 304
+}
+
+/// Returns `true` if the response to a `CONNECT` request means that the tunnel has been established.
+///
+/// The body of such a response is always empty,
+/// and the subsequent bytes belong to the tunneled protocol (RFC 7230, section 3.3.3).
+fn is_tunneled<T>(response: &Response<T>) -> bool {
+    let status_code = response.status_code().as_u16();
+    (200..300).contains(&status_code)
+}
+
+/// Returns `true` if the connection used for receiving the response can be kept alive.
+pub(crate) fn is_keep_alive<T>(response: &Response<T>) -> bool {
+    match response.http_version() {
+        HttpVersion::V1_0 => response.header().get_field("Connection") == Some("keep-alive"),
+        HttpVersion::V1_1 => response.header().get_field("Connection") != Some("close"),
+    }
+}
+
+/// Returns the idle timeout of the connection advertised by `Keep-Alive` header (e.g., `timeout=5, max=100`).
+fn keep_alive_timeout<T>(response: &Response<T>) -> Option<Duration> {
+    let header = response.header();
+    let value = header.get_field("Keep-Alive")?;
+    value.split(',').find_map(|param| {
+        let mut kv = param.splitn(2, '=');
+        let name = kv.next()?.trim();
+        let value = kv.next()?.trim();
+        if name.eq_ignore_ascii_case("timeout") {
+            value.parse().ok().map(Duration::from_secs)
+        } else {
+            None
+        }
+    })
+}
+
+/// A body decoder that copies the bytes consumed by the inner decoder to a writer.
+#[derive(Debug)]
+struct Capture<D> {
+    inner: D,
+    tee: Option<Tee>,
+}
+impl<D> Capture<D> {
+    fn new(inner: D, tee: Option<Tee>) -> Self {
+        Capture { inner, tee }
+    }
+}
+impl<D: Decode> Decode for Capture<D> {
+    type Item = D::Item;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        let size = track!(self.inner.decode(buf, eos))?;
+        if let Some(ref tee) = self.tee {
+            let mut writer = tee.0.lock().expect("never fails");
+            track!(writer.write_all(&buf[..size]).map_err(|e| {
+                bytecodec::ErrorKind::Other.cause(e)
+            }); "Cannot write the response body to the tee writer")?;
+        }
+        Ok(size)
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        track!(self.inner.finish_decoding())
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.inner.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+}
+impl<D: BodyDecode> BodyDecode for Capture<D> {
+    fn initialize(&mut self, header: &Header) -> bytecodec::Result<()> {
+        track!(self.inner.initialize(header))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fibers_global;
+    use futures::future::{self, FutureResult};
+    use futures::Future;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime};
+    use url::Url;
+
+    use super::{keep_alive_timeout, Backoff, Execute, Outcome, RequestOptions};
+    use bytecodec::bytes::{BytesEncoder, RemainingBytesDecoder, Utf8Decoder};
+    use bytecodec::DecodeExt;
+    use connection::{
+        AcquireConnection, ConnectionPool, ConnectionPoolBuilder, ConnectionState, Mock, Oneshot,
+    };
+    use gzip;
+    use httpcodec::{
+        BodyDecoder, BodyEncoder, HeaderField, HttpVersion, Method, Request, RequestTarget,
+        ResponseDecoder,
+    };
+    use resolver::Resolve;
+    use rng::Rng;
+    use {
+        Client, Error, ErrorKind, Progress, RequestEvent, ResponseExt, StatusError, TargetForm,
+        TimeoutPhase,
+    };
+
+    #[test]
+    fn error_for_status_works() {
+        let addr = serve("HTTP/1.1 404 Not Found\r\nContent-Length: 3\r\n\r\nfoo");
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let mut client = Client::new(Oneshot);
+        let future = client.request(&url).error_for_status(true).get();
+        let e = fibers_global::execute(future).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::StatusError);
+
+        let cause = e.concrete_cause::<StatusError>().unwrap();
+        assert_eq!(cause.status_code(), 404);
+        assert_eq!(cause.body(), b"foo");
+        #[cfg(feature = "metrics")]
+        assert_eq!(client.metrics().requests("GET"), 1);
+        #[cfg(feature = "metrics")]
+        assert_eq!(client.metrics().failed_requests("GET"), 1);
+        #[cfg(feature = "metrics")]
+        assert_eq!(client.metrics().request_errors(ErrorKind::StatusError), 1);
+        #[cfg(feature = "metrics")]
+        assert_eq!(client.metrics().request_errors(ErrorKind::Timeout), 0);
+    }
+
+    #[test]
+    fn timed_works() {
+        let mock = Mock::new();
+        mock.push_response("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo");
+        let url = Url::parse("http://example.com/").unwrap();
+        let mut client = Client::new(mock);
+
+        // The time is measured from the creation of the future
+        let future = client.request(&url).get().timed();
+        thread::sleep(Duration::from_millis(50));
+        let timed = fibers_global::execute(future).unwrap();
+        assert!(timed.elapsed() >= Duration::from_millis(50));
+        assert_eq!(timed.get_ref().status_code().as_u16(), 200);
+        assert_eq!(timed.into_inner().body(), b"foo");
+    }
+
+    #[test]
+    fn with_peer_addr_works() {
+        let addr = serve("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo");
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let mut client = Client::new(Oneshot);
+        let future = client.request(&url).get().with_peer_addr();
+        let (response, peer_addr) = fibers_global::execute(future).unwrap();
+        assert_eq!(response.body(), b"foo");
+        assert_eq!(peer_addr, addr);
+    }
+
+    #[test]
+    fn trace_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = Vec::new();
+            let mut chunk = [0; 1024];
+            while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut chunk).unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            String::from_utf8(buf).unwrap()
+        });
+
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let mut client = Client::new(Oneshot);
+        let response = fibers_global::execute(client.request(&url).trace()).unwrap();
+        assert_eq!(response.status_code().as_u16(), 200);
+
+        let head = handle.join().unwrap();
+        assert!(head.starts_with("TRACE / HTTP/1.1\r\n"));
+        assert!(!head.to_lowercase().contains("content-length"));
+    }
+
+    #[test]
+    fn on_event_works() {
+        let addr = serve("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo");
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut client = Client::new(Oneshot);
+        {
+            let events = events.clone();
+            #[cfg(feature = "metrics")]
+            let metrics = client.metrics().clone();
+            client.on_event(move |event| {
+                let event = match *event {
+                    RequestEvent::Requested { method, .. } => {
+                        #[cfg(feature = "metrics")]
+                        assert_eq!(metrics.in_flight_requests(), 1);
+                        method.to_owned()
+                    }
+                    RequestEvent::Informational { status_code, .. }
+                    | RequestEvent::Responded { status_code, .. } => status_code.to_string(),
+                    RequestEvent::Failed { .. } => "failed".to_owned(),
+                };
+                events.lock().unwrap().push(event);
+            });
+        }
+        fibers_global::execute(client.request(&url).get()).unwrap();
+        assert_eq!(*events.lock().unwrap(), ["GET", "200"]);
+        #[cfg(feature = "metrics")]
+        assert_eq!(client.metrics().in_flight_requests(), 0);
+    }
+
+    #[test]
+    fn informational_response_works() {
+        let url = Url::parse("http://example.com/").unwrap();
+        let mock = Mock::new();
+        mock.push_response(
+            "HTTP/1.1 103 Early Hints\r\nLink: </style.css>; rel=preload\r\n\r\n\
+             HTTP/1.1 100 Continue\r\n\r\n\
+             HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo",
+        );
+        let mut client = Client::new(mock.clone());
+        let hints = Arc::new(Mutex::new(Vec::new()));
+        {
+            let hints = hints.clone();
+            client.on_event(move |event| {
+                if let RequestEvent::Informational {
+                    status_code,
+                    ref header,
+                    ..
+                } = *event
+                {
+                    let link = header.get_field("Link").map(str::to_owned);
+                    hints.lock().unwrap().push((status_code, link));
+                }
+            });
+        }
+
+        let response = fibers_global::execute(client.request(&url).get()).unwrap();
+        assert_eq!(response.status_code().as_u16(), 200);
+        assert_eq!(response.body(), b"foo");
+        assert_eq!(
+            *hints.lock().unwrap(),
+            [
+                (103, Some("</style.css>; rel=preload".to_owned())),
+                (100, None)
+            ]
+        );
+    }
+
+    #[test]
+    fn endless_informational_responses_work() {
+        let url = Url::parse("http://example.com/").unwrap();
+        let mock = Mock::new();
+        let mut response = "HTTP/1.1 100 Continue\r\n\r\n".repeat(10);
+        response.push_str("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        mock.push_response(response.clone());
+        mock.push_response(response);
+        let mut client = Client::new(mock.clone());
+
+        // The heads of the interim responses are counted towards the limit
+        let future = client.request(&url).max_header_size(128).get();
+        let e = fibers_global::execute(future).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::HeaderTooLarge);
+
+        let future = client.request(&url).max_header_size(512).get();
+        assert!(fibers_global::execute(future).is_ok());
+    }
+
+    #[test]
+    fn compress_request_works() {
+        let addr = serve_decompress(4);
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let mut client = Client::new(Oneshot);
+        let json = br#"{"id":1,"name":"foo"}"#.repeat(100);
+        let encoding = |response: &::httpcodec::Response<Vec<u8>>| {
+            response
+                .header()
+                .get_field("X-Content-Encoding")
+                .map(str::to_owned)
+        };
+
+        let future = client
+            .request(&url)
+            .compress_request(100)
+            .post(json.clone());
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(encoding(&response).as_ref().map(|s| &s[..]), Some("gzip"));
+        assert_eq!(response.into_body(), json);
+
+        // Too small
+        let future = client
+            .request(&url)
+            .compress_request(100)
+            .put(b"abc".to_vec());
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(encoding(&response), None);
+        assert_eq!(response.into_body(), b"abc");
+
+        // `Content-Encoding` is given by the caller
+        let future = client
+            .request(&url)
+            .header_field("Content-Encoding", "identity")
+            .compress_request(100)
+            .post(json.clone());
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(
+            encoding(&response).as_ref().map(|s| &s[..]),
+            Some("identity")
+        );
+        assert_eq!(response.into_body(), json);
+
+        let future = client
+            .request(&url)
+            .compress_request(100)
+            .call("PATCH", json.clone());
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(encoding(&response).as_ref().map(|s| &s[..]), Some("gzip"));
+        assert_eq!(response.into_body(), json);
+    }
+
+    #[test]
+    fn request_timeout_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let mut client = Client::new(Oneshot);
+        let future = client
+            .request(&url)
+            .timeout(Duration::from_millis(50))
+            .get();
+        let e = fibers_global::execute(future).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::Timeout);
+        assert_eq!(e.timeout_phase(), Some(TimeoutPhase::Request));
+        #[cfg(feature = "metrics")]
+        assert_eq!(client.metrics().request_errors(ErrorKind::Timeout), 1);
+    }
+
+    #[test]
+    fn deadline_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        let mut client = Client::new(Oneshot);
+
+        let future = client
+            .request(&url)
+            .deadline(Instant::now() + Duration::from_millis(50))
+            .timeout(Duration::from_secs(60))
+            .get();
+        let e = fibers_global::execute(future).err().unwrap();
+        assert_eq!(e.timeout_phase(), Some(TimeoutPhase::Request));
+
+        // Already past
+        let future = client.request(&url).deadline(Instant::now()).get();
+        let e = fibers_global::execute(future).err().unwrap();
+        assert_eq!(e.timeout_phase(), Some(TimeoutPhase::Request));
+    }
+
+    #[test]
+    fn no_host_works() {
+        let addr = serve_echo();
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let mut client = Client::new(Oneshot);
+        let response = fibers_global::execute(client.request(&url).get()).unwrap();
+        let head = String::from_utf8(response.into_body()).unwrap();
+        assert!(head.contains(&format!("\r\nHost: {}\r\n", addr)));
+
+        let url = Url::parse(&format!("http://{}/", serve_echo())).unwrap();
+        let future = client.request(&url).no_host().get();
+        let head = String::from_utf8(fibers_global::execute(future).unwrap().into_body()).unwrap();
+        assert!(!head.contains("Host:"));
+
+        let url = Url::parse(&format!("http://{}/", serve_echo())).unwrap();
+        let future = client
+            .request(&url)
+            .header_field("Host", "example.com")
+            .get();
+        let head = String::from_utf8(fibers_global::execute(future).unwrap().into_body()).unwrap();
+        assert!(head.contains("\r\nHost: example.com\r\n"));
+        assert_eq!(head.matches("Host:").count(), 1);
+    }
+
+    #[test]
+    fn head_content_length_works() {
+        let addr = serve("HTTP/1.1 200 OK\r\nContent-Length: 1234\r\n\r\n");
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let mut client = Client::new(Oneshot);
+        let response = fibers_global::execute(client.request(&url).head()).unwrap();
+        assert_eq!(response.content_length(), Some(1234));
+    }
+
+    #[test]
+    fn tee_works() {
+        #[derive(Clone)]
+        struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+        impl Write for SharedBuf {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let addr =
+            serve("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nfoo\r\n0\r\n\r\n");
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let mut client = Client::new(Oneshot);
+        let buf = SharedBuf(Arc::new(Mutex::new(Vec::new())));
+        let future = client.request(&url).tee(buf.clone()).get();
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(response.body(), b"foo");
+        assert_eq!(&buf.0.lock().unwrap()[..], &b"3\r\nfoo\r\n0\r\n\r\n"[..]);
+
+        // Writer errors
+        struct BrokenWriter;
+        impl Write for BrokenWriter {
+            fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("broken"))
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        let addr = serve("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo");
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let future = client.request(&url).tee(BrokenWriter).get();
+        let e = fibers_global::execute(future).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::Io);
+    }
+
+    #[test]
+    fn headers_works() {
+        let mock = Mock::new();
+        mock.push_response("HTTP/1.1 204 No Content\r\n\r\n");
+        let url = Url::parse("http://example.com/").unwrap();
+        let mut client = Client::new(mock.clone());
+        let future = client
+            .request(&url)
+            .header_field("A", "1")
+            .headers(vec![("C", "3".to_owned()), ("B", "2".to_owned())])
+            .head();
+        fibers_global::execute(future).unwrap();
+        assert_eq!(
+            mock.requests(),
+            vec![b"HEAD / HTTP/1.1\r\nA: 1\r\nC: 3\r\nB: 2\r\nHost: example.com\r\nContent-Length: 0\r\n\r\n".to_vec()]
+        );
+    }
+
+    #[test]
+    fn default_headers_works() {
+        let url = Url::parse(&format!("http://{}/", serve_echo())).unwrap();
+        let mut client = Client::new(Oneshot);
+        client.default_headers(vec![("X-Api-Key", "foo"), ("Accept", "text/plain")]);
+        let future = client.request(&url).header_field("accept", "*/*").get();
+        let head = String::from_utf8(fibers_global::execute(future).unwrap().into_body()).unwrap();
+        assert!(head.contains("\r\nX-Api-Key: foo\r\n"));
+        assert!(head.contains("\r\naccept: */*\r\n"));
+        assert!(!head.contains("text/plain"));
+    }
+
+    #[test]
+    fn client_builder_works() {
+        let url = Url::parse(&format!("http://{}/foo/", serve_echo())).unwrap();
+        let mut client = Client::builder()
+            .base_url(url)
+            .user_agent("foo/0.1")
+            .header_field("X-Api-Key", "bar")
+            .finish(Oneshot);
+        let future = client.request_path("baz").unwrap().get();
+        let head = String::from_utf8(fibers_global::execute(future).unwrap().into_body()).unwrap();
+        assert!(head.starts_with("GET /foo/baz HTTP/1.1\r\n"));
+        assert!(head.contains("\r\nUser-Agent: foo/0.1\r\n"));
+        assert!(head.contains("\r\nX-Api-Key: bar\r\n"));
+    }
+
+    #[test]
+    fn proxy_works() {
+        let proxy = Url::parse(&format!("http://foo:bar@{}/", serve_echo())).unwrap();
+        let url = Url::parse("http://example.com/baz?qux").unwrap();
+        let mut client = Client::new(Oneshot);
+        client.proxy(proxy).unwrap();
+        let future = client.request(&url).get();
+        let head = String::from_utf8(fibers_global::execute(future).unwrap().into_body()).unwrap();
+        assert!(head.starts_with("GET http://example.com/baz?qux HTTP/1.1\r\n"));
+        assert!(head.contains("\r\nHost: example.com\r\n"));
+        assert!(head.contains("\r\nProxy-Authorization: Basic Zm9vOmJhcg==\r\n"));
+
+        let proxy = Url::parse(&format!(
+            "http://{}/",
+            serve("HTTP/1.1 407 Proxy Authentication Required\r\nContent-Length: 0\r\n\r\n")
+        ))
+        .unwrap();
+        let mut client = Client::new(Oneshot);
+        client.proxy(proxy).unwrap();
+        let e = fibers_global::execute(client.request(&url).get())
+            .err()
+            .unwrap();
+        assert_eq!(*e.kind(), ErrorKind::ProxyAuthenticationRequired);
+    }
+
+    #[test]
+    fn request_target_works() {
+        let cases = [
+            ("/foo bar", "/foo%20bar"),
+            ("/\u{3042}", "/%E3%81%82"),
+            ("/a\"b<c>d`e{f}g", "/a%22b%3Cc%3Ed%60e%7Bf%7Dg"),
+            ("/foo?a b=c\"d", "/foo?a%20b=c%22d"),
+            ("/foo?q=\u{3042}#bar baz", "/foo?q=%E3%81%82"),
+            ("/a:b@c;d=e,f$g!h'i(j)k*l+m", "/a:b@c;d=e,f$g!h'i(j)k*l+m"),
+            ("/%2F%20?x=%26", "/%2F%20?x=%26"),
+        ];
+        for &(path, target) in &cases {
+            let url = Url::parse(&format!("http://{}{}", serve_echo(), path)).unwrap();
+            let mut client = Client::new(Oneshot);
+            let future = client.request(&url).get();
+            let response = fibers_global::execute(future).unwrap();
+            let head = String::from_utf8(response.into_body()).unwrap();
+            let request_line = format!("GET {} HTTP/1.1\r\n", target);
+            assert!(head.starts_with(&request_line), "{:?}: {:?}", path, head);
+        }
+    }
+
+    #[test]
+    fn target_form_works() {
+        let url = Url::parse("http://example.com/foo?bar#baz").unwrap();
+        let mut client = Client::new(Mock::new());
+        let cases = [
+            ("GET", None, "/foo?bar"),
+            ("CONNECT", None, "example.com:80"),
+            ("GET", Some(TargetForm::Origin), "/foo?bar"),
+            (
+                "GET",
+                Some(TargetForm::Absolute),
+                "http://example.com/foo?bar",
+            ),
+            ("GET", Some(TargetForm::Authority), "example.com:80"),
+            ("OPTIONS", Some(TargetForm::Asterisk), "*"),
+        ];
+        for &(method, form, target) in &cases {
+            let mut builder = client.request(&url);
+            if let Some(form) = form {
+                builder = builder.target_form(form);
+            }
+            let request = builder.build(method, Vec::new()).unwrap();
+            assert_eq!(request.request_target().as_str(), target);
+        }
+
+        let url = Url::parse("http://[::1]:3000/").unwrap();
+        let request = client.request(&url).build("CONNECT", Vec::new()).unwrap();
+        assert_eq!(request.request_target().as_str(), "[::1]:3000");
+    }
+
+    #[test]
+    fn mock_works() {
+        let mock = Mock::new();
+        mock.push_response("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo");
+        let url = Url::parse("http://example.com/bar").unwrap();
+        let mut client = Client::new(mock.clone());
+        let response = fibers_global::execute(client.request(&url).post(b"baz".to_vec())).unwrap();
+        assert_eq!(response.body(), b"foo");
+        assert_eq!(
+            mock.requests(),
+            vec![b"POST /bar HTTP/1.1\r\nContent-Type: application/octet-stream\r\nHost: example.com\r\nContent-Length: 3\r\n\r\nbaz".to_vec()]
+        );
+
+        // No response is queued
+        let e = fibers_global::execute(client.request(&url).get())
+            .err()
+            .unwrap();
+        assert_eq!(*e.kind(), ErrorKind::UnexpectedEos);
+    }
+
+    #[test]
+    fn truncated_response_works() {
+        let cases = [
+            (
+                "HTTP/1.1 200 OK\r\nContent-Le",
+                "after receiving 27 bytes of the response head",
+            ),
+            (
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nfoo\r\n",
+                "after receiving 8 bytes of the response body",
+            ),
+        ];
+        for &(response, message) in &cases {
+            let mock = Mock::new();
+            mock.push_response(response);
+            let addr = "127.0.0.1:80".parse().unwrap();
+            let mut connection = mock.clone().acquire_connection(addr).wait().unwrap();
+
+            let method = Method::new("GET").unwrap();
+            let target = RequestTarget::new("/").unwrap();
+            let request = Request::new(method, target, HttpVersion::V1_1, Vec::new());
+            let execute = Execute::new(
+                &mut connection,
+                request,
+                BodyEncoder::new(BytesEncoder::new()),
+                BodyDecoder::new(RemainingBytesDecoder::new()),
+                false,
+                &RequestOptions::default(),
+            );
+            let e = execute.wait().err().unwrap();
+            assert_eq!(*e.kind(), ErrorKind::UnexpectedEos);
+            assert!(e.to_string().contains(message), "{}", e);
+            assert_eq!(connection.state(), ConnectionState::Closed);
+        }
+    }
+
+    #[test]
+    fn content_length_mismatch_works() {
+        let cases = [
+            (
+                "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nfoo",
+                "after receiving 3 bytes of the response body (Content-Length: 10)",
+            ),
+            (
+                "HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoobar",
+                "sent 3 bytes after the response body (Content-Length: 3)",
+            ),
+            (
+                "HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfooHTTP/1.1 200 OK\r\n\r\n",
+                "sent 19 bytes after the response body (Content-Length: 3)",
+            ),
+        ];
+        for &(response, message) in &cases {
+            let mock = Mock::new();
+            mock.push_response(response);
+            let addr = "127.0.0.1:80".parse().unwrap();
+            let mut connection = mock.clone().acquire_connection(addr).wait().unwrap();
+
+            let method = Method::new("GET").unwrap();
+            let target = RequestTarget::new("/").unwrap();
+            let request = Request::new(method, target, HttpVersion::V1_1, Vec::new());
+            let execute = Execute::new(
+                &mut connection,
+                request,
+                BodyEncoder::new(BytesEncoder::new()),
+                BodyDecoder::new(RemainingBytesDecoder::new()),
+                false,
+                &RequestOptions::default(),
+            );
+            let e = execute.wait().err().unwrap();
+            assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+            assert!(e.to_string().contains(message), "{}", e);
+            assert_eq!(connection.state(), ConnectionState::Closed);
+        }
+    }
+
+    #[test]
+    fn conflicting_framing_works() {
+        let cases = [
+            (
+                "Content-Length: 3\r\nTransfer-Encoding: chunked\r\n",
+                "both Content-Length and Transfer-Encoding",
+            ),
+            (
+                "transfer-encoding: chunked\r\ncontent-length: 3\r\n",
+                "both Content-Length and Transfer-Encoding",
+            ),
+            (
+                "Content-Length: 3\r\nContent-Length: 4\r\n",
+                "Conflicting Content-Length values: 3 and 4",
+            ),
+            (
+                "Content-Length: 3, 30\r\n",
+                "Conflicting Content-Length values: 3 and 30",
+            ),
+            ("Content-Length: 3\r\nContent-Length: foo\r\n", "foo"),
+        ];
+        for &(fields, message) in &cases {
+            let mock = Mock::new();
+            mock.push_response(format!(
+                "HTTP/1.1 200 OK\r\n{}\r\n3\r\nfoo\r\n0\r\n\r\n",
+                fields
+            ));
+            let addr = "127.0.0.1:80".parse().unwrap();
+            let mut connection = mock.clone().acquire_connection(addr).wait().unwrap();
+
+            let method = Method::new("GET").unwrap();
+            let target = RequestTarget::new("/").unwrap();
+            let request = Request::new(method, target, HttpVersion::V1_1, Vec::new());
+            let execute = Execute::new(
+                &mut connection,
+                request,
+                BodyEncoder::new(BytesEncoder::new()),
+                BodyDecoder::new(RemainingBytesDecoder::new()),
+                false,
+                &RequestOptions::default(),
+            );
+            let e = execute.wait().err().unwrap();
+            assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+            assert!(e.to_string().contains(message), "{}", e);
+            assert_eq!(connection.state(), ConnectionState::Closed);
+        }
+
+        // Identical values are allowed
+        let mock = Mock::new();
+        mock.push_response(
+            "HTTP/1.1 200 OK\r\nContent-Length: 3\r\nContent-Length: 3, 3\r\n\r\nfoo",
+        );
+        let url = Url::parse("http://example.com/").unwrap();
+        let mut client = Client::new(mock);
+        let response = fibers_global::execute(client.request(&url).get()).unwrap();
+        assert_eq!(response.body(), b"foo");
+    }
+
+    #[test]
+    fn connection_state_works() {
+        let cases = [
+            ("HTTP/1.1 200 OK\r\n", ConnectionState::Recyclable),
+            (
+                "HTTP/1.1 200 OK\r\nConnection: close\r\n",
+                ConnectionState::Closed,
+            ),
+            ("HTTP/1.0 200 OK\r\n", ConnectionState::Closed),
+            (
+                "HTTP/1.0 200 OK\r\nConnection: keep-alive\r\n",
+                ConnectionState::Recyclable,
+            ),
+        ];
+        for &(head, state) in &cases {
+            let mut mock = Mock::new();
+            mock.push_response(format!("{}Content-Length: 3\r\n\r\nfoo", head));
+            let addr = "127.0.0.1:80".parse().unwrap();
+            let mut connection = mock.acquire_connection(addr).wait().unwrap();
+
+            let method = Method::new("GET").unwrap();
+            let target = RequestTarget::new("/").unwrap();
+            let request = Request::new(method, target, HttpVersion::V1_1, Vec::new());
+            let execute = Execute::new(
+                &mut connection,
+                request,
+                BodyEncoder::new(BytesEncoder::new()),
+                BodyDecoder::new(RemainingBytesDecoder::new()),
+                false,
+                &RequestOptions::default(),
+            );
+            match execute.wait().unwrap() {
+                Outcome::Response(response, _) => assert_eq!(response.body(), b"foo"),
+                Outcome::Retry(_) => panic!(),
+            }
+            assert_eq!(connection.state(), state, "{:?}", head);
+            assert_eq!(
+                mock.requests(),
+                vec![b"GET / HTTP/1.1\r\nContent-Length: 0\r\n\r\n".to_vec()]
+            );
+        }
+    }
+
+    #[test]
+    fn close_connection_works() {
+        let url = Url::parse("http://example.com/").unwrap();
+        let mock = Mock::new();
+        mock.push_response("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        let mut client = Client::new(mock.clone());
+        fibers_global::execute(client.request(&url).close_connection().get()).unwrap();
+        assert_eq!(
+            mock.requests(),
+            vec![b"GET / HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\nContent-Length: 0\r\n\r\n".to_vec()]
+        );
+
+        // The connection is closed even though the response allows keep-alive
+        let mut mock = Mock::new();
+        mock.push_response("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        let addr = "127.0.0.1:80".parse().unwrap();
+        let mut connection = mock.acquire_connection(addr).wait().unwrap();
+
+        let method = Method::new("GET").unwrap();
+        let target = RequestTarget::new("/").unwrap();
+        let mut request = Request::new(method, target, HttpVersion::V1_1, Vec::new());
+        request
+            .header_mut()
+            .add_field(HeaderField::new("Connection", "close").unwrap());
+        let execute = Execute::new(
+            &mut connection,
+            request,
+            BodyEncoder::new(BytesEncoder::new()),
+            BodyDecoder::new(RemainingBytesDecoder::new()),
+            false,
+            &RequestOptions::default(),
+        );
+        execute.wait().unwrap();
+        assert_eq!(connection.state(), ConnectionState::Closed);
+    }
+
+    #[test]
+    fn upgrade_works() {
+        let url = Url::parse("http://example.com/chat").unwrap();
+        let mock = Mock::new();
+        mock.push_response("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\r\nframe");
+        mock.push_response("HTTP/1.1 400 Bad Request\r\nContent-Length: 3\r\n\r\nfoo");
+        let mut client = Client::new(mock.clone());
+
+        let future = client
+            .request(&url)
+            .header_field("Sec-WebSocket-Version", "13")
+            .upgrade("websocket");
+        let mut upgraded = fibers_global::execute(future).unwrap();
+        assert_eq!(upgraded.response().status_code().as_u16(), 101);
+
+        // The bytes following the response are readable
+        let connection = upgraded.connection_mut();
+        let mut buf = Vec::new();
+        connection.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"frame");
+        connection.write_all(b"bar").unwrap();
+        assert_eq!(connection.state(), ConnectionState::Closed);
+        assert_eq!(
+            mock.requests(),
+            vec![b"GET /chat HTTP/1.1\r\nSec-WebSocket-Version: 13\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nHost: example.com\r\n\r\nbar".to_vec()]
+        );
+
+        // Not upgraded
+        let e = fibers_global::execute(client.request(&url).upgrade("websocket"))
+            .err()
+            .unwrap();
+        assert_eq!(*e.kind(), ErrorKind::StatusError);
+        let cause = e.concrete_cause::<StatusError>().unwrap();
+        assert_eq!(cause.status_code(), 400);
+        assert_eq!(cause.body(), b"foo");
+    }
+
+    #[test]
+    fn connect_tunnel_works() {
+        let url = Url::parse("http://example.com:443/").unwrap();
+        let mock = Mock::new();
+        mock.push_response("HTTP/1.1 200 Connection Established\r\n\r\nhello");
+        mock.push_response("HTTP/1.1 200 Connection Established\r\nContent-Length: 3\r\n\r\nfoo");
+        mock.push_response("HTTP/1.1 403 Forbidden\r\nContent-Length: 3\r\n\r\nbar");
+        let mut client = Client::new(mock.clone());
+
+        let tunnel = fibers_global::execute(client.request(&url).connect_tunnel()).unwrap();
+        assert_eq!(tunnel.response().status_code().as_u16(), 200);
+        let (_, mut connection, buffered) = tunnel.into_parts();
+        assert_eq!(buffered, b"hello");
+        assert_eq!(connection.state(), ConnectionState::Closed);
+        connection.write_all(b"bar").unwrap();
+        assert_eq!(
+            mock.requests()[0],
+            b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\nbar".to_vec()
+        );
+
+        // The body declared by the header is ignored, and belongs to the tunnel
+        let mut tunnel = fibers_global::execute(client.request(&url).connect_tunnel()).unwrap();
+        let mut buf = Vec::new();
+        tunnel.connection_mut().read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"foo");
+
+        // Not established
+        let e = fibers_global::execute(client.request(&url).connect_tunnel())
+            .err()
+            .unwrap();
+        assert_eq!(*e.kind(), ErrorKind::StatusError);
+        let cause = e.concrete_cause::<StatusError>().unwrap();
+        assert_eq!(cause.status_code(), 403);
+        assert_eq!(cause.body(), b"bar");
+    }
+
+    #[test]
+    fn get_works() {
+        let addr = serve("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo");
+        let url = format!("http://{}/", addr);
+        let response = fibers_global::execute(::get(url.as_str())).unwrap();
+        assert_eq!(response.body(), b"foo");
+
+        let e = fibers_global::execute(::get("foo")).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn send_request_works() {
+        let url = Url::parse("http://example.com/").unwrap();
+        let mock = Mock::new();
+        mock.push_response("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo");
+        let mut client = Client::new(mock.clone());
+        client.default_headers(vec![("X-Foo", "bar")]);
+
+        // Sent as it is (e.g., neither default headers nor `Host` are added)
+        let method = Method::new("PATCH").unwrap();
+        let target = RequestTarget::new("/baz?qux").unwrap();
+        let mut request = Request::new(method, target, HttpVersion::V1_1, b"abc".to_vec());
+        request
+            .header_mut()
+            .add_field(HeaderField::new("Host", "other.example.com").unwrap());
+        let response = fibers_global::execute(client.execute(&url, request)).unwrap();
+        assert_eq!(response.body(), b"foo");
+        assert_eq!(
+            mock.requests(),
+            vec![b"PATCH /baz?qux HTTP/1.1\r\nHost: other.example.com\r\nContent-Length: 3\r\n\r\nabc".to_vec()]
+        );
+
+        // The decoder is configurable
+        mock.push_response("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo");
+        let method = Method::new("GET").unwrap();
+        let request = Request::new(
+            method,
+            RequestTarget::new("/").unwrap(),
+            HttpVersion::V1_1,
+            Vec::new(),
+        );
+        let future = client
+            .request(&url)
+            .decoder(Utf8Decoder::new())
+            .send_request(request);
+        assert_eq!(fibers_global::execute(future).unwrap().into_body(), "foo");
+
+        let method = Method::new("HEAD").unwrap();
+        let request = Request::new(
+            method,
+            RequestTarget::new("/").unwrap(),
+            HttpVersion::V1_1,
+            Vec::new(),
+        );
+        let e = fibers_global::execute(client.execute(&url, request))
+            .err()
+            .unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn empty_body_works() {
+        let url = Url::parse("http://example.com/foo").unwrap();
+        let mock = Mock::new();
+        mock.push_response("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n");
+        mock.push_response("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        mock.push_response("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        let mut client = Client::new(mock.clone());
+
+        let future = client
+            .request(&url)
+            .max_retries(1)
+            .backoff(Duration::from_millis(1), Duration::from_millis(1), false)
+            .post_empty();
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(response.status_code().as_u16(), 200);
+
+        let future = client.request(&url).put_empty();
+        fibers_global::execute(future).unwrap();
+
+        let post = b"POST /foo HTTP/1.1\r\nHost: example.com\r\nContent-Length: 0\r\n\r\n";
+        let put = b"PUT /foo HTTP/1.1\r\nHost: example.com\r\nContent-Length: 0\r\n\r\n";
+        assert_eq!(
+            mock.requests(),
+            vec![post.to_vec(), post.to_vec(), put.to_vec()]
+        );
+    }
+
+    #[test]
+    fn conditional_request_works() {
+        let url = Url::parse("http://example.com/foo").unwrap();
+        let mock = Mock::new();
+        let mut client = Client::new(mock.clone());
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_777);
+        for _ in 0..4 {
+            mock.push_response("HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\n\r\n");
+        }
+
+        let future = client
+            .request(&url)
+            .if_none_match("v1")
+            .if_modified_since(time)
+            .get();
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(response.status_code().as_u16(), 304);
+        assert_eq!(response.body(), b"");
+
+        for etag in &["\"v1\"", "W/\"v1\"", "*"] {
+            let future = client.request(&url).if_none_match(*etag).get();
+            fibers_global::execute(future).unwrap();
+        }
+
+        let requests = mock
+            .requests()
+            .into_iter()
+            .map(|r| String::from_utf8(r).unwrap())
+            .collect::<Vec<_>>();
+        assert!(requests[0].contains(
+            "If-None-Match: \"v1\"\r\nIf-Modified-Since: Sun, 06 Nov 1994 08:49:37 GMT\r\n"
+        ));
+        assert!(requests[1].contains("If-None-Match: \"v1\"\r\n"));
+        assert!(requests[2].contains("If-None-Match: W/\"v1\"\r\n"));
+        assert!(requests[3].contains("If-None-Match: *\r\n"));
+
+        // Control characters are rejected
+        let future = client
+            .request(&url)
+            .header_field("X-Foo", "bar\r\nX-Injected: baz")
+            .get();
+        let e = fibers_global::execute(future).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn range_works() {
+        let url = Url::parse("http://example.com/foo").unwrap();
+        let mock = Mock::new();
+        let mut client = Client::new(mock.clone());
+
+        mock.push_response(
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 3-5/10\r\nContent-Length: 3\r\n\r\nbar",
+        );
+        let future = client.request(&url).range(3, Some(5)).get();
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(response.body(), b"bar");
+        let range = response.content_range().unwrap();
+        assert_eq!(
+            (range.first, range.last, range.complete_length),
+            (3, 5, Some(10))
+        );
+
+        mock.push_response(
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 7-9/*\r\nContent-Length: 3\r\n\r\nbaz",
+        );
+        let future = client.request(&url).range(7, None).get();
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(response.content_range().unwrap().len(), 3);
+
+        // Ignored by the server
+        mock.push_response("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo");
+        let future = client.request(&url).range(3, Some(5)).get();
+        let e = fibers_global::execute(future).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+
+        // Unexpected range
+        mock.push_response(
+            "HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 0-2/10\r\nContent-Length: 3\r\n\r\nfoo",
+        );
+        let future = client.request(&url).range(3, Some(5)).get();
+        let e = fibers_global::execute(future).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+
+        // Not satisfiable
+        mock.push_response(
+            "HTTP/1.1 416 Range Not Satisfiable\r\nContent-Range: bytes */10\r\nContent-Length: 0\r\n\r\n",
+        );
+        let future = client.request(&url).range(10, None).get();
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(response.status_code().as_u16(), 416);
+        assert_eq!(response.content_range(), None);
+
+        // Invalid range
+        let future = client.request(&url).range(5, Some(3)).get();
+        let e = fibers_global::execute(future).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+
+        let requests = mock
+            .requests()
+            .into_iter()
+            .map(|r| String::from_utf8(r).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(requests.len(), 5);
+        assert!(requests[0].contains("\r\nRange: bytes=3-5\r\n"));
+        assert!(requests[1].contains("\r\nRange: bytes=7-\r\n"));
+    }
+
+    #[test]
+    fn template_works() {
+        let url = Url::parse("http://example.com/foo").unwrap();
+        let mock = Mock::new();
+        mock.push_response("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        mock.push_response("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        let mut client = Client::new(mock.clone());
+
+        let template = client
+            .request(&url)
+            .header_field("X-Foo", "bar")
+            .close_connection()
+            .to_template();
+        let mut other = template.clone();
+        other.set_url(Url::parse("http://example.org/baz").unwrap());
+        assert_eq!(template.url().as_str(), "http://example.com/foo");
+
+        for t in &[template, other] {
+            let future = client.request_from(t).get();
+            fibers_global::execute(future).unwrap();
+        }
+        assert_eq!(
+            mock.requests(),
+            vec![
+                b"GET /foo HTTP/1.1\r\nX-Foo: bar\r\nHost: example.com\r\nConnection: close\r\nContent-Length: 0\r\n\r\n".to_vec(),
+                b"GET /baz HTTP/1.1\r\nX-Foo: bar\r\nHost: example.org\r\nConnection: close\r\nContent-Length: 0\r\n\r\n".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn build_works() {
+        let url = Url::parse("http://example.com/foo").unwrap();
+        let mock = Mock::new();
+        mock.push_response("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo");
+        let mut client = Client::new(mock.clone());
+        client.default_headers(vec![("X-Foo", "bar")]);
+
+        let builder = client.request(&url);
+        let mut request = builder.build("POST", b"abc".to_vec()).unwrap();
+        assert_eq!(request.method().as_str(), "POST");
+        assert_eq!(request.request_target().as_str(), "/foo");
+        assert_eq!(
+            request.header().get_field("Content-Type"),
+            Some("application/octet-stream")
+        );
+        assert_eq!(request.header().get_field("X-Foo"), Some("bar"));
+        assert_eq!(request.header().get_field("Host"), Some("example.com"));
+
+        // The built request can be modified (e.g., signed) and then sent
+        request
+            .header_mut()
+            .add_field(HeaderField::new("X-Signature", "sig").unwrap());
+        let response = fibers_global::execute(builder.send_request(request)).unwrap();
+        assert_eq!(response.body(), b"foo");
+        assert_eq!(
+            mock.requests(),
+            vec![b"POST /foo HTTP/1.1\r\nContent-Type: application/octet-stream\r\nX-Foo: bar\r\nHost: example.com\r\nX-Signature: sig\r\nContent-Length: 3\r\n\r\nabc".to_vec()]
+        );
+
+        // `Content-Type` is added implicitly only to `PUT` and `POST` requests
+        let request = client.request(&url).build("GET", Vec::new()).unwrap();
+        assert_eq!(request.header().get_field("Content-Type"), None);
+
+        let e = client
+            .request(&url)
+            .build("BAD METHOD", Vec::new())
+            .err()
+            .unwrap();
+        assert_eq!(*e.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn max_concurrency_works() {
+        // A server that accepts connections but never responds
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let silent_addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let streams = listener.incoming().collect::<Vec<_>>();
+            drop(streams);
+        });
+        let silent_url = Url::parse(&format!("http://{}/", silent_addr)).unwrap();
+
+        // Fail-fast
+        let mut client = Client::new(Oneshot);
+        client.max_concurrency(1, true);
+        let future = client
+            .request(&silent_url)
+            .timeout(Duration::from_millis(300))
+            .get();
+        let (tx, rx) = std::sync::mpsc::channel();
+        fibers_global::spawn(future.then(move |r| {
+            let _ = tx.send(r.is_err());
+            Ok(())
+        }));
+        thread::sleep(Duration::from_millis(50));
+        #[cfg(feature = "metrics")]
+        assert_eq!(client.metrics().permits_in_use(), 1);
+
+        let url = Url::parse(&format!(
+            "http://{}/",
+            serve("HTTP/1.1 204 No Content\r\n\r\n")
+        ))
+        .unwrap();
+        let e = fibers_global::execute(client.request(&url).get())
+            .err()
+            .unwrap();
+        assert_eq!(*e.kind(), ErrorKind::TemporarilyUnavailable);
+        assert!(rx.recv().unwrap());
+        #[cfg(feature = "metrics")]
+        assert_eq!(client.metrics().permits_in_use(), 0);
+
+        // Wait
+        let mut client = Client::new(Oneshot);
+        client.max_concurrency(1, false);
+        let future = client
+            .request(&silent_url)
+            .timeout(Duration::from_millis(300))
+            .get();
+        let (tx, rx) = std::sync::mpsc::channel();
+        fibers_global::spawn(future.then(move |r| {
+            let _ = tx.send(r.is_err());
+            Ok(())
+        }));
+        thread::sleep(Duration::from_millis(50));
+
+        let start = Instant::now();
+        let response = fibers_global::execute(client.request(&url).get()).unwrap();
+        assert_eq!(response.status_code().as_u16(), 204);
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        assert!(rx.recv().unwrap());
+        #[cfg(feature = "metrics")]
+        assert_eq!(client.metrics().permits_in_use(), 0);
+    }
+
+    #[test]
+    fn max_header_size_works() {
+        let addr = serve("HTTP/1.1 200 OK\r\nFoo: 0123456789\r\nContent-Length: 0\r\n\r\n");
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let mut client = Client::new(Oneshot);
+        let future = client.request(&url).max_header_size(32).get();
+        let e = fibers_global::execute(future).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::HeaderTooLarge);
+    }
+
+    #[test]
+    fn max_header_fields_works() {
+        let url = Url::parse("http://example.com/").unwrap();
+        let mock = Mock::new();
+        mock.push_response("HTTP/1.1 200 OK\r\nA: 1\r\nB: 2\r\nContent-Length: 0\r\n\r\n");
+        mock.push_response("HTTP/1.1 200 OK\r\nA: 1\r\nB: 2\r\nC: 3\r\nContent-Length: 0\r\n\r\n");
+        let mut client = Client::new(mock.clone());
+
+        let future = client.request(&url).max_header_fields(3).get();
+        assert!(fibers_global::execute(future).is_ok());
+
+        let future = client.request(&url).max_header_fields(3).get();
+        let e = fibers_global::execute(future).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::HeaderTooLarge);
+
+        // The limit is enforced before the end of the head is received
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nA: 1\r\nB: 2\r\nC: 3\r\nD: 4\r\n")
+                .unwrap();
+            thread::sleep(Duration::from_secs(3)); // Keeps the connection open
+        });
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let mut client = Client::new(Oneshot);
+        let future = client
+            .request(&url)
+            .max_header_fields(3)
+            .timeout(Duration::from_secs(1))
+            .get();
+        let e = fibers_global::execute(future).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::HeaderTooLarge);
+    }
+
+    #[test]
+    fn connect_addr_works() {
+        let addr = serve("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo");
+        let url = Url::parse("http://example.invalid/").unwrap();
+        let mut client = Client::new(Oneshot);
+        let future = client.request(&url).connect_addr(addr).get();
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(response.body(), b"foo");
+    }
+
+    #[test]
+    fn resolver_works() {
+        struct StaticResolver(SocketAddr);
+        impl Resolve for StaticResolver {
+            type Future = FutureResult<Vec<SocketAddr>, Error>;
+
+            fn resolve(&mut self, host: &str, port: u16) -> Self::Future {
+                assert_eq!((host, port), ("example.invalid", 80));
+                future::ok(vec![self.0])
+            }
+        }
+
+        let addr = serve("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo");
+        let url = Url::parse("http://example.invalid/").unwrap();
+        let mut client = Client::new(Oneshot::builder().resolver(StaticResolver(addr)).finish());
+        let response = fibers_global::execute(client.request(&url).get()).unwrap();
+        assert_eq!(response.body(), b"foo");
+    }
+
+    #[test]
+    fn resolve_override_works() {
+        let addr = serve("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo");
+        let url = Url::parse("http://Example.invalid:8080/").unwrap();
+        let mut builder = ConnectionPoolBuilder::new();
+        builder.resolve_override("example.invalid", 8080, vec![addr]);
+        let pool = builder.finish(fibers_global::handle());
+        let mut client = Client::new(pool.handle());
+        fibers_global::spawn(pool.map_err(|e| panic!("{}", e)));
+
+        let response = fibers_global::execute(client.request(&url).get()).unwrap();
+        assert_eq!(response.body(), b"foo");
+    }
+
+    #[test]
+    fn idn_host_works() {
+        let url = Url::parse("http://\u{4f8b}\u{3048}.jp/").unwrap();
+        let mut builder = ConnectionPoolBuilder::new();
+        builder.resolve_override("xn--r8jz45g.jp", 80, vec![serve_echo()]);
+        let pool = builder.finish(fibers_global::handle());
+        let mut client = Client::new(pool.handle());
+        fibers_global::spawn(pool.map_err(|e| panic!("{}", e)));
+
+        let response = fibers_global::execute(client.request(&url).get()).unwrap();
+        let head = String::from_utf8(response.into_body()).unwrap();
+        assert!(head.contains("\r\nHost: xn--r8jz45g.jp\r\n"), "{:?}", head);
+    }
+
+    #[test]
+    fn connection_refused_works() {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let mut client = Client::new(Oneshot);
+        let e = fibers_global::execute(client.request(&url).get())
+            .err()
+            .unwrap();
+        assert_eq!(*e.kind(), ErrorKind::ConnectionRefused);
+    }
+
+    #[test]
+    fn max_connect_attempts_works() {
+        #[derive(Clone)]
+        struct Refused(Arc<AtomicUsize>);
+        impl AcquireConnection for Refused {
+            type Connection = ::connection::Connection;
+            type Future = FutureResult<Self::Connection, Error>;
+
+            fn acquire_connection(&mut self, _addr: SocketAddr) -> Self::Future {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                future::err(ErrorKind::ConnectionRefused.into())
+            }
+        }
+
+        let url = Url::parse("http://127.0.0.1:3000/").unwrap();
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let mut client = Client::new(Refused(attempts.clone()));
+        let future = client
+            .request(&url)
+            .max_retries(5)
+            .max_connect_attempts(3)
+            .backoff(Duration::from_millis(1), Duration::from_millis(1), false)
+            .get();
+        let e = fibers_global::execute(future).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::ConnectionRefused);
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        // Also bounds the retries caused by responses
+        let mock = Mock::new();
+        for _ in 0..3 {
+            mock.push_response("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n");
+        }
+        let mut client = Client::new(mock.clone());
+        let future = client
+            .request(&url)
+            .max_retries(5)
+            .max_connect_attempts(2)
+            .backoff(Duration::from_millis(1), Duration::from_millis(1), false)
+            .get();
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(response.status_code().as_u16(), 503);
+        assert_eq!(mock.requests().len(), 2);
+    }
+
+    #[test]
+    fn retry_after_works() {
+        let addr = serve_all(vec![
+            "HTTP/1.1 503 Service Unavailable\r\nRetry-After: 3600\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 429 Too Many Requests\r\n\
+             Retry-After: Thu, 01 Jan 1970 00:00:00 GMT\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo",
+        ]);
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let mut client = Client::new(Oneshot);
+
+        // The delay of the first response is truncated to 10 milliseconds
+        let future = client
+            .request(&url)
+            .max_retries(2)
+            .max_retry_after(Duration::from_millis(10))
+            .timeout(Duration::from_secs(10))
+            .get();
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(response.body(), b"foo");
+
+        // Retries are exhausted
+        let addr = serve_all(vec![
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 429 Too Many Requests\r\nContent-Length: 0\r\n\r\n",
+        ]);
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let future = client.request(&url).max_retries(1).get();
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(response.status_code().as_u16(), 429);
+    }
+
+    #[test]
+    fn retry_on_status_works() {
+        let addr = serve_all(vec![
+            "HTTP/1.1 502 Bad Gateway\r\nContent-Length: 3\r\n\r\nbad",
+            "HTTP/1.1 504 Gateway Timeout\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo",
+        ]);
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let mut client = Client::new(Oneshot);
+        let future = client
+            .request(&url)
+            .max_retries(2)
+            .retry_on_status(&[502, 504])
+            .backoff(Duration::from_millis(1), Duration::from_millis(1), false)
+            .get();
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(response.body(), b"foo");
+
+        // The default statuses are replaced
+        let addr = serve_all(vec![
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n",
+        ]);
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let future = client
+            .request(&url)
+            .max_retries(1)
+            .retry_on_status(&[502])
+            .get();
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(response.status_code().as_u16(), 503);
+    }
+
+    #[test]
+    fn drain_threshold_works() {
+        let cases = [
+            (
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 3\r\n\r\nbad",
+                4096,
+                ConnectionState::Recyclable,
+            ),
+            (
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 3\r\n\r\nbad",
+                2,
+                ConnectionState::Closed,
+            ),
+            (
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 10\r\n\r\nbad",
+                4096,
+                ConnectionState::Closed,
+            ),
+            (
+                "HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\nContent-Length: 3\r\n\r\nbad",
+                4096,
+                ConnectionState::Closed,
+            ),
+            (
+                "HTTP/1.1 503 Service Unavailable\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nbad\r\n0\r\n\r\n",
+                4096,
+                ConnectionState::Closed,
+            ),
+        ];
+        for &(response, threshold, state) in &cases {
+            let mock = Mock::new();
+            mock.push_response(response);
+            let addr = "127.0.0.1:80".parse().unwrap();
+            let mut connection = mock.clone().acquire_connection(addr).wait().unwrap();
+
+            let method = Method::new("GET").unwrap();
+            let target = RequestTarget::new("/").unwrap();
+            let request = Request::new(method, target, HttpVersion::V1_1, Vec::new());
+            let options = RequestOptions {
+                drain_threshold: threshold,
+                ..RequestOptions::default()
+            };
+            let execute = Execute::new(
+                &mut connection,
+                request,
+                BodyEncoder::new(BytesEncoder::new()),
+                BodyDecoder::new(RemainingBytesDecoder::new()),
+                true,
+                &options,
+            );
+            match execute.wait().unwrap() {
+                Outcome::Retry(_) => {}
+                Outcome::Response(..) => panic!(),
+            }
+            assert_eq!(connection.state(), state, "{:?}", response);
+        }
+    }
+
+    #[test]
+    fn error_for_status_drain_threshold_works() {
+        let cases: [(&str, u64, ConnectionState, &[u8]); 4] = [
+            (
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 3\r\n\r\nbad",
+                4096,
+                ConnectionState::Recyclable,
+                b"bad",
+            ),
+            (
+                "HTTP/1.1 404 Not Found\r\nContent-Length: 3\r\n\r\nbad",
+                2,
+                ConnectionState::Closed,
+                b"",
+            ),
+            (
+                "HTTP/1.1 500 Internal Server Error\r\nConnection: close\r\nContent-Length: 3\r\n\r\nbad",
+                4096,
+                ConnectionState::Closed,
+                b"bad",
+            ),
+            (
+                "HTTP/1.1 500 Internal Server Error\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nbad\r\n0\r\n\r\n",
+                4096,
+                ConnectionState::Closed,
+                b"",
+            ),
+        ];
+        for &(response, threshold, state, body) in &cases {
+            let mock = Mock::new();
+            mock.push_response(response);
+            let addr = "127.0.0.1:80".parse().unwrap();
+            let mut connection = mock.clone().acquire_connection(addr).wait().unwrap();
+
+            let method = Method::new("GET").unwrap();
+            let target = RequestTarget::new("/").unwrap();
+            let request = Request::new(method, target, HttpVersion::V1_1, Vec::new());
+            let options = RequestOptions {
+                drain_threshold: threshold,
+                error_for_status: true,
+                ..RequestOptions::default()
+            };
+            let execute = Execute::new(
+                &mut connection,
+                request,
+                BodyEncoder::new(BytesEncoder::new()),
+                BodyDecoder::new(RemainingBytesDecoder::new()),
+                false,
+                &options,
+            );
+            let e = execute.wait().err().unwrap();
+            assert_eq!(*e.kind(), ErrorKind::StatusError, "{:?}", response);
+            assert_eq!(e.concrete_cause::<StatusError>().unwrap().body(), body);
+            assert_eq!(connection.state(), state, "{:?}", response);
+        }
+    }
+
+    #[test]
+    fn retry_bodiless_response_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut streams = Vec::new();
+            for response in &[
+                "HTTP/1.1 503 Service Unavailable\r\nContent-Length: 10\r\n\r\n",
+                "HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\n",
+            ] {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf).unwrap();
+                stream.write_all(response.as_bytes()).unwrap();
+                streams.push(stream); // Keeps the connection open
+            }
+            thread::sleep(Duration::from_secs(3));
+        });
+
+        // The body of the response to `HEAD` is empty regardless of `Content-Length`
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let mut client = Client::new(Oneshot);
+        let future = client
+            .request(&url)
+            .max_retries(1)
+            .max_retry_after(Duration::from_millis(1))
+            .timeout(Duration::from_secs(1))
+            .head();
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(response.status_code().as_u16(), 200);
+    }
+
+    #[test]
+    fn backoff_works() {
+        let mut backoff = Backoff {
+            base: Duration::from_millis(100),
+            max: Duration::from_secs(1),
+            jitter: false,
+            rng: None,
+        };
+        assert_eq!(backoff.delay(1), Duration::from_millis(100));
+        assert_eq!(backoff.delay(3), Duration::from_millis(400));
+        assert_eq!(backoff.delay(5), Duration::from_secs(1));
+        assert_eq!(backoff.delay(100), Duration::from_secs(1));
+
+        let mut backoff = Backoff {
+            jitter: true,
+            ..backoff
+        };
+        assert!((0..100).all(|_| backoff.delay(3) <= Duration::from_millis(400)));
+
+        // Deterministic for the same seed
+        let delays = |seed| {
+            let mut backoff = Backoff {
+                rng: Some(Rng::new(seed)),
+                ..backoff.clone()
+            };
+            (1..4).map(|n| backoff.delay(n)).collect::<Vec<_>>()
+        };
+        assert_eq!(delays(1), delays(1));
+        assert_ne!(delays(1), delays(2));
+
+        // The request fails before waiting beyond the deadline
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let mut client = Client::new(Oneshot);
+        let future = client
+            .request(&url)
+            .max_retries(3)
+            .backoff(Duration::from_secs(10), Duration::from_secs(10), false)
+            .timeout(Duration::from_secs(5))
+            .get();
+        let e = fibers_global::execute(future).err().unwrap();
+        assert_eq!(e.timeout_phase(), Some(TimeoutPhase::Request));
+    }
+
+    #[test]
+    fn cancel_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let _streams = listener.incoming().collect::<Vec<_>>();
+        });
+
+        let pool = ConnectionPool::new(fibers_global::handle());
+        let handle = pool.handle();
+        let mut client = Client::new(pool.handle());
+        fibers_global::spawn(pool.map_err(|e| panic!("{}", e)));
+
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let future = client.request(&url).get();
+        let cancel = future.cancel_handle();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            cancel.cancel();
+        });
+        let e = fibers_global::execute(future).err().unwrap();
+        assert!(e.is_canceled());
+
+        // The connection has been discarded instead of being returned to the pool
+        thread::sleep(Duration::from_millis(50));
+        let stats = fibers_global::execute(handle.stats()).unwrap();
+        assert_eq!((stats.total(), stats.idle()), (0, 0));
+    }
+
+    #[test]
+    fn completed_requests_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut chunk = [0; 1024];
+            for _ in 0..2 {
+                let mut buf = Vec::new();
+                while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut chunk).unwrap();
+                    buf.extend_from_slice(&chunk[..n]);
+                }
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo")
+                    .unwrap();
+            }
+            let _ = stream.read(&mut chunk);
+        });
+
+        let pool = ConnectionPool::new(fibers_global::handle());
+        let mut handle = pool.handle();
+        let mut client = Client::new(pool.handle());
+        fibers_global::spawn(pool.map_err(|e| panic!("{}", e)));
+
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        for _ in 0..2 {
+            let response = fibers_global::execute(client.request(&url).get()).unwrap();
+            assert_eq!(response.body(), b"foo");
+        }
+
+        let mut connection = fibers_global::execute(handle.acquire_connection(addr)).unwrap();
+        assert_eq!(connection.completed_requests(), 2);
+        assert_eq!(connection.as_mut().requests(), 2);
+    }
+
+    #[test]
+    fn request_path_works() {
+        let addr = serve("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo");
+        let mut client = Client::new(Oneshot);
+        assert!(client.request_path("/foo").is_err());
+
+        client.base_url(Url::parse(&format!("http://{}/bar/", addr)).unwrap());
+        let future = client.request_path("/foo").unwrap().get();
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(response.body(), b"foo");
+    }
+
+    #[test]
+    fn shared_body_works() {
+        let mock = Mock::new();
+        mock.push_response("HTTP/1.1 204 No Content\r\n\r\n");
+        let url = Url::parse("http://example.com/").unwrap();
+        let body: Arc<[u8]> = Arc::from(&b"foo"[..]);
+        let mut client = Client::new(mock.clone());
+        let future = client
+            .request(&url)
+            .encoder(BytesEncoder::<Arc<[u8]>>::new())
+            .put(body.clone());
+        fibers_global::execute(future).unwrap();
+        assert_eq!(
+            mock.requests(),
+            vec![b"PUT / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 3\r\n\r\nfoo".to_vec()]
+        );
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn bytes_body_works() {
+        let mock = Mock::new();
+        mock.push_response("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nbar");
+        let url = Url::parse("http://example.com/").unwrap();
+        let body = bytes::Bytes::from_static(b"foo");
+        let mut client = Client::new(mock.clone());
+        let future = client
+            .request(&url)
+            .bytes_encoder()
+            .bytes_decoder()
+            .post(body);
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(response.into_body(), bytes::Bytes::from_static(b"bar"));
+        assert_eq!(
+            mock.requests(),
+            vec![b"POST / HTTP/1.1\r\nContent-Type: application/octet-stream\r\nHost: example.com\r\nContent-Length: 3\r\n\r\nfoo".to_vec()]
+        );
+    }
+
+    #[test]
+    fn progress_works() {
+        let url = Url::parse("http://example.com/").unwrap();
+        let mock = Mock::new();
+        mock.push_response("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo");
+        mock.push_response(
+            "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nfoo\r\n0\r\n\r\n",
+        );
+        let mut client = Client::new(mock.clone());
+
+        let progresses = Arc::new(Mutex::new(Vec::new()));
+        let p = progresses.clone();
+        let future = client
+            .request(&url)
+            .on_progress(move |x| p.lock().unwrap().push(x))
+            .post(b"bar".to_vec());
+        fibers_global::execute(future).unwrap();
+
+        let progresses = progresses.lock().unwrap().clone();
+        let request_size = mock.requests()[0].len() as u64;
+        assert_eq!(
+            progresses.last(),
+            Some(&Progress {
+                sent: request_size,
+                send_total: Some(request_size),
+                received: 3,
+                receive_total: Some(3),
+            })
+        );
+        assert!(progresses.windows(2).all(|w| w[0] != w[1]));
+
+        // The size of a chunked body is unknown
+        let progresses = Arc::new(Mutex::new(Vec::new()));
+        let p = progresses.clone();
+        let future = client
+            .request(&url)
+            .on_progress(move |x| p.lock().unwrap().push(x))
+            .get();
+        fibers_global::execute(future).unwrap();
+
+        let last = *progresses.lock().unwrap().last().unwrap();
+        assert_eq!(last.receive_total, None);
+        assert!(last.received > 0);
+    }
+
+    #[test]
+    fn keep_alive_timeout_works() {
+        let response = |header: &str| {
+            let mut decoder = ResponseDecoder::<BodyDecoder<RemainingBytesDecoder>>::default();
+            let bytes = format!("HTTP/1.1 200 OK\r\n{}Content-Length: 0\r\n\r\n", header);
+            decoder.decode_from_bytes(bytes.as_bytes()).unwrap()
+        };
+        let timeout = |header| keep_alive_timeout(&response(header));
+        assert_eq!(
+            timeout("Keep-Alive: timeout=5, max=100\r\n"),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(
+            timeout("Keep-Alive: max=100,Timeout = 7\r\n"),
+            Some(Duration::from_secs(7))
+        );
+        assert_eq!(timeout("Keep-Alive: max=100\r\n"), None);
+        assert_eq!(timeout("Keep-Alive: timeout=foo\r\n"), None);
+        assert_eq!(timeout(""), None);
+    }
+
+    #[test]
+    fn auto_content_type_works() {
+        let mock = Mock::new();
+        let url = Url::parse("http://example.com/").unwrap();
+        let mut client = Client::new(mock.clone());
+        for _ in 0..3 {
+            mock.push_response("HTTP/1.1 204 No Content\r\n\r\n");
+        }
+        let future = client.request(&url).post(b"foo".to_vec());
+        fibers_global::execute(future).unwrap();
+        let future = client.request(&url).text_encoder().post("foo".to_owned());
+        fibers_global::execute(future).unwrap();
+        let future = client
+            .request(&url)
+            .encoder(BytesEncoder::<&[u8]>::new())
+            .post(b"foo");
+        fibers_global::execute(future).unwrap();
+        assert_eq!(
+            mock.requests(),
+            vec![
+                b"POST / HTTP/1.1\r\nContent-Type: application/octet-stream\r\nHost: example.com\r\nContent-Length: 3\r\n\r\nfoo".to_vec(),
+                b"POST / HTTP/1.1\r\nContent-Type: text/plain; charset=utf-8\r\nHost: example.com\r\nContent-Length: 3\r\n\r\nfoo".to_vec(),
+                b"POST / HTTP/1.1\r\nHost: example.com\r\nContent-Length: 3\r\n\r\nfoo".to_vec(),
+            ]
+        );
+    }
+
+    #[test]
+    fn raw_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo")
+                .unwrap();
+            thread::sleep(Duration::from_secs(3)); // Keeps the connection open
+        });
+
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let mut client = Client::new(Oneshot);
+        let future = client
+            .request(&url)
+            .raw()
+            .timeout(Duration::from_secs(1))
+            .get();
+        let response = fibers_global::execute(future).unwrap();
+        assert_eq!(response.body(), b"foo");
+    }
+
+    #[test]
+    fn keep_alive_works() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        {
+            let accepted = accepted.clone();
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let mut stream = stream.unwrap();
+                    accepted.fetch_add(1, Ordering::SeqCst);
+                    thread::spawn(move || {
+                        let responses: [&[u8]; 2] = [
+                            b"HTTP/1.1 204 No Content\r\n\r\n",
+                            b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo",
+                        ];
+                        let mut buf = Vec::new();
+                        let mut chunk = [0; 1024];
+                        let mut count = 0;
+                        loop {
+                            while let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                                buf.drain(..i + 4);
+                                stream.write_all(responses[count % 2]).unwrap();
+                                count += 1;
+                            }
+                            match stream.read(&mut chunk) {
+                                Ok(0) | Err(_) => break,
+                                Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                            }
+                        }
+                    });
+                }
+            });
+        }
+
+        let pool = ConnectionPool::new(fibers_global::handle());
+        #[cfg(feature = "metrics")]
+        let metrics = pool.metrics().clone();
+        let mut client = Client::new(pool.handle());
+        fibers_global::spawn(pool.map_err(|e| panic!("{}", e)));
+
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        for _ in 0..2 {
+            let response = fibers_global::execute(client.request(&url).get()).unwrap();
+            assert_eq!(response.status_code().as_u16(), 204);
+            assert!(response.body().is_empty());
+
+            let response = fibers_global::execute(client.request(&url).get()).unwrap();
+            assert_eq!(response.body(), b"foo");
         }
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        #[cfg(feature = "metrics")]
+        assert_eq!(metrics.new_connections(), 1);
+        #[cfg(feature = "metrics")]
+        assert_eq!(metrics.reused_connections(), 3);
+    }
+
+    fn serve(response: &'static str) -> SocketAddr {
+        serve_all(vec![response])
+    }
+
+    /// Replies with the head of the received request as the body.
+    fn serve_echo() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = Vec::new();
+            let mut chunk = [0; 1024];
+            while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut chunk).unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+            }
+            let header = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", buf.len());
+            stream.write_all(header.as_bytes()).unwrap();
+            stream.write_all(&buf).unwrap();
+        });
+        addr
+    }
+
+    /// Replies with the (decompressed) body of the received request, for `n` connections.
+    ///
+    /// The `Content-Encoding` of the request is returned as `X-Content-Encoding` header.
+    fn serve_decompress(n: usize) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for _ in 0..n {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buf = Vec::new();
+                let mut chunk = [0; 1024];
+                while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut chunk).unwrap();
+                    buf.extend_from_slice(&chunk[..n]);
+                }
+                let head_len = buf.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+                let head = String::from_utf8(buf[..head_len].to_vec()).unwrap();
+                let field = |name: &str| {
+                    head.split("\r\n")
+                        .filter_map(|line| line.split_once(": "))
+                        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                        .map(|(_, v)| v.to_owned())
+                };
+                let content_length: usize = field("Content-Length").unwrap().parse().unwrap();
+                let mut body = buf[head_len..].to_vec();
+                while body.len() < content_length {
+                    let n = stream.read(&mut chunk).unwrap();
+                    body.extend_from_slice(&chunk[..n]);
+                }
+
+                let mut response = "HTTP/1.1 200 OK\r\n".to_owned();
+                if let Some(encoding) = field("Content-Encoding") {
+                    if encoding == "gzip" {
+                        body = gzip::decompress(&body);
+                    }
+                    response += &format!("X-Content-Encoding: {}\r\n", encoding);
+                }
+                response += &format!("Content-Length: {}\r\n\r\n", body.len());
+                stream.write_all(response.as_bytes()).unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+        addr
+    }
+
+    /// Serves each of `responses` on a new connection.
+    fn serve_all(responses: Vec<&'static str>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buf = Vec::new();
+                let mut chunk = [0; 1024];
+                while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+                    let n = stream.read(&mut chunk).unwrap();
+                    buf.extend_from_slice(&chunk[..n]);
+                }
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        addr
     }
 }
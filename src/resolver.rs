@@ -0,0 +1,312 @@
+//! Name resolution.
+use fibers::sync::oneshot;
+use futures::future::{self, Either, FutureResult};
+use futures::Future;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, OnceLock};
+use std::thread;
+use trackable::error::ErrorKindExt;
+
+use {Error, ErrorKind};
+
+const MAX_RESOLVER_THREADS: usize = 4;
+
+static RESOLVER_POOL: OnceLock<ResolverPool> = OnceLock::new();
+
+/// `Future` that returns the addresses resolved by a boxed resolver.
+pub type BoxResolveFuture = Box<dyn Future<Item = Vec<SocketAddr>, Error = Error> + Send + 'static>;
+
+/// This trait allows for resolving host names to TCP addresses.
+pub trait Resolve {
+    /// `Future` for resolving a host name.
+    type Future: Future<Item = Vec<SocketAddr>, Error = Error>;
+
+    /// Returns a `Future` for resolving the given host name to the TCP addresses.
+    fn resolve(&mut self, host: &str, port: u16) -> Self::Future;
+}
+
+/// The default implementation of [`Resolve`] trait.
+///
+/// This resolves host names by using the standard `ToSocketAddrs` trait.
+/// Because it may block, the resolution is executed on a pool of at most four threads shared by the whole process
+/// (except the case the host name is an IP address).
+/// The threads are spawned on demand, and the resolutions exceeding the capacity of the pool wait in a queue.
+///
+/// [`Resolve`]: ./trait.Resolve.html
+#[derive(Debug, Default, Clone)]
+pub struct DefaultResolver;
+impl Resolve for DefaultResolver {
+    type Future = Either<FutureResult<Vec<SocketAddr>, Error>, DefaultResolveFuture>;
+
+    fn resolve(&mut self, host: &str, port: u16) -> Self::Future {
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Either::A(future::ok(vec![SocketAddr::new(ip, port)]));
+        }
+
+        let (reply_tx, reply_rx) = oneshot::monitor();
+        let job = ResolveJob {
+            host: host.to_owned(),
+            port,
+            reply_tx,
+        };
+        RESOLVER_POOL.get_or_init(ResolverPool::new).submit(job);
+        Either::B(DefaultResolveFuture(reply_rx))
+    }
+}
+
+/// `Future` returned by [`DefaultResolver`].
+///
+/// [`DefaultResolver`]: ./struct.DefaultResolver.html
+#[derive(Debug)]
+pub struct DefaultResolveFuture(oneshot::Monitor<Vec<SocketAddr>, Error>);
+impl Future for DefaultResolveFuture {
+    type Item = Vec<SocketAddr>;
+    type Error = Error;
+
+    fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
+        self.0.poll().map_err(|e| {
+            e.unwrap_or_else(|| {
+                track!(ErrorKind::Other.cause("Resolver thread exited unexpectedly")).into()
+            })
+        })
+    }
+}
+
+struct ResolveJob {
+    host: String,
+    port: u16,
+    reply_tx: oneshot::Monitored<Vec<SocketAddr>, Error>,
+}
+impl ResolveJob {
+    fn run(self) {
+        let (host, port) = (self.host, self.port);
+        let result = (host.as_str(), port)
+            .to_socket_addrs()
+            .map(|addrs| addrs.collect())
+            .map_err(|e| track!(Error::from(ErrorKind::Dns.cause(e)); host, port));
+        self.reply_tx.exit(result);
+    }
+}
+
+/// The threads that execute the blocking resolutions of `DefaultResolver`.
+struct ResolverPool {
+    state: Mutex<ResolverPoolState>,
+    queued: Condvar,
+}
+struct ResolverPoolState {
+    jobs: VecDeque<ResolveJob>,
+    threads: usize,
+    idle_threads: usize,
+}
+impl ResolverPool {
+    fn new() -> Self {
+        ResolverPool {
+            state: Mutex::new(ResolverPoolState {
+                jobs: VecDeque::new(),
+                threads: 0,
+                idle_threads: 0,
+            }),
+            queued: Condvar::new(),
+        }
+    }
+
+    fn submit(&'static self, job: ResolveJob) {
+        let mut state = self.lock();
+        state.jobs.push_back(job);
+        if state.jobs.len() > state.idle_threads && state.threads < MAX_RESOLVER_THREADS {
+            let spawned = thread::Builder::new()
+                .name("fibers_http_client_resolver".to_owned())
+                .spawn(move || self.run());
+            match spawned {
+                Ok(_) => state.threads += 1,
+                Err(e) => {
+                    if state.threads == 0 {
+                        // No thread will ever take the job
+                        let job = state.jobs.pop_back().expect("never fails");
+                        let e = track!(Error::from(ErrorKind::Other.cause(e)));
+                        job.reply_tx.exit(Err(e));
+                        return;
+                    }
+                }
+            }
+        }
+        self.queued.notify_one();
+    }
+
+    fn run(&self) {
+        loop {
+            let job = {
+                let mut state = self.lock();
+                state.idle_threads += 1;
+                while state.jobs.is_empty() {
+                    state = self.queued.wait(state).unwrap_or_else(|e| e.into_inner());
+                }
+                state.idle_threads -= 1;
+                state.jobs.pop_front().expect("never fails")
+            };
+            job.run();
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, ResolverPoolState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The address family tried first when a host name is resolved to multiple addresses.
+///
+/// The resolved addresses are reordered, not filtered: the addresses of the other family are kept after the preferred ones.
+/// A [`ConnectionPool`] tries the first [`connect_parallelism`] addresses of the reordered list concurrently,
+/// so the other family is also tried if the number of the preferred addresses is less than the parallelism.
+/// The other connection providers (e.g., [`ConfiguredOneshot`]) only connect to the first address.
+///
+/// [`ConnectionPool`]: ../connection/struct.ConnectionPool.html
+/// [`connect_parallelism`]: ../connection/struct.ConnectionPoolBuilder.html#method.connect_parallelism
+/// [`ConfiguredOneshot`]: ../connection/struct.ConfiguredOneshot.html
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AddressFamily {
+    /// Keeps the order of the addresses returned by the resolver.
+    #[default]
+    System,
+
+    /// Moves IPv4 addresses to the front.
+    V4,
+
+    /// Moves IPv6 addresses to the front.
+    V6,
+}
+impl AddressFamily {
+    fn sort(self, addrs: &mut [SocketAddr]) {
+        match self {
+            AddressFamily::System => {}
+            AddressFamily::V4 => addrs.sort_by_key(|a| !a.is_ipv4()),
+            AddressFamily::V6 => addrs.sort_by_key(|a| !a.is_ipv6()),
+        }
+    }
+}
+
+type Overrides = HashMap<(String, u16), Vec<SocketAddr>>;
+
+/// A resolver shared by connection providers.
+///
+/// The overridden addresses are consulted before the inner resolver.
+#[derive(Clone)]
+pub(crate) struct SharedResolver {
+    inner: Arc<Mutex<dyn Resolve<Future = BoxResolveFuture> + Send>>,
+    overrides: Arc<Overrides>,
+    address_family: AddressFamily,
+}
+impl SharedResolver {
+    pub fn set_resolver<R>(&mut self, resolver: R)
+    where
+        R: Resolve + Send + 'static,
+        R::Future: Send + 'static,
+    {
+        self.inner = Arc::new(Mutex::new(Boxed(resolver)));
+    }
+
+    pub fn add_override(&mut self, host: &str, port: u16, addrs: Vec<SocketAddr>) {
+        let key = (host.to_ascii_lowercase(), port);
+        Arc::make_mut(&mut self.overrides).insert(key, addrs);
+    }
+
+    pub fn set_address_family(&mut self, family: AddressFamily) {
+        self.address_family = family;
+    }
+
+    pub fn resolve(&self, host: &str, port: u16) -> BoxResolveFuture {
+        let family = self.address_family;
+        if !self.overrides.is_empty() {
+            let key = (host.to_ascii_lowercase(), port);
+            if let Some(addrs) = self.overrides.get(&key) {
+                let mut addrs = addrs.clone();
+                family.sort(&mut addrs);
+                return Box::new(future::ok(addrs));
+            }
+        }
+        let future = self.inner.lock().expect("never fails").resolve(host, port);
+        if family == AddressFamily::System {
+            return future;
+        }
+        Box::new(future.map(move |mut addrs| {
+            family.sort(&mut addrs);
+            addrs
+        }))
+    }
+}
+impl Default for SharedResolver {
+    fn default() -> Self {
+        SharedResolver {
+            inner: Arc::new(Mutex::new(Boxed(DefaultResolver))),
+            overrides: Arc::new(HashMap::new()),
+            address_family: AddressFamily::default(),
+        }
+    }
+}
+impl fmt::Debug for SharedResolver {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedResolver {{ .. }}")
+    }
+}
+
+struct Boxed<R>(R);
+impl<R> Resolve for Boxed<R>
+where
+    R: Resolve,
+    R::Future: Send + 'static,
+{
+    type Future = BoxResolveFuture;
+
+    fn resolve(&mut self, host: &str, port: u16) -> Self::Future {
+        Box::new(self.0.resolve(host, port))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fibers_global;
+    use futures::Future;
+
+    use super::*;
+
+    #[test]
+    fn address_family_works() {
+        let v4: SocketAddr = "127.0.0.1:80".parse().unwrap();
+        let v6: SocketAddr = "[::1]:80".parse().unwrap();
+        let v4_2: SocketAddr = "127.0.0.2:80".parse().unwrap();
+
+        let mut resolver = SharedResolver::default();
+        resolver.add_override("example.com", 80, vec![v6, v4, v4_2]);
+        let cases = [
+            (AddressFamily::System, vec![v6, v4, v4_2]),
+            (AddressFamily::V4, vec![v4, v4_2, v6]),
+            (AddressFamily::V6, vec![v6, v4, v4_2]),
+        ];
+        for (family, expected) in cases.iter().cloned() {
+            resolver.set_address_family(family);
+            let addrs = resolver.resolve("example.com", 80).wait().unwrap();
+            assert_eq!(addrs, expected, "{:?}", family);
+        }
+
+        resolver.set_address_family(AddressFamily::V6);
+        let addrs = resolver.resolve("127.0.0.1", 80).wait().unwrap();
+        assert_eq!(addrs, vec![v4]);
+    }
+
+    #[test]
+    fn default_resolver_works() {
+        let futures = (0..16)
+            .map(|_| DefaultResolver.resolve("localhost", 80))
+            .collect::<Vec<_>>();
+        for future in futures {
+            let addrs = fibers_global::execute(future).unwrap();
+            assert!(addrs.iter().all(|a| a.ip().is_loopback() && a.port() == 80));
+        }
+
+        // The resolutions share a bounded number of threads
+        let threads = RESOLVER_POOL.get().unwrap().lock().threads;
+        assert!(0 < threads && threads <= MAX_RESOLVER_THREADS);
+    }
+}
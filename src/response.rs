@@ -0,0 +1,241 @@
+use httpcodec::Response;
+
+/// Extension methods for HTTP responses.
+pub trait ResponseExt {
+    /// Returns the value of `Content-Length` header.
+    ///
+    /// This is especially useful for `HEAD` responses whose bodies are always empty
+    /// but whose headers declare the size of the resource.
+    ///
+    /// If the header is missing or malformed, this returns `None`.
+    fn content_length(&self) -> Option<u64>;
+
+    /// Returns the parsed value of `Content-Range` header (e.g., of a `206 Partial Content` response).
+    ///
+    /// If the header is missing, malformed or does not describe a satisfied byte range
+    /// (e.g., `bytes */1234` of a `416 Range Not Satisfiable` response), this returns `None`.
+    fn content_range(&self) -> Option<ContentRange>;
+
+    /// Returns the values of all the fields that have the name `name` in the header, in the order of appearance.
+    ///
+    /// Names are compared case-insensitively.
+    /// This is useful for the fields that may be repeated (e.g., `Set-Cookie` or `Via`),
+    /// while `Header::get_field` returns only the first one.
+    /// Each value is returned as it is (i.e., comma-separated lists are not split).
+    fn header_values(&self, name: &str) -> Vec<String>;
+
+    /// Returns the class of the status code of the response.
+    ///
+    /// If the status code is not in any of the classes (i.e., `600` or greater), this returns `None`.
+    fn status_class(&self) -> Option<StatusClass>;
+}
+impl<T> ResponseExt for Response<T> {
+    fn content_length(&self) -> Option<u64> {
+        self.header()
+            .parse_field("Content-Length")
+            .ok()
+            .and_then(|n| n)
+    }
+
+    fn content_range(&self) -> Option<ContentRange> {
+        self.header()
+            .get_field("Content-Range")
+            .and_then(ContentRange::parse)
+    }
+
+    fn header_values(&self, name: &str) -> Vec<String> {
+        self.header()
+            .fields()
+            .filter(|f| f.name().eq_ignore_ascii_case(name))
+            .map(|f| f.value().to_owned())
+            .collect()
+    }
+
+    fn status_class(&self) -> Option<StatusClass> {
+        StatusClass::from_status_code(self.status_code().as_u16())
+    }
+}
+
+/// The class of a response status code (RFC 7231, section 6).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum StatusClass {
+    /// `1xx`
+    Informational,
+
+    /// `2xx`
+    Success,
+
+    /// `3xx`
+    Redirection,
+
+    /// `4xx`
+    ClientError,
+
+    /// `5xx`
+    ServerError,
+}
+impl StatusClass {
+    /// Returns the class of the given status code.
+    ///
+    /// If the code is not in `100..600`, this returns `None`.
+    pub fn from_status_code(status_code: u16) -> Option<Self> {
+        match status_code {
+            100..=199 => Some(StatusClass::Informational),
+            200..=299 => Some(StatusClass::Success),
+            300..=399 => Some(StatusClass::Redirection),
+            400..=499 => Some(StatusClass::ClientError),
+            500..=599 => Some(StatusClass::ServerError),
+            _ => None,
+        }
+    }
+
+    /// Returns the string representation of the class (e.g., `"2xx"`), which is suitable for metric labels.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            StatusClass::Informational => "1xx",
+            StatusClass::Success => "2xx",
+            StatusClass::Redirection => "3xx",
+            StatusClass::ClientError => "4xx",
+            StatusClass::ServerError => "5xx",
+        }
+    }
+
+    /// Returns `true` if the class is `4xx` or `5xx`.
+    pub fn is_error(self) -> bool {
+        matches!(self, StatusClass::ClientError | StatusClass::ServerError)
+    }
+}
+
+/// A byte range of the body of a response, declared by `Content-Range` header (RFC 7233, section 4.2).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ContentRange {
+    /// The position of the first byte of the range.
+    pub first: u64,
+
+    /// The position of the last byte of the range (inclusive).
+    pub last: u64,
+
+    /// The length of the whole representation, if known.
+    pub complete_length: Option<u64>,
+}
+impl ContentRange {
+    /// Returns the number of bytes in the range.
+    pub fn len(&self) -> u64 {
+        self.last - self.first + 1
+    }
+
+    /// Always returns `false` since a range contains at least one byte.
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        let value = value.trim();
+        let (unit, rest) = value.split_once(' ')?;
+        if !unit.eq_ignore_ascii_case("bytes") {
+            return None;
+        }
+        let (range, complete_length) = rest.trim().split_once('/')?;
+        let (first, last) = range.split_once('-')?;
+        let first: u64 = first.parse().ok()?;
+        let last: u64 = last.parse().ok()?;
+        let complete_length = match complete_length {
+            "*" => None,
+            n => Some(n.parse().ok()?),
+        };
+        if last < first || complete_length.is_some_and(|n| last >= n) {
+            return None;
+        }
+        Some(ContentRange {
+            first,
+            last,
+            complete_length,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytecodec::bytes::RemainingBytesDecoder;
+    use bytecodec::DecodeExt;
+    use httpcodec::{BodyDecoder, ResponseDecoder};
+
+    use super::*;
+
+    #[test]
+    fn header_values_works() {
+        let mut decoder = ResponseDecoder::<BodyDecoder<RemainingBytesDecoder>>::default();
+        let response = decoder
+            .decode_from_bytes(
+                b"HTTP/1.1 200 OK\r\nSet-Cookie: a=1; Expires=Wed, 21 Oct 2026 07:28:00 GMT\r\n\
+                  Via: 1.1 foo\r\nset-cookie: b=2\r\nContent-Length: 0\r\n\r\n",
+            )
+            .unwrap();
+        assert_eq!(
+            response.header_values("Set-Cookie"),
+            ["a=1; Expires=Wed, 21 Oct 2026 07:28:00 GMT", "b=2"]
+        );
+        assert_eq!(response.header_values("VIA"), ["1.1 foo"]);
+        assert!(response.header_values("Vary").is_empty());
+    }
+
+    #[test]
+    fn status_class_works() {
+        assert_eq!(StatusClass::from_status_code(99), None);
+        assert_eq!(
+            StatusClass::from_status_code(101),
+            Some(StatusClass::Informational)
+        );
+        assert_eq!(
+            StatusClass::from_status_code(200),
+            Some(StatusClass::Success)
+        );
+        assert_eq!(
+            StatusClass::from_status_code(304),
+            Some(StatusClass::Redirection)
+        );
+        assert_eq!(
+            StatusClass::from_status_code(499),
+            Some(StatusClass::ClientError)
+        );
+        assert_eq!(
+            StatusClass::from_status_code(599),
+            Some(StatusClass::ServerError)
+        );
+        assert_eq!(StatusClass::from_status_code(600), None);
+        assert_eq!(StatusClass::ServerError.as_str(), "5xx");
+        assert!(StatusClass::ClientError.is_error());
+        assert!(!StatusClass::Redirection.is_error());
+
+        let mut decoder = ResponseDecoder::<BodyDecoder<RemainingBytesDecoder>>::default();
+        let response = decoder
+            .decode_from_bytes(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n")
+            .unwrap();
+        assert_eq!(response.status_class(), Some(StatusClass::ServerError));
+    }
+
+    #[test]
+    fn content_range_parse_works() {
+        assert_eq!(
+            ContentRange::parse("bytes 0-499/1234"),
+            Some(ContentRange {
+                first: 0,
+                last: 499,
+                complete_length: Some(1234)
+            })
+        );
+        assert_eq!(
+            ContentRange::parse("bytes 500-999/*"),
+            Some(ContentRange {
+                first: 500,
+                last: 999,
+                complete_length: None
+            })
+        );
+        assert_eq!(ContentRange::parse("bytes 500-999/*").unwrap().len(), 500);
+        assert_eq!(ContentRange::parse("bytes */1234"), None);
+        assert_eq!(ContentRange::parse("bytes 10-5/1234"), None);
+        assert_eq!(ContentRange::parse("bytes 0-1234/1234"), None);
+        assert_eq!(ContentRange::parse("items 0-1/2"), None);
+    }
+}
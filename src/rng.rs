@@ -0,0 +1,49 @@
+//! A pseudo random number generator for jitters.
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// SplitMix64 generator.
+///
+/// It is not cryptographically secure, but is good enough for spreading out timings.
+/// The sequence is determined by the seed, so that the timings can be reproduced in tests.
+#[derive(Debug, Clone)]
+pub(crate) struct Rng(u64);
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed)
+    }
+
+    /// Makes a generator with a random seed.
+    pub fn from_entropy() -> Self {
+        Rng(RandomState::new().build_hasher().finish())
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Returns a 53-bit random fraction in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rng_works() {
+        // The first outputs of the reference implementation for the seed `1234567`
+        let mut rng = Rng::new(1_234_567);
+        assert_eq!(rng.next_u64(), 6_457_827_717_110_365_317);
+        assert_eq!(rng.next_u64(), 3_203_168_211_198_807_973);
+
+        let mut rng = Rng::from_entropy();
+        assert!((0..100).all(|_| (0.0..1.0).contains(&rng.next_f64())));
+    }
+}
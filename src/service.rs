@@ -0,0 +1,248 @@
+//! [`tower_service::Service`] adapter (available if the `tower` feature is enabled).
+//!
+//! [`HttpService`] makes the client usable with the layers of the tower ecosystem
+//! (e.g., timeouts and load-shedding).
+//! Since the requests are executed by fibers, each call is spawned onto a fibers executor,
+//! and the returned future can be polled by any `std::future::Future` executor.
+//! Dropping the returned future cancels the request.
+//!
+//! [`tower_service::Service`]: https://docs.rs/tower-service/0.3/tower_service/trait.Service.html
+//! [`HttpService`]: ./struct.HttpService.html
+use fibers::Spawn;
+use futures::executor::{self, Notify, NotifyHandle};
+use futures::sync::oneshot;
+use futures::{Async, Future as Future01};
+use httpcodec::{Request, Response};
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use tower_service::Service;
+use trackable::error::ErrorKindExt;
+use url::Url;
+
+use connection::AcquireConnection;
+use {CancelHandle, Client, Error, ErrorKind};
+
+/// An implementation of [`tower_service::Service`] that sends requests to the server specified by a URL.
+///
+/// Each request is sent as it is (see [`RequestBuilder::send_request`]),
+/// so it should have the header fields required by the server (e.g., `Host`).
+///
+/// `poll_ready` always returns `Poll::Ready(Ok(()))`,
+/// since the requests wait for connections (and for the permits of [`Client::max_concurrency`]) by themselves.
+///
+/// [`tower_service::Service`]: https://docs.rs/tower-service/0.3/tower_service/trait.Service.html
+/// [`RequestBuilder::send_request`]: ../struct.RequestBuilder.html#method.send_request
+/// [`Client::max_concurrency`]: ../struct.Client.html#method.max_concurrency
+#[derive(Debug, Clone)]
+pub struct HttpService<C, S> {
+    client: Client<C>,
+    url: Url,
+    spawner: S,
+}
+impl<C, S> HttpService<C, S>
+where
+    C: AcquireConnection,
+    S: Spawn,
+{
+    /// Makes a new `HttpService` instance.
+    ///
+    /// The requests are sent to the server specified by `url`, and executed on the fibers spawned by `spawner`.
+    pub fn new(client: Client<C>, url: Url, spawner: S) -> Self {
+        HttpService {
+            client,
+            url,
+            spawner,
+        }
+    }
+
+    /// Returns a reference to the underlying client.
+    pub fn client(&self) -> &Client<C> {
+        &self.client
+    }
+
+    /// Returns a mutable reference to the underlying client.
+    pub fn client_mut(&mut self) -> &mut Client<C> {
+        &mut self.client
+    }
+}
+impl<C, S> Service<Request<Vec<u8>>> for HttpService<C, S>
+where
+    C: AcquireConnection + Clone + Send + 'static,
+    C::Connection: Send + 'static,
+    C::Future: Send + 'static,
+    S: Spawn,
+{
+    type Response = Response<Vec<u8>>;
+    type Error = Error;
+    type Future = ServiceFuture<Response<Vec<u8>>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Vec<u8>>) -> Self::Future {
+        // `fibers::sync` channels only wake fibers up, so `futures::sync::oneshot` is used instead
+        let (tx, rx) = oneshot::channel();
+        let future = self.client.request(&self.url).send_request(request);
+        let cancel = future.cancel_handle();
+        self.spawner.spawn(future.then(move |result| {
+            let _ = tx.send(result);
+            Ok(())
+        }));
+        ServiceFuture {
+            rx: executor::spawn(rx),
+            cancel,
+        }
+    }
+}
+
+/// `std::future::Future` that resolves to the result of a request executed by [`HttpService`].
+///
+/// If this is dropped before the request completes, the request is canceled (see [`CancelHandle`]).
+///
+/// [`HttpService`]: ./struct.HttpService.html
+/// [`CancelHandle`]: ../struct.CancelHandle.html
+pub struct ServiceFuture<T> {
+    rx: executor::Spawn<oneshot::Receiver<Result<T, Error>>>,
+    cancel: CancelHandle,
+}
+impl<T> Future for ServiceFuture<T> {
+    type Output = Result<T, Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let notify = NotifyHandle::from(Arc::new(WakerNotify(cx.waker().clone())));
+        match self.get_mut().rx.poll_future_notify(&notify, 0) {
+            Ok(Async::NotReady) => Poll::Pending,
+            Ok(Async::Ready(result)) => Poll::Ready(result),
+            Err(oneshot::Canceled) => {
+                let e = ErrorKind::Other.cause("The request fiber aborted");
+                Poll::Ready(Err(track!(Error::from(e))))
+            }
+        }
+    }
+}
+impl<T> Drop for ServiceFuture<T> {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+impl<T> fmt::Debug for ServiceFuture<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ServiceFuture {{ .. }}")
+    }
+}
+
+/// Wakes the `std` task when the `futures` 0.1 task is notified.
+struct WakerNotify(Waker);
+impl Notify for WakerNotify {
+    fn notify(&self, _id: usize) {
+        self.0.wake_by_ref();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use fibers_global;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::task::Wake;
+    use std::thread::{self, Thread};
+    use std::time::Duration;
+
+    use super::*;
+    use connection::{Mock, Oneshot};
+
+    #[test]
+    fn service_works() {
+        let mock = Mock::new();
+        mock.push_response("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo");
+        let url = Url::parse("http://example.com/").unwrap();
+        let client = Client::new(mock.clone());
+        let mut service = HttpService::new(client, url.clone(), fibers_global::handle());
+
+        let mut request = service
+            .client_mut()
+            .request(&url)
+            .build("GET", Vec::new())
+            .unwrap();
+        request
+            .header_mut()
+            .add_field(::httpcodec::HeaderField::new("X-Foo", "bar").unwrap());
+        let response = block_on(service.call(request)).unwrap();
+        assert_eq!(response.body(), b"foo");
+        assert_eq!(
+            mock.requests(),
+            vec![
+                b"GET / HTTP/1.1\r\nHost: example.com\r\nX-Foo: bar\r\nContent-Length: 0\r\n\r\n"
+                    .to_vec()
+            ]
+        );
+
+        // No response is queued
+        let request = service
+            .client_mut()
+            .request(&url)
+            .build("GET", Vec::new())
+            .unwrap();
+        let e = block_on(service.call(request)).err().unwrap();
+        assert_eq!(*e.kind(), ErrorKind::UnexpectedEos);
+    }
+
+    #[test]
+    fn drop_cancels_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (received_tx, received_rx) = mpsc::channel();
+        let (closed_tx, closed_rx) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf);
+            received_tx.send(()).unwrap();
+
+            stream
+                .set_read_timeout(Some(Duration::from_secs(5)))
+                .unwrap();
+            let closed = matches!(stream.read(&mut buf), Ok(0));
+            closed_tx.send(closed).unwrap();
+        });
+
+        let url = Url::parse(&format!("http://{}/", addr)).unwrap();
+        let client = Client::new(Oneshot);
+        let mut service = HttpService::new(client, url.clone(), fibers_global::handle());
+        let request = service
+            .client_mut()
+            .request(&url)
+            .build("GET", Vec::new())
+            .unwrap();
+        let future = service.call(request);
+        received_rx.recv().unwrap();
+
+        // The connection is closed instead of waiting for the response
+        drop(future);
+        assert!(closed_rx.recv().unwrap());
+    }
+
+    struct ThreadWaker(Thread);
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            thread::park();
+        }
+    }
+}
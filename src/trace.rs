@@ -0,0 +1,304 @@
+//! Structured diagnostics emitted via `tracing` (available if the `tracing` feature is enabled).
+//!
+//! Each request is wrapped in a `http_request` span that has `method`, `host`, `status` and `elapsed_ms` fields,
+//! and events are emitted when a connection is acquired, the request is sent, and the response is received.
+//! The values of the sensitive header fields (e.g., `Authorization`) are never logged.
+//!
+//! Without the feature, everything in this module is a no-op.
+use futures::{Future, Poll};
+#[cfg(any(test, feature = "tracing"))]
+use httpcodec::Header;
+#[cfg(any(test, feature = "tracing"))]
+use std::fmt;
+use std::net::SocketAddr;
+#[cfg(feature = "tracing")]
+use std::time::Instant;
+#[cfg(feature = "tracing")]
+use tracing::{field, Span};
+
+use RequestEvent;
+
+/// The header fields whose values are replaced with `<redacted>` in the logs.
+#[cfg(any(test, feature = "tracing"))]
+const SENSITIVE_HEADERS: &[&str] = &[
+    "Authorization",
+    "Proxy-Authorization",
+    "Cookie",
+    "Set-Cookie",
+];
+
+/// The span that covers the whole execution of a request (including retries).
+#[derive(Debug, Clone)]
+pub(crate) struct RequestSpan {
+    #[cfg(feature = "tracing")]
+    span: Span,
+    #[cfg(feature = "tracing")]
+    start: Instant,
+}
+#[cfg(feature = "tracing")]
+impl RequestSpan {
+    pub fn new(method: &str, host: &str) -> Self {
+        let span = debug_span!(
+            "http_request",
+            method,
+            host,
+            status = field::Empty,
+            elapsed_ms = field::Empty
+        );
+        RequestSpan {
+            span,
+            start: Instant::now(),
+        }
+    }
+
+    /// Records the outcome of the request.
+    pub fn finish(&self, status_code: Option<u16>) {
+        if let Some(status_code) = status_code {
+            self.span.record("status", status_code);
+        }
+        let elapsed = self.start.elapsed();
+        self.span.record("elapsed_ms", elapsed.as_millis() as u64);
+    }
+
+    pub fn instrument<F: Future>(self, future: F) -> Instrumented<F> {
+        Instrumented {
+            inner: future,
+            span: self.span,
+        }
+    }
+}
+#[cfg(not(feature = "tracing"))]
+impl RequestSpan {
+    pub fn new(_method: &str, _host: &str) -> Self {
+        RequestSpan {}
+    }
+
+    pub fn finish(&self, _status_code: Option<u16>) {}
+
+    pub fn instrument<F: Future>(self, future: F) -> Instrumented<F> {
+        Instrumented { inner: future }
+    }
+}
+
+/// `Future` that enters the span of the request whenever it is polled.
+#[derive(Debug)]
+pub(crate) struct Instrumented<F> {
+    inner: F,
+    #[cfg(feature = "tracing")]
+    span: Span,
+}
+impl<F: Future> Future for Instrumented<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        #[cfg(feature = "tracing")]
+        let _enter = self.span.enter();
+        self.inner.poll()
+    }
+}
+
+/// Emits the event notified to the handler registered via `Client::on_event`.
+#[cfg(feature = "tracing")]
+pub(crate) fn event(event: &RequestEvent) {
+    match *event {
+        RequestEvent::Requested {
+            peer_addr,
+            method,
+            target,
+            ref header,
+        } => debug!(
+            %peer_addr,
+            method,
+            target,
+            header = %Redacted(header),
+            "request started"
+        ),
+        RequestEvent::Informational {
+            peer_addr,
+            status_code,
+            reason_phrase,
+            ref header,
+        } => debug!(
+            %peer_addr,
+            status_code,
+            reason_phrase,
+            header = %Redacted(header),
+            "interim response received"
+        ),
+        RequestEvent::Responded {
+            peer_addr,
+            status_code,
+            reason_phrase,
+            ref header,
+        } => debug!(
+            %peer_addr,
+            status_code,
+            reason_phrase,
+            header = %Redacted(header),
+            "response received"
+        ),
+        RequestEvent::Failed { error } => warn!(%error, "request failed"),
+    }
+}
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn event(_event: &RequestEvent) {}
+
+/// Emits the event that a connection to `peer_addr` has been acquired.
+///
+/// `completed_requests` is the number of the requests that the connection has served so far.
+#[cfg(feature = "tracing")]
+pub(crate) fn connected(peer_addr: SocketAddr, completed_requests: usize) {
+    debug!(
+        %peer_addr,
+        reused = completed_requests > 0,
+        completed_requests,
+        "connection acquired"
+    );
+}
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn connected(_peer_addr: SocketAddr, _completed_requests: usize) {}
+
+/// Emits the event that the whole request has been written to the connection.
+#[cfg(feature = "tracing")]
+pub(crate) fn request_sent(peer_addr: SocketAddr, bytes: u64) {
+    debug!(%peer_addr, bytes, "request sent");
+}
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn request_sent(_peer_addr: SocketAddr, _bytes: u64) {}
+
+/// Formats header fields, hiding the values of the sensitive ones.
+#[cfg(any(test, feature = "tracing"))]
+pub(crate) struct Redacted<'a, 'b: 'a>(pub &'a Header<'b>);
+#[cfg(any(test, feature = "tracing"))]
+impl<'a, 'b> fmt::Display for Redacted<'a, 'b> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, field) in self.0.fields().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            if SENSITIVE_HEADERS
+                .iter()
+                .any(|name| name.eq_ignore_ascii_case(field.name()))
+            {
+                write!(f, "{}: <redacted>", field.name())?;
+            } else {
+                write!(f, "{}: {}", field.name(), field.value())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use httpcodec::{HeaderField, HttpVersion, Method, Request, RequestTarget};
+
+    #[cfg(feature = "tracing")]
+    use std::sync::{Arc, Mutex};
+    #[cfg(feature = "tracing")]
+    use tracing::field::{Field, Visit};
+    #[cfg(feature = "tracing")]
+    use tracing::span::{Attributes, Id, Record};
+    #[cfg(feature = "tracing")]
+    use tracing::{subscriber, Event, Metadata, Subscriber};
+
+    use super::*;
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing_works() {
+        use blocking::block_on;
+        use connection::Mock;
+        use url::Url;
+        use Client;
+
+        let mock = Mock::new();
+        mock.push_response("HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nfoo");
+        let mut client = Client::new(mock);
+        let url = Url::parse("http://example.com/").unwrap();
+
+        let recorder = Recorder::default();
+        subscriber::with_default(recorder.clone(), || {
+            let future = client
+                .request(&url)
+                .header_field("Authorization", "secret")
+                .get();
+            block_on(future).unwrap();
+        });
+        let records = recorder.0.lock().unwrap();
+        assert_eq!(
+            records[0],
+            r#"http_request method="GET" host="example.com""#
+        );
+        assert!(records[1].starts_with("event message=connection acquired"));
+        assert!(records[2].ends_with("header=Authorization: <redacted>, Host: example.com"));
+        assert!(records[3].starts_with("event message=request sent"));
+        assert!(records[4].starts_with("event message=response received"));
+        assert_eq!(records[5], "record status=200");
+        assert!(records[6].starts_with("record elapsed_ms="));
+    }
+
+    #[cfg(feature = "tracing")]
+    #[derive(Clone, Default)]
+    struct Recorder(Arc<Mutex<Vec<String>>>);
+    #[cfg(feature = "tracing")]
+    impl Subscriber for Recorder {
+        fn enabled(&self, _metadata: &Metadata) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes) -> Id {
+            let mut record = span.metadata().name().to_owned();
+            span.record(&mut Visitor(&mut record));
+            self.0.lock().unwrap().push(record);
+            Id::from_u64(1)
+        }
+
+        fn record(&self, _span: &Id, values: &Record) {
+            let mut record = "record".to_owned();
+            values.record(&mut Visitor(&mut record));
+            self.0.lock().unwrap().push(record);
+        }
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, event: &Event) {
+            let mut record = "event".to_owned();
+            event.record(&mut Visitor(&mut record));
+            self.0.lock().unwrap().push(record);
+        }
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    #[cfg(feature = "tracing")]
+    struct Visitor<'a>(&'a mut String);
+    #[cfg(feature = "tracing")]
+    impl<'a> Visit for Visitor<'a> {
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            self.0.push_str(&format!(" {}={:?}", field.name(), value));
+        }
+    }
+
+    #[test]
+    fn redacted_works() {
+        let mut request = Request::new(
+            Method::new("GET").unwrap(),
+            RequestTarget::new("/").unwrap(),
+            HttpVersion::V1_1,
+            (),
+        );
+        request
+            .header_mut()
+            .add_field(HeaderField::new("Host", "example.com").unwrap())
+            .add_field(HeaderField::new("authorization", "secret").unwrap())
+            .add_field(HeaderField::new("Cookie", "id=secret").unwrap());
+        assert_eq!(
+            Redacted(&request.header()).to_string(),
+            "Host: example.com, authorization: <redacted>, Cookie: <redacted>"
+        );
+    }
+}